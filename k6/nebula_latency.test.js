@@ -114,11 +114,11 @@ function buildRoomWsUrl(roomId, token) {
 //
 
 function extractTokenFromLoginResponse(res) {
-  // 1) Try JSON: { token } or { access_token }
+  // 1) Try JSON: { accessToken }, { token } or { access_token }
   try {
     const body = res.json();
-    if (body && (body.token || body.access_token)) {
-      return body.token || body.access_token;
+    if (body && (body.accessToken || body.token || body.access_token)) {
+      return body.accessToken || body.token || body.access_token;
     }
   } catch (_) {
     // ignore, will try plain text
@@ -373,7 +373,7 @@ export function httpEndpointLatencyScenario(data) {
   });
 
   // /rooms/{id}/messages GET
-  const msgRes = http.get(apiUrl(`/rooms/${roomId}/messages?page=1&page_size=20`), {
+  const msgRes = http.get(apiUrl(`/rooms/${roomId}/messages?page_size=20`), {
     headers: defaultHeaders(token),
     tags: { endpoint: 'room_messages_get' },
   });
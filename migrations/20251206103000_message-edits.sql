@@ -0,0 +1,17 @@
+-- Add migration script here
+
+-- Up migration
+ALTER TABLE messages
+    ADD COLUMN edited_at  TIMESTAMPTZ NULL,
+    ADD COLUMN deleted_at TIMESTAMPTZ NULL;
+
+CREATE TABLE message_edits (
+    id               UUID PRIMARY KEY DEFAULT gen_random_uuid(),
+    message_id       UUID NOT NULL REFERENCES messages(id) ON DELETE CASCADE,
+    previous_content TEXT NOT NULL,
+    edited_by        UUID NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+    edited_at        TIMESTAMPTZ NOT NULL DEFAULT now()
+);
+
+CREATE INDEX idx_message_edits_message_edited_at
+    ON message_edits (message_id, edited_at DESC);
@@ -0,0 +1,20 @@
+-- Refresh tokens are stored hashed, every rotation keeps the family so a reused token can
+-- revoke the whole chain
+CREATE TABLE refresh_tokens (
+    id          UUID PRIMARY KEY,
+    user_id     UUID NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+    family_id   UUID NOT NULL,
+    token_hash  TEXT NOT NULL UNIQUE,
+    expires_at  TIMESTAMPTZ NOT NULL,
+    created_at  TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+    revoked_at  TIMESTAMPTZ,
+    replaced_by UUID
+);
+
+CREATE INDEX idx_refresh_tokens_family ON refresh_tokens(family_id);
+
+-- Access tokens killed before they expire, rows can be dropped once expires_at has passed
+CREATE TABLE revoked_access_tokens (
+    jti        UUID PRIMARY KEY,
+    expires_at TIMESTAMPTZ NOT NULL
+);
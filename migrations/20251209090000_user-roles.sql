@@ -0,0 +1,4 @@
+-- Global role of the user, independent of the role it has in each room
+ALTER TABLE users
+    ADD COLUMN role TEXT NOT NULL DEFAULT 'user',
+    ADD CONSTRAINT chk_user_role CHECK (role IN ('user', 'admin'));
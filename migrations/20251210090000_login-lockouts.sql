@@ -0,0 +1,11 @@
+-- Audit trail of the lockouts triggered by failed logins, either an account or a client address
+CREATE TABLE login_lockouts (
+    id              UUID PRIMARY KEY,
+    user_id         UUID REFERENCES users(id) ON DELETE SET NULL,
+    ip_address      TEXT,
+    failed_attempts INTEGER NOT NULL,
+    locked_until    TIMESTAMPTZ NOT NULL,
+    created_at      TIMESTAMPTZ NOT NULL DEFAULT NOW()
+);
+
+CREATE INDEX idx_login_lockouts_user ON login_lockouts(user_id);
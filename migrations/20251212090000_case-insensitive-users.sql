@@ -0,0 +1,31 @@
+-- Usernames and emails are unique regardless of case, lookups go through these indexes too.
+-- The previous constraints were case sensitive, so accounts whose usernames or emails differ only
+-- in case have to be renamed or merged by hand first. Rather than failing on the index with a
+-- bare unique violation, the migration stops and lists the ids of the clashing accounts
+DO $$
+DECLARE
+    clashes TEXT;
+BEGIN
+    SELECT string_agg(format('%s (%s)', field, ids), '; ')
+    INTO clashes
+    FROM (
+        SELECT 'username' AS field, string_agg(id::text, ', ' ORDER BY created_at) AS ids
+        FROM users
+        GROUP BY LOWER(username)
+        HAVING COUNT(*) > 1
+        UNION ALL
+        SELECT 'email' AS field, string_agg(id::text, ', ' ORDER BY created_at) AS ids
+        FROM users
+        GROUP BY LOWER(email)
+        HAVING COUNT(*) > 1
+    ) AS duplicates;
+
+    IF clashes IS NOT NULL THEN
+        RAISE EXCEPTION 'Accounts differ only in the case of their username or email: %', clashes
+            USING HINT = 'Rename or merge these accounts so every username and email is unique regardless of case, then run the migrations again';
+    END IF;
+END
+$$;
+
+CREATE UNIQUE INDEX users_username_lower_key ON users (LOWER(username));
+CREATE UNIQUE INDEX users_email_lower_key ON users (LOWER(email));
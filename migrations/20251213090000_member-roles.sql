@@ -0,0 +1,4 @@
+-- Admins and moderators share some of the permissions of the owner
+ALTER TABLE room_members DROP CONSTRAINT chk_room_member_role;
+ALTER TABLE room_members
+    ADD CONSTRAINT chk_room_member_role CHECK (role IN ('owner', 'admin', 'moderator', 'member'));
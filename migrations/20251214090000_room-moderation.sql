@@ -0,0 +1,33 @@
+-- Bans and mutes in force, a ban without `expires_at` is permanent
+CREATE TABLE room_bans (
+    room_id     UUID NOT NULL REFERENCES rooms(id) ON DELETE CASCADE,
+    user_id     UUID NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+    expires_at  TIMESTAMPTZ NULL,
+
+    PRIMARY KEY (room_id, user_id)
+);
+
+CREATE TABLE room_mutes (
+    room_id     UUID NOT NULL REFERENCES rooms(id) ON DELETE CASCADE,
+    user_id     UUID NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+    expires_at  TIMESTAMPTZ NOT NULL,
+
+    PRIMARY KEY (room_id, user_id)
+);
+
+-- Every moderation action, kept after the ban or mute it applied is over
+CREATE TABLE moderation_log (
+    id            UUID PRIMARY KEY,
+    room_id       UUID NOT NULL REFERENCES rooms(id) ON DELETE CASCADE,
+    target_id     UUID NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+    moderator_id  UUID NULL REFERENCES users(id) ON DELETE SET NULL,
+    action        TEXT NOT NULL,
+    reason        TEXT NULL,
+    expires_at    TIMESTAMPTZ NULL,
+    created_at    TIMESTAMPTZ NOT NULL DEFAULT now(),
+
+    CONSTRAINT chk_moderation_action
+        CHECK (action IN ('kick', 'ban', 'unban', 'mute', 'unmute'))
+);
+
+CREATE INDEX idx_moderation_log_room ON moderation_log (room_id, created_at DESC);
@@ -0,0 +1,3 @@
+-- Rooms left by their last member are archived rather than deleted, keeping their history.
+-- Archived rooms are hidden from the listings and can't be joined anymore
+ALTER TABLE rooms ADD COLUMN archived_at TIMESTAMPTZ NULL;
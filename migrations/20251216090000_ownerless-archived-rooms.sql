@@ -0,0 +1,8 @@
+-- Deleting an account hands its rooms over or archives them. Archived rooms outlive the account of
+-- their last owner, so they are the only rooms that can be left without one
+ALTER TABLE rooms ALTER COLUMN created_by DROP NOT NULL;
+
+ALTER TABLE rooms
+    DROP CONSTRAINT rooms_created_by_fkey,
+    ADD CONSTRAINT rooms_created_by_fkey FOREIGN KEY (created_by) REFERENCES users(id) ON DELETE SET NULL,
+    ADD CONSTRAINT chk_room_owned_unless_archived CHECK (created_by IS NOT NULL OR archived_at IS NOT NULL);
@@ -0,0 +1,9 @@
+-- Deleting an account deletes its messages like their sender would, keeping them as tombstones so
+-- the history, its cursors and the read markers pointing at them stay valid. Only deleted
+-- messages can be left without a sender
+ALTER TABLE messages ALTER COLUMN sender_id DROP NOT NULL;
+
+ALTER TABLE messages
+    DROP CONSTRAINT messages_sender_id_fkey,
+    ADD CONSTRAINT messages_sender_id_fkey FOREIGN KEY (sender_id) REFERENCES users(id) ON DELETE SET NULL,
+    ADD CONSTRAINT chk_message_sent_unless_deleted CHECK (sender_id IS NOT NULL OR deleted_at IS NOT NULL);
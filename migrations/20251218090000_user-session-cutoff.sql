@@ -0,0 +1,3 @@
+-- Access tokens of a user issued before this time are refused, so revoking every session of the
+-- user also logs out the access tokens still running, not only the refresh tokens
+ALTER TABLE users ADD COLUMN sessions_revoked_at TIMESTAMPTZ;
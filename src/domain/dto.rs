@@ -2,17 +2,31 @@ use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
 use uuid::Uuid;
 
-use crate::domain::room::RoomVisibility;
+use crate::domain::room::{MemberRole, RoomVisibility};
 
+/// `content` is empty once the message is deleted. The sender is `None` once its account is
+/// deleted, which deletes the message too
 #[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub struct MessageView {
     pub id: Uuid,
     pub room_id: Uuid,
-    pub sender_id: Uuid,
-    pub sender_username: String,
+    pub sender_id: Option<Uuid>,
+    pub sender_username: Option<String>,
     pub content: String,
     pub created_at: DateTime<Utc>,
+    pub edited_at: Option<DateTime<Utc>>,
+    pub deleted_at: Option<DateTime<Utc>>,
+}
+
+/// Member of a room as the other members see it, without the contact details of the account
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct MemberView {
+    pub user_id: Uuid,
+    pub username: String,
+    pub role: MemberRole,
+    pub joined_at: DateTime<Utc>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -27,3 +41,31 @@ pub struct RoomSummary {
     pub member_count: i64,
     pub is_member: bool,
 }
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct UserRoom {
+    #[serde(flatten)]
+    pub room: RoomSummary,
+    pub unread_count: i64,
+}
+
+/// One page of a room history, newest message first. `next_cursor` is passed as `before` to get
+/// older messages and `prev_cursor` as `after` to get newer ones, both are `None` at the ends
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct MessagePage {
+    pub messages: Vec<MessageView>,
+    pub next_cursor: Option<Uuid>,
+    pub prev_cursor: Option<Uuid>,
+}
+
+/// Tokens handed out on login and refresh, `expires_in` is the lifetime of the access token in
+/// seconds
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct TokenPair {
+    pub access_token: String,
+    pub refresh_token: String,
+    pub expires_in: i64,
+}
@@ -0,0 +1,126 @@
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use crate::domain::{
+    dto::MessageView,
+    room::{
+        MembershipChange, ModerationEntry, RoleChange, Room, RoomDeletion, RoomReadState,
+        TypingIndicator,
+    },
+};
+
+/// Version of the event format published by this build, bumped on breaking changes to a variant
+pub const EVENT_VERSION: u16 = 1;
+
+/// Events fanned out to the sockets connected to a room
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "camelCase")]
+pub enum RoomEvent {
+    Message(MessageView),
+    /// Carries the message as it is after the edit, so clients can replace it in place
+    MessageEdited(MessageView),
+    /// Carries the message with its `deleted_at` set and its content cleared
+    MessageDeleted(MessageView),
+    Read(RoomReadState),
+    Typing(TypingIndicator),
+    Membership(MembershipChange),
+    RoleChanged(RoleChange),
+    Moderation(ModerationEntry),
+    /// Carries the room as it is after the change, without its password hash
+    RoomUpdated(Room),
+    RoomDeleted(RoomDeletion),
+}
+
+impl RoomEvent {
+    pub fn room_id(&self) -> Uuid {
+        match self {
+            RoomEvent::Message(message)
+            | RoomEvent::MessageEdited(message)
+            | RoomEvent::MessageDeleted(message) => message.room_id,
+            RoomEvent::Read(read_state) => read_state.room_id,
+            RoomEvent::Typing(typing) => typing.room_id,
+            RoomEvent::Membership(change) => change.room_id,
+            RoomEvent::RoleChanged(change) => change.room_id,
+            RoomEvent::Moderation(entry) => entry.room_id,
+            RoomEvent::RoomUpdated(room) => room.id,
+            RoomEvent::RoomDeleted(deletion) => deletion.room_id,
+        }
+    }
+
+    /// User the event is also published to, besides the room, so the sockets of the user follow
+    /// the rooms it joins and leaves or is removed from
+    pub fn user_topic(&self) -> Option<Uuid> {
+        match self {
+            RoomEvent::Membership(change) => Some(change.user_id),
+            RoomEvent::Moderation(entry) => Some(entry.target_id),
+            _ => None,
+        }
+    }
+}
+
+/// How an event travels through the broker and the sockets. The version goes in a `v` field next
+/// to the `type` tag, so clients that do not know about it read the events as before, and events
+/// published without it are taken as version 1
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct EventEnvelope {
+    #[serde(rename = "v", default = "first_version")]
+    pub version: u16,
+    #[serde(flatten)]
+    pub event: RoomEvent,
+}
+
+fn first_version() -> u16 {
+    1
+}
+
+impl EventEnvelope {
+    pub fn new(event: RoomEvent) -> EventEnvelope {
+        EventEnvelope {
+            version: EVENT_VERSION,
+            event,
+        }
+    }
+}
+
+impl From<RoomEvent> for EventEnvelope {
+    fn from(event: RoomEvent) -> Self {
+        EventEnvelope::new(event)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use uuid::Uuid;
+
+    use crate::domain::{
+        event::{EVENT_VERSION, EventEnvelope, RoomEvent},
+        room::TypingIndicator,
+    };
+
+    fn typing_event() -> RoomEvent {
+        RoomEvent::Typing(TypingIndicator {
+            room_id: Uuid::new_v4(),
+            user_id: Uuid::new_v4(),
+            is_typing: true,
+        })
+    }
+
+    #[test]
+    fn envelope_keeps_the_event_fields_at_the_top_level() {
+        let json = serde_json::to_value(EventEnvelope::new(typing_event())).unwrap();
+
+        assert_eq!(json["v"], EVENT_VERSION);
+        assert_eq!(json["type"], "typing");
+        assert_eq!(json["isTyping"], true);
+    }
+
+    #[test]
+    fn events_without_version_are_read_as_the_first_one() {
+        let json = serde_json::to_string(&typing_event()).unwrap();
+
+        let envelope: EventEnvelope = serde_json::from_str(&json).unwrap();
+
+        assert_eq!(envelope.version, 1);
+        assert!(matches!(envelope.event, RoomEvent::Typing(_)));
+    }
+}
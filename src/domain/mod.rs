@@ -1,3 +1,4 @@
 pub mod dto;
+pub mod event;
 pub mod room;
 pub mod user;
@@ -2,7 +2,7 @@ use std::fmt::Display;
 
 use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
-use sqlx::{FromRow, prelude::Type};
+use sqlx::FromRow;
 use uuid::Uuid;
 
 #[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
@@ -36,10 +36,13 @@ pub struct Room {
     pub created_at: DateTime<Utc>,
 }
 
-#[derive(Debug, Clone, Copy, Serialize, Deserialize, sqlx::Type)]
+/// Role of a member in a room, from the highest to the lowest
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq, sqlx::Type)]
 #[serde(rename_all = "lowercase")]
 pub enum MemberRole {
     Owner,
+    Admin,
+    Moderator,
     Member,
 }
 
@@ -47,11 +50,63 @@ impl Display for MemberRole {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
             MemberRole::Owner => write!(f, "owner"),
+            MemberRole::Admin => write!(f, "admin"),
+            MemberRole::Moderator => write!(f, "moderator"),
             MemberRole::Member => write!(f, "member"),
         }
     }
 }
 
+/// What the members of a room can do, depending on their role
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RoomPermission {
+    PostMessages,
+    DeleteOthersMessages,
+    KickMembers,
+    MuteMembers,
+    BanMembers,
+    EditRoom,
+    /// Promoting and demoting the members ranked below
+    ManageRoles,
+    ReadModerationLog,
+    /// Handing the room over to another member, who becomes its only owner
+    TransferOwnership,
+    DeleteRoom,
+}
+
+impl MemberRole {
+    /// Permission matrix of the rooms
+    pub fn can(self, permission: RoomPermission) -> bool {
+        match permission {
+            RoomPermission::PostMessages => true,
+            RoomPermission::DeleteOthersMessages
+            | RoomPermission::KickMembers
+            | RoomPermission::MuteMembers
+            | RoomPermission::ReadModerationLog => self != MemberRole::Member,
+            RoomPermission::BanMembers | RoomPermission::EditRoom | RoomPermission::ManageRoles => {
+                matches!(self, MemberRole::Owner | MemberRole::Admin)
+            }
+            RoomPermission::TransferOwnership | RoomPermission::DeleteRoom => {
+                self == MemberRole::Owner
+            }
+        }
+    }
+
+    /// Members can only act on the members whose role is below theirs
+    pub fn outranks(self, other: MemberRole) -> bool {
+        self.rank() > other.rank()
+    }
+
+    fn rank(self) -> u8 {
+        match self {
+            MemberRole::Owner => 3,
+            MemberRole::Admin => 2,
+            MemberRole::Moderator => 1,
+            MemberRole::Member => 0,
+        }
+    }
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize, FromRow)]
 #[serde(rename_all = "camelCase")]
 pub struct RoomMember {
@@ -71,6 +126,26 @@ pub struct Message {
     pub created_at: DateTime<Utc>,
 }
 
+/// Content a message had before one of its edits
+#[derive(Debug, Clone, Serialize, Deserialize, FromRow)]
+#[serde(rename_all = "camelCase")]
+pub struct MessageEdit {
+    pub id: Uuid,
+    pub message_id: Uuid,
+    pub previous_content: String,
+    pub edited_by: Uuid,
+    pub edited_at: DateTime<Utc>,
+}
+
+/// Message a page of the room history is positioned against, `Latest` starts from the newest one
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum MessageAnchor {
+    Latest,
+    Before(Uuid),
+    After(Uuid),
+    Around(Uuid),
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize, FromRow)]
 #[serde(rename_all = "camelCase")]
 pub struct RoomReadState {
@@ -79,3 +154,163 @@ pub struct RoomReadState {
     pub last_read_message_id: Option<Uuid>,
     pub last_read_at: DateTime<Utc>,
 }
+
+#[derive(Debug, Clone, Serialize, Deserialize, FromRow)]
+#[serde(rename_all = "camelCase")]
+pub struct RoomUnreadCount {
+    pub room_id: Uuid,
+    pub unread_count: i64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct TypingIndicator {
+    pub room_id: Uuid,
+    pub user_id: Uuid,
+    pub is_typing: bool,
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum MembershipAction {
+    Joined,
+    Left,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct MembershipChange {
+    pub room_id: Uuid,
+    pub user_id: Uuid,
+    pub action: MembershipAction,
+}
+
+/// What a moderator did to a user of a room
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq, sqlx::Type)]
+#[serde(rename_all = "lowercase")]
+#[sqlx(type_name = "text", rename_all = "lowercase")]
+pub enum ModerationAction {
+    Kick,
+    Ban,
+    Unban,
+    Mute,
+    Unmute,
+}
+
+impl Display for ModerationAction {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ModerationAction::Kick => write!(f, "kick"),
+            ModerationAction::Ban => write!(f, "ban"),
+            ModerationAction::Unban => write!(f, "unban"),
+            ModerationAction::Mute => write!(f, "mute"),
+            ModerationAction::Unmute => write!(f, "unmute"),
+        }
+    }
+}
+
+/// Entry of the moderation log of a room, also published to the room and to the target. Bans
+/// without `expires_at` are permanent, mutes always have one
+#[derive(Debug, Clone, Serialize, Deserialize, FromRow)]
+#[serde(rename_all = "camelCase")]
+pub struct ModerationEntry {
+    pub id: Uuid,
+    pub room_id: Uuid,
+    pub target_id: Uuid,
+    /// `None` once the moderator deleted their account
+    pub moderator_id: Option<Uuid>,
+    pub action: ModerationAction,
+    pub reason: Option<String>,
+    pub expires_at: Option<DateTime<Utc>>,
+    pub created_at: DateTime<Utc>,
+}
+
+impl ModerationEntry {
+    /// Whether the action takes the target out of the room
+    pub fn removes_target(&self) -> bool {
+        matches!(self.action, ModerationAction::Kick | ModerationAction::Ban)
+    }
+}
+
+/// Ban or mute in force, until `expires_at` when there is one
+#[derive(Debug, Clone, Serialize, Deserialize, FromRow)]
+#[serde(rename_all = "camelCase")]
+pub struct RoomSanction {
+    pub room_id: Uuid,
+    pub user_id: Uuid,
+    pub expires_at: Option<DateTime<Utc>>,
+}
+
+/// Published when a member is promoted or demoted
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RoleChange {
+    pub room_id: Uuid,
+    pub user_id: Uuid,
+    pub role: MemberRole,
+    pub changed_by: Uuid,
+}
+
+/// Published when the owner deletes a room, the sockets subscribed to it are closed afterwards
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RoomDeletion {
+    pub room_id: Uuid,
+    pub deleted_by: Uuid,
+}
+
+/// Room of a deleted account, handed over to its oldest remaining member or archived when
+/// `new_owner` is `None`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RoomHandover {
+    pub room_id: Uuid,
+    pub new_owner: Option<Uuid>,
+}
+
+#[cfg(test)]
+mod test {
+    use crate::domain::room::{MemberRole, RoomPermission};
+
+    #[test]
+    fn every_role_can_post_but_only_the_owner_can_delete_the_room() {
+        let roles = [
+            MemberRole::Owner,
+            MemberRole::Admin,
+            MemberRole::Moderator,
+            MemberRole::Member,
+        ];
+
+        assert!(
+            roles
+                .iter()
+                .all(|role| role.can(RoomPermission::PostMessages))
+        );
+        assert_eq!(
+            roles.map(|role| role.can(RoomPermission::DeleteRoom)),
+            [true, false, false, false]
+        );
+        assert_eq!(
+            roles.map(|role| role.can(RoomPermission::TransferOwnership)),
+            [true, false, false, false]
+        );
+    }
+
+    #[test]
+    fn moderators_can_kick_but_not_ban() {
+        assert!(MemberRole::Moderator.can(RoomPermission::KickMembers));
+        assert!(MemberRole::Moderator.can(RoomPermission::MuteMembers));
+        assert!(MemberRole::Moderator.can(RoomPermission::DeleteOthersMessages));
+        assert!(!MemberRole::Moderator.can(RoomPermission::BanMembers));
+        assert!(!MemberRole::Moderator.can(RoomPermission::EditRoom));
+        assert!(!MemberRole::Member.can(RoomPermission::KickMembers));
+        assert!(MemberRole::Admin.can(RoomPermission::ManageRoles));
+    }
+
+    #[test]
+    fn roles_only_outrank_the_ones_below() {
+        assert!(MemberRole::Owner.outranks(MemberRole::Admin));
+        assert!(MemberRole::Admin.outranks(MemberRole::Moderator));
+        assert!(!MemberRole::Admin.outranks(MemberRole::Admin));
+        assert!(!MemberRole::Moderator.outranks(MemberRole::Admin));
+    }
+}
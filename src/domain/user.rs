@@ -1,8 +1,12 @@
+use std::fmt::Display;
+
 use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
 use sqlx::FromRow;
 use uuid::Uuid;
 
+use crate::domain::{dto::MessageView, room::RoomHandover};
+
 #[derive(Debug, Clone, Serialize, Deserialize, FromRow)]
 #[serde(rename_all = "camelCase")]
 pub struct User {
@@ -13,6 +17,93 @@ pub struct User {
     #[serde(skip_serializing)]
     pub password_hash: String,
 
+    pub role: UserRole,
+
+    /// Set once the user follows the verification email
+    pub email_verified_at: Option<DateTime<Utc>>,
     pub created_at: DateTime<Utc>,
     pub updated_at: DateTime<Utc>,
 }
+
+/// Role of the user across the whole service, room roles are separate
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize, PartialEq, Eq, sqlx::Type)]
+#[serde(rename_all = "lowercase")]
+#[sqlx(type_name = "text", rename_all = "lowercase")]
+pub enum UserRole {
+    #[default]
+    User,
+    Admin,
+}
+
+impl Display for UserRole {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            UserRole::User => write!(f, "user"),
+            UserRole::Admin => write!(f, "admin"),
+        }
+    }
+}
+
+/// User behind a validated access token, read from its claims without touching the database
+#[derive(Debug, Clone)]
+pub struct AuthenticatedUser {
+    pub id: Uuid,
+    pub username: String,
+    pub role: UserRole,
+}
+
+/// Refresh token as stored, only the hash of the token handed to the client is kept. Tokens
+/// issued by rotating another one share its family
+#[derive(Debug, Clone, FromRow)]
+pub struct RefreshToken {
+    pub id: Uuid,
+    pub user_id: Uuid,
+    pub family_id: Uuid,
+    pub token_hash: String,
+    pub expires_at: DateTime<Utc>,
+    pub created_at: DateTime<Utc>,
+
+    /// Set once the token is rotated or logged out, using it again means it leaked
+    pub revoked_at: Option<DateTime<Utc>>,
+}
+
+/// What a token sent by email is good for, every token works once
+#[derive(Debug, Clone, Copy, PartialEq, Eq, sqlx::Type)]
+#[sqlx(type_name = "text", rename_all = "snake_case")]
+pub enum UserTokenPurpose {
+    EmailVerification,
+    PasswordReset,
+}
+
+/// Token sent by email, stored hashed
+#[derive(Debug, Clone, FromRow)]
+pub struct UserToken {
+    pub id: Uuid,
+    pub user_id: Uuid,
+    pub purpose: UserTokenPurpose,
+    pub token_hash: String,
+    pub expires_at: DateTime<Utc>,
+    pub created_at: DateTime<Utc>,
+    pub used_at: Option<DateTime<Utc>>,
+}
+
+/// Audit record of a lockout, either of an account or of a client address
+#[derive(Debug, Clone, FromRow)]
+pub struct LoginLockout {
+    pub id: Uuid,
+    pub user_id: Option<Uuid>,
+    pub ip_address: Option<String>,
+    pub failed_attempts: i32,
+    pub locked_until: DateTime<Utc>,
+    pub created_at: DateTime<Utc>,
+}
+
+/// What deleting an account changed in the rooms of the user, for their members to be told
+#[derive(Debug, Clone, Default)]
+pub struct AccountDeletion {
+    /// Every room the user was a member of
+    pub room_ids: Vec<Uuid>,
+    pub handovers: Vec<RoomHandover>,
+    /// Messages of the user, deleted with the account
+    pub deleted_messages: Vec<MessageView>,
+}
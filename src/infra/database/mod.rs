@@ -1,13 +1,22 @@
 use anyhow::{Context, Result};
 use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
-use sqlx::{FromRow, PgPool, migrate::Migrator, postgres::PgPoolOptions};
+use sqlx::{
+    FromRow, PgConnection, PgPool, error::ErrorKind, migrate::Migrator, postgres::PgPoolOptions,
+};
 use uuid::Uuid;
 
 use crate::{
     domain::{
-        room::{Message, Room, RoomMember, RoomVisibility},
-        user::User,
+        dto::{MemberView, MessageView, RoomSummary},
+        room::{
+            MemberRole, Message, MessageEdit, ModerationAction, ModerationEntry, Room,
+            RoomHandover, RoomMember, RoomReadState, RoomSanction, RoomUnreadCount, RoomVisibility,
+        },
+        user::{
+            AccountDeletion, LoginLockout, RefreshToken, User, UserRole, UserToken,
+            UserTokenPurpose,
+        },
     },
     use_cases::{
         room_database::{RoomDatabase, RoomDatabaseError, RoomDatabaseResult},
@@ -34,52 +43,490 @@ impl PostgresDatabase {
     }
 }
 
+/// Why a query failed, told apart so the use cases can answer each case differently
+enum QueryFailure {
+    NotFound,
+    UniqueViolation(String),
+    ForeignKeyViolation(String),
+    Unavailable(String),
+    Internal(String),
+}
+
+impl From<sqlx::Error> for QueryFailure {
+    fn from(err: sqlx::Error) -> Self {
+        match &err {
+            sqlx::Error::RowNotFound => QueryFailure::NotFound,
+            sqlx::Error::Database(db_err) => {
+                let constraint = db_err.constraint().unwrap_or_default().to_string();
+                // Connection exceptions, shutdowns and too many connections
+                let unavailable = db_err.code().is_some_and(|code| {
+                    code.starts_with("08") || matches!(code.as_ref(), "53300" | "57P01" | "57P03")
+                });
+
+                match db_err.kind() {
+                    ErrorKind::UniqueViolation => QueryFailure::UniqueViolation(constraint),
+                    ErrorKind::ForeignKeyViolation => QueryFailure::ForeignKeyViolation(constraint),
+                    _ if unavailable => QueryFailure::Unavailable(err.to_string()),
+                    _ => QueryFailure::Internal(err.to_string()),
+                }
+            }
+            sqlx::Error::Io(_)
+            | sqlx::Error::Tls(_)
+            | sqlx::Error::PoolTimedOut
+            | sqlx::Error::PoolClosed
+            | sqlx::Error::WorkerCrashed => QueryFailure::Unavailable(err.to_string()),
+            _ => QueryFailure::Internal(err.to_string()),
+        }
+    }
+}
+
+impl From<sqlx::Error> for UserDatabaseError {
+    fn from(err: sqlx::Error) -> Self {
+        match QueryFailure::from(err) {
+            QueryFailure::NotFound => UserDatabaseError::NotFound,
+            // The unique constraints of the user are named after the field they cover
+            QueryFailure::UniqueViolation(constraint) if constraint.contains("username") => {
+                UserDatabaseError::UsernameTaken
+            }
+            QueryFailure::UniqueViolation(constraint) if constraint.contains("email") => {
+                UserDatabaseError::EmailTaken
+            }
+            QueryFailure::UniqueViolation(constraint) => {
+                UserDatabaseError::UniqueViolation(constraint)
+            }
+            QueryFailure::ForeignKeyViolation(constraint) => {
+                UserDatabaseError::ForeignKeyViolation(constraint)
+            }
+            QueryFailure::Unavailable(err) => UserDatabaseError::Unavailable(err),
+            QueryFailure::Internal(err) => UserDatabaseError::InternalDBError(err),
+        }
+    }
+}
+
+impl From<sqlx::Error> for RoomDatabaseError {
+    fn from(err: sqlx::Error) -> Self {
+        match QueryFailure::from(err) {
+            QueryFailure::NotFound => RoomDatabaseError::NotFound,
+            QueryFailure::UniqueViolation(constraint) => {
+                RoomDatabaseError::UniqueViolation(constraint)
+            }
+            QueryFailure::ForeignKeyViolation(constraint) => {
+                RoomDatabaseError::ForeignKeyViolation(constraint)
+            }
+            QueryFailure::Unavailable(err) => RoomDatabaseError::Unavailable(err),
+            QueryFailure::Internal(err) => RoomDatabaseError::InternalDBError(err),
+        }
+    }
+}
+
 impl UserDatabase for PostgresDatabase {
     async fn create_user(&self, user: User) -> UserDatabaseResult<()> {
         sqlx::query!(
-            "INSERT INTO users (id, username, email, password_hash) VALUES ($1, $2, $3, $4)",
+            "INSERT INTO users (id, username, email, password_hash, role) VALUES ($1, $2, $3, $4, $5)",
             user.id,
             user.username,
             user.email,
-            user.password_hash
+            user.password_hash,
+            user.role as UserRole
         )
         .execute(&self.pool)
         .await
-        .map_err(|err| UserDatabaseError::InternalDBError(err.to_string()))
+        .map_err(UserDatabaseError::from)
         .map(|_| ())
     }
 
     async fn get_user_by_username(&self, username: String) -> UserDatabaseResult<User> {
         sqlx::query_as!(
             User,
-            "SELECT id, username, email, created_at, updated_at, password_hash FROM users WHERE username = $1",
+            "SELECT id, username, email, role AS \"role: UserRole\", email_verified_at, created_at, updated_at, password_hash FROM users WHERE LOWER(username) = LOWER($1)",
             username
         )
         .fetch_one(&self.pool)
         .await
-        .map_err(|err| UserDatabaseError::InternalDBError(err.to_string()))
+        .map_err(UserDatabaseError::from)
     }
 
     async fn get_user_by_email(&self, email: String) -> UserDatabaseResult<User> {
         sqlx::query_as!(
             User,
-            "SELECT password_hash, id, username, email, created_at, updated_at FROM users WHERE email = $1",
+            "SELECT password_hash, id, username, email, role AS \"role: UserRole\", email_verified_at, created_at, updated_at FROM users WHERE LOWER(email) = LOWER($1)",
             email
         )
         .fetch_one(&self.pool)
         .await
-        .map_err(|err| UserDatabaseError::InternalDBError(err.to_string()))
+        .map_err(UserDatabaseError::from)
     }
 
     async fn get_user_by_id(&self, id: Uuid) -> UserDatabaseResult<User> {
         sqlx::query_as!(
             User,
-            "SELECT password_hash, id, username, email, created_at, updated_at FROM users WHERE id = $1",
+            "SELECT password_hash, id, username, email, role AS \"role: UserRole\", email_verified_at, created_at, updated_at FROM users WHERE id = $1",
             id
         )
         .fetch_one(&self.pool)
         .await
-        .map_err(|err| UserDatabaseError::InternalDBError(err.to_string()))
+        .map_err(UserDatabaseError::from)
+    }
+
+    async fn update_user(&self, user: User) -> UserDatabaseResult<User> {
+        sqlx::query_as!(
+            User,
+            r#"
+            UPDATE users SET username = $2, email = $3, email_verified_at = $4, updated_at = NOW()
+            WHERE id = $1
+            RETURNING password_hash, id, username, email, role AS "role: UserRole", email_verified_at,
+                created_at, updated_at
+            "#,
+            user.id,
+            user.username,
+            user.email,
+            user.email_verified_at
+        )
+        .fetch_one(&self.pool)
+        .await
+        .map_err(UserDatabaseError::from)
+    }
+
+    async fn delete_user(&self, user_id: Uuid) -> UserDatabaseResult<AccountDeletion> {
+        let mut tx = self.pool.begin().await.map_err(UserDatabaseError::from)?;
+
+        // Archived rooms lose their owner with the account, the others are handed over first
+        let owned_rooms = sqlx::query_scalar!(
+            "SELECT id FROM rooms WHERE created_by = $1 AND archived_at IS NULL FOR UPDATE",
+            user_id
+        )
+        .fetch_all(&mut *tx)
+        .await
+        .map_err(UserDatabaseError::from)?;
+
+        let mut handovers = Vec::with_capacity(owned_rooms.len());
+        for room_id in owned_rooms {
+            let new_owner = hand_over_room(&mut tx, room_id, user_id)
+                .await
+                .map_err(UserDatabaseError::from)?;
+
+            handovers.push(RoomHandover { room_id, new_owner });
+        }
+
+        let room_ids = sqlx::query_scalar!(
+            "SELECT room_id FROM room_members WHERE user_id = $1 ORDER BY joined_at",
+            user_id
+        )
+        .fetch_all(&mut *tx)
+        .await
+        .map_err(UserDatabaseError::from)?;
+
+        // Kept as tombstones, like the messages deleted one by one, so the history and the read
+        // markers pointing at them stay valid. Only the ones not deleted yet need announcing
+        let deleted_messages = sqlx::query_as!(
+            MessageView,
+            r#"WITH deleted AS (
+                UPDATE messages SET deleted_at = now()
+                WHERE sender_id = $1 AND deleted_at IS NULL
+                RETURNING id, room_id, created_at, edited_at, deleted_at
+             )
+             SELECT id, room_id, NULL::uuid AS "sender_id?", NULL::text AS "sender_username?",
+                    '' AS "content!", created_at, edited_at, deleted_at
+             FROM deleted
+             ORDER BY created_at, id"#,
+            user_id
+        )
+        .fetch_all(&mut *tx)
+        .await
+        .map_err(UserDatabaseError::from)?;
+
+        // Unlike a deletion by the sender, the content and its earlier versions go with the account
+        sqlx::query!(
+            "DELETE FROM message_edits
+             WHERE message_id IN (SELECT id FROM messages WHERE sender_id = $1)",
+            user_id
+        )
+        .execute(&mut *tx)
+        .await
+        .map_err(UserDatabaseError::from)?;
+
+        sqlx::query!(
+            "UPDATE messages SET content = '' WHERE sender_id = $1",
+            user_id
+        )
+        .execute(&mut *tx)
+        .await
+        .map_err(UserDatabaseError::from)?;
+
+        sqlx::query!("DELETE FROM users WHERE id = $1", user_id)
+            .execute(&mut *tx)
+            .await
+            .map_err(UserDatabaseError::from)?;
+
+        tx.commit().await.map_err(UserDatabaseError::from)?;
+
+        Ok(AccountDeletion {
+            room_ids,
+            handovers,
+            deleted_messages,
+        })
+    }
+
+    async fn create_refresh_token(&self, token: RefreshToken) -> UserDatabaseResult<()> {
+        sqlx::query!(
+            r#"
+            INSERT INTO refresh_tokens (id, user_id, family_id, token_hash, expires_at, created_at)
+            VALUES ($1, $2, $3, $4, $5, $6)
+            "#,
+            token.id,
+            token.user_id,
+            token.family_id,
+            token.token_hash,
+            token.expires_at,
+            token.created_at
+        )
+        .execute(&self.pool)
+        .await
+        .map_err(UserDatabaseError::from)
+        .map(|_| ())
+    }
+
+    async fn get_refresh_token(
+        &self,
+        token_hash: String,
+    ) -> UserDatabaseResult<Option<RefreshToken>> {
+        sqlx::query_as!(
+            RefreshToken,
+            r#"
+            SELECT id, user_id, family_id, token_hash, expires_at, created_at, revoked_at
+            FROM refresh_tokens
+            WHERE token_hash = $1
+            "#,
+            token_hash
+        )
+        .fetch_optional(&self.pool)
+        .await
+        .map_err(UserDatabaseError::from)
+    }
+
+    async fn rotate_refresh_token(
+        &self,
+        old_id: Uuid,
+        replacement: RefreshToken,
+    ) -> UserDatabaseResult<bool> {
+        let mut tx = self.pool.begin().await.map_err(UserDatabaseError::from)?;
+
+        let revoked = sqlx::query!(
+            r#"
+            UPDATE refresh_tokens
+            SET revoked_at = NOW(), replaced_by = $2
+            WHERE id = $1 AND revoked_at IS NULL
+            "#,
+            old_id,
+            replacement.id
+        )
+        .execute(&mut *tx)
+        .await
+        .map_err(UserDatabaseError::from)?;
+
+        if revoked.rows_affected() == 0 {
+            return Ok(false);
+        }
+
+        sqlx::query!(
+            r#"
+            INSERT INTO refresh_tokens (id, user_id, family_id, token_hash, expires_at, created_at)
+            VALUES ($1, $2, $3, $4, $5, $6)
+            "#,
+            replacement.id,
+            replacement.user_id,
+            replacement.family_id,
+            replacement.token_hash,
+            replacement.expires_at,
+            replacement.created_at
+        )
+        .execute(&mut *tx)
+        .await
+        .map_err(UserDatabaseError::from)?;
+
+        tx.commit().await.map_err(UserDatabaseError::from)?;
+
+        Ok(true)
+    }
+
+    async fn revoke_token_family(&self, family_id: Uuid) -> UserDatabaseResult<()> {
+        sqlx::query!(
+            "UPDATE refresh_tokens SET revoked_at = NOW() WHERE family_id = $1 AND revoked_at IS NULL",
+            family_id
+        )
+        .execute(&self.pool)
+        .await
+        .map_err(UserDatabaseError::from)
+        .map(|_| ())
+    }
+
+    async fn revoke_access_token(
+        &self,
+        jti: Uuid,
+        expires_at: DateTime<Utc>,
+    ) -> UserDatabaseResult<()> {
+        // Expired entries can't match a valid token anymore, so they are dropped along the way
+        sqlx::query!("DELETE FROM revoked_access_tokens WHERE expires_at < NOW()")
+            .execute(&self.pool)
+            .await
+            .map_err(UserDatabaseError::from)?;
+
+        sqlx::query!(
+            r#"
+            INSERT INTO revoked_access_tokens (jti, expires_at)
+            VALUES ($1, $2)
+            ON CONFLICT (jti) DO NOTHING
+            "#,
+            jti,
+            expires_at
+        )
+        .execute(&self.pool)
+        .await
+        .map_err(UserDatabaseError::from)
+        .map(|_| ())
+    }
+
+    async fn is_access_token_revoked(
+        &self,
+        jti: Uuid,
+        user_id: Uuid,
+        issued_at: DateTime<Utc>,
+    ) -> UserDatabaseResult<bool> {
+        sqlx::query_scalar!(
+            r#"
+            SELECT EXISTS(SELECT 1 FROM revoked_access_tokens WHERE jti = $1)
+                OR NOT EXISTS(
+                    SELECT 1 FROM users
+                    WHERE id = $2 AND (sessions_revoked_at IS NULL OR sessions_revoked_at <= $3)
+                ) AS "revoked!"
+            "#,
+            jti,
+            user_id,
+            issued_at
+        )
+        .fetch_one(&self.pool)
+        .await
+        .map_err(UserDatabaseError::from)
+    }
+
+    async fn record_login_lockout(&self, lockout: LoginLockout) -> UserDatabaseResult<()> {
+        sqlx::query!(
+            r#"
+            INSERT INTO login_lockouts (id, user_id, ip_address, failed_attempts, locked_until, created_at)
+            VALUES ($1, $2, $3, $4, $5, $6)
+            "#,
+            lockout.id,
+            lockout.user_id,
+            lockout.ip_address,
+            lockout.failed_attempts,
+            lockout.locked_until,
+            lockout.created_at
+        )
+        .execute(&self.pool)
+        .await
+        .map_err(UserDatabaseError::from)?;
+
+        Ok(())
+    }
+
+    async fn create_user_token(&self, token: UserToken) -> UserDatabaseResult<()> {
+        sqlx::query!(
+            r#"
+            INSERT INTO user_tokens (id, user_id, purpose, token_hash, expires_at, created_at)
+            VALUES ($1, $2, $3, $4, $5, $6)
+            "#,
+            token.id,
+            token.user_id,
+            token.purpose as UserTokenPurpose,
+            token.token_hash,
+            token.expires_at,
+            token.created_at
+        )
+        .execute(&self.pool)
+        .await
+        .map_err(UserDatabaseError::from)?;
+
+        Ok(())
+    }
+
+    async fn consume_user_token(
+        &self,
+        token_hash: String,
+        purpose: UserTokenPurpose,
+    ) -> UserDatabaseResult<Option<UserToken>> {
+        // A single conditional update, so two requests can't both use the token
+        sqlx::query_as!(
+            UserToken,
+            r#"
+            UPDATE user_tokens SET used_at = NOW()
+            WHERE token_hash = $1 AND purpose = $2 AND used_at IS NULL AND expires_at > NOW()
+            RETURNING id, user_id, purpose AS "purpose: UserTokenPurpose", token_hash, expires_at,
+                created_at, used_at
+            "#,
+            token_hash,
+            purpose as UserTokenPurpose
+        )
+        .fetch_optional(&self.pool)
+        .await
+        .map_err(UserDatabaseError::from)
+    }
+
+    async fn mark_email_verified(&self, user_id: Uuid) -> UserDatabaseResult<()> {
+        sqlx::query!(
+            "UPDATE users SET email_verified_at = COALESCE(email_verified_at, NOW()), updated_at = NOW() WHERE id = $1",
+            user_id
+        )
+        .execute(&self.pool)
+        .await
+        .map_err(UserDatabaseError::from)?;
+
+        Ok(())
+    }
+
+    async fn update_password(
+        &self,
+        user_id: Uuid,
+        password_hash: String,
+    ) -> UserDatabaseResult<()> {
+        sqlx::query!(
+            "UPDATE users SET password_hash = $2, updated_at = NOW() WHERE id = $1",
+            user_id,
+            password_hash
+        )
+        .execute(&self.pool)
+        .await
+        .map_err(UserDatabaseError::from)?;
+
+        Ok(())
+    }
+
+    /// `iat` only has seconds, so the cutoff is truncated to the second, keeping the pair issued
+    /// right after the revocation valid, along with any other token of that same second
+    async fn revoke_user_sessions(
+        &self,
+        user_id: Uuid,
+        revoked_at: DateTime<Utc>,
+    ) -> UserDatabaseResult<()> {
+        let mut tx = self.pool.begin().await.map_err(UserDatabaseError::from)?;
+
+        sqlx::query!(
+            "UPDATE refresh_tokens SET revoked_at = NOW() WHERE user_id = $1 AND revoked_at IS NULL",
+            user_id
+        )
+        .execute(&mut *tx)
+        .await
+        .map_err(UserDatabaseError::from)?;
+
+        sqlx::query!(
+            "UPDATE users SET sessions_revoked_at = date_trunc('second', $2::timestamptz) WHERE id = $1",
+            user_id,
+            revoked_at
+        )
+        .execute(&mut *tx)
+        .await
+        .map_err(UserDatabaseError::from)?;
+
+        tx.commit().await.map_err(UserDatabaseError::from)
     }
 }
 
@@ -93,22 +540,38 @@ pub struct DbRoom {
     pub created_at: DateTime<Utc>,
 }
 
+fn parse_visibility(visibility: Option<String>) -> RoomDatabaseResult<RoomVisibility> {
+    match visibility {
+        Some(visibility_string) => match visibility_string.as_str() {
+            "public" => Ok(RoomVisibility::Public),
+            "private" => Ok(RoomVisibility::Private),
+            _ => Err(RoomDatabaseError::InternalDBError(format!(
+                "{visibility_string}: is not public nor private, error deserializing in the db"
+            ))),
+        },
+        None => Err(RoomDatabaseError::InternalDBError(
+            "visibility doesn't contain any string, database error".to_string(),
+        )),
+    }
+}
+
+fn parse_role(role: &str) -> RoomDatabaseResult<MemberRole> {
+    match role {
+        "owner" => Ok(MemberRole::Owner),
+        "admin" => Ok(MemberRole::Admin),
+        "moderator" => Ok(MemberRole::Moderator),
+        "member" => Ok(MemberRole::Member),
+        _ => Err(RoomDatabaseError::InternalDBError(format!(
+            "{role}: is not a valid member role, error deserializing in the db"
+        ))),
+    }
+}
+
 impl TryInto<Room> for DbRoom {
     type Error = RoomDatabaseError;
 
     fn try_into(self) -> std::result::Result<Room, Self::Error> {
-        let visibility = match self.visibility {
-            Some(visibility_string) => match visibility_string.as_str() {
-                "public" => Ok(RoomVisibility::Public),
-                "private" => Ok(RoomVisibility::Private),
-                _ => Err(RoomDatabaseError::InternalDBError(format!(
-                    "{visibility_string}: is not public nor private, error deserializing in the db"
-                ))),
-            },
-            None => Err(RoomDatabaseError::InternalDBError(
-                "visibility doesn't contain any string, database error".to_string(),
-            )),
-        }?;
+        let visibility = parse_visibility(self.visibility)?;
 
         Ok(Room {
             id: self.id,
@@ -121,6 +584,46 @@ impl TryInto<Room> for DbRoom {
     }
 }
 
+#[derive(Debug, Clone, Serialize, Deserialize, FromRow)]
+pub struct DbRoomSummary {
+    pub id: Uuid,
+    pub name: String,
+    pub visibility: Option<String>,
+    pub has_password: bool,
+    pub created_by: Uuid,
+    pub created_at: DateTime<Utc>,
+    pub member_count: i64,
+    pub is_member: bool,
+}
+
+impl TryInto<RoomSummary> for DbRoomSummary {
+    type Error = RoomDatabaseError;
+
+    fn try_into(self) -> std::result::Result<RoomSummary, Self::Error> {
+        let visibility = parse_visibility(self.visibility)?;
+
+        Ok(RoomSummary {
+            id: self.id,
+            name: self.name,
+            visibility,
+            has_password: self.has_password,
+            created_by: self.created_by,
+            created_at: self.created_at,
+            member_count: self.member_count,
+            is_member: self.is_member,
+        })
+    }
+}
+
+fn summaries_db_to_summaries(
+    summaries_db: Vec<DbRoomSummary>,
+) -> RoomDatabaseResult<Vec<RoomSummary>> {
+    summaries_db
+        .into_iter()
+        .map(|summary_db| summary_db.try_into())
+        .collect()
+}
+
 fn rooms_db_to_rooms(rooms_db: Vec<DbRoom>) -> RoomDatabaseResult<Vec<Room>> {
     let mut rooms = Vec::new();
 
@@ -132,24 +635,96 @@ fn rooms_db_to_rooms(rooms_db: Vec<DbRoom>) -> RoomDatabaseResult<Vec<Room>> {
     Ok(rooms)
 }
 
+/// Makes the oldest member other than the owner the owner of the locked room, or archives the
+/// room when nobody else is in it. The owner keeps its membership, returns the new owner
+async fn hand_over_room(
+    conn: &mut PgConnection,
+    room_id: Uuid,
+    owner_id: Uuid,
+) -> Result<Option<Uuid>, sqlx::Error> {
+    let heir = sqlx::query_scalar!(
+        r#"
+        SELECT user_id FROM room_members
+        WHERE room_id = $1 AND user_id <> $2
+        ORDER BY joined_at, user_id
+        LIMIT 1
+        "#,
+        room_id,
+        owner_id
+    )
+    .fetch_optional(&mut *conn)
+    .await?;
+
+    match heir {
+        Some(heir) => {
+            sqlx::query!(
+                "UPDATE rooms SET created_by = $2 WHERE id = $1",
+                room_id,
+                heir
+            )
+            .execute(&mut *conn)
+            .await?;
+
+            sqlx::query!(
+                "UPDATE room_members SET role = 'owner' WHERE room_id = $1 AND user_id = $2",
+                room_id,
+                heir
+            )
+            .execute(&mut *conn)
+            .await?;
+        }
+        None => {
+            sqlx::query!(
+                "UPDATE rooms SET archived_at = NOW() WHERE id = $1",
+                room_id
+            )
+            .execute(&mut *conn)
+            .await?;
+        }
+    }
+
+    Ok(heir)
+}
+
 impl RoomDatabase for PostgresDatabase {
-    async fn get_public_rooms(&self) -> RoomDatabaseResult<Vec<Room>> {
-        let rooms_db = sqlx::query_as!(
-            DbRoom,
-            "SELECT id, name, visibility::text, password_hash, created_by, created_at FROM rooms WHERE visibility = 'public' ORDER BY created_at DESC"
+    async fn get_public_room_summaries(
+        &self,
+        user_id: Uuid,
+    ) -> RoomDatabaseResult<Vec<RoomSummary>> {
+        let summaries_db = sqlx::query_as!(
+            DbRoomSummary,
+            r#"SELECT r.id, r.name, r.visibility::text, r.password_hash IS NOT NULL AS "has_password!", r.created_by AS "created_by!", r.created_at,
+                   (SELECT COUNT(*) FROM room_members c WHERE c.room_id = r.id) AS "member_count!",
+                   EXISTS(SELECT 1 FROM room_members m WHERE m.room_id = r.id AND m.user_id = $1) AS "is_member!"
+               FROM rooms r WHERE r.visibility = 'public' AND r.archived_at IS NULL ORDER BY r.created_at DESC"#,
+            user_id
         ).fetch_all(&self.pool).await
-            .map_err(|err| RoomDatabaseError::InternalDBError(err.to_string()))?;
+            .map_err(RoomDatabaseError::from)?;
 
-        rooms_db_to_rooms(rooms_db)
+        summaries_db_to_summaries(summaries_db)
+    }
+
+    async fn get_user_room_summaries(&self, user_id: Uuid) -> RoomDatabaseResult<Vec<RoomSummary>> {
+        let summaries_db = sqlx::query_as!(
+            DbRoomSummary,
+            r#"SELECT r.id, r.name, r.visibility::text, r.password_hash IS NOT NULL AS "has_password!", r.created_by AS "created_by!", r.created_at,
+                   (SELECT COUNT(*) FROM room_members c WHERE c.room_id = r.id) AS "member_count!",
+                   TRUE AS "is_member!"
+               FROM rooms r WHERE r.id IN (SELECT room_id FROM room_members WHERE user_id = $1) ORDER BY r.created_at DESC"#,
+            user_id
+        ).fetch_all(&self.pool).await
+            .map_err(RoomDatabaseError::from)?;
+
+        summaries_db_to_summaries(summaries_db)
     }
 
     async fn get_user_rooms(&self, user_id: Uuid) -> RoomDatabaseResult<Vec<Room>> {
         let rooms_db = sqlx::query_as!(
             DbRoom,
-            "SELECT id, name, visibility::text, password_hash, created_by, created_at FROM rooms WHERE id IN (SELECT room_id FROM room_members WHERE user_id = $1) ORDER BY created_at DESC",
+            r#"SELECT id, name, visibility::text, password_hash, created_by AS "created_by!", created_at FROM rooms WHERE id IN (SELECT room_id FROM room_members WHERE user_id = $1) ORDER BY created_at DESC"#,
             user_id
         ).fetch_all(&self.pool).await
-            .map_err(|err| RoomDatabaseError::InternalDBError(err.to_string()))?;
+            .map_err(RoomDatabaseError::from)?;
 
         rooms_db_to_rooms(rooms_db)
     }
@@ -157,10 +732,11 @@ impl RoomDatabase for PostgresDatabase {
     async fn get_room(&self, id: Uuid) -> RoomDatabaseResult<Room> {
         let room_db = sqlx::query_as!(
             DbRoom,
-            "SELECT id, name, visibility::text, password_hash, created_by, created_at FROM rooms WHERE id = $1",
+            // Only archived rooms can be left without an owner
+            r#"SELECT id, name, visibility::text, password_hash, created_by AS "created_by!", created_at FROM rooms WHERE id = $1 AND archived_at IS NULL"#,
             id
         ).fetch_one(&self.pool).await
-            .map_err(|err| RoomDatabaseError::InternalDBError(err.to_string()))?;
+            .map_err(RoomDatabaseError::from)?;
 
         room_db.try_into()
     }
@@ -177,14 +753,46 @@ impl RoomDatabase for PostgresDatabase {
         )
         .execute(&self.pool)
         .await
-        .map_err(|err| RoomDatabaseError::InternalDBError(err.to_string()))?;
+        .map_err(RoomDatabaseError::from)?;
+
+        Ok(())
+    }
+
+    async fn update_room(&self, room: Room) -> RoomDatabaseResult<Room> {
+        let room_db = sqlx::query_as!(
+            DbRoom,
+            r#"UPDATE rooms SET name = $2, visibility = $3, password_hash = $4 WHERE id = $1
+             RETURNING id, name, visibility::text, password_hash, created_by AS "created_by!", created_at"#,
+            room.id,
+            room.name,
+            room.visibility.to_string(),
+            room.password_hash
+        )
+        .fetch_one(&self.pool)
+        .await
+        .map_err(RoomDatabaseError::from)?;
+
+        room_db.try_into()
+    }
+
+    async fn delete_room(&self, room_id: Uuid) -> RoomDatabaseResult<()> {
+        let result = sqlx::query!("DELETE FROM rooms WHERE id = $1", room_id)
+            .execute(&self.pool)
+            .await
+            .map_err(RoomDatabaseError::from)?;
+
+        if result.rows_affected() == 0 {
+            return Err(RoomDatabaseError::NotFound);
+        }
 
         Ok(())
     }
 
     async fn create_room_membership(&self, room_member: RoomMember) -> RoomDatabaseResult<()> {
-        sqlx::query!(
-            "INSERT INTO room_members (room_id, user_id, role, joined_at) VALUES ($1, $2, $3, $4)",
+        // The lock waits for an owner leaving meanwhile, and then sees whether the room was archived
+        let result = sqlx::query!(
+            "INSERT INTO room_members (room_id, user_id, role, joined_at)
+             SELECT id, $2, $3, $4 FROM rooms WHERE id = $1 AND archived_at IS NULL FOR KEY SHARE",
             room_member.room_id,
             room_member.user_id,
             room_member.role.to_string(),
@@ -192,71 +800,564 @@ impl RoomDatabase for PostgresDatabase {
         )
         .execute(&self.pool)
         .await
-        .map_err(|err| RoomDatabaseError::InternalDBError(err.to_string()))?;
+        .map_err(RoomDatabaseError::from)?;
+
+        if result.rows_affected() == 0 {
+            return Err(RoomDatabaseError::NotFound);
+        }
 
         Ok(())
     }
 
+    async fn get_member_role(
+        &self,
+        room_id: Uuid,
+        user_id: Uuid,
+    ) -> RoomDatabaseResult<Option<MemberRole>> {
+        let role = sqlx::query_scalar!(
+            "SELECT role FROM room_members WHERE room_id = $1 AND user_id = $2",
+            room_id,
+            user_id
+        )
+        .fetch_optional(&self.pool)
+        .await
+        .map_err(RoomDatabaseError::from)?;
+
+        role.as_deref().map(parse_role).transpose()
+    }
+
+    async fn update_member_role(
+        &self,
+        room_id: Uuid,
+        user_id: Uuid,
+        role: MemberRole,
+    ) -> RoomDatabaseResult<RoomMember> {
+        let member = sqlx::query!(
+            "UPDATE room_members SET role = $3 WHERE room_id = $1 AND user_id = $2
+             RETURNING room_id, user_id, role, joined_at",
+            room_id,
+            user_id,
+            role.to_string()
+        )
+        .fetch_one(&self.pool)
+        .await
+        .map_err(RoomDatabaseError::from)?;
+
+        Ok(RoomMember {
+            room_id: member.room_id,
+            user_id: member.user_id,
+            role: parse_role(&member.role)?,
+            joined_at: member.joined_at,
+        })
+    }
+
     async fn delete_room_membership(&self, room_id: Uuid, user_id: Uuid) -> RoomDatabaseResult<()> {
         sqlx::query!(
-            "DELETE FROM room_members WHERE room_id = $1 AND user_id = $2",
+            "DELETE FROM room_members WHERE room_id = $1 AND user_id = $2 AND role <> 'owner'",
             room_id,
             user_id
         )
         .execute(&self.pool)
         .await
-        .map_err(|err| RoomDatabaseError::InternalDBError(err.to_string()))?;
+        .map_err(RoomDatabaseError::from)?;
 
         Ok(())
     }
 
-    async fn get_room_members(&self, room_id: Uuid) -> RoomDatabaseResult<Vec<User>> {
-        let users = sqlx::query_as!(
-            User,
-            "SELECT password_hash, id, username, email, created_at, updated_at FROM users WHERE id IN (SELECT user_id FROM room_members WHERE room_id = $1)",
+    async fn leave_owned_room(
+        &self,
+        room_id: Uuid,
+        owner_id: Uuid,
+    ) -> RoomDatabaseResult<Option<Uuid>> {
+        let mut tx = self.pool.begin().await.map_err(RoomDatabaseError::from)?;
+
+        // Locks the room, so members joining meanwhile can't end up in an archived room
+        sqlx::query_scalar!("SELECT id FROM rooms WHERE id = $1 FOR UPDATE", room_id)
+            .fetch_one(&mut *tx)
+            .await
+            .map_err(RoomDatabaseError::from)?;
+
+        let heir = hand_over_room(&mut tx, room_id, owner_id)
+            .await
+            .map_err(RoomDatabaseError::from)?;
+
+        sqlx::query!(
+            "DELETE FROM room_members WHERE room_id = $1 AND user_id = $2",
+            room_id,
+            owner_id
+        )
+        .execute(&mut *tx)
+        .await
+        .map_err(RoomDatabaseError::from)?;
+
+        tx.commit().await.map_err(RoomDatabaseError::from)?;
+
+        Ok(heir)
+    }
+
+    async fn transfer_ownership(
+        &self,
+        room_id: Uuid,
+        owner_id: Uuid,
+        new_owner_id: Uuid,
+    ) -> RoomDatabaseResult<Option<RoomMember>> {
+        let mut tx = self.pool.begin().await.map_err(RoomDatabaseError::from)?;
+
+        // Serializes the transfers, leaves and deletions of the room
+        sqlx::query!("SELECT id FROM rooms WHERE id = $1 FOR UPDATE", room_id)
+            .fetch_one(&mut *tx)
+            .await
+            .map_err(RoomDatabaseError::from)?;
+
+        let demoted = sqlx::query!(
+            "UPDATE room_members SET role = 'admin'
+             WHERE room_id = $1 AND user_id = $2 AND role = 'owner'",
+            room_id,
+            owner_id
+        )
+        .execute(&mut *tx)
+        .await
+        .map_err(RoomDatabaseError::from)?
+        .rows_affected();
+
+        if demoted == 0 {
+            return Ok(None);
+        }
+
+        let member = sqlx::query!(
+            "UPDATE room_members SET role = 'owner' WHERE room_id = $1 AND user_id = $2
+             RETURNING room_id, user_id, role, joined_at",
+            room_id,
+            new_owner_id
+        )
+        .fetch_one(&mut *tx)
+        .await
+        .map_err(RoomDatabaseError::from)?;
+
+        sqlx::query!(
+            "UPDATE rooms SET created_by = $2 WHERE id = $1",
+            room_id,
+            new_owner_id
+        )
+        .execute(&mut *tx)
+        .await
+        .map_err(RoomDatabaseError::from)?;
+
+        tx.commit().await.map_err(RoomDatabaseError::from)?;
+
+        Ok(Some(RoomMember {
+            room_id: member.room_id,
+            user_id: member.user_id,
+            role: parse_role(&member.role)?,
+            joined_at: member.joined_at,
+        }))
+    }
+
+    async fn get_active_ban(
+        &self,
+        room_id: Uuid,
+        user_id: Uuid,
+    ) -> RoomDatabaseResult<Option<RoomSanction>> {
+        sqlx::query_as!(
+            RoomSanction,
+            "SELECT room_id, user_id, expires_at FROM room_bans
+             WHERE room_id = $1 AND user_id = $2 AND (expires_at IS NULL OR expires_at > now())",
+            room_id,
+            user_id
+        )
+        .fetch_optional(&self.pool)
+        .await
+        .map_err(RoomDatabaseError::from)
+    }
+
+    async fn get_active_mute(
+        &self,
+        room_id: Uuid,
+        user_id: Uuid,
+    ) -> RoomDatabaseResult<Option<RoomSanction>> {
+        sqlx::query_as!(
+            RoomSanction,
+            r#"SELECT room_id, user_id, expires_at AS "expires_at?" FROM room_mutes
+               WHERE room_id = $1 AND user_id = $2 AND expires_at > now()"#,
+            room_id,
+            user_id
+        )
+        .fetch_optional(&self.pool)
+        .await
+        .map_err(RoomDatabaseError::from)
+    }
+
+    async fn moderate_member(&self, entry: ModerationEntry) -> RoomDatabaseResult<()> {
+        let mut tx = self.pool.begin().await.map_err(RoomDatabaseError::from)?;
+
+        let affected = match entry.action {
+            ModerationAction::Kick => sqlx::query!(
+                "DELETE FROM room_members WHERE room_id = $1 AND user_id = $2",
+                entry.room_id,
+                entry.target_id
+            )
+            .execute(&mut *tx)
+            .await
+            .map_err(RoomDatabaseError::from)?
+            .rows_affected(),
+            // Users can be banned before they ever join, only an unknown user is not found
+            ModerationAction::Ban => {
+                sqlx::query!(
+                    "DELETE FROM room_members WHERE room_id = $1 AND user_id = $2",
+                    entry.room_id,
+                    entry.target_id
+                )
+                .execute(&mut *tx)
+                .await
+                .map_err(RoomDatabaseError::from)?;
+
+                sqlx::query!(
+                    "INSERT INTO room_bans (room_id, user_id, expires_at)
+                     SELECT $1, id, $3 FROM users WHERE id = $2
+                     ON CONFLICT (room_id, user_id) DO UPDATE SET expires_at = EXCLUDED.expires_at",
+                    entry.room_id,
+                    entry.target_id,
+                    entry.expires_at
+                )
+                .execute(&mut *tx)
+                .await
+                .map_err(RoomDatabaseError::from)?
+                .rows_affected()
+            }
+            ModerationAction::Unban => sqlx::query!(
+                "DELETE FROM room_bans
+                 WHERE room_id = $1 AND user_id = $2 AND (expires_at IS NULL OR expires_at > now())",
+                entry.room_id,
+                entry.target_id
+            )
+            .execute(&mut *tx)
+            .await
+            .map_err(RoomDatabaseError::from)?
+            .rows_affected(),
+            ModerationAction::Unmute => sqlx::query!(
+                "DELETE FROM room_mutes WHERE room_id = $1 AND user_id = $2 AND expires_at > now()",
+                entry.room_id,
+                entry.target_id
+            )
+            .execute(&mut *tx)
+            .await
+            .map_err(RoomDatabaseError::from)?
+            .rows_affected(),
+            ModerationAction::Mute => {
+                sqlx::query!(
+                    "INSERT INTO room_mutes (room_id, user_id, expires_at) VALUES ($1, $2, $3)
+                     ON CONFLICT (room_id, user_id) DO UPDATE SET expires_at = EXCLUDED.expires_at",
+                    entry.room_id,
+                    entry.target_id,
+                    entry.expires_at
+                )
+                .execute(&mut *tx)
+                .await
+                .map_err(RoomDatabaseError::from)?
+                .rows_affected()
+            }
+        };
+
+        if affected == 0 {
+            return Err(RoomDatabaseError::NotFound);
+        }
+
+        sqlx::query!(
+            "INSERT INTO moderation_log
+                (id, room_id, target_id, moderator_id, action, reason, expires_at, created_at)
+             VALUES ($1, $2, $3, $4, $5, $6, $7, $8)",
+            entry.id,
+            entry.room_id,
+            entry.target_id,
+            entry.moderator_id,
+            entry.action as ModerationAction,
+            entry.reason,
+            entry.expires_at,
+            entry.created_at
+        )
+        .execute(&mut *tx)
+        .await
+        .map_err(RoomDatabaseError::from)?;
+
+        tx.commit().await.map_err(RoomDatabaseError::from)
+    }
+
+    async fn get_moderation_log(
+        &self,
+        room_id: Uuid,
+        limit: i64,
+    ) -> RoomDatabaseResult<Vec<ModerationEntry>> {
+        sqlx::query_as!(
+            ModerationEntry,
+            r#"SELECT id, room_id, target_id, moderator_id, action AS "action: ModerationAction",
+                      reason, expires_at, created_at
+               FROM moderation_log WHERE room_id = $1
+               ORDER BY created_at DESC, id DESC LIMIT $2"#,
+            room_id,
+            limit
+        )
+        .fetch_all(&self.pool)
+        .await
+        .map_err(RoomDatabaseError::from)
+    }
+
+    async fn get_room_members(&self, room_id: Uuid) -> RoomDatabaseResult<Vec<MemberView>> {
+        let members = sqlx::query!(
+            "SELECT m.user_id, u.username, m.role, m.joined_at
+             FROM room_members m JOIN users u ON u.id = m.user_id
+             WHERE m.room_id = $1
+             ORDER BY m.joined_at, m.user_id",
             room_id
         )
         .fetch_all(&self.pool)
         .await
-        .map_err(|err| RoomDatabaseError::InternalDBError(err.to_string()))?;
+        .map_err(RoomDatabaseError::from)?;
 
-        Ok(users)
+        members
+            .into_iter()
+            .map(|member| {
+                Ok(MemberView {
+                    user_id: member.user_id,
+                    username: member.username,
+                    role: parse_role(&member.role)?,
+                    joined_at: member.joined_at,
+                })
+            })
+            .collect()
     }
 
-    async fn create_message(&self, message: Message) -> RoomDatabaseResult<()> {
-        sqlx::query!(
-            "INSERT INTO messages (id, room_id, sender_id, content) VALUES ($1, $2, $3, $4)",
+    async fn create_message(&self, message: Message) -> RoomDatabaseResult<MessageView> {
+        sqlx::query_as!(
+            MessageView,
+            "WITH inserted AS (
+                INSERT INTO messages (id, room_id, sender_id, content) VALUES ($1, $2, $3, $4)
+                RETURNING id, room_id, sender_id, content, created_at, edited_at, deleted_at
+             )
+             SELECT i.id, i.room_id, i.sender_id AS \"sender_id?\", u.username AS \"sender_username?\", i.content,
+                    i.created_at, i.edited_at, i.deleted_at
+             FROM inserted i JOIN users u ON u.id = i.sender_id",
             message.id,
             message.room_id,
             message.sender_id,
             message.content
         )
-        .execute(&self.pool)
+        .fetch_one(&self.pool)
         .await
-        .map_err(|err| RoomDatabaseError::InternalDBError(err.to_string()))?;
+        .map_err(RoomDatabaseError::from)
+    }
 
-        Ok(())
+    async fn get_room_messages_before(
+        &self,
+        room_id: Uuid,
+        cursor: Option<Uuid>,
+        limit: i64,
+    ) -> RoomDatabaseResult<Vec<MessageView>> {
+        let messages = match cursor {
+            Some(cursor) => {
+                sqlx::query_as!(
+                    MessageView,
+                    "SELECT m.id, m.room_id, m.sender_id AS \"sender_id?\", u.username AS \"sender_username?\",
+                            CASE WHEN m.deleted_at IS NULL THEN m.content ELSE '' END AS \"content!\",
+                            m.created_at, m.edited_at, m.deleted_at
+                     FROM messages m LEFT JOIN users u ON u.id = m.sender_id
+                     WHERE m.room_id = $1
+                       AND (m.created_at, m.id) < (SELECT created_at, id FROM messages WHERE id = $2)
+                     ORDER BY m.created_at DESC, m.id DESC LIMIT $3",
+                    room_id,
+                    cursor,
+                    limit
+                )
+                .fetch_all(&self.pool)
+                .await
+            }
+            None => {
+                sqlx::query_as!(
+                    MessageView,
+                    "SELECT m.id, m.room_id, m.sender_id AS \"sender_id?\", u.username AS \"sender_username?\",
+                            CASE WHEN m.deleted_at IS NULL THEN m.content ELSE '' END AS \"content!\",
+                            m.created_at, m.edited_at, m.deleted_at
+                     FROM messages m LEFT JOIN users u ON u.id = m.sender_id
+                     WHERE m.room_id = $1
+                     ORDER BY m.created_at DESC, m.id DESC LIMIT $2",
+                    room_id,
+                    limit
+                )
+                .fetch_all(&self.pool)
+                .await
+            }
+        };
+
+        messages.map_err(RoomDatabaseError::from)
     }
 
-    async fn get_room_messages(
+    async fn get_room_messages_after(
         &self,
         room_id: Uuid,
-        page: u32,
-        page_size: u8,
-    ) -> RoomDatabaseResult<Vec<Message>> {
-        let offset = ((page - 1) * page_size as u32) as i64;
-
-        let messages = sqlx::query_as!(
-            Message,
-            "SELECT * FROM messages WHERE room_id = $1 ORDER BY created_at DESC LIMIT $2 OFFSET $3",
+        cursor: Uuid,
+        limit: i64,
+    ) -> RoomDatabaseResult<Vec<MessageView>> {
+        let mut messages = sqlx::query_as!(
+            MessageView,
+            "SELECT m.id, m.room_id, m.sender_id AS \"sender_id?\", u.username AS \"sender_username?\",
+                    CASE WHEN m.deleted_at IS NULL THEN m.content ELSE '' END AS \"content!\",
+                    m.created_at, m.edited_at, m.deleted_at
+             FROM messages m LEFT JOIN users u ON u.id = m.sender_id
+             WHERE m.room_id = $1
+               AND (m.created_at, m.id) > (SELECT created_at, id FROM messages WHERE id = $2)
+             ORDER BY m.created_at ASC, m.id ASC LIMIT $3",
             room_id,
-            page_size as i64,
-            offset
+            cursor,
+            limit
         )
         .fetch_all(&self.pool)
         .await
-        .map_err(|err| RoomDatabaseError::InternalDBError(err.to_string()))?;
+        .map_err(RoomDatabaseError::from)?;
+
+        messages.reverse();
 
         Ok(messages)
     }
+
+    async fn get_messages_since(
+        &self,
+        room_ids: Vec<Uuid>,
+        cursor: Uuid,
+        limit: i64,
+    ) -> RoomDatabaseResult<Vec<MessageView>> {
+        sqlx::query_as!(
+            MessageView,
+            "SELECT m.id, m.room_id, m.sender_id AS \"sender_id?\", u.username AS \"sender_username?\",
+                    CASE WHEN m.deleted_at IS NULL THEN m.content ELSE '' END AS \"content!\",
+                    m.created_at, m.edited_at, m.deleted_at
+             FROM messages m LEFT JOIN users u ON u.id = m.sender_id
+             WHERE m.room_id = ANY($1)
+               AND (m.created_at, m.id) > (SELECT created_at, id FROM messages WHERE id = $2)
+             ORDER BY m.created_at ASC, m.id ASC LIMIT $3",
+            &room_ids,
+            cursor,
+            limit
+        )
+        .fetch_all(&self.pool)
+        .await
+        .map_err(RoomDatabaseError::from)
+    }
+
+    async fn get_message(&self, message_id: Uuid) -> RoomDatabaseResult<MessageView> {
+        sqlx::query_as!(
+            MessageView,
+            "SELECT m.id, m.room_id, m.sender_id AS \"sender_id?\", u.username AS \"sender_username?\",
+                    CASE WHEN m.deleted_at IS NULL THEN m.content ELSE '' END AS \"content!\",
+                    m.created_at, m.edited_at, m.deleted_at
+             FROM messages m LEFT JOIN users u ON u.id = m.sender_id
+             WHERE m.id = $1",
+            message_id
+        )
+        .fetch_one(&self.pool)
+        .await
+        .map_err(RoomDatabaseError::from)
+    }
+
+    async fn edit_message(
+        &self,
+        message_id: Uuid,
+        edited_by: Uuid,
+        content: String,
+    ) -> RoomDatabaseResult<MessageView> {
+        sqlx::query_as!(
+            MessageView,
+            "WITH previous AS (
+                SELECT id, content FROM messages WHERE id = $1 AND deleted_at IS NULL FOR UPDATE
+             ), history AS (
+                INSERT INTO message_edits (message_id, previous_content, edited_by)
+                SELECT id, content, $2 FROM previous
+             ), updated AS (
+                UPDATE messages m SET content = $3, edited_at = now()
+                FROM previous p WHERE m.id = p.id
+                RETURNING m.id, m.room_id, m.sender_id, m.content, m.created_at, m.edited_at, m.deleted_at
+             )
+             SELECT up.id, up.room_id, up.sender_id AS \"sender_id?\", u.username AS \"sender_username?\", up.content,
+                    up.created_at, up.edited_at, up.deleted_at
+             FROM updated up JOIN users u ON u.id = up.sender_id",
+            message_id,
+            edited_by,
+            content
+        )
+        .fetch_one(&self.pool)
+        .await
+        .map_err(RoomDatabaseError::from)
+    }
+
+    async fn delete_message(&self, message_id: Uuid) -> RoomDatabaseResult<MessageView> {
+        sqlx::query_as!(
+            MessageView,
+            "WITH deleted AS (
+                UPDATE messages SET deleted_at = now()
+                WHERE id = $1 AND deleted_at IS NULL
+                RETURNING id, room_id, sender_id, created_at, edited_at, deleted_at
+             )
+             SELECT d.id, d.room_id, d.sender_id AS \"sender_id?\", u.username AS \"sender_username?\", '' AS \"content!\",
+                    d.created_at, d.edited_at, d.deleted_at
+             FROM deleted d LEFT JOIN users u ON u.id = d.sender_id",
+            message_id
+        )
+        .fetch_one(&self.pool)
+        .await
+        .map_err(RoomDatabaseError::from)
+    }
+
+    async fn get_message_edits(&self, message_id: Uuid) -> RoomDatabaseResult<Vec<MessageEdit>> {
+        sqlx::query_as!(
+            MessageEdit,
+            "SELECT id, message_id, previous_content, edited_by, edited_at
+             FROM message_edits WHERE message_id = $1
+             ORDER BY edited_at DESC, id DESC",
+            message_id
+        )
+        .fetch_all(&self.pool)
+        .await
+        .map_err(RoomDatabaseError::from)
+    }
+
+    async fn update_read_state(&self, read_state: RoomReadState) -> RoomDatabaseResult<bool> {
+        let result = sqlx::query!(
+            "INSERT INTO room_read_state (room_id, user_id, last_read_message_id, last_read_at)
+             VALUES ($1, $2, $3, $4)
+             ON CONFLICT (room_id, user_id) DO UPDATE
+             SET last_read_message_id = EXCLUDED.last_read_message_id, last_read_at = EXCLUDED.last_read_at
+             WHERE room_read_state.last_read_message_id IS NULL
+                OR (SELECT (created_at, id) FROM messages WHERE id = room_read_state.last_read_message_id)
+                    < (SELECT (created_at, id) FROM messages WHERE id = EXCLUDED.last_read_message_id)",
+            read_state.room_id,
+            read_state.user_id,
+            read_state.last_read_message_id,
+            read_state.last_read_at
+        )
+        .execute(&self.pool)
+        .await
+        .map_err(RoomDatabaseError::from)?;
+
+        Ok(result.rows_affected() > 0)
+    }
+
+    async fn get_unread_counts(&self, user_id: Uuid) -> RoomDatabaseResult<Vec<RoomUnreadCount>> {
+        sqlx::query_as!(
+            RoomUnreadCount,
+            r#"SELECT rm.room_id, COUNT(m.id) AS "unread_count!"
+               FROM room_members rm
+               LEFT JOIN room_read_state rs ON rs.room_id = rm.room_id AND rs.user_id = rm.user_id
+               LEFT JOIN messages lm ON lm.id = rs.last_read_message_id
+               LEFT JOIN messages m ON m.room_id = rm.room_id
+                   AND m.sender_id <> rm.user_id
+                   AND m.deleted_at IS NULL
+                   AND (rs.room_id IS NULL
+                        OR (lm.id IS NULL AND m.created_at > rs.last_read_at)
+                        OR (m.created_at, m.id) > (lm.created_at, lm.id))
+               WHERE rm.user_id = $1
+               GROUP BY rm.room_id"#,
+            user_id
+        )
+        .fetch_all(&self.pool)
+        .await
+        .map_err(RoomDatabaseError::from)
+    }
 }
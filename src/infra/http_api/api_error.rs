@@ -0,0 +1,321 @@
+use axum::{
+    Json,
+    http::{StatusCode, header},
+    response::{IntoResponse, Response},
+};
+use chrono::{DateTime, Duration, Utc};
+use serde::Serialize;
+use tracing::error;
+
+use crate::use_cases::{
+    auth_service::AuthError, room_database::RoomDatabaseError, room_service::RoomError,
+    user_database::UserDatabaseError, validation::FieldError,
+};
+
+/// Error answered by the api, serialized as `{"code", "message", "errors"?}`. The `code` is
+/// stable so clients can match on it, the message is only meant for humans
+#[derive(Debug, Serialize)]
+pub struct ApiError {
+    #[serde(skip)]
+    status: StatusCode,
+    code: &'static str,
+    message: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    errors: Option<Vec<FieldError>>,
+    #[serde(skip)]
+    retry_after: Option<Duration>,
+}
+
+impl ApiError {
+    pub fn new(status: StatusCode, code: &'static str, message: impl Into<String>) -> ApiError {
+        ApiError {
+            status,
+            code,
+            message: message.into(),
+            errors: None,
+            retry_after: None,
+        }
+    }
+
+    pub fn bad_request(code: &'static str, message: impl Into<String>) -> ApiError {
+        ApiError::new(StatusCode::BAD_REQUEST, code, message)
+    }
+
+    pub fn unauthorized(code: &'static str, message: impl Into<String>) -> ApiError {
+        ApiError::new(StatusCode::UNAUTHORIZED, code, message)
+    }
+
+    pub fn forbidden(code: &'static str, message: impl Into<String>) -> ApiError {
+        ApiError::new(StatusCode::FORBIDDEN, code, message)
+    }
+
+    pub fn not_found(code: &'static str, message: impl Into<String>) -> ApiError {
+        ApiError::new(StatusCode::NOT_FOUND, code, message)
+    }
+
+    pub fn conflict(code: &'static str, message: impl Into<String>) -> ApiError {
+        ApiError::new(StatusCode::CONFLICT, code, message)
+    }
+
+    /// Logs the cause, which is not sent to the client either
+    pub fn unavailable(cause: impl std::fmt::Display) -> ApiError {
+        error!("Dependency unavailable answering a request: {cause}");
+        ApiError::new(
+            StatusCode::SERVICE_UNAVAILABLE,
+            "service_unavailable",
+            "the service is temporarily unavailable",
+        )
+    }
+
+    /// Logs the cause, which is not sent to the client
+    pub fn internal(cause: impl std::fmt::Display) -> ApiError {
+        error!("Internal error answering a request: {cause}");
+        ApiError::new(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "internal_error",
+            "internal server error",
+        )
+    }
+
+    pub fn status(&self) -> StatusCode {
+        self.status
+    }
+}
+
+impl IntoResponse for ApiError {
+    fn into_response(self) -> Response {
+        let mut response = (self.status, Json(&self)).into_response();
+
+        if let Some(retry_after) = self.retry_after {
+            // Rounded up, so retrying right on time doesn't hit the lockout again
+            let seconds = (retry_after.num_milliseconds() + 999) / 1000;
+            response
+                .headers_mut()
+                .insert(header::RETRY_AFTER, seconds.max(1).into());
+        }
+
+        response
+    }
+}
+
+impl From<AuthError> for ApiError {
+    fn from(err: AuthError) -> Self {
+        match err {
+            AuthError::InvalidCredentials => {
+                ApiError::unauthorized("invalid_credentials", err.to_string())
+            }
+            AuthError::InvalidFields(errors) => ApiError {
+                errors: Some(errors.errors),
+                ..ApiError::new(
+                    StatusCode::UNPROCESSABLE_ENTITY,
+                    "validation_failed",
+                    "some fields are invalid",
+                )
+            },
+            AuthError::AlreadyExisting(ref field) => ApiError::conflict(
+                if field == "email" {
+                    "email_taken"
+                } else {
+                    "username_taken"
+                },
+                err.to_string(),
+            ),
+            AuthError::InvalidRefreshToken => {
+                ApiError::unauthorized("invalid_refresh_token", err.to_string())
+            }
+            AuthError::RefreshTokenReused => {
+                ApiError::unauthorized("refresh_token_reused", err.to_string())
+            }
+            AuthError::TooManyAttempts(retry_after) => ApiError {
+                retry_after: Some(retry_after),
+                ..ApiError::new(
+                    StatusCode::TOO_MANY_REQUESTS,
+                    "too_many_attempts",
+                    err.to_string(),
+                )
+            },
+            AuthError::InvalidEmailToken => {
+                ApiError::bad_request("invalid_email_token", err.to_string())
+            }
+            AuthError::EmailAlreadyVerified => {
+                ApiError::conflict("email_already_verified", err.to_string())
+            }
+            AuthError::UserNotFound => ApiError::not_found("user_not_found", err.to_string()),
+            AuthError::Unavailable(cause) => ApiError::unavailable(cause),
+            AuthError::PasswordHashingFailed(_)
+            | AuthError::DatabaseError(_)
+            | AuthError::ErrorVerifying(_)
+            | AuthError::EncodingTokenError
+            | AuthError::MailerError(_)
+            | AuthError::BroadcastError(_) => ApiError::internal(err),
+        }
+    }
+}
+
+impl From<RoomError> for ApiError {
+    fn from(err: RoomError) -> Self {
+        match err {
+            RoomError::PasswordNotGiven => {
+                ApiError::bad_request("room_password_required", err.to_string())
+            }
+            RoomError::InvalidRoomPassword => {
+                ApiError::forbidden("invalid_room_password", err.to_string())
+            }
+            RoomError::NotRoomMember => ApiError::forbidden("not_room_member", err.to_string()),
+            RoomError::MessageNotInRoom => {
+                ApiError::not_found("message_not_found", err.to_string())
+            }
+            RoomError::NotMessageSender => {
+                ApiError::forbidden("not_message_sender", err.to_string())
+            }
+            RoomError::MessageDeleted => {
+                ApiError::new(StatusCode::GONE, "message_deleted", err.to_string())
+            }
+            RoomError::RoomNotFound => ApiError::not_found("room_not_found", err.to_string()),
+            RoomError::InsufficientRole => {
+                ApiError::forbidden("insufficient_role", err.to_string())
+            }
+            RoomError::MemberNotFound => ApiError::not_found("member_not_found", err.to_string()),
+            RoomError::Banned(until) => {
+                ApiError::forbidden("banned_from_room", with_end(err.to_string(), until))
+            }
+            RoomError::Muted(until) => {
+                ApiError::forbidden("muted_in_room", with_end(err.to_string(), until))
+            }
+            RoomError::NotSanctioned => ApiError::not_found("sanction_not_found", err.to_string()),
+            RoomError::AlreadyRoomMember => {
+                ApiError::conflict("already_room_member", err.to_string())
+            }
+            RoomError::AlreadyRoomOwner => {
+                ApiError::conflict("already_room_owner", err.to_string())
+            }
+            RoomError::Unavailable(cause) => ApiError::unavailable(cause),
+            RoomError::DatabaseError(_)
+            | RoomError::PasswordHashError(_)
+            | RoomError::BroadcastError(_)
+            | RoomError::NotificationError(_)
+            | RoomError::BcryptError(_) => ApiError::internal(err),
+        }
+    }
+}
+
+/// Tells until when a ban or mute lasts, when it's not for good
+fn with_end(message: String, until: Option<DateTime<Utc>>) -> String {
+    match until {
+        Some(until) => format!("{message} until {}", until.to_rfc3339()),
+        None => message,
+    }
+}
+
+impl From<UserDatabaseError> for ApiError {
+    fn from(err: UserDatabaseError) -> Self {
+        match err {
+            UserDatabaseError::UsernameTaken => {
+                ApiError::conflict("username_taken", err.to_string())
+            }
+            UserDatabaseError::EmailTaken => ApiError::conflict("email_taken", err.to_string()),
+            UserDatabaseError::Unavailable(cause) => ApiError::unavailable(cause),
+            UserDatabaseError::NotFound
+            | UserDatabaseError::UniqueViolation(_)
+            | UserDatabaseError::ForeignKeyViolation(_)
+            | UserDatabaseError::InternalDBError(_) => ApiError::internal(err),
+        }
+    }
+}
+
+impl From<RoomDatabaseError> for ApiError {
+    fn from(err: RoomDatabaseError) -> Self {
+        match err {
+            RoomDatabaseError::Unavailable(cause) => ApiError::unavailable(cause),
+            err => ApiError::internal(err),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use axum::{
+        body::to_bytes,
+        http::{StatusCode, header},
+        response::IntoResponse,
+    };
+    use chrono::Duration;
+    use serde_json::{Value, json};
+
+    use crate::{
+        infra::http_api::api_error::ApiError,
+        use_cases::{
+            auth_service::AuthError,
+            room_service::RoomError,
+            validation::{FieldError, ValidationErrors},
+        },
+    };
+
+    async fn body(error: ApiError) -> (StatusCode, Option<String>, Value) {
+        let response = error.into_response();
+        let status = response.status();
+        let retry_after = response
+            .headers()
+            .get(header::RETRY_AFTER)
+            .map(|value| value.to_str().unwrap().to_string());
+        let bytes = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+
+        (status, retry_after, serde_json::from_slice(&bytes).unwrap())
+    }
+
+    #[tokio::test]
+    async fn validation_errors_list_every_field() {
+        let errors = ValidationErrors {
+            errors: vec![FieldError {
+                field: "username",
+                message: "too short".to_string(),
+            }],
+        };
+
+        let (status, _, body) = body(AuthError::InvalidFields(errors).into()).await;
+
+        assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY);
+        assert_eq!(body["code"], "validation_failed");
+        assert_eq!(
+            body["errors"],
+            json!([{ "field": "username", "message": "too short" }])
+        );
+    }
+
+    #[tokio::test]
+    async fn lockouts_tell_when_to_retry() {
+        let error = AuthError::TooManyAttempts(Duration::milliseconds(1500)).into();
+
+        let (status, retry_after, body) = body(error).await;
+
+        assert_eq!(status, StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(retry_after.as_deref(), Some("2"));
+        assert_eq!(body["code"], "too_many_attempts");
+    }
+
+    #[tokio::test]
+    async fn internal_errors_hide_their_cause() {
+        let error = RoomError::DatabaseError("connection refused".to_string()).into();
+
+        let (status, _, body) = body(error).await;
+
+        assert_eq!(status, StatusCode::INTERNAL_SERVER_ERROR);
+        assert_eq!(
+            body,
+            json!({ "code": "internal_error", "message": "internal server error" })
+        );
+    }
+
+    #[test]
+    fn room_errors_get_their_status() {
+        let status = |err: RoomError| ApiError::from(err).status();
+
+        assert_eq!(status(RoomError::PasswordNotGiven), StatusCode::BAD_REQUEST);
+        assert_eq!(
+            status(RoomError::InvalidRoomPassword),
+            StatusCode::FORBIDDEN
+        );
+        assert_eq!(status(RoomError::MessageNotInRoom), StatusCode::NOT_FOUND);
+        assert_eq!(status(RoomError::MessageDeleted), StatusCode::GONE);
+    }
+}
@@ -0,0 +1,192 @@
+//! Extractors of the endpoints, wrapping the ones of axum so their rejections are answered as
+//! `ApiError`s, with a `code`, like every other error of the api
+
+use axum::{
+    extract::{
+        FromRequest, FromRequestParts, OptionalFromRequest, Request,
+        rejection::{JsonRejection, PathRejection, QueryRejection},
+    },
+    http::request::Parts,
+    response::{IntoResponse, Response},
+};
+use serde::{Serialize, de::DeserializeOwned};
+
+use crate::infra::http_api::api_error::ApiError;
+
+/// JSON body of a request, rejected with `invalid_body`. Also answers JSON like `axum::Json`
+pub struct Json<T>(pub T);
+
+/// Path segments of a request, rejected with `invalid_path` when they don't parse
+pub struct Path<T>(pub T);
+
+/// Query string of a request, rejected with `invalid_query`
+pub struct Query<T>(pub T);
+
+impl From<JsonRejection> for ApiError {
+    fn from(rejection: JsonRejection) -> Self {
+        ApiError::new(rejection.status(), "invalid_body", rejection.body_text())
+    }
+}
+
+impl From<PathRejection> for ApiError {
+    fn from(rejection: PathRejection) -> Self {
+        ApiError::new(rejection.status(), "invalid_path", rejection.body_text())
+    }
+}
+
+impl From<QueryRejection> for ApiError {
+    fn from(rejection: QueryRejection) -> Self {
+        ApiError::new(rejection.status(), "invalid_query", rejection.body_text())
+    }
+}
+
+impl<T, S> FromRequest<S> for Json<T>
+where
+    T: DeserializeOwned,
+    S: Send + Sync,
+{
+    type Rejection = ApiError;
+
+    async fn from_request(req: Request, state: &S) -> Result<Self, Self::Rejection> {
+        let axum::Json(value) = <axum::Json<T> as FromRequest<S>>::from_request(req, state).await?;
+
+        Ok(Json(value))
+    }
+}
+
+/// Bodies are optional when no `Content-Type` is sent, malformed ones are still rejected
+impl<T, S> OptionalFromRequest<S> for Json<T>
+where
+    T: DeserializeOwned,
+    S: Send + Sync,
+{
+    type Rejection = ApiError;
+
+    async fn from_request(req: Request, state: &S) -> Result<Option<Self>, Self::Rejection> {
+        let value = <axum::Json<T> as OptionalFromRequest<S>>::from_request(req, state).await?;
+
+        Ok(value.map(|axum::Json(value)| Json(value)))
+    }
+}
+
+impl<T: Serialize> IntoResponse for Json<T> {
+    fn into_response(self) -> Response {
+        axum::Json(self.0).into_response()
+    }
+}
+
+impl<T, S> FromRequestParts<S> for Path<T>
+where
+    T: DeserializeOwned + Send,
+    S: Send + Sync,
+{
+    type Rejection = ApiError;
+
+    async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
+        let axum::extract::Path(value) =
+            axum::extract::Path::from_request_parts(parts, state).await?;
+
+        Ok(Path(value))
+    }
+}
+
+impl<T, S> FromRequestParts<S> for Query<T>
+where
+    T: DeserializeOwned,
+    S: Send + Sync,
+{
+    type Rejection = ApiError;
+
+    async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
+        let axum::extract::Query(value) =
+            axum::extract::Query::from_request_parts(parts, state).await?;
+
+        Ok(Query(value))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use axum::{
+        Router,
+        body::{Body, to_bytes},
+        http::{Request, StatusCode, header},
+        routing::post,
+    };
+    use serde::Deserialize;
+    use serde_json::Value;
+    use tower::ServiceExt;
+    use uuid::Uuid;
+
+    use crate::infra::http_api::extract::{Json, Path, Query};
+
+    #[derive(Deserialize)]
+    struct RoomName {
+        name: String,
+    }
+
+    #[derive(Deserialize)]
+    struct Page {
+        page_size: Option<u8>,
+    }
+
+    async fn handler(
+        Path(room_id): Path<Uuid>,
+        Query(page): Query<Page>,
+        Json(room): Json<RoomName>,
+    ) -> String {
+        format!("{room_id} {:?} {}", page.page_size, room.name)
+    }
+
+    async fn send(uri: &str, body: &'static str) -> (StatusCode, Value) {
+        let app = Router::new().route("/rooms/{room_id}", post(handler));
+        let request = Request::post(uri)
+            .header(header::CONTENT_TYPE, "application/json")
+            .body(Body::from(body))
+            .unwrap();
+
+        let response = app.oneshot(request).await.unwrap();
+        let status = response.status();
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+
+        (status, serde_json::from_slice(&body).unwrap())
+    }
+
+    fn code(error: &Value) -> &str {
+        assert!(error["message"].is_string(), "no message in {error}");
+        error["code"].as_str().unwrap()
+    }
+
+    #[tokio::test]
+    async fn malformed_bodies_are_answered_as_api_errors() {
+        let uri = format!("/rooms/{}", Uuid::new_v4());
+
+        let (status, error) = send(&uri, "{\"name\":").await;
+        assert_eq!(
+            (status, code(&error)),
+            (StatusCode::BAD_REQUEST, "invalid_body")
+        );
+
+        let (status, error) = send(&uri, "{\"name\":42}").await;
+        assert_eq!(
+            (status, code(&error)),
+            (StatusCode::UNPROCESSABLE_ENTITY, "invalid_body")
+        );
+    }
+
+    #[tokio::test]
+    async fn malformed_paths_and_queries_are_answered_as_api_errors() {
+        let (status, error) = send("/rooms/not-a-uuid", "{\"name\":\"a\"}").await;
+        assert_eq!(
+            (status, code(&error)),
+            (StatusCode::BAD_REQUEST, "invalid_path")
+        );
+
+        let uri = format!("/rooms/{}?page_size=256", Uuid::new_v4());
+        let (status, error) = send(&uri, "{\"name\":\"a\"}").await;
+        assert_eq!(
+            (status, code(&error)),
+            (StatusCode::BAD_REQUEST, "invalid_query")
+        );
+    }
+}
@@ -3,81 +3,85 @@ use std::str::FromStr;
 use axum::{
     extract::{Request, State},
     middleware::Next,
-    response::Response,
+    response::{IntoResponse, Response},
 };
-use jsonwebtoken::{DecodingKey, Validation, decode};
+use chrono::DateTime;
 use uuid::Uuid;
 
-use crate::{infra::http_api::AppState, use_cases::auth_service::Claims};
+use crate::{
+    domain::user::AuthenticatedUser,
+    infra::http_api::{AppState, api_error::ApiError},
+    use_cases::{auth_service::Claims, user_database::UserDatabase},
+};
 
 pub async fn middleware_fn(
     State(state): State<AppState>,
     mut request: Request,
     next: Next,
 ) -> Response {
-    let bearer_token = match request.headers().get("authorization") {
-        Some(auth) => auth.to_str(),
-        None => {
-            return Response::builder()
-                .status(401)
-                .body("invalid/missing auth token".into())
-                .unwrap();
-        }
-    };
-
-    let bearer_token = if let Ok(bearer_token) = bearer_token {
-        bearer_token
-    } else {
-        return Response::builder()
-            .status(401)
-            .body("invalid/missing auth token".into())
-            .unwrap();
+    let Some(bearer_token) = request
+        .headers()
+        .get("authorization")
+        .and_then(|auth| auth.to_str().ok())
+    else {
+        return ApiError::unauthorized("missing_token", "invalid/missing auth token")
+            .into_response();
     };
 
     let jwt_token = match bearer_token.strip_prefix("Bearer ") {
         Some(token) => token.trim().to_string(),
         None => {
-            return Response::builder()
-                .status(401)
-                .body("worng header format".into())
-                .unwrap();
+            return ApiError::unauthorized("invalid_token", "wrong header format").into_response();
         }
     };
 
-    let user_id = match extract_user_id_from_jwt(jwt_token, &state.jwt_secret) {
-        Ok(id) => id,
-        Err(res) => return res,
+    let (user, claims) = match extract_user_from_jwt(jwt_token, &state).await {
+        Ok(authenticated) => authenticated,
+        Err(err) => return err.into_response(),
     };
 
-    request.extensions_mut().insert(user_id);
+    request.extensions_mut().insert(user);
+    request.extensions_mut().insert(claims);
 
     next.run(request).await
 }
 
-pub fn extract_user_id_from_jwt(jwt_token: String, jwt_secret: &str) -> Result<Uuid, Response> {
-    let my_claims: Claims = match decode(
-        jwt_token,
-        &DecodingKey::from_secret(jwt_secret.as_ref()),
-        &Validation::default(),
-    ) {
-        Ok(clamis) => clamis.claims,
-        Err(_err) => {
-            return Err(Response::builder()
-                .status(401)
-                .body("invalid jwt format or expired".into())
-                .unwrap());
-        }
-    };
+/// Validates the token (signature, `exp`, `iss` and `aud`) and checks its `jti` against the
+/// revocation list, and its `iat` against the last time every session of its user was revoked
+pub async fn extract_user_from_jwt(
+    jwt_token: String,
+    state: &AppState,
+) -> Result<(AuthenticatedUser, Claims), ApiError> {
+    let my_claims: Claims = state
+        .jwt_keys
+        .decode(&jwt_token)
+        .map_err(|err| ApiError::unauthorized("invalid_token", err.to_string()))?;
 
-    let user_id = match Uuid::from_str(&my_claims.sub) {
-        Ok(uuid_real) => uuid_real,
-        Err(_) => {
-            return Err(Response::builder()
-                .status(401)
-                .body("wrong user id format".into())
-                .unwrap());
-        }
+    let (Ok(user_id), Ok(jti)) = (
+        Uuid::from_str(&my_claims.sub),
+        Uuid::from_str(&my_claims.jti),
+    ) else {
+        return Err(ApiError::unauthorized(
+            "invalid_token",
+            "wrong user id format",
+        ));
     };
 
-    Ok(user_id)
+    let issued_at = DateTime::from_timestamp(my_claims.iat as i64, 0).unwrap_or_default();
+    if state
+        .db
+        .is_access_token_revoked(jti, user_id, issued_at)
+        .await?
+    {
+        return Err(ApiError::unauthorized("revoked_token", "revoked jwt"));
+    }
+
+    Ok((
+        AuthenticatedUser {
+            id: user_id,
+            username: my_claims.username.clone(),
+            role: my_claims.role,
+        },
+        my_claims,
+    ))
 }
@@ -1,80 +1,130 @@
+pub mod api_error;
+pub mod extract;
 pub mod middleware_auth;
 pub mod room_endpoints;
 pub mod user_endpoints;
-use std::sync::Arc;
+use std::{net::SocketAddr, sync::Arc};
 
 use axum::{
-    Extension, Router, middleware,
-    routing::{delete, get, post},
+    Extension, Json, Router,
+    extract::State,
+    http::StatusCode,
+    middleware,
+    routing::{delete, get, patch, post, put},
 };
 use axum_prometheus::PrometheusMetricLayer;
-use dashmap::DashMap;
-use tokio::sync::broadcast;
+use serde::Serialize;
 use tower_http::cors::CorsLayer;
 use tracing::info;
-use uuid::Uuid;
 
 use crate::{
-    domain::room::Message,
+    domain::user::AuthenticatedUser,
     infra::{
         database::PostgresDatabase,
         http_api::{
             room_endpoints::{
-                create_room_end, get_all_public_rooms_end, get_messages, get_room_members_end,
-                get_user_rooms_end, join_room_end, leave_room_end, send_message_end,
+                ban_member_end, change_member_role_end, create_room_end, delete_message_end,
+                delete_room_end, edit_message_end, get_all_public_rooms_end, get_message_edits_end,
+                get_messages, get_moderation_log_end, get_room_members_end, get_user_rooms_end,
+                join_room_end, kick_member_end, leave_room_end, mark_room_read_end,
+                mute_member_end, send_message_end, transfer_ownership_end, unban_user_end,
+                unmute_member_end, update_room_end,
+            },
+            user_endpoints::{
+                change_password_end, delete_account_end, forgot_password_end, get_user_info_end,
+                jwks_end, login_end, logout_end, refresh_end, register_end,
+                resend_verification_email_end, reset_password_end, update_profile_end,
+                verify_email_end,
             },
-            user_endpoints::{get_user_info_end, login_end, register_end},
         },
+        mailer::AppMailer,
         rabbit_mq::RabbitMQ,
-        redis::RedisPublisher,
-        web_socket::ws_handler,
+        redis::{RedisEventPublisher, login_attempts::RedisLoginAttempts},
+        web_socket::{multiplexed_ws_handler, ws_handler},
+    },
+    use_cases::{
+        jwt_keys::JwtKeys,
+        realtime_service::{BrokerHealth, EventChannels},
     },
 };
 
 #[derive(Clone)]
 pub struct AppState {
     pub db: Arc<PostgresDatabase>,
-    pub jwt_secret: String,
-    pub rooms_channels: Arc<DashMap<Uuid, broadcast::Sender<Message>>>,
-    redis_publisher: Arc<RedisPublisher>,
-    rabbit_mq: Arc<RabbitMQ>,
+    pub jwt_keys: Arc<JwtKeys>,
+    pub channels: Arc<EventChannels>,
+    pub redis_publisher: Arc<RedisEventPublisher>,
+    pub rabbit_mq: Arc<RabbitMQ>,
+    pub broker_health: Arc<BrokerHealth>,
+    pub login_attempts: Arc<RedisLoginAttempts>,
+    /// Whether the client address is read from the `X-Real-IP` header set by the proxy
+    pub trust_proxy_headers: bool,
+    pub mailer: Arc<AppMailer>,
+    /// Frontend url, the links sent by email point to it
+    pub app_url: String,
 }
 
-pub async fn start_http_api(
-    addr: String,
-    jwt_secret: String,
-    db: Arc<PostgresDatabase>,
-    rooms_channels: Arc<DashMap<Uuid, broadcast::Sender<Message>>>,
-    redis_publisher: Arc<RedisPublisher>,
-    message_processing: Arc<RabbitMQ>,
-    dev_mode: bool,
-) {
-    let auth_state = AppState {
-        db,
-        jwt_secret,
-        rooms_channels,
-        redis_publisher,
-        rabbit_mq: message_processing,
-    };
-
+pub async fn start_http_api(addr: String, auth_state: AppState, dev_mode: bool) {
     let cors_layer = CorsLayer::very_permissive();
 
     let (prom_layer, metric_handle) = PrometheusMetricLayer::pair();
 
     let mut app = Router::new()
         .route("/health", get(auth_health_check))
+        .route("/auth/logout", post(logout_end))
+        .route("/auth/email/resend", post(resend_verification_email_end))
         .route("/rooms/public", get(get_all_public_rooms_end))
         .route("/rooms", get(get_user_rooms_end).post(create_room_end))
+        .route(
+            "/rooms/{room_id}",
+            patch(update_room_end).delete(delete_room_end),
+        )
         .route(
             "/rooms/{room_id}/members",
             get(get_room_members_end).post(join_room_end),
         )
         .route("/rooms/{room_id}/members/me", delete(leave_room_end))
+        .route("/rooms/{room_id}/owner", put(transfer_ownership_end))
+        .route(
+            "/rooms/{room_id}/members/{user_id}/role",
+            put(change_member_role_end),
+        )
+        .route(
+            "/rooms/{room_id}/members/{user_id}/kick",
+            post(kick_member_end),
+        )
+        .route(
+            "/rooms/{room_id}/bans/{user_id}",
+            put(ban_member_end).delete(unban_user_end),
+        )
+        .route(
+            "/rooms/{room_id}/mutes/{user_id}",
+            put(mute_member_end).delete(unmute_member_end),
+        )
+        .route(
+            "/rooms/{room_id}/moderation-log",
+            get(get_moderation_log_end),
+        )
         .route(
             "/rooms/{room_id}/messages",
             get(get_messages).post(send_message_end),
         )
-        .route("/me", get(get_user_info_end))
+        .route(
+            "/rooms/{room_id}/messages/{message_id}",
+            patch(edit_message_end).delete(delete_message_end),
+        )
+        .route(
+            "/rooms/{room_id}/messages/{message_id}/edits",
+            get(get_message_edits_end),
+        )
+        .route("/rooms/{room_id}/read", put(mark_room_read_end))
+        .route(
+            "/me",
+            get(get_user_info_end)
+                .patch(update_profile_end)
+                .delete(delete_account_end),
+        )
+        .route("/me/password", put(change_password_end))
         .route_layer(middleware::from_fn_with_state(
             auth_state.clone(),
             middleware_auth::middleware_fn,
@@ -83,10 +133,17 @@ pub async fn start_http_api(
             "/metrics",
             get(move || async move { metric_handle.render() }),
         )
+        .route("/ws", get(multiplexed_ws_handler))
         .route("/ws/rooms/{room_id}", get(ws_handler))
         .route("/", get(health_check))
+        .route("/health/ready", get(readiness_check))
         .route("/auth/register", post(register_end))
         .route("/auth/login", post(login_end))
+        .route("/auth/refresh", post(refresh_end))
+        .route("/auth/email/verify", post(verify_email_end))
+        .route("/auth/password/forgot", post(forgot_password_end))
+        .route("/auth/password/reset", post(reset_password_end))
+        .route("/.well-known/jwks.json", get(jwks_end))
         .layer(prom_layer)
         .with_state(auth_state);
 
@@ -96,13 +153,56 @@ pub async fn start_http_api(
 
     let listener = tokio::net::TcpListener::bind(addr.clone()).await.unwrap();
     info!("Starting server in: {addr}");
-    axum::serve(listener, app).await.unwrap();
+    axum::serve(
+        listener,
+        app.into_make_service_with_connect_info::<SocketAddr>(),
+    )
+    .await
+    .unwrap();
 }
 
 pub async fn health_check() -> &'static str {
     "hello"
 }
 
-pub async fn auth_health_check(Extension(user_id): Extension<Uuid>) -> String {
-    format!("hello user with id: {user_id}")
+/// Answers 503 while the realtime broker is reconnecting, since sockets get no events meanwhile.
+/// The body stays the plain greeting, `/health/ready` details the state of the broker
+pub async fn auth_health_check(
+    State(state): State<AppState>,
+    Extension(AuthenticatedUser { id: user_id, .. }): Extension<AuthenticatedUser>,
+) -> (StatusCode, String) {
+    let code = if state.broker_health.is_connected() {
+        StatusCode::OK
+    } else {
+        StatusCode::SERVICE_UNAVAILABLE
+    };
+
+    (code, format!("hello user with id: {user_id}"))
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ReadinessStatus {
+    status: &'static str,
+    realtime_broker_connected: bool,
+    skipped_broker_messages: u64,
+}
+
+/// Readiness probe, open to load balancers and orchestrators. Answers 503 while the realtime
+/// broker is reconnecting, since sockets get no events meanwhile
+pub async fn readiness_check(State(state): State<AppState>) -> (StatusCode, Json<ReadinessStatus>) {
+    let connected = state.broker_health.is_connected();
+    let (code, status) = if connected {
+        (StatusCode::OK, "ok")
+    } else {
+        (StatusCode::SERVICE_UNAVAILABLE, "degraded")
+    };
+
+    let readiness = ReadinessStatus {
+        status,
+        realtime_broker_connected: connected,
+        skipped_broker_messages: state.broker_health.skipped_messages(),
+    };
+
+    (code, Json(readiness))
 }
@@ -1,19 +1,26 @@
-use axum::{
-    Extension,
-    extract::{Json, Path, Query, State, rejection::JsonRejection},
-    http::StatusCode,
-    response::IntoResponse,
-};
+use std::num::NonZeroU32;
+
+use axum::{Extension, extract::State, http::StatusCode, response::IntoResponse};
+use chrono::{Duration, Utc};
 use serde::{Deserialize, Serialize};
-use tracing::error;
 use uuid::Uuid;
 
 use crate::{
-    domain::room::RoomVisibility,
-    infra::http_api::AppState,
+    domain::{
+        room::{MemberRole, MessageAnchor, RoomVisibility},
+        user::AuthenticatedUser,
+    },
+    infra::http_api::{
+        AppState,
+        api_error::ApiError,
+        extract::{Json, Path, Query},
+    },
     use_cases::room_service::{
-        create_room, get_all_public_rooms, get_user_rooms_use, join_room, leave_room,
-        obtain_messages, obtain_room_members, send_message, user_is_in_room,
+        ban_member, change_member_role, create_room, delete_message, delete_room, edit_message,
+        get_all_public_rooms, get_user_rooms_with_unread, join_room, kick_member, leave_room,
+        mark_room_read, mute_member, obtain_message_edits, obtain_messages, obtain_moderation_log,
+        obtain_room_members, send_message, transfer_ownership, unban_user, unmute_member,
+        update_room,
     },
 };
 
@@ -24,134 +31,431 @@ pub struct RoomInfo {
     visibility: RoomVisibility,
 }
 
+/// Fields left out are kept as they are
+#[derive(Deserialize, Serialize)]
+pub struct RoomChanges {
+    name: Option<String>,
+    visibility: Option<RoomVisibility>,
+    password: Option<String>,
+}
+
 #[derive(Deserialize, Serialize)]
 pub struct JoinRoomInfo {
     password: Option<String>,
 }
 
+/// At most one of `before`, `after` and `around` can be given, without any the latest messages
+/// are returned
 #[derive(Deserialize, Serialize)]
 pub struct Pagination {
-    page: u32,
+    before: Option<Uuid>,
+    after: Option<Uuid>,
+    around: Option<Uuid>,
+    #[serde(default = "default_page_size")]
     page_size: u8,
 }
 
+fn default_page_size() -> u8 {
+    50
+}
+
+impl Pagination {
+    fn anchor(&self) -> Option<MessageAnchor> {
+        match (self.before, self.after, self.around) {
+            (None, None, None) => Some(MessageAnchor::Latest),
+            (Some(id), None, None) => Some(MessageAnchor::Before(id)),
+            (None, Some(id), None) => Some(MessageAnchor::After(id)),
+            (None, None, Some(id)) => Some(MessageAnchor::Around(id)),
+            _ => None,
+        }
+    }
+}
+
 #[derive(Deserialize, Serialize)]
 pub struct MessageInfo {
     content: String,
 }
 
+#[derive(Deserialize, Serialize)]
+pub struct RoleInfo {
+    role: MemberRole,
+}
+
+#[derive(Deserialize, Serialize)]
+pub struct OwnerInfo {
+    user_id: Uuid,
+}
+
+#[derive(Deserialize, Serialize)]
+pub struct KickInfo {
+    reason: Option<String>,
+}
+
+/// Bans without `duration_minutes` are for good
+#[derive(Deserialize, Serialize)]
+pub struct BanInfo {
+    reason: Option<String>,
+    duration_minutes: Option<NonZeroU32>,
+}
+
+#[derive(Deserialize, Serialize)]
+pub struct MuteInfo {
+    reason: Option<String>,
+    duration_minutes: NonZeroU32,
+}
+
+#[derive(Deserialize, Serialize)]
+pub struct ModerationLogQuery {
+    #[serde(default = "default_page_size")]
+    page_size: u8,
+}
+
+#[derive(Deserialize, Serialize)]
+pub struct ReadInfo {
+    message_id: Uuid,
+}
+
 pub async fn create_room_end(
     State(state): State<AppState>,
-    Extension(user_id): Extension<Uuid>,
+    Extension(AuthenticatedUser { id: user_id, .. }): Extension<AuthenticatedUser>,
     Json(room_info): Json<RoomInfo>,
-) -> impl IntoResponse {
-    match create_room(
+) -> Result<impl IntoResponse, ApiError> {
+    create_room(
         state.db,
         room_info.visibility,
         room_info.password,
         room_info.name,
         user_id,
+        state.redis_publisher,
     )
-    .await
-    {
-        Ok(_) => (StatusCode::OK, "".to_string()),
-        Err(err) => (StatusCode::INTERNAL_SERVER_ERROR, err.to_string()),
-    }
+    .await?;
+
+    Ok(StatusCode::OK)
+}
+
+pub async fn update_room_end(
+    State(state): State<AppState>,
+    Extension(AuthenticatedUser { id: user_id, .. }): Extension<AuthenticatedUser>,
+    Path(room_id): Path<Uuid>,
+    Json(changes): Json<RoomChanges>,
+) -> Result<impl IntoResponse, ApiError> {
+    let room = update_room(
+        state.db,
+        room_id,
+        user_id,
+        changes.name,
+        changes.visibility,
+        changes.password,
+        state.redis_publisher,
+    )
+    .await?;
+
+    Ok((StatusCode::OK, Json(room)))
+}
+
+pub async fn delete_room_end(
+    State(state): State<AppState>,
+    Extension(AuthenticatedUser { id: user_id, .. }): Extension<AuthenticatedUser>,
+    Path(room_id): Path<Uuid>,
+) -> Result<impl IntoResponse, ApiError> {
+    delete_room(state.db, room_id, user_id, state.redis_publisher).await?;
+
+    Ok(StatusCode::OK)
 }
 
 pub async fn join_room_end(
     State(state): State<AppState>,
-    Extension(user_id): Extension<Uuid>,
+    Extension(AuthenticatedUser { id: user_id, .. }): Extension<AuthenticatedUser>,
     Path(room_id): Path<Uuid>,
     Json(join_room_info): Json<JoinRoomInfo>,
-) -> impl IntoResponse {
-    match join_room(
+) -> Result<impl IntoResponse, ApiError> {
+    join_room(
         state.db,
         room_id,
         user_id,
         join_room_info.password,
         state.rabbit_mq,
+        state.redis_publisher,
     )
-    .await
-    {
-        Ok(_) => (StatusCode::OK, "".to_string()),
-        Err(err) => (StatusCode::INTERNAL_SERVER_ERROR, err.to_string()),
-    }
+    .await?;
+
+    Ok(StatusCode::OK)
 }
 
 pub async fn leave_room_end(
     State(state): State<AppState>,
-    Extension(user_id): Extension<Uuid>,
+    Extension(AuthenticatedUser { id: user_id, .. }): Extension<AuthenticatedUser>,
     Path(room_id): Path<Uuid>,
-) -> impl IntoResponse {
-    match leave_room(state.db, state.rabbit_mq, room_id, user_id).await {
-        Ok(_) => (StatusCode::OK, "".to_string()),
-        Err(err) => (StatusCode::INTERNAL_SERVER_ERROR, err.to_string()),
-    }
+) -> Result<impl IntoResponse, ApiError> {
+    leave_room(
+        state.db,
+        state.rabbit_mq,
+        state.redis_publisher,
+        room_id,
+        user_id,
+    )
+    .await?;
+
+    Ok(StatusCode::OK)
 }
 
 pub async fn get_user_rooms_end(
     State(state): State<AppState>,
-    Extension(user_id): Extension<Uuid>,
-) -> Result<impl IntoResponse, impl IntoResponse> {
-    match get_user_rooms_use(state.db, user_id).await {
-        Ok(rooms) => Ok((StatusCode::OK, Json(rooms))),
-        Err(err) => Err((StatusCode::INTERNAL_SERVER_ERROR, err.to_string())),
-    }
+    Extension(AuthenticatedUser { id: user_id, .. }): Extension<AuthenticatedUser>,
+) -> Result<impl IntoResponse, ApiError> {
+    let rooms = get_user_rooms_with_unread(state.db, user_id).await?;
+
+    Ok((StatusCode::OK, Json(rooms)))
 }
 
 pub async fn get_all_public_rooms_end(
     State(state): State<AppState>,
-) -> Result<impl IntoResponse, impl IntoResponse> {
-    match get_all_public_rooms(state.db).await {
-        Ok(rooms) => Ok((StatusCode::OK, Json(rooms))),
-        Err(err) => Err((StatusCode::INTERNAL_SERVER_ERROR, err.to_string())),
-    }
+    Extension(AuthenticatedUser { id: user_id, .. }): Extension<AuthenticatedUser>,
+) -> Result<impl IntoResponse, ApiError> {
+    let rooms = get_all_public_rooms(state.db, user_id).await?;
+
+    Ok((StatusCode::OK, Json(rooms)))
 }
 
 pub async fn send_message_end(
     State(state): State<AppState>,
-    Extension(user_id): Extension<Uuid>,
+    Extension(AuthenticatedUser { id: user_id, .. }): Extension<AuthenticatedUser>,
     Path(room_id): Path<Uuid>,
     Json(message_info): Json<MessageInfo>,
-) -> impl IntoResponse {
-    match send_message(
+) -> Result<impl IntoResponse, ApiError> {
+    let message = send_message(
         state.db,
         room_id,
         user_id,
         message_info.content,
         state.redis_publisher,
     )
-    .await
-    {
-        Ok(_) => (StatusCode::OK, "".to_string()),
-        Err(err) => {
-            error!("Error sending message: {err}");
-            (StatusCode::INTERNAL_SERVER_ERROR, err.to_string())
-        }
-    }
+    .await?;
+
+    Ok((StatusCode::OK, Json(message)))
 }
 
 pub async fn get_messages(
     State(state): State<AppState>,
+    Extension(AuthenticatedUser { id: user_id, .. }): Extension<AuthenticatedUser>,
     Path(room_id): Path<Uuid>,
-    pegination: Query<Pagination>,
-) -> Result<impl IntoResponse, impl IntoResponse> {
-    match obtain_messages(state.db, pegination.page, pegination.page_size, room_id).await {
-        Ok(messages) => Ok((StatusCode::OK, Json(messages))),
-        Err(err) => {
-            error!("Error getting messages: {err}");
-            Err((StatusCode::INTERNAL_SERVER_ERROR, err.to_string()))
-        }
-    }
+    Query(pegination): Query<Pagination>,
+) -> Result<impl IntoResponse, ApiError> {
+    let Some(anchor) = pegination.anchor() else {
+        return Err(ApiError::bad_request(
+            "invalid_pagination",
+            "only one of before, after and around can be given",
+        ));
+    };
+
+    let page = obtain_messages(state.db, room_id, user_id, anchor, pegination.page_size).await?;
+
+    Ok((StatusCode::OK, Json(page)))
+}
+
+pub async fn edit_message_end(
+    State(state): State<AppState>,
+    Extension(AuthenticatedUser { id: user_id, .. }): Extension<AuthenticatedUser>,
+    Path((room_id, message_id)): Path<(Uuid, Uuid)>,
+    Json(message_info): Json<MessageInfo>,
+) -> Result<impl IntoResponse, ApiError> {
+    let message = edit_message(
+        state.db,
+        room_id,
+        message_id,
+        user_id,
+        message_info.content,
+        state.redis_publisher,
+    )
+    .await?;
+
+    Ok((StatusCode::OK, Json(message)))
+}
+
+pub async fn delete_message_end(
+    State(state): State<AppState>,
+    Extension(AuthenticatedUser { id: user_id, .. }): Extension<AuthenticatedUser>,
+    Path((room_id, message_id)): Path<(Uuid, Uuid)>,
+) -> Result<impl IntoResponse, ApiError> {
+    delete_message(
+        state.db,
+        room_id,
+        message_id,
+        user_id,
+        state.redis_publisher,
+    )
+    .await?;
+
+    Ok(StatusCode::OK)
+}
+
+pub async fn get_message_edits_end(
+    State(state): State<AppState>,
+    Extension(AuthenticatedUser { id: user_id, .. }): Extension<AuthenticatedUser>,
+    Path((room_id, message_id)): Path<(Uuid, Uuid)>,
+) -> Result<impl IntoResponse, ApiError> {
+    let edits = obtain_message_edits(state.db, room_id, message_id, user_id).await?;
+
+    Ok((StatusCode::OK, Json(edits)))
 }
 
 pub async fn get_room_members_end(
     State(state): State<AppState>,
+    Extension(AuthenticatedUser { id: user_id, .. }): Extension<AuthenticatedUser>,
     Path(room_id): Path<Uuid>,
-) -> Result<impl IntoResponse, impl IntoResponse> {
-    match obtain_room_members(state.db, room_id).await {
-        Ok(users) => Ok((StatusCode::OK, Json(users))),
-        Err(err) => Err((StatusCode::INTERNAL_SERVER_ERROR, err.to_string())),
-    }
+) -> Result<impl IntoResponse, ApiError> {
+    let users = obtain_room_members(state.db, room_id, user_id).await?;
+
+    Ok((StatusCode::OK, Json(users)))
+}
+
+pub async fn change_member_role_end(
+    State(state): State<AppState>,
+    Extension(AuthenticatedUser { id: user_id, .. }): Extension<AuthenticatedUser>,
+    Path((room_id, member_id)): Path<(Uuid, Uuid)>,
+    Json(role_info): Json<RoleInfo>,
+) -> Result<impl IntoResponse, ApiError> {
+    let member = change_member_role(
+        state.db,
+        room_id,
+        user_id,
+        member_id,
+        role_info.role,
+        state.redis_publisher,
+    )
+    .await?;
+
+    Ok((StatusCode::OK, Json(member)))
+}
+
+pub async fn transfer_ownership_end(
+    State(state): State<AppState>,
+    Extension(AuthenticatedUser { id: user_id, .. }): Extension<AuthenticatedUser>,
+    Path(room_id): Path<Uuid>,
+    Json(owner_info): Json<OwnerInfo>,
+) -> Result<impl IntoResponse, ApiError> {
+    let owner = transfer_ownership(
+        state.db,
+        room_id,
+        user_id,
+        owner_info.user_id,
+        state.redis_publisher,
+    )
+    .await?;
+
+    Ok((StatusCode::OK, Json(owner)))
+}
+
+pub async fn kick_member_end(
+    State(state): State<AppState>,
+    Extension(AuthenticatedUser { id: user_id, .. }): Extension<AuthenticatedUser>,
+    Path((room_id, member_id)): Path<(Uuid, Uuid)>,
+    Json(kick_info): Json<KickInfo>,
+) -> Result<impl IntoResponse, ApiError> {
+    let entry = kick_member(
+        state.db,
+        room_id,
+        user_id,
+        member_id,
+        kick_info.reason,
+        state.redis_publisher,
+    )
+    .await?;
+
+    Ok((StatusCode::OK, Json(entry)))
+}
+
+pub async fn ban_member_end(
+    State(state): State<AppState>,
+    Extension(AuthenticatedUser { id: user_id, .. }): Extension<AuthenticatedUser>,
+    Path((room_id, member_id)): Path<(Uuid, Uuid)>,
+    Json(ban_info): Json<BanInfo>,
+) -> Result<impl IntoResponse, ApiError> {
+    let until = ban_info
+        .duration_minutes
+        .map(|minutes| Utc::now() + Duration::minutes(minutes.get().into()));
+
+    let entry = ban_member(
+        state.db,
+        room_id,
+        user_id,
+        member_id,
+        ban_info.reason,
+        until,
+        state.redis_publisher,
+    )
+    .await?;
+
+    Ok((StatusCode::OK, Json(entry)))
+}
+
+pub async fn unban_user_end(
+    State(state): State<AppState>,
+    Extension(AuthenticatedUser { id: user_id, .. }): Extension<AuthenticatedUser>,
+    Path((room_id, target_id)): Path<(Uuid, Uuid)>,
+) -> Result<impl IntoResponse, ApiError> {
+    let entry = unban_user(state.db, room_id, user_id, target_id, state.redis_publisher).await?;
+
+    Ok((StatusCode::OK, Json(entry)))
+}
+
+pub async fn mute_member_end(
+    State(state): State<AppState>,
+    Extension(AuthenticatedUser { id: user_id, .. }): Extension<AuthenticatedUser>,
+    Path((room_id, member_id)): Path<(Uuid, Uuid)>,
+    Json(mute_info): Json<MuteInfo>,
+) -> Result<impl IntoResponse, ApiError> {
+    let until = Utc::now() + Duration::minutes(mute_info.duration_minutes.get().into());
+
+    let entry = mute_member(
+        state.db,
+        room_id,
+        user_id,
+        member_id,
+        mute_info.reason,
+        until,
+        state.redis_publisher,
+    )
+    .await?;
+
+    Ok((StatusCode::OK, Json(entry)))
+}
+
+pub async fn unmute_member_end(
+    State(state): State<AppState>,
+    Extension(AuthenticatedUser { id: user_id, .. }): Extension<AuthenticatedUser>,
+    Path((room_id, member_id)): Path<(Uuid, Uuid)>,
+) -> Result<impl IntoResponse, ApiError> {
+    let entry = unmute_member(state.db, room_id, user_id, member_id, state.redis_publisher).await?;
+
+    Ok((StatusCode::OK, Json(entry)))
+}
+
+pub async fn get_moderation_log_end(
+    State(state): State<AppState>,
+    Extension(AuthenticatedUser { id: user_id, .. }): Extension<AuthenticatedUser>,
+    Path(room_id): Path<Uuid>,
+    Query(query): Query<ModerationLogQuery>,
+) -> Result<impl IntoResponse, ApiError> {
+    let entries = obtain_moderation_log(state.db, room_id, user_id, query.page_size).await?;
+
+    Ok((StatusCode::OK, Json(entries)))
+}
+
+pub async fn mark_room_read_end(
+    State(state): State<AppState>,
+    Extension(AuthenticatedUser { id: user_id, .. }): Extension<AuthenticatedUser>,
+    Path(room_id): Path<Uuid>,
+    Json(read_info): Json<ReadInfo>,
+) -> Result<impl IntoResponse, ApiError> {
+    mark_room_read(
+        state.db,
+        room_id,
+        user_id,
+        read_info.message_id,
+        state.redis_publisher,
+    )
+    .await?;
+
+    Ok(StatusCode::OK)
 }
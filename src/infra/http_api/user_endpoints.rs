@@ -1,15 +1,23 @@
+use std::net::{IpAddr, SocketAddr};
+
 use axum::{
     Extension,
-    extract::{Json, State},
-    http::StatusCode,
+    extract::{ConnectInfo, State},
+    http::{HeaderMap, StatusCode},
     response::IntoResponse,
 };
+use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
 use uuid::Uuid;
 
 use crate::{
-    infra::http_api::AppState,
-    use_cases::auth_service::{get_user_by_id_use, login, register},
+    domain::user::AuthenticatedUser,
+    infra::http_api::{AppState, api_error::ApiError, extract::Json},
+    use_cases::auth_service::{
+        AuthError, Claims, change_password, delete_account, forgot_password, get_user_by_id_use,
+        login, logout, refresh_tokens, register, resend_verification_email, reset_password,
+        update_profile, verify_email,
+    },
 };
 
 #[derive(Deserialize, Serialize)]
@@ -18,6 +26,47 @@ pub struct AuthInfo {
     password: String,
 }
 
+#[derive(Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RefreshInfo {
+    refresh_token: String,
+}
+
+#[derive(Deserialize, Serialize, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct LogoutInfo {
+    refresh_token: Option<String>,
+}
+
+#[derive(Deserialize, Serialize)]
+pub struct EmailTokenInfo {
+    token: String,
+}
+
+#[derive(Deserialize, Serialize)]
+pub struct ForgotPasswordInfo {
+    email: String,
+}
+
+#[derive(Deserialize, Serialize)]
+pub struct ResetPasswordInfo {
+    token: String,
+    password: String,
+}
+
+#[derive(Deserialize, Serialize)]
+pub struct ProfileInfo {
+    username: Option<String>,
+    email: Option<String>,
+}
+
+#[derive(Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ChangePasswordInfo {
+    current_password: String,
+    new_password: String,
+}
+
 #[derive(Deserialize, Serialize)]
 pub struct RegisterInfo {
     username: String,
@@ -25,46 +74,204 @@ pub struct RegisterInfo {
     password: String,
 }
 
+/// Behind the proxy the peer is the proxy itself, the client is in its `X-Real-IP` header
+fn client_ip(state: &AppState, headers: &HeaderMap, peer: SocketAddr) -> IpAddr {
+    if !state.trust_proxy_headers {
+        return peer.ip();
+    }
+
+    headers
+        .get("x-real-ip")
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.trim().parse().ok())
+        .unwrap_or(peer.ip())
+}
+
 pub async fn login_end(
     State(state): State<AppState>,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
     Json(auth_info): Json<AuthInfo>,
-) -> impl IntoResponse {
-    match login(
+) -> Result<impl IntoResponse, ApiError> {
+    let client_ip = client_ip(&state, &headers, peer);
+
+    let tokens = login(
         state.db,
+        state.login_attempts,
         auth_info.identifier,
         auth_info.password,
-        state.jwt_secret,
+        client_ip,
+        state.jwt_keys,
     )
-    .await
-    {
-        Ok(token) => (StatusCode::OK, token),
-        Err(err) => (StatusCode::UNAUTHORIZED, err.to_string()),
+    .await?;
+
+    Ok((StatusCode::OK, Json(tokens)))
+}
+
+pub async fn refresh_end(
+    State(state): State<AppState>,
+    Json(refresh_info): Json<RefreshInfo>,
+) -> Result<impl IntoResponse, ApiError> {
+    let tokens = refresh_tokens(state.db, refresh_info.refresh_token, state.jwt_keys).await?;
+
+    Ok((StatusCode::OK, Json(tokens)))
+}
+
+/// The `jti` and expiration of the access token the request was authenticated with
+fn access_token_ids(claims: &Claims) -> Result<(Uuid, DateTime<Utc>), ApiError> {
+    match (
+        Uuid::parse_str(&claims.jti),
+        DateTime::from_timestamp(claims.exp as i64, 0),
+    ) {
+        (Ok(jti), Some(expires_at)) => Ok((jti, expires_at)),
+        _ => Err(ApiError::unauthorized(
+            "invalid_token",
+            "invalid jwt claims",
+        )),
     }
 }
 
+/// The body is optional, without a refresh token only the access token is revoked
+pub async fn logout_end(
+    State(state): State<AppState>,
+    Extension(AuthenticatedUser { id: user_id, .. }): Extension<AuthenticatedUser>,
+    Extension(claims): Extension<Claims>,
+    logout_info: Option<Json<LogoutInfo>>,
+) -> Result<impl IntoResponse, ApiError> {
+    let logout_info = logout_info.map(|Json(info)| info).unwrap_or_default();
+    let (jti, expires_at) = access_token_ids(&claims)?;
+
+    logout(
+        state.db,
+        user_id,
+        jti,
+        expires_at,
+        logout_info.refresh_token,
+    )
+    .await?;
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
 pub async fn register_end(
     State(state): State<AppState>,
     Json(register_info): Json<RegisterInfo>,
-) -> impl IntoResponse {
-    match register(
+) -> Result<impl IntoResponse, ApiError> {
+    register(
         state.db,
+        state.mailer,
         register_info.username,
         register_info.password,
         register_info.email,
+        &state.app_url,
     )
-    .await
-    {
-        Ok(_) => (StatusCode::OK, "".to_string()),
-        Err(err) => (StatusCode::UNAUTHORIZED, err.to_string()),
-    }
+    .await?;
+
+    Ok(StatusCode::OK)
+}
+
+pub async fn verify_email_end(
+    State(state): State<AppState>,
+    Json(token_info): Json<EmailTokenInfo>,
+) -> Result<impl IntoResponse, ApiError> {
+    verify_email(state.db, token_info.token).await?;
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+pub async fn resend_verification_email_end(
+    State(state): State<AppState>,
+    Extension(AuthenticatedUser { id: user_id, .. }): Extension<AuthenticatedUser>,
+) -> Result<impl IntoResponse, ApiError> {
+    resend_verification_email(state.db, state.mailer, user_id, &state.app_url).await?;
+
+    Ok(StatusCode::ACCEPTED)
+}
+
+/// Accepted whether or not the email belongs to an account
+pub async fn forgot_password_end(
+    State(state): State<AppState>,
+    Json(forgot_info): Json<ForgotPasswordInfo>,
+) -> Result<impl IntoResponse, ApiError> {
+    forgot_password(state.db, state.mailer, forgot_info.email, &state.app_url).await?;
+
+    Ok(StatusCode::ACCEPTED)
+}
+
+pub async fn reset_password_end(
+    State(state): State<AppState>,
+    Json(reset_info): Json<ResetPasswordInfo>,
+) -> Result<impl IntoResponse, ApiError> {
+    reset_password(state.db, reset_info.token, reset_info.password).await?;
+
+    Ok(StatusCode::NO_CONTENT)
 }
 
 pub async fn get_user_info_end(
     State(state): State<AppState>,
-    Extension(user_id): Extension<Uuid>,
-) -> Result<impl IntoResponse, impl IntoResponse> {
-    match get_user_by_id_use(state.db, user_id).await {
-        Ok(user) => Ok((StatusCode::OK, Json(user))),
-        Err(err) => Err((StatusCode::INTERNAL_SERVER_ERROR, err.to_string())),
-    }
+    Extension(AuthenticatedUser { id: user_id, .. }): Extension<AuthenticatedUser>,
+) -> Result<impl IntoResponse, ApiError> {
+    let user = get_user_by_id_use(state.db, user_id).await?;
+
+    Ok((StatusCode::OK, Json(user)))
+}
+
+pub async fn update_profile_end(
+    State(state): State<AppState>,
+    Extension(AuthenticatedUser { id: user_id, .. }): Extension<AuthenticatedUser>,
+    Json(profile_info): Json<ProfileInfo>,
+) -> Result<impl IntoResponse, ApiError> {
+    let user = update_profile(
+        state.db,
+        state.mailer,
+        user_id,
+        profile_info.username,
+        profile_info.email,
+        &state.app_url,
+    )
+    .await?;
+
+    Ok((StatusCode::OK, Json(user)))
+}
+
+/// Answers with a new token pair, every other session is logged out
+pub async fn change_password_end(
+    State(state): State<AppState>,
+    Extension(AuthenticatedUser { id: user_id, .. }): Extension<AuthenticatedUser>,
+    Json(password_info): Json<ChangePasswordInfo>,
+) -> Result<impl IntoResponse, ApiError> {
+    let tokens = change_password(
+        state.db,
+        user_id,
+        password_info.current_password,
+        password_info.new_password,
+        state.jwt_keys,
+    )
+    .await
+    .map_err(|err| match err {
+        // The user is authenticated, so a wrong password is forbidden rather than unauthorized
+        AuthError::InvalidCredentials => {
+            ApiError::forbidden("wrong_password", "the current password is wrong")
+        }
+        err => err.into(),
+    })?;
+
+    Ok((StatusCode::OK, Json(tokens)))
+}
+
+pub async fn delete_account_end(
+    State(state): State<AppState>,
+    Extension(AuthenticatedUser { id: user_id, .. }): Extension<AuthenticatedUser>,
+    Extension(claims): Extension<Claims>,
+) -> Result<impl IntoResponse, ApiError> {
+    let (jti, expires_at) = access_token_ids(&claims)?;
+
+    delete_account(state.db, user_id, jti, expires_at, state.redis_publisher).await?;
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// Public keys that verify the access tokens, for the services that consume them
+pub async fn jwks_end(State(state): State<AppState>) -> impl IntoResponse {
+    Json(state.jwt_keys.jwks())
 }
@@ -1,5 +1,6 @@
 pub mod database;
 pub mod http_api;
+pub mod mailer;
 pub mod rabbit_mq;
 pub mod redis;
 pub mod web_socket;
@@ -0,0 +1,105 @@
+use chrono::Duration;
+use deadpool_redis::{Connection, Pool};
+
+use crate::use_cases::login_attempts::{LoginAttempts, LoginAttemptsError, LoginAttemptsResult};
+
+pub struct RedisLoginAttempts {
+    pool: Pool,
+}
+
+impl RedisLoginAttempts {
+    pub fn new(pool: Pool) -> RedisLoginAttempts {
+        RedisLoginAttempts { pool }
+    }
+
+    async fn connection(&self) -> LoginAttemptsResult<Connection> {
+        self.pool
+            .get()
+            .await
+            .map_err(|err| LoginAttemptsError::InternalError(err.to_string()))
+    }
+
+    /// Increments the counter, its window starts with the first increment and isn't extended
+    async fn count_in_window(&self, counter: String, window: Duration) -> LoginAttemptsResult<u32> {
+        let mut conn = self.connection().await?;
+
+        let (count,): (u32,) = redis::pipe()
+            .incr(&counter, 1)
+            .cmd("EXPIRE")
+            .arg(&counter)
+            .arg(window.num_seconds().max(1))
+            .arg("NX")
+            .ignore()
+            .query_async(&mut conn)
+            .await
+            .map_err(|err| LoginAttemptsError::InternalError(err.to_string()))?;
+
+        Ok(count)
+    }
+}
+
+fn failures_key(key: &str) -> String {
+    format!("login:failures:{key}")
+}
+
+fn lockouts_key(key: &str) -> String {
+    format!("login:lockouts:{key}")
+}
+
+fn locked_key(key: &str) -> String {
+    format!("login:locked:{key}")
+}
+
+impl LoginAttempts for RedisLoginAttempts {
+    async fn locked_for(&self, key: &str) -> LoginAttemptsResult<Option<Duration>> {
+        let mut conn = self.connection().await?;
+
+        // Negative when the key doesn't exist
+        let remaining_ms: i64 = redis::cmd("PTTL")
+            .arg(locked_key(key))
+            .query_async(&mut conn)
+            .await
+            .map_err(|err| LoginAttemptsError::InternalError(err.to_string()))?;
+
+        Ok((remaining_ms > 0).then(|| Duration::milliseconds(remaining_ms)))
+    }
+
+    async fn record_failure(&self, key: &str, window: Duration) -> LoginAttemptsResult<u32> {
+        self.count_in_window(failures_key(key), window).await
+    }
+
+    async fn record_lockout(&self, key: &str, window: Duration) -> LoginAttemptsResult<u32> {
+        self.count_in_window(lockouts_key(key), window).await
+    }
+
+    async fn lock(&self, key: &str, duration: Duration) -> LoginAttemptsResult<()> {
+        let mut conn = self.connection().await?;
+
+        let _: () = redis::pipe()
+            .cmd("SET")
+            .arg(locked_key(key))
+            .arg(1)
+            .arg("PX")
+            .arg(duration.num_milliseconds().max(1))
+            .ignore()
+            .del(failures_key(key))
+            .ignore()
+            .query_async(&mut conn)
+            .await
+            .map_err(|err| LoginAttemptsError::InternalError(err.to_string()))?;
+
+        Ok(())
+    }
+
+    async fn clear_failures(&self, key: &str) -> LoginAttemptsResult<()> {
+        let mut conn = self.connection().await?;
+
+        let _: () = redis::cmd("DEL")
+            .arg(failures_key(key))
+            .query_async(&mut conn)
+            .await
+            .map_err(|err| LoginAttemptsError::InternalError(err.to_string()))?;
+
+        Ok(())
+    }
+}
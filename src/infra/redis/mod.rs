@@ -1,12 +1,22 @@
+pub mod login_attempts;
+pub mod streams;
+
+use std::collections::HashSet;
+
 use deadpool_redis::{Config, Pool};
 use futures::StreamExt;
 
-use redis::{AsyncCommands, aio::PubSubStream};
+use redis::{
+    RedisResult,
+    aio::{PubSubSink, PubSubStream},
+};
+use serde::Deserialize;
 
 use crate::{
-    domain::room::Message,
+    domain::event::{EventEnvelope, RoomEvent},
+    infra::redis::streams::{RedisStreamConsumer, RedisStreamPublisher},
     use_cases::realtime_broker::{
-        MessagePublisher, MessageSubscriber, RealTimeBrokerError, RealTimeBrokerResult,
+        EventTopic, MessagePublisher, MessageSubscriber, RealTimeBrokerError, RealTimeBrokerResult,
     },
 };
 
@@ -24,21 +34,55 @@ impl RedisPublisher {
 
         RedisPublisher { pool }
     }
+
+    pub fn pool(&self) -> Pool {
+        self.pool.clone()
+    }
+}
+
+/// Every topic has its own channel, so instances only receive the events of the rooms and users
+/// they have sockets for
+fn channel_name(topic: EventTopic) -> String {
+    match topic {
+        EventTopic::Room(room_id) => format!("chat:room:{room_id}"),
+        EventTopic::User(user_id) => format!("chat:user:{user_id}"),
+    }
+}
+
+fn parse_channel_name(channel_name: &str) -> Option<EventTopic> {
+    if let Some(room_id) = channel_name.strip_prefix("chat:room:") {
+        return room_id.parse().ok().map(EventTopic::Room);
+    }
+
+    channel_name
+        .strip_prefix("chat:user:")
+        .and_then(|user_id| user_id.parse().ok())
+        .map(EventTopic::User)
 }
 
 impl MessagePublisher for RedisPublisher {
-    async fn broadcast_message(&self, message: Message) -> RealTimeBrokerResult<()> {
+    async fn publish(&self, event: RoomEvent) -> RealTimeBrokerResult<()> {
         let mut conn = self
             .pool
             .get()
             .await
             .map_err(|err| RealTimeBrokerError::InternalBrokerError(err.to_string()))?;
 
-        let message_str = serde_json::to_string(&message)
+        let mut topics = vec![EventTopic::Room(event.room_id())];
+        if let Some(user_id) = event.user_topic() {
+            topics.push(EventTopic::User(user_id));
+        }
+
+        let event_str = serde_json::to_string(&EventEnvelope::new(event))
             .map_err(|err| RealTimeBrokerError::InternalBrokerError(err.to_string()))?;
 
-        let _: i64 = conn
-            .publish("chat:messages", message_str)
+        let mut pipe = redis::pipe();
+        for topic in topics {
+            pipe.publish(channel_name(topic), &event_str).ignore();
+        }
+
+        let _: () = pipe
+            .query_async(&mut conn)
             .await
             .map_err(|err| RealTimeBrokerError::InternalBrokerError(err.to_string()))?;
 
@@ -48,59 +92,163 @@ impl MessagePublisher for RedisPublisher {
 
 pub struct RedisConsumer {
     redis_url: String,
-    redis_channel: RedisChannel,
+    topics: HashSet<EventTopic>,
+    sink: PubSubSink,
     pubsubstream: PubSubStream,
 }
 
-pub enum RedisChannel {
-    ChatMessages,
+async fn open_pubsub(redis_url: &str) -> RedisResult<(PubSubSink, PubSubStream)> {
+    let client = redis::Client::open(redis_url)?;
+
+    Ok(client.get_async_pubsub().await?.split())
 }
 
 impl RedisConsumer {
-    pub async fn new(redis_url: &str, redis_channel: RedisChannel) -> RedisConsumer {
-        let client = redis::Client::open(redis_url).expect("Error, redis connection failed");
-
-        let mut pubsub = client
-            .get_async_pubsub()
+    /// Starts without subscriptions, topics are added as local sockets need them
+    pub async fn new(redis_url: &str) -> RedisConsumer {
+        let (sink, pubsubstream) = open_pubsub(redis_url)
             .await
             .expect("Error creating pubsub async for redis");
 
-        match redis_channel {
-            RedisChannel::ChatMessages => pubsub
-                .subscribe("chat:messages")
-                .await
-                .expect("Error subscribing to redis"),
-        };
-
-        let msgs: PubSubStream = pubsub.into_on_message();
-
         RedisConsumer {
             redis_url: redis_url.to_string(),
-            redis_channel,
-            pubsubstream: msgs,
+            topics: HashSet::new(),
+            sink,
+            pubsubstream,
         }
     }
-
-    pub async fn reconect(&mut self) -> RealTimeBrokerResult<()> {
-        todo!()
-    }
 }
 
 impl MessageSubscriber for RedisConsumer {
-    async fn consume_message(&mut self) -> RealTimeBrokerResult<Message> {
+    async fn consume_event(&mut self) -> RealTimeBrokerResult<(EventTopic, EventEnvelope)> {
         let msg = self
             .pubsubstream
             .next()
             .await
             .ok_or(RealTimeBrokerError::BrokerConnectionClosed)?;
 
-        let message_str: String = msg
+        let topic = parse_channel_name(msg.get_channel_name()).ok_or_else(|| {
+            RealTimeBrokerError::UndecodableMessage(format!(
+                "unknown channel {}",
+                msg.get_channel_name()
+            ))
+        })?;
+
+        let event_str: String = msg
             .get_payload()
-            .map_err(|err| RealTimeBrokerError::InternalBrokerError(err.to_string()))?;
+            .map_err(|err| RealTimeBrokerError::UndecodableMessage(err.to_string()))?;
+
+        let event: EventEnvelope = serde_json::from_str(&event_str)
+            .map_err(|err| RealTimeBrokerError::UndecodableMessage(err.to_string()))?;
+
+        Ok((topic, event))
+    }
+
+    /// The topic is remembered even when the command fails, so the next reconnection picks it up
+    async fn subscribe(&mut self, topic: EventTopic) -> RealTimeBrokerResult<()> {
+        self.topics.insert(topic);
+
+        self.sink
+            .subscribe(channel_name(topic))
+            .await
+            .map_err(|err| RealTimeBrokerError::InternalBrokerError(err.to_string()))
+    }
+
+    async fn unsubscribe(&mut self, topic: EventTopic) -> RealTimeBrokerResult<()> {
+        self.topics.remove(&topic);
+
+        self.sink
+            .unsubscribe(channel_name(topic))
+            .await
+            .map_err(|err| RealTimeBrokerError::InternalBrokerError(err.to_string()))
+    }
 
-        let message: Message = serde_json::from_str(&message_str)
+    async fn reconect(&mut self) -> RealTimeBrokerResult<()> {
+        let (mut sink, pubsubstream) = open_pubsub(&self.redis_url)
+            .await
             .map_err(|err| RealTimeBrokerError::InternalBrokerError(err.to_string()))?;
 
-        Ok(message)
+        if !self.topics.is_empty() {
+            let channel_names: Vec<String> =
+                self.topics.iter().copied().map(channel_name).collect();
+
+            sink.subscribe(channel_names)
+                .await
+                .map_err(|err| RealTimeBrokerError::InternalBrokerError(err.to_string()))?;
+        }
+
+        self.sink = sink;
+        self.pubsubstream = pubsubstream;
+
+        Ok(())
+    }
+}
+
+/// How events travel between instances. Pub/sub is fire-and-forget, streams let an instance read
+/// the events it missed while restarting or disconnected
+#[derive(Debug, Clone, Copy, Default, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum RealtimeBackend {
+    #[default]
+    PubSub,
+    Streams,
+}
+
+pub enum RedisEventPublisher {
+    PubSub(RedisPublisher),
+    Streams(RedisStreamPublisher),
+}
+
+impl RedisEventPublisher {
+    /// Connection pool of the publisher, shared with the rest of the redis state
+    pub fn pool(&self) -> Pool {
+        match self {
+            RedisEventPublisher::PubSub(publisher) => publisher.pool(),
+            RedisEventPublisher::Streams(publisher) => publisher.pool(),
+        }
+    }
+}
+
+impl MessagePublisher for RedisEventPublisher {
+    async fn publish(&self, event: RoomEvent) -> RealTimeBrokerResult<()> {
+        match self {
+            RedisEventPublisher::PubSub(publisher) => publisher.publish(event).await,
+            RedisEventPublisher::Streams(publisher) => publisher.publish(event).await,
+        }
+    }
+}
+
+pub enum RedisEventConsumer {
+    PubSub(RedisConsumer),
+    Streams(Box<RedisStreamConsumer>),
+}
+
+impl MessageSubscriber for RedisEventConsumer {
+    async fn consume_event(&mut self) -> RealTimeBrokerResult<(EventTopic, EventEnvelope)> {
+        match self {
+            RedisEventConsumer::PubSub(consumer) => consumer.consume_event().await,
+            RedisEventConsumer::Streams(consumer) => consumer.consume_event().await,
+        }
+    }
+
+    async fn subscribe(&mut self, topic: EventTopic) -> RealTimeBrokerResult<()> {
+        match self {
+            RedisEventConsumer::PubSub(consumer) => consumer.subscribe(topic).await,
+            RedisEventConsumer::Streams(consumer) => consumer.subscribe(topic).await,
+        }
+    }
+
+    async fn unsubscribe(&mut self, topic: EventTopic) -> RealTimeBrokerResult<()> {
+        match self {
+            RedisEventConsumer::PubSub(consumer) => consumer.unsubscribe(topic).await,
+            RedisEventConsumer::Streams(consumer) => consumer.unsubscribe(topic).await,
+        }
+    }
+
+    async fn reconect(&mut self) -> RealTimeBrokerResult<()> {
+        match self {
+            RedisEventConsumer::PubSub(consumer) => consumer.reconect().await,
+            RedisEventConsumer::Streams(consumer) => consumer.reconect().await,
+        }
     }
 }
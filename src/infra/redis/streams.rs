@@ -0,0 +1,301 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use deadpool_redis::{Config, Pool};
+use redis::{
+    AsyncCommands, RedisResult,
+    aio::MultiplexedConnection,
+    streams::{
+        StreamAddOptions, StreamId, StreamRangeReply, StreamReadOptions, StreamReadReply,
+        StreamTrimOptions, StreamTrimStrategy, StreamTrimmingMode,
+    },
+};
+
+use crate::{
+    domain::event::{EventEnvelope, RoomEvent},
+    infra::redis::{channel_name, parse_channel_name},
+    use_cases::realtime_broker::{
+        EventTopic, MessagePublisher, MessageSubscriber, RealTimeBrokerError, RealTimeBrokerResult,
+    },
+};
+
+/// Field of the stream entries holding the serialized envelope
+const EVENT_FIELD: &str = "event";
+
+/// How long a read waits for new entries. A subscription change cancels the read, and the next
+/// one is queued behind it on the server, so this also bounds the delay of new subscriptions
+const READ_BLOCK_MS: usize = 1_000;
+
+const READ_COUNT: usize = 100;
+
+/// How the streams are trimmed, entries are dropped when either limit is exceeded
+#[derive(Debug, Clone, Copy)]
+pub struct StreamRetention {
+    pub max_len: usize,
+    pub max_age: Duration,
+}
+
+impl StreamRetention {
+    /// Oldest entry id kept by the age limit
+    fn min_id(&self) -> String {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+
+        format!("{}-0", now.saturating_sub(self.max_age).as_millis())
+    }
+}
+
+/// Appends the events to a stream per topic, so instances that were away can read what they
+/// missed, as long as it was not trimmed yet
+pub struct RedisStreamPublisher {
+    pool: Pool,
+    retention: StreamRetention,
+}
+
+impl RedisStreamPublisher {
+    pub fn new(redis_url: &str, retention: StreamRetention) -> RedisStreamPublisher {
+        let cfg = Config::from_url(redis_url);
+
+        let pool = cfg
+            .create_pool(Some(deadpool_redis::Runtime::Tokio1))
+            .unwrap();
+
+        RedisStreamPublisher { pool, retention }
+    }
+
+    pub fn pool(&self) -> Pool {
+        self.pool.clone()
+    }
+}
+
+impl MessagePublisher for RedisStreamPublisher {
+    async fn publish(&self, event: RoomEvent) -> RealTimeBrokerResult<()> {
+        let mut conn = self
+            .pool
+            .get()
+            .await
+            .map_err(|err| RealTimeBrokerError::InternalBrokerError(err.to_string()))?;
+
+        let mut topics = vec![EventTopic::Room(event.room_id())];
+        if let Some(user_id) = event.user_topic() {
+            topics.push(EventTopic::User(user_id));
+        }
+
+        let event_str = serde_json::to_string(&EventEnvelope::new(event))
+            .map_err(|err| RealTimeBrokerError::InternalBrokerError(err.to_string()))?;
+
+        let add_options = StreamAddOptions::default().trim(StreamTrimStrategy::maxlen(
+            StreamTrimmingMode::Approx,
+            self.retention.max_len,
+        ));
+        let trim_options =
+            StreamTrimOptions::minid(StreamTrimmingMode::Approx, self.retention.min_id());
+
+        let mut pipe = redis::pipe();
+        for topic in topics {
+            let key = channel_name(topic);
+            pipe.xadd_options(&key, "*", &[(EVENT_FIELD, &event_str)], &add_options)
+                .ignore()
+                .xtrim_options(&key, &trim_options)
+                .ignore()
+                // Streams of rooms nobody writes to anymore disappear on their own
+                .expire(&key, self.retention.max_age.as_secs() as i64)
+                .ignore();
+        }
+
+        let _: () = pipe
+            .query_async(&mut conn)
+            .await
+            .map_err(|err| RealTimeBrokerError::InternalBrokerError(err.to_string()))?;
+
+        Ok(())
+    }
+}
+
+/// Reads the streams of the subscribed topics from the last entry it handed out. Positions are
+/// kept per instance in Redis, so a reconnection or a restart with the same instance id resumes
+/// where it stopped instead of losing the events in between
+pub struct RedisStreamConsumer {
+    client: redis::Client,
+    positions_key: String,
+    retention: StreamRetention,
+    positions: HashMap<EventTopic, String>,
+    buffered: VecDeque<RealTimeBrokerResult<(EventTopic, EventEnvelope)>>,
+    reader: MultiplexedConnection,
+    commands: MultiplexedConnection,
+}
+
+async fn open_connections(
+    client: &redis::Client,
+) -> RedisResult<(MultiplexedConnection, MultiplexedConnection)> {
+    // Blocking reads get their own connection, so they never hold back the other commands
+    let reader = client.get_multiplexed_async_connection().await?;
+    let commands = client.get_multiplexed_async_connection().await?;
+
+    Ok((reader, commands))
+}
+
+fn decode_entry(entry: &StreamId) -> RealTimeBrokerResult<EventEnvelope> {
+    let event_str: String = entry.get(EVENT_FIELD).ok_or_else(|| {
+        RealTimeBrokerError::UndecodableMessage(format!("entry {} has no event", entry.id))
+    })?;
+
+    serde_json::from_str(&event_str)
+        .map_err(|err| RealTimeBrokerError::UndecodableMessage(err.to_string()))
+}
+
+impl RedisStreamConsumer {
+    /// Starts without subscriptions, topics are added as local sockets need them
+    pub async fn new(
+        redis_url: &str,
+        instance_id: &str,
+        retention: StreamRetention,
+    ) -> RedisStreamConsumer {
+        let client = redis::Client::open(redis_url).expect("Error creating redis client");
+        let (reader, commands) = open_connections(&client)
+            .await
+            .expect("Error creating stream connections for redis");
+
+        RedisStreamConsumer {
+            client,
+            positions_key: format!("chat:positions:{instance_id}"),
+            retention,
+            positions: HashMap::new(),
+            buffered: VecDeque::new(),
+            reader,
+            commands,
+        }
+    }
+
+    /// Position stored by a previous run of this instance, or the current end of the stream
+    async fn start_position(&mut self, key: &str) -> RedisResult<String> {
+        let stored: Option<String> = self.commands.hget(&self.positions_key, key).await?;
+        if let Some(position) = stored {
+            return Ok(position);
+        }
+
+        let last: StreamRangeReply = self.commands.xrevrange_count(key, "+", "-", 1).await?;
+
+        Ok(last
+            .ids
+            .into_iter()
+            .next()
+            .map(|entry| entry.id)
+            .unwrap_or_else(|| "0-0".to_string()))
+    }
+
+    async fn read_entries(&mut self) -> RealTimeBrokerResult<()> {
+        let (keys, ids): (Vec<String>, Vec<String>) = self
+            .positions
+            .iter()
+            .map(|(topic, position)| (channel_name(*topic), position.clone()))
+            .unzip();
+
+        let options = StreamReadOptions::default()
+            .block(READ_BLOCK_MS)
+            .count(READ_COUNT);
+        let reply: Option<StreamReadReply> = self
+            .reader
+            .xread_options(&keys, &ids, &options)
+            .await
+            .map_err(|err| RealTimeBrokerError::InternalBrokerError(err.to_string()))?;
+
+        let Some(reply) = reply else {
+            return Ok(());
+        };
+
+        let mut advanced = Vec::new();
+        for stream in reply.keys {
+            let Some(topic) = parse_channel_name(&stream.key) else {
+                continue;
+            };
+            let Some(last) = stream.ids.last() else {
+                continue;
+            };
+
+            self.positions.insert(topic, last.id.clone());
+            advanced.push((stream.key.clone(), last.id.clone()));
+
+            for entry in &stream.ids {
+                self.buffered
+                    .push_back(decode_entry(entry).map(|envelope| (topic, envelope)));
+            }
+        }
+
+        if !advanced.is_empty() {
+            let _: () = redis::pipe()
+                .hset_multiple(&self.positions_key, &advanced)
+                .ignore()
+                .expire(&self.positions_key, self.retention.max_age.as_secs() as i64)
+                .ignore()
+                .query_async(&mut self.commands)
+                .await
+                .map_err(|err| RealTimeBrokerError::InternalBrokerError(err.to_string()))?;
+        }
+
+        Ok(())
+    }
+}
+
+impl MessageSubscriber for RedisStreamConsumer {
+    /// Positions only move once a read returned, so a read cancelled by a subscription change
+    /// loses nothing
+    async fn consume_event(&mut self) -> RealTimeBrokerResult<(EventTopic, EventEnvelope)> {
+        loop {
+            if let Some(consumed) = self.buffered.pop_front() {
+                return consumed;
+            }
+
+            if self.positions.is_empty() {
+                // Nothing to read until the broker loop subscribes to a topic
+                std::future::pending::<()>().await;
+            }
+
+            self.read_entries().await?;
+        }
+    }
+
+    async fn subscribe(&mut self, topic: EventTopic) -> RealTimeBrokerResult<()> {
+        let key = channel_name(topic);
+        let position = self
+            .start_position(&key)
+            .await
+            .map_err(|err| RealTimeBrokerError::InternalBrokerError(err.to_string()))?;
+
+        let _: () = self
+            .commands
+            .hset(&self.positions_key, &key, &position)
+            .await
+            .map_err(|err| RealTimeBrokerError::InternalBrokerError(err.to_string()))?;
+        self.positions.insert(topic, position);
+
+        Ok(())
+    }
+
+    /// Forgets the position, a later subscription starts from the end of the stream again
+    async fn unsubscribe(&mut self, topic: EventTopic) -> RealTimeBrokerResult<()> {
+        self.positions.remove(&topic);
+        self.buffered
+            .retain(|consumed| !matches!(consumed, Ok((buffered, _)) if *buffered == topic));
+
+        self.commands
+            .hdel(&self.positions_key, channel_name(topic))
+            .await
+            .map_err(|err| RealTimeBrokerError::InternalBrokerError(err.to_string()))
+    }
+
+    /// Positions are kept, so the events published while disconnected are read next
+    async fn reconect(&mut self) -> RealTimeBrokerResult<()> {
+        let (reader, commands) = open_connections(&self.client)
+            .await
+            .map_err(|err| RealTimeBrokerError::InternalBrokerError(err.to_string()))?;
+
+        self.reader = reader;
+        self.commands = commands;
+
+        Ok(())
+    }
+}
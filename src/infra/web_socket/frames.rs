@@ -0,0 +1,163 @@
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+/// Frames a client can send through a WebSocket, `room_id` can be left out on a room socket
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(
+    tag = "type",
+    rename_all = "camelCase",
+    rename_all_fields = "camelCase"
+)]
+pub enum ClientFrame {
+    /// Persists and broadcasts a message, the server answers with an `ack` frame carrying the
+    /// same `request_id`
+    SendMessage {
+        request_id: Option<String>,
+        room_id: Option<Uuid>,
+        content: String,
+    },
+    Typing {
+        room_id: Option<Uuid>,
+        is_typing: bool,
+    },
+    Ping,
+    /// Only on the multiplexed socket, starts receiving the events of a joined room
+    Subscribe {
+        room_id: Uuid,
+    },
+    /// Only on the multiplexed socket, stops receiving the events of a room without leaving it
+    Unsubscribe {
+        room_id: Uuid,
+    },
+}
+
+/// Frames the server sends in reply to a `ClientFrame`, room events are sent as they are
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(
+    tag = "type",
+    rename_all = "camelCase",
+    rename_all_fields = "camelCase"
+)]
+pub enum ServerFrame {
+    Ack {
+        request_id: Option<String>,
+        message_id: Uuid,
+        created_at: DateTime<Utc>,
+    },
+    Error {
+        request_id: Option<String>,
+        error: String,
+    },
+    Pong,
+    Subscribed {
+        room_id: Uuid,
+    },
+    Unsubscribed {
+        room_id: Uuid,
+    },
+    /// Events were lost, the client has to reload the room, or every room when `room_id` is
+    /// `None`, through the HTTP API
+    ResyncRequired {
+        room_id: Option<Uuid>,
+    },
+}
+
+impl ClientFrame {
+    /// Room the frame targets, when it names one
+    pub fn room_id(&self) -> Option<Uuid> {
+        match self {
+            ClientFrame::SendMessage { room_id, .. } | ClientFrame::Typing { room_id, .. } => {
+                *room_id
+            }
+            ClientFrame::Subscribe { room_id } | ClientFrame::Unsubscribe { room_id } => {
+                Some(*room_id)
+            }
+            ClientFrame::Ping => None,
+        }
+    }
+
+    pub fn request_id(&self) -> Option<String> {
+        match self {
+            ClientFrame::SendMessage { request_id, .. } => request_id.clone(),
+            _ => None,
+        }
+    }
+}
+
+impl ServerFrame {
+    pub fn error(request_id: Option<String>, error: impl ToString) -> ServerFrame {
+        ServerFrame::Error {
+            request_id,
+            error: error.to_string(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use chrono::Utc;
+    use uuid::Uuid;
+
+    use crate::infra::web_socket::frames::{ClientFrame, ServerFrame};
+
+    #[test]
+    fn client_frames_are_read_by_their_camel_case_tag() {
+        let frame: ClientFrame = serde_json::from_str(
+            r#"{"type": "sendMessage", "requestId": "r-1", "content": "hello"}"#,
+        )
+        .unwrap();
+
+        assert!(matches!(&frame, ClientFrame::SendMessage { content, .. } if content == "hello"));
+        assert_eq!(frame.request_id().as_deref(), Some("r-1"));
+        assert_eq!(frame.room_id(), None);
+    }
+
+    #[test]
+    fn client_frames_name_the_room_they_target() {
+        let room_id = Uuid::new_v4();
+
+        let frame: ClientFrame = serde_json::from_str(&format!(
+            r#"{{"type": "typing", "roomId": "{room_id}", "isTyping": true}}"#
+        ))
+        .unwrap();
+
+        assert!(matches!(
+            frame,
+            ClientFrame::Typing {
+                is_typing: true,
+                ..
+            }
+        ));
+        assert_eq!(frame.room_id(), Some(room_id));
+        assert_eq!(frame.request_id(), None);
+    }
+
+    #[test]
+    fn unknown_client_frames_are_rejected() {
+        let frame = serde_json::from_str::<ClientFrame>(
+            r#"{"type": "ack", "messageId": "00000000-0000-0000-0000-000000000000"}"#,
+        );
+
+        assert!(frame.is_err());
+    }
+
+    #[test]
+    fn server_frames_are_written_with_camel_case_fields() {
+        let message_id = Uuid::new_v4();
+
+        let ack = serde_json::to_value(ServerFrame::Ack {
+            request_id: Some("r-1".to_string()),
+            message_id,
+            created_at: Utc::now(),
+        })
+        .unwrap();
+        let resync = serde_json::to_value(ServerFrame::ResyncRequired { room_id: None }).unwrap();
+
+        assert_eq!(ack["type"], "ack");
+        assert_eq!(ack["requestId"], "r-1");
+        assert_eq!(ack["messageId"], message_id.to_string());
+        assert_eq!(resync["type"], "resyncRequired");
+        assert!(resync["roomId"].is_null());
+    }
+}
@@ -1,87 +1,722 @@
+use std::collections::{HashMap, HashSet, hash_map::Entry};
+
 use axum::{
-    extract::{Path, Query, State, WebSocketUpgrade},
-    response::Response,
+    extract::{
+        State, WebSocketUpgrade,
+        ws::{CloseFrame, Message as WsMessage, WebSocket, close_code},
+    },
+    response::{IntoResponse, Response},
+};
+use futures::{
+    SinkExt, StreamExt,
+    stream::{SplitSink, SplitStream},
 };
-use serde::Deserialize;
-use tokio::sync::broadcast;
-use tracing::{error, info};
+use serde::{Deserialize, Serialize};
+use tokio::{
+    sync::{broadcast::error::RecvError, mpsc},
+    task::JoinHandle,
+};
+use tracing::{error, info, warn};
 use uuid::Uuid;
 
 use crate::{
-    infra::http_api::{AppState, middleware_auth::extract_user_id_from_jwt},
-    use_cases::room_service::user_is_in_room,
+    domain::{
+        event::{EventEnvelope, RoomEvent},
+        room::MembershipAction,
+    },
+    infra::{
+        http_api::{
+            AppState,
+            api_error::ApiError,
+            extract::{Path, Query},
+            middleware_auth::extract_user_from_jwt,
+        },
+        web_socket::frames::{ClientFrame, ServerFrame},
+    },
+    use_cases::{
+        realtime_broker::EventTopic,
+        room_service::{
+            check_room_access, get_user_rooms_use, replay_messages, send_message, send_typing,
+            user_is_in_room,
+        },
+    },
 };
 
+pub mod frames;
+
+type SocketSender = SplitSink<WebSocket, WsMessage>;
+
+/// Most messages a reconnecting socket gets replayed before it is asked to resync instead
+const REPLAY_LIMIT: u16 = 500;
+
+/// `last_message_id` is the newest message the client received before reconnecting, the
+/// messages sent after it are replayed before the live events
 #[derive(Deserialize)]
 pub struct WsAuth {
     pub token: String,
+    pub last_message_id: Option<Uuid>,
+}
+
+/// Events a room forwarder hands to a multiplexed socket
+enum RoomFeed {
+    Event(EventEnvelope),
+    Lagged(Uuid),
 }
 
 pub async fn ws_handler(
     Path(room_id): Path<Uuid>,
     ws: WebSocketUpgrade,
-    Query(WsAuth { token }): Query<WsAuth>,
+    Query(WsAuth {
+        token,
+        last_message_id,
+    }): Query<WsAuth>,
     State(state): State<AppState>,
 ) -> Response {
-    let user_id = match extract_user_id_from_jwt(token, &state.jwt_secret) {
-        Ok(id) => id,
-        Err(res) => return res,
+    let user_id = match extract_user_from_jwt(token, &state).await {
+        Ok((user, _)) => user.id,
+        Err(err) => return err.into_response(),
     };
 
     info!("User with id: {user_id} joining room: {}", room_id);
 
-    let is_in_room = match user_is_in_room(state.db.clone(), user_id, room_id).await {
-        Ok(res) => res,
-        Err(_) => {
-            error!("Error verifying room, check the room id");
-            return Response::builder()
-                .status(400)
-                .body("Invalid room".into())
-                .unwrap();
-        }
+    if let Err(err) = check_room_access(&state.db, room_id, user_id).await {
+        return ApiError::from(err).into_response();
+    }
+
+    ws.on_upgrade(move |socket| handle_socket(socket, room_id, user_id, last_message_id, state))
+}
+
+pub async fn multiplexed_ws_handler(
+    ws: WebSocketUpgrade,
+    Query(WsAuth {
+        token,
+        last_message_id,
+    }): Query<WsAuth>,
+    State(state): State<AppState>,
+) -> Response {
+    let user_id = match extract_user_from_jwt(token, &state).await {
+        Ok((user, _)) => user.id,
+        Err(err) => return err.into_response(),
+    };
+
+    let room_ids: Vec<Uuid> = match get_user_rooms_use(state.db.clone(), user_id).await {
+        Ok(rooms) => rooms.iter().map(|room| room.id).collect(),
+        Err(err) => return ApiError::from(err).into_response(),
     };
 
-    if !is_in_room {
-        return Response::builder()
-            .status(403)
-            .body("Not eough access to enter the room".into())
-            .unwrap();
+    info!(
+        "User with id: {user_id} subscribing to {} rooms",
+        room_ids.len()
+    );
+
+    ws.on_upgrade(move |socket| {
+        handle_multiplexed_socket(socket, room_ids, user_id, last_message_id, state)
+    })
+}
+
+/// Events caused by the user are not echoed back to their sockets, except read markers, which
+/// the other devices of the user need to clear their unread counts, and the changes made through
+/// the HTTP API, such as edits, deletions, role changes and leaving the room
+fn is_own_event(event: &RoomEvent, user_id: Uuid) -> bool {
+    match event {
+        RoomEvent::Message(message) => message.sender_id == Some(user_id),
+        RoomEvent::Typing(typing) => typing.user_id == user_id,
+        RoomEvent::Membership(change) => {
+            change.user_id == user_id && change.action == MembershipAction::Joined
+        }
+        RoomEvent::Read(_)
+        | RoomEvent::MessageEdited(_)
+        | RoomEvent::MessageDeleted(_)
+        | RoomEvent::RoleChanged(_)
+        | RoomEvent::Moderation(_)
+        | RoomEvent::RoomUpdated(_)
+        | RoomEvent::RoomDeleted(_) => false,
+    }
+}
+
+/// Reason to close a room socket once the event is sent, when the user can't be in the room anymore
+fn closing_reason(event: &RoomEvent, user_id: Uuid) -> Option<&'static str> {
+    match event {
+        RoomEvent::RoomDeleted(_) => Some("the room was deleted"),
+        RoomEvent::Membership(change)
+            if change.user_id == user_id && change.action == MembershipAction::Left =>
+        {
+            Some("the user left the room")
+        }
+        RoomEvent::Moderation(entry) if entry.target_id == user_id && entry.removes_target() => {
+            Some("the user was removed from the room")
+        }
+        _ => None,
     }
+}
+
+/// Live messages that were already sent to the socket while replaying the missed ones
+fn was_replayed(event: &RoomEvent, replayed: &HashSet<Uuid>) -> bool {
+    matches!(event, RoomEvent::Message(message) if replayed.contains(&message.id))
+}
+
+/// Sends the messages persisted after `last_message_id`, returning their ids, or `None` when the
+/// socket can no longer be written to. Must run after subscribing to the rooms, which returns
+/// once the broker receives their events, so nothing sent in between is lost
+async fn replay_missed(
+    sender: &mut SocketSender,
+    state: &AppState,
+    room_ids: Vec<Uuid>,
+    resync_room: Option<Uuid>,
+    last_message_id: Uuid,
+) -> Option<HashSet<Uuid>> {
+    let frame =
+        match replay_messages(state.db.clone(), room_ids, last_message_id, REPLAY_LIMIT).await {
+            Ok(Some(messages)) => {
+                let mut replayed = HashSet::with_capacity(messages.len());
+
+                for message in messages {
+                    replayed.insert(message.id);
+
+                    if !send_frame(sender, &EventEnvelope::new(RoomEvent::Message(message))).await {
+                        return None;
+                    }
+                }
+
+                return Some(replayed);
+            }
+            Ok(None) => ServerFrame::ResyncRequired {
+                room_id: resync_room,
+            },
+            Err(err) => {
+                error!("Error replaying missed messages: {err}");
+                ServerFrame::error(None, err)
+            }
+        };
+
+    send_frame(sender, &frame).await.then(HashSet::new)
+}
 
-    ws.on_upgrade(move |socket| handle_socket(socket, room_id, user_id, state))
+/// Serializes and sends a frame, returns false when the socket can no longer be written to
+async fn send_frame(sender: &mut SocketSender, frame: &impl Serialize) -> bool {
+    let frame_json = match serde_json::to_string(frame) {
+        Ok(frame_json) => frame_json,
+        Err(err) => {
+            error!("Error converting frame to a string: {err}");
+            return false;
+        }
+    };
+
+    sender.send(frame_json.into()).await.is_ok()
+}
+
+/// Reads the next client frame, `None` means the socket was closed
+async fn next_client_frame(
+    socket_receiver: &mut SplitStream<WebSocket>,
+) -> Option<Result<ClientFrame, ServerFrame>> {
+    loop {
+        match socket_receiver.next().await {
+            Some(Ok(WsMessage::Text(text))) => {
+                return Some(
+                    serde_json::from_str::<ClientFrame>(&text)
+                        .map_err(|err| ServerFrame::error(None, format!("invalid frame: {err}"))),
+                );
+            }
+            Some(Ok(WsMessage::Close(_))) | Some(Err(_)) | None => return None,
+            Some(Ok(_)) => continue,
+        }
+    }
 }
 
 async fn handle_socket(
-    socket: axum::extract::ws::WebSocket,
+    socket: WebSocket,
     room_id: Uuid,
     user_id: Uuid,
+    last_message_id: Option<Uuid>,
     state: AppState,
 ) {
-    let room_tx = state
-        .rooms_channels
-        .entry(room_id)
-        .or_insert_with(|| broadcast::channel(1_000).0)
-        .value()
-        .clone();
+    let mut receiver = state.channels.subscribe(EventTopic::Room(room_id)).await;
 
-    let mut receiver = room_tx.subscribe();
+    let (mut sender, mut socket_receiver) = socket.split();
 
-    let mut sender = socket;
+    let replayed = match last_message_id {
+        Some(last_message_id) => {
+            match replay_missed(
+                &mut sender,
+                &state,
+                vec![room_id],
+                Some(room_id),
+                last_message_id,
+            )
+            .await
+            {
+                Some(replayed) => replayed,
+                None => return,
+            }
+        }
+        None => HashSet::new(),
+    };
+
+    loop {
+        tokio::select! {
+            event = receiver.recv() => {
+                let event = match event {
+                    Ok(event) => event,
+                    Err(RecvError::Lagged(skipped)) => {
+                        warn!("Socket of user {user_id} lagged {skipped} events behind {room_id}");
+
+                        let resync = ServerFrame::ResyncRequired {
+                            room_id: Some(room_id),
+                        };
+                        if !send_frame(&mut sender, &resync).await {
+                            break;
+                        }
+
+                        continue;
+                    }
+                    Err(RecvError::Closed) => break,
+                };
+
+                if is_own_event(&event.event, user_id) || was_replayed(&event.event, &replayed) {
+                    continue;
+                }
+
+                if !send_frame(&mut sender, &event).await {
+                    break;
+                }
 
-    while let Ok(msg) = receiver.recv().await {
-        if msg.sender_id == user_id {
-            continue;
+                if let Some(reason) = closing_reason(&event.event, user_id) {
+                    let close = CloseFrame {
+                        code: close_code::NORMAL,
+                        reason: reason.into(),
+                    };
+                    let _ = sender.send(WsMessage::Close(Some(close))).await;
+                    break;
+                }
+            }
+            frame = next_client_frame(&mut socket_receiver) => {
+                let reply = match frame {
+                    Some(Ok(frame)) => match frame.room_id() {
+                        Some(frame_room) if frame_room != room_id => Some(ServerFrame::error(
+                            frame.request_id(),
+                            "the frame targets a different room than the socket",
+                        )),
+                        _ => handle_client_frame(frame, Some(room_id), user_id, &state).await,
+                    },
+                    Some(Err(reply)) => Some(reply),
+                    None => break,
+                };
+
+                if let Some(reply) = reply
+                    && !send_frame(&mut sender, &reply).await
+                {
+                    break;
+                }
+            }
         }
+    }
+}
 
-        let msg_json = if let Ok(msg_json) = serde_json::to_string(&msg) {
-            msg_json
-        } else {
-            error!("Error converting message to a string: {msg:?}");
-            break;
+/// Starts forwarding the events of a room into the channel of a multiplexed socket, once the
+/// broker is subscribed to it
+async fn forward_room(
+    state: &AppState,
+    room_id: Uuid,
+    events_tx: mpsc::Sender<RoomFeed>,
+) -> JoinHandle<()> {
+    let mut receiver = state.channels.subscribe(EventTopic::Room(room_id)).await;
+
+    tokio::spawn(async move {
+        loop {
+            let feed = match receiver.recv().await {
+                Ok(event) => RoomFeed::Event(event),
+                Err(RecvError::Lagged(_)) => RoomFeed::Lagged(room_id),
+                Err(RecvError::Closed) => break,
+            };
+
+            if events_tx.send(feed).await.is_err() {
+                break;
+            }
+        }
+    })
+}
+
+async fn handle_multiplexed_socket(
+    socket: WebSocket,
+    room_ids: Vec<Uuid>,
+    user_id: Uuid,
+    last_message_id: Option<Uuid>,
+    state: AppState,
+) {
+    let (events_tx, mut events_rx) = mpsc::channel(1_000);
+
+    let mut subscriptions: HashMap<Uuid, JoinHandle<()>> = HashMap::with_capacity(room_ids.len());
+    for &room_id in &room_ids {
+        let subscription = forward_room(&state, room_id, events_tx.clone()).await;
+        subscriptions.insert(room_id, subscription);
+    }
+
+    let mut user_receiver = state.channels.subscribe(EventTopic::User(user_id)).await;
+
+    let (mut sender, mut socket_receiver) = socket.split();
+
+    let replayed = match last_message_id {
+        Some(last_message_id) => {
+            match replay_missed(&mut sender, &state, room_ids, None, last_message_id).await {
+                Some(replayed) => replayed,
+                None => return,
+            }
+        }
+        None => HashSet::new(),
+    };
+
+    loop {
+        let reply = tokio::select! {
+            feed = events_rx.recv() => {
+                match feed {
+                    Some(RoomFeed::Event(event)) => {
+                        // Events about the user itself come through the topic of the user
+                        if is_own_event(&event.event, user_id)
+                            || was_replayed(&event.event, &replayed)
+                            || event.event.user_topic() == Some(user_id)
+                        {
+                            continue;
+                        }
+
+                        if !send_frame(&mut sender, &event).await {
+                            break;
+                        }
+
+                        // The subscription of a deleted room is dropped once its members know
+                        let RoomEvent::RoomDeleted(deletion) = event.event else {
+                            continue;
+                        };
+                        if let Some(subscription) = subscriptions.remove(&deletion.room_id) {
+                            subscription.abort();
+                        }
+                        Some(ServerFrame::Unsubscribed {
+                            room_id: deletion.room_id,
+                        })
+                    }
+                    Some(RoomFeed::Lagged(room_id)) => {
+                        warn!("Socket of user {user_id} lagged behind room {room_id}");
+                        Some(ServerFrame::ResyncRequired {
+                            room_id: Some(room_id),
+                        })
+                    }
+                    None => break,
+                }
+            }
+            user_event = user_receiver.recv() => {
+                let event = match user_event {
+                    Ok(event) => event,
+                    Err(RecvError::Lagged(_)) => {
+                        let resync = ServerFrame::ResyncRequired { room_id: None };
+                        if !send_frame(&mut sender, &resync).await {
+                            break;
+                        }
+
+                        continue;
+                    }
+                    Err(RecvError::Closed) => break,
+                };
+
+                match &event.event {
+                    RoomEvent::Membership(change) => match change.action {
+                        MembershipAction::Joined => {
+                            if let Entry::Vacant(entry) = subscriptions.entry(change.room_id) {
+                                entry.insert(
+                                    forward_room(&state, change.room_id, events_tx.clone()).await,
+                                );
+                            }
+                            Some(ServerFrame::Subscribed {
+                                room_id: change.room_id,
+                            })
+                        }
+                        MembershipAction::Left => {
+                            if let Some(subscription) = subscriptions.remove(&change.room_id) {
+                                subscription.abort();
+                            }
+                            Some(ServerFrame::Unsubscribed {
+                                room_id: change.room_id,
+                            })
+                        }
+                    },
+                    RoomEvent::Moderation(entry) => {
+                        if !send_frame(&mut sender, &event).await {
+                            break;
+                        }
+
+                        if !entry.removes_target() {
+                            continue;
+                        }
+                        if let Some(subscription) = subscriptions.remove(&entry.room_id) {
+                            subscription.abort();
+                        }
+                        Some(ServerFrame::Unsubscribed {
+                            room_id: entry.room_id,
+                        })
+                    }
+                    _ => continue,
+                }
+            }
+            frame = next_client_frame(&mut socket_receiver) => {
+                match frame {
+                    Some(Ok(frame)) => {
+                        handle_multiplexed_frame(frame, &mut subscriptions, &events_tx, user_id, &state)
+                            .await
+                    }
+                    Some(Err(reply)) => Some(reply),
+                    None => break,
+                }
+            }
         };
 
-        if sender.send(msg_json.into()).await.is_err() {
+        if let Some(reply) = reply
+            && !send_frame(&mut sender, &reply).await
+        {
             break;
         }
     }
+
+    for subscription in subscriptions.into_values() {
+        subscription.abort();
+    }
+}
+
+async fn handle_multiplexed_frame(
+    frame: ClientFrame,
+    subscriptions: &mut HashMap<Uuid, JoinHandle<()>>,
+    events_tx: &mpsc::Sender<RoomFeed>,
+    user_id: Uuid,
+    state: &AppState,
+) -> Option<ServerFrame> {
+    match frame {
+        ClientFrame::Subscribe { room_id } => {
+            if let Entry::Vacant(entry) = subscriptions.entry(room_id) {
+                match user_is_in_room(state.db.clone(), user_id, room_id).await {
+                    Ok(true) => {
+                        entry.insert(forward_room(state, room_id, events_tx.clone()).await);
+                    }
+                    Ok(false) => {
+                        return Some(ServerFrame::error(
+                            None,
+                            "the user is not a member of the room",
+                        ));
+                    }
+                    Err(err) => return Some(ServerFrame::error(None, err)),
+                }
+            }
+
+            Some(ServerFrame::Subscribed { room_id })
+        }
+        ClientFrame::Unsubscribe { room_id } => {
+            if let Some(subscription) = subscriptions.remove(&room_id) {
+                subscription.abort();
+            }
+
+            Some(ServerFrame::Unsubscribed { room_id })
+        }
+        frame => match frame.room_id() {
+            Some(room_id) if !subscriptions.contains_key(&room_id) => Some(ServerFrame::error(
+                frame.request_id(),
+                "the socket is not subscribed to the room",
+            )),
+            room_id => handle_client_frame(frame, room_id, user_id, state).await,
+        },
+    }
+}
+
+/// Runs the use case behind a client frame, returning the frame to answer with, if any
+async fn handle_client_frame(
+    frame: ClientFrame,
+    room_id: Option<Uuid>,
+    user_id: Uuid,
+    state: &AppState,
+) -> Option<ServerFrame> {
+    match (frame, room_id) {
+        (
+            ClientFrame::SendMessage {
+                request_id,
+                content,
+                ..
+            },
+            Some(room_id),
+        ) => match send_message(
+            state.db.clone(),
+            room_id,
+            user_id,
+            content,
+            state.redis_publisher.clone(),
+        )
+        .await
+        {
+            Ok(message) => Some(ServerFrame::Ack {
+                request_id,
+                message_id: message.id,
+                created_at: message.created_at,
+            }),
+            Err(err) => {
+                error!("Error sending message through the socket: {err}");
+                Some(ServerFrame::error(request_id, err))
+            }
+        },
+        (ClientFrame::Typing { is_typing, .. }, Some(room_id)) => {
+            match send_typing(
+                state.db.clone(),
+                room_id,
+                user_id,
+                is_typing,
+                state.redis_publisher.clone(),
+            )
+            .await
+            {
+                Ok(_) => None,
+                Err(err) => Some(ServerFrame::error(None, err)),
+            }
+        }
+        (ClientFrame::Ping, _) => Some(ServerFrame::Pong),
+        (ClientFrame::Subscribe { .. } | ClientFrame::Unsubscribe { .. }, _) => Some(
+            ServerFrame::error(None, "subscriptions are only available on the /ws socket"),
+        ),
+        (frame, None) => Some(ServerFrame::error(
+            frame.request_id(),
+            "the frame must name the room it targets",
+        )),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::HashSet;
+
+    use chrono::Utc;
+    use uuid::Uuid;
+
+    use crate::{
+        domain::{
+            dto::MessageView,
+            event::RoomEvent,
+            room::{
+                MembershipAction, MembershipChange, ModerationAction, ModerationEntry,
+                RoomDeletion, TypingIndicator,
+            },
+        },
+        infra::web_socket::{closing_reason, is_own_event, was_replayed},
+    };
+
+    fn message_from(sender_id: Uuid) -> RoomEvent {
+        RoomEvent::Message(MessageView {
+            id: Uuid::new_v4(),
+            room_id: Uuid::new_v4(),
+            sender_id: Some(sender_id),
+            sender_username: Some("someone".to_string()),
+            content: "hello".to_string(),
+            created_at: Utc::now(),
+            edited_at: None,
+            deleted_at: None,
+        })
+    }
+
+    fn moderation_of(target_id: Uuid, action: ModerationAction) -> RoomEvent {
+        RoomEvent::Moderation(ModerationEntry {
+            id: Uuid::new_v4(),
+            room_id: Uuid::new_v4(),
+            target_id,
+            moderator_id: Some(Uuid::new_v4()),
+            action,
+            reason: None,
+            expires_at: None,
+            created_at: Utc::now(),
+        })
+    }
+
+    #[test]
+    fn own_messages_and_typing_are_not_echoed() {
+        let user_id = Uuid::new_v4();
+
+        assert!(is_own_event(&message_from(user_id), user_id));
+        assert!(!is_own_event(&message_from(Uuid::new_v4()), user_id));
+        assert!(is_own_event(
+            &RoomEvent::Typing(TypingIndicator {
+                room_id: Uuid::new_v4(),
+                user_id,
+                is_typing: true,
+            }),
+            user_id
+        ));
+    }
+
+    #[test]
+    fn own_changes_through_the_http_api_are_echoed() {
+        let user_id = Uuid::new_v4();
+
+        assert!(!is_own_event(
+            &RoomEvent::RoomDeleted(RoomDeletion {
+                room_id: Uuid::new_v4(),
+                deleted_by: user_id,
+            }),
+            user_id
+        ));
+        assert!(!is_own_event(
+            &moderation_of(user_id, ModerationAction::Mute),
+            user_id
+        ));
+        assert!(!is_own_event(
+            &RoomEvent::Membership(MembershipChange {
+                room_id: Uuid::new_v4(),
+                user_id,
+                action: MembershipAction::Left,
+            }),
+            user_id
+        ));
+    }
+
+    #[test]
+    fn sockets_are_closed_when_the_user_leaves_is_removed_or_the_room_deleted() {
+        let user_id = Uuid::new_v4();
+        let membership_of = |user_id, action| {
+            RoomEvent::Membership(MembershipChange {
+                room_id: Uuid::new_v4(),
+                user_id,
+                action,
+            })
+        };
+
+        let deleted = RoomEvent::RoomDeleted(RoomDeletion {
+            room_id: Uuid::new_v4(),
+            deleted_by: Uuid::new_v4(),
+        });
+        assert!(closing_reason(&deleted, user_id).is_some());
+        assert!(closing_reason(&moderation_of(user_id, ModerationAction::Ban), user_id).is_some());
+        assert!(closing_reason(&moderation_of(user_id, ModerationAction::Mute), user_id).is_none());
+        assert!(
+            closing_reason(
+                &moderation_of(Uuid::new_v4(), ModerationAction::Kick),
+                user_id
+            )
+            .is_none()
+        );
+        assert!(closing_reason(&membership_of(user_id, MembershipAction::Left), user_id).is_some());
+        assert!(
+            closing_reason(&membership_of(user_id, MembershipAction::Joined), user_id).is_none()
+        );
+        assert!(
+            closing_reason(
+                &membership_of(Uuid::new_v4(), MembershipAction::Left),
+                user_id
+            )
+            .is_none()
+        );
+    }
+
+    #[test]
+    fn only_replayed_messages_are_skipped() {
+        let message = message_from(Uuid::new_v4());
+        let RoomEvent::Message(view) = &message else {
+            unreachable!()
+        };
+
+        assert!(was_replayed(&message, &HashSet::from([view.id])));
+        assert!(!was_replayed(&message, &HashSet::new()));
+        assert!(!was_replayed(
+            &message_from(Uuid::new_v4()),
+            &HashSet::from([view.id])
+        ));
+    }
 }
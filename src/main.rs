@@ -1,36 +1,137 @@
-use std::sync::Arc;
+use std::{sync::Arc, time::Duration};
 
-use dashmap::DashMap;
 use dotenvy::dotenv;
 use serde::Deserialize;
 use tracing::info;
+use uuid::Uuid;
 
-use crate::{
+use nebula_backend::{
     infra::{
         database::PostgresDatabase,
-        http_api::start_http_api,
+        http_api::{AppState, start_http_api},
+        mailer::{AppMailer, FileMailer, SmtpMailer},
         rabbit_mq::RabbitMQ,
-        redis::{RedisConsumer, RedisPublisher},
+        redis::{
+            RealtimeBackend, RedisConsumer, RedisEventConsumer, RedisEventPublisher,
+            RedisPublisher,
+            login_attempts::RedisLoginAttempts,
+            streams::{RedisStreamConsumer, RedisStreamPublisher, StreamRetention},
+        },
+    },
+    use_cases::{
+        jwt_keys::{DEFAULT_AUDIENCE, DEFAULT_ISSUER, JwtKeys},
+        realtime_service::{BrokerHealth, EventChannels, realtime_messsage_broker},
     },
-    use_cases::{realtime_service::realtime_messsage_broker, user_database::UserDatabase},
 };
 
-mod domain;
-mod infra;
-mod use_cases;
-
 #[derive(Deserialize, Debug)]
 struct EnvVariables {
     backend_addr: String,
     database_url: String,
-    jwt_secret: String,
+    /// HS256 secret, also verifies the tokens without `kid` once signing keys are set
+    jwt_secret: Option<String>,
+    jwt_signing_key_path: Option<String>,
+    jwt_signing_key_id: Option<String>,
+    /// Directory of `{kid}.pem` public keys, every one of them verifies tokens
+    jwt_public_keys_dir: Option<String>,
+    #[serde(default = "default_jwt_issuer")]
+    jwt_issuer: String,
+    #[serde(default = "default_jwt_audience")]
+    jwt_audience: String,
     redis_url: String,
+    #[serde(default)]
+    realtime_backend: RealtimeBackend,
+    #[serde(default = "default_redis_stream_max_len")]
+    redis_stream_max_len: usize,
+    #[serde(default = "default_redis_stream_max_age_secs")]
+    redis_stream_max_age_secs: u64,
+    /// Keeps the stream positions of the instance across restarts, random when not set
+    instance_id: Option<String>,
     rabbitmq_host: String,
     rabbitmq_port: u16,
     rabbitmq_username: String,
     rabbitmq_password: String,
     rabbitmq_vhost: String,
     dev_mode: bool,
+    /// Set when the backend is only reachable through the proxy, which sets `X-Real-IP`
+    #[serde(default)]
+    trust_proxy_headers: bool,
+    /// Emails are written to `mail_dir` when not set
+    smtp_url: Option<String>,
+    #[serde(default = "default_mail_from")]
+    mail_from: String,
+    #[serde(default = "default_mail_dir")]
+    mail_dir: String,
+    #[serde(default = "default_app_url")]
+    app_url: String,
+}
+
+fn default_jwt_issuer() -> String {
+    DEFAULT_ISSUER.to_string()
+}
+
+fn default_jwt_audience() -> String {
+    DEFAULT_AUDIENCE.to_string()
+}
+
+fn default_mail_from() -> String {
+    "Nebula <no-reply@nebula.local>".to_string()
+}
+
+fn default_mail_dir() -> String {
+    "./mails".to_string()
+}
+
+fn default_app_url() -> String {
+    "http://localhost".to_string()
+}
+
+fn default_redis_stream_max_len() -> usize {
+    10_000
+}
+
+fn default_redis_stream_max_age_secs() -> u64 {
+    3_600
+}
+
+fn load_jwt_keys(env_vars: &EnvVariables) -> JwtKeys {
+    let (Some(signing_key_path), Some(signing_kid), Some(public_keys_dir)) = (
+        &env_vars.jwt_signing_key_path,
+        &env_vars.jwt_signing_key_id,
+        &env_vars.jwt_public_keys_dir,
+    ) else {
+        let secret = env_vars
+            .jwt_secret
+            .as_deref()
+            .expect("JWT_SECRET or the JWT signing keys must be set");
+        return JwtKeys::from_secret(secret);
+    };
+
+    let signing_pem = std::fs::read(signing_key_path).expect("Error reading the JWT signing key");
+
+    let mut public_pems = Vec::new();
+    for entry in std::fs::read_dir(public_keys_dir).expect("Error reading the JWT public keys") {
+        let path = entry.expect("Error reading the JWT public keys").path();
+        if path.extension().is_some_and(|extension| extension == "pem")
+            && let Some(kid) = path.file_stem().and_then(|stem| stem.to_str())
+        {
+            let pem = std::fs::read(&path).expect("Error reading a JWT public key");
+            public_pems.push((kid.to_string(), pem));
+        }
+    }
+
+    info!(
+        "Signing JWTs with key {signing_kid}, {} verification keys",
+        public_pems.len()
+    );
+
+    JwtKeys::from_pem(
+        signing_kid,
+        &signing_pem,
+        public_pems,
+        env_vars.jwt_secret.as_deref(),
+    )
+    .expect("Error loading the JWT keys")
 }
 
 #[tokio::main(flavor = "multi_thread")]
@@ -40,17 +141,58 @@ async fn main() {
 
     let env_vars = envy::from_env::<EnvVariables>().unwrap();
 
+    let jwt_keys = Arc::new(
+        load_jwt_keys(&env_vars)
+            .with_issuer_and_audience(&env_vars.jwt_issuer, &env_vars.jwt_audience),
+    );
+
+    let mailer = Arc::new(match &env_vars.smtp_url {
+        Some(smtp_url) => AppMailer::Smtp(SmtpMailer::new(smtp_url, &env_vars.mail_from)),
+        None => {
+            info!(
+                "SMTP_URL not set, writing the emails to {}",
+                env_vars.mail_dir
+            );
+            std::fs::create_dir_all(&env_vars.mail_dir).expect("Error creating the mail directory");
+            AppMailer::File(FileMailer::new(&env_vars.mail_dir, &env_vars.mail_from))
+        }
+    });
+
     let postgres_database = Arc::new(PostgresDatabase::new(&env_vars.database_url).await.unwrap());
 
-    let message_publisher = Arc::new(RedisPublisher::new(&env_vars.redis_url).await);
+    let (message_publisher, message_consumer) = match env_vars.realtime_backend {
+        RealtimeBackend::PubSub => (
+            RedisEventPublisher::PubSub(RedisPublisher::new(&env_vars.redis_url).await),
+            RedisEventConsumer::PubSub(RedisConsumer::new(&env_vars.redis_url).await),
+        ),
+        RealtimeBackend::Streams => {
+            let retention = StreamRetention {
+                max_len: env_vars.redis_stream_max_len,
+                max_age: Duration::from_secs(env_vars.redis_stream_max_age_secs),
+            };
+            let instance_id = env_vars
+                .instance_id
+                .clone()
+                .unwrap_or_else(|| Uuid::new_v4().to_string());
+            info!("Using redis streams as instance {instance_id}");
 
-    let message_consumer = RedisConsumer::new(
-        &env_vars.redis_url,
-        infra::redis::RedisChannel::ChatMessages,
-    )
-    .await;
+            (
+                RedisEventPublisher::Streams(RedisStreamPublisher::new(
+                    &env_vars.redis_url,
+                    retention,
+                )),
+                RedisEventConsumer::Streams(Box::new(
+                    RedisStreamConsumer::new(&env_vars.redis_url, &instance_id, retention).await,
+                )),
+            )
+        }
+    };
+    let login_attempts = Arc::new(RedisLoginAttempts::new(message_publisher.pool()));
+    let message_publisher = Arc::new(message_publisher);
 
-    let rooms_channels = Arc::new(DashMap::new());
+    let (channels, subscription_changes) = EventChannels::new();
+    let channels = Arc::new(channels);
+    let broker_health = Arc::new(BrokerHealth::default());
 
     info!("Initializating rabbit mq");
     let rabbit_mq = Arc::new(
@@ -66,19 +208,30 @@ async fn main() {
 
     info!("the addr is: {}", env_vars.backend_addr);
 
-    let rooms_channels1 = rooms_channels.clone();
+    let channels1 = channels.clone();
+    let broker_health1 = broker_health.clone();
     tokio::spawn(async move {
-        realtime_messsage_broker(message_consumer, rooms_channels1).await;
+        realtime_messsage_broker(
+            message_consumer,
+            channels1,
+            subscription_changes,
+            broker_health1,
+        )
+        .await;
     });
 
-    start_http_api(
-        env_vars.backend_addr,
-        env_vars.jwt_secret,
-        postgres_database,
-        rooms_channels.clone(),
-        message_publisher,
+    let app_state = AppState {
+        db: postgres_database,
+        jwt_keys,
+        channels,
+        redis_publisher: message_publisher,
         rabbit_mq,
-        env_vars.dev_mode,
-    )
-    .await;
+        broker_health,
+        login_attempts,
+        trust_proxy_headers: env_vars.trust_proxy_headers,
+        mailer,
+        app_url: env_vars.app_url,
+    };
+
+    start_http_api(env_vars.backend_addr, app_state, env_vars.dev_mode).await;
 }
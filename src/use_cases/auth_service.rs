@@ -1,115 +1,756 @@
-use std::sync::Arc;
+use std::{net::IpAddr, sync::Arc};
 
+use base64::{Engine, engine::general_purpose::URL_SAFE_NO_PAD};
 use bcrypt::{DEFAULT_COST, hash, verify};
 use chrono::{DateTime, Duration, Utc};
-use jsonwebtoken::{EncodingKey, Header, encode};
+use rand::RngCore;
 use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
 use thiserror::Error;
-use tracing::error;
+use tracing::{error, warn};
 use uuid::Uuid;
 
-use crate::{domain::user::User, use_cases::user_database::UserDatabase};
+use crate::{
+    domain::{
+        dto::TokenPair,
+        event::RoomEvent,
+        room::{MemberRole, MembershipAction, MembershipChange, RoleChange},
+        user::{LoginLockout, RefreshToken, User, UserRole, UserToken, UserTokenPurpose},
+    },
+    use_cases::{
+        jwt_keys::JwtKeys,
+        login_attempts::LoginAttempts,
+        mailer::{Email, Mailer},
+        realtime_broker::MessagePublisher,
+        user_database::{UserDatabase, UserDatabaseError},
+        validation::{
+            FieldError, ValidationErrors, validate_email, validate_password, validate_registration,
+            validate_username,
+        },
+    },
+};
 
 type AuthResult<T> = Result<T, AuthError>;
 
-#[derive(Debug, Serialize, Deserialize)]
+/// Access tokens are short lived, revoking them one by one is only needed on logout
+const ACCESS_TOKEN_MINUTES: i64 = 15;
+
+const REFRESH_TOKEN_DAYS: i64 = 30;
+
+const ACCOUNT_MAX_FAILURES: u32 = 5;
+
+/// Higher than the account limit, since many users can share an address
+const IP_MAX_FAILURES: u32 = 20;
+
+const FAILURES_WINDOW_MINUTES: i64 = 15;
+
+/// The first lockout lasts this long, every other one within a day doubles it
+const BASE_LOCKOUT_SECONDS: i64 = 30;
+
+const MAX_LOCKOUT_SECONDS: i64 = 60 * 60;
+
+const LOCKOUTS_WINDOW_HOURS: i64 = 24;
+
+const EMAIL_VERIFICATION_HOURS: i64 = 48;
+
+const PASSWORD_RESET_MINUTES: i64 = 60;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Claims {
     pub exp: usize,
+    pub iat: usize,
+    pub iss: String,
+    pub aud: String,
     pub sub: String,
+    /// Identifies the token in the revocation list
+    pub jti: String,
+    pub username: String,
+    pub role: UserRole,
+}
+
+fn hash_token(refresh_token: &str) -> String {
+    format!("{:x}", Sha256::digest(refresh_token.as_bytes()))
+}
+
+fn new_token() -> String {
+    let mut bytes = [0u8; 32];
+    rand::rng().fill_bytes(&mut bytes);
+
+    URL_SAFE_NO_PAD.encode(bytes)
+}
+
+fn encode_access_token(user: &User, jwt_keys: &JwtKeys) -> AuthResult<String> {
+    let now = Utc::now();
+    let my_claims = Claims {
+        exp: (now + Duration::minutes(ACCESS_TOKEN_MINUTES)).timestamp() as usize,
+        iat: now.timestamp() as usize,
+        iss: jwt_keys.issuer().to_string(),
+        aud: jwt_keys.audience().to_string(),
+        sub: format!("{}", user.id),
+        jti: format!("{}", Uuid::new_v4()),
+        username: user.username.clone(),
+        role: user.role,
+    };
+
+    jwt_keys
+        .encode(&my_claims)
+        .map_err(|_| AuthError::EncodingTokenError)
+}
+
+/// Issues an access token and a refresh token of the family. When `rotated` is set that token is
+/// revoked in the same step, and a token that was already rotated revokes the whole family
+async fn issue_tokens(
+    database: &impl UserDatabase,
+    user: &User,
+    family_id: Uuid,
+    rotated: Option<Uuid>,
+    jwt_keys: &JwtKeys,
+) -> AuthResult<TokenPair> {
+    let refresh_token = new_token();
+    let now = Utc::now();
+    let stored = RefreshToken {
+        id: Uuid::new_v4(),
+        user_id: user.id,
+        family_id,
+        token_hash: hash_token(&refresh_token),
+        expires_at: now + Duration::days(REFRESH_TOKEN_DAYS),
+        created_at: now,
+        revoked_at: None,
+    };
+
+    match rotated {
+        Some(old_id) => {
+            let rotated = database
+                .rotate_refresh_token(old_id, stored)
+                .await
+                .map_err(AuthError::from)?;
+
+            if !rotated {
+                return Err(revoke_reused_family(database, family_id).await);
+            }
+        }
+        None => database
+            .create_refresh_token(stored)
+            .await
+            .map_err(AuthError::from)?,
+    }
+
+    Ok(TokenPair {
+        access_token: encode_access_token(user, jwt_keys)?,
+        refresh_token,
+        expires_in: ACCESS_TOKEN_MINUTES * 60,
+    })
+}
+
+async fn revoke_reused_family(database: &impl UserDatabase, family_id: Uuid) -> AuthError {
+    warn!("Refresh token of family {family_id} reused, revoking the family");
+
+    match database.revoke_token_family(family_id).await {
+        Ok(_) => AuthError::RefreshTokenReused,
+        Err(err) => err.into(),
+    }
+}
+
+/// Whose failed logins are counted, an account or a client address
+#[derive(Debug, Clone, Copy)]
+enum AttemptsKey {
+    Account(Uuid),
+    Ip(IpAddr),
+}
+
+impl AttemptsKey {
+    fn key(&self) -> String {
+        match self {
+            AttemptsKey::Account(user_id) => format!("account:{user_id}"),
+            AttemptsKey::Ip(ip) => format!("ip:{ip}"),
+        }
+    }
+
+    fn max_failures(&self) -> u32 {
+        match self {
+            AttemptsKey::Account(_) => ACCOUNT_MAX_FAILURES,
+            AttemptsKey::Ip(_) => IP_MAX_FAILURES,
+        }
+    }
 }
 
+fn lockout_duration(lockouts: u32) -> Duration {
+    let doublings = lockouts.saturating_sub(1).min(16);
+
+    Duration::seconds((BASE_LOCKOUT_SECONDS << doublings).min(MAX_LOCKOUT_SECONDS))
+}
+
+/// Logins keep working while the attempts store is down, only without throttling
+async fn check_lockout(login_attempts: &impl LoginAttempts, key: AttemptsKey) -> AuthResult<()> {
+    match login_attempts.locked_for(&key.key()).await {
+        Ok(Some(remaining)) => Err(AuthError::TooManyAttempts(remaining)),
+        Ok(None) => Ok(()),
+        Err(err) => {
+            error!("Error checking the lockout of {}: {err}", key.key());
+            Ok(())
+        }
+    }
+}
+
+/// Counts the failure and locks the key once it reaches its limit, returning the lockout
+async fn register_failure(
+    database: &impl UserDatabase,
+    login_attempts: &impl LoginAttempts,
+    key: AttemptsKey,
+) -> Option<Duration> {
+    let failures = match login_attempts
+        .record_failure(&key.key(), Duration::minutes(FAILURES_WINDOW_MINUTES))
+        .await
+    {
+        Ok(failures) => failures,
+        Err(err) => {
+            error!("Error recording a failed login of {}: {err}", key.key());
+            return None;
+        }
+    };
+
+    if failures < key.max_failures() {
+        return None;
+    }
+
+    let lockouts = login_attempts
+        .record_lockout(&key.key(), Duration::hours(LOCKOUTS_WINDOW_HOURS))
+        .await
+        .unwrap_or_else(|err| {
+            error!("Error counting the lockouts of {}: {err}", key.key());
+            1
+        });
+    let duration = lockout_duration(lockouts);
+
+    if let Err(err) = login_attempts.lock(&key.key(), duration).await {
+        error!("Error locking {}: {err}", key.key());
+        return None;
+    }
+
+    warn!(
+        "Locking {} for {}s after {failures} failed logins",
+        key.key(),
+        duration.num_seconds()
+    );
+
+    let now = Utc::now();
+    let (user_id, ip_address) = match key {
+        AttemptsKey::Account(user_id) => (Some(user_id), None),
+        AttemptsKey::Ip(ip) => (None, Some(ip.to_string())),
+    };
+    let lockout = LoginLockout {
+        id: Uuid::new_v4(),
+        user_id,
+        ip_address,
+        failed_attempts: failures as i32,
+        locked_until: now + duration,
+        created_at: now,
+    };
+    if let Err(err) = database.record_login_lockout(lockout).await {
+        error!("Error recording the lockout of {}: {err}", key.key());
+    }
+
+    Some(duration)
+}
+
+/// The failure that locks a key is already answered as a lockout
+async fn failed_login(
+    database: &impl UserDatabase,
+    login_attempts: &impl LoginAttempts,
+    keys: &[AttemptsKey],
+) -> AuthError {
+    let mut locked_for = None;
+    for key in keys {
+        locked_for = locked_for.max(register_failure(database, login_attempts, *key).await);
+    }
+
+    locked_for.map_or(AuthError::InvalidCredentials, AuthError::TooManyAttempts)
+}
+
+/// Failed attempts are counted per account and per client address, both are locked out for
+/// longer every time they reach their limit
 pub async fn login(
     database: Arc<impl UserDatabase>,
+    login_attempts: Arc<impl LoginAttempts>,
     identificator: String,
     password: String,
-    jwt_secret: String,
-) -> AuthResult<String> {
+    client_ip: IpAddr,
+    jwt_keys: Arc<JwtKeys>,
+) -> AuthResult<TokenPair> {
+    let ip_key = AttemptsKey::Ip(client_ip);
+    check_lockout(login_attempts.as_ref(), ip_key).await?;
+
+    // Only an unknown identifier counts as a failure, an outage mustn't lock anyone out
     let user = match database.get_user_by_username(identificator.clone()).await {
         Ok(user) => user,
-        Err(_) => match database.get_user_by_email(identificator.clone()).await {
-            Ok(user) => user,
-            Err(err) => {
-                error!("err: {err}");
-                return Err(AuthError::InvalidCredentials);
+        Err(UserDatabaseError::NotFound) => {
+            match database.get_user_by_email(identificator.clone()).await {
+                Ok(user) => user,
+                Err(UserDatabaseError::NotFound) => {
+                    return Err(failed_login(
+                        database.as_ref(),
+                        login_attempts.as_ref(),
+                        &[ip_key],
+                    )
+                    .await);
+                }
+                Err(err) => return Err(err.into()),
             }
-        },
+        }
+        Err(err) => return Err(err.into()),
     };
 
+    let account_key = AttemptsKey::Account(user.id);
+    check_lockout(login_attempts.as_ref(), account_key).await?;
+
     let succesful = verify(password, &user.password_hash)
         .map_err(|err| AuthError::ErrorVerifying(err.to_string()))?;
 
     if succesful {
-        let my_claims = Claims {
-            exp: (Utc::now() + Duration::hours(3)).timestamp() as usize,
-            sub: format!("{}", user.id),
-        };
+        if let Err(err) = login_attempts.clear_failures(&account_key.key()).await {
+            error!(
+                "Error clearing the failed logins of {}: {err}",
+                account_key.key()
+            );
+        }
 
-        let token = encode(
-            &Header::default(),
-            &my_claims,
-            &EncodingKey::from_secret(jwt_secret.as_ref()),
+        issue_tokens(database.as_ref(), &user, Uuid::new_v4(), None, &jwt_keys).await
+    } else {
+        Err(failed_login(
+            database.as_ref(),
+            login_attempts.as_ref(),
+            &[account_key, ip_key],
         )
-        .map_err(|_| AuthError::EncodingTokenError)?;
+        .await)
+    }
+}
 
-        Ok(token)
-    } else {
-        Err(AuthError::InvalidCredentials)
+/// Exchanges a refresh token for a new pair, the token can't be used again afterwards
+pub async fn refresh_tokens(
+    database: Arc<impl UserDatabase>,
+    refresh_token: String,
+    jwt_keys: Arc<JwtKeys>,
+) -> AuthResult<TokenPair> {
+    let stored = database
+        .get_refresh_token(hash_token(&refresh_token))
+        .await
+        .map_err(AuthError::from)?
+        .ok_or(AuthError::InvalidRefreshToken)?;
+
+    if stored.revoked_at.is_some() {
+        return Err(revoke_reused_family(database.as_ref(), stored.family_id).await);
     }
+
+    if stored.expires_at <= Utc::now() {
+        return Err(AuthError::InvalidRefreshToken);
+    }
+
+    // Read again, so the new access token carries the current username and role
+    let user = database
+        .get_user_by_id(stored.user_id)
+        .await
+        .map_err(AuthError::from)?;
+
+    issue_tokens(
+        database.as_ref(),
+        &user,
+        stored.family_id,
+        Some(stored.id),
+        &jwt_keys,
+    )
+    .await
+}
+
+/// Revokes the access token, and the family of the refresh token when it belongs to the user
+pub async fn logout(
+    database: Arc<impl UserDatabase>,
+    user_id: Uuid,
+    jti: Uuid,
+    access_expires_at: DateTime<Utc>,
+    refresh_token: Option<String>,
+) -> AuthResult<()> {
+    database
+        .revoke_access_token(jti, access_expires_at)
+        .await
+        .map_err(AuthError::from)?;
+
+    let Some(refresh_token) = refresh_token else {
+        return Ok(());
+    };
+
+    let stored = database
+        .get_refresh_token(hash_token(&refresh_token))
+        .await
+        .map_err(AuthError::from)?;
+
+    if let Some(stored) = stored.filter(|stored| stored.user_id == user_id) {
+        database
+            .revoke_token_family(stored.family_id)
+            .await
+            .map_err(AuthError::from)?;
+    }
+
+    Ok(())
+}
+
+/// Stores a single-use token for the user and emails it as a link to the frontend
+async fn send_token_email(
+    database: &impl UserDatabase,
+    mailer: &impl Mailer,
+    user: &User,
+    purpose: UserTokenPurpose,
+    app_url: &str,
+) -> AuthResult<()> {
+    let token = new_token();
+    let now = Utc::now();
+    let expires_in = match purpose {
+        UserTokenPurpose::EmailVerification => Duration::hours(EMAIL_VERIFICATION_HOURS),
+        UserTokenPurpose::PasswordReset => Duration::minutes(PASSWORD_RESET_MINUTES),
+    };
+
+    database
+        .create_user_token(UserToken {
+            id: Uuid::new_v4(),
+            user_id: user.id,
+            purpose,
+            token_hash: hash_token(&token),
+            expires_at: now + expires_in,
+            created_at: now,
+            used_at: None,
+        })
+        .await
+        .map_err(AuthError::from)?;
+
+    let email = match purpose {
+        UserTokenPurpose::EmailVerification => Email {
+            to: user.email.clone(),
+            subject: "Verify your Nebula email".to_string(),
+            body: format!(
+                "Hi {},\n\nOpen {app_url}/verify-email?token={token} to verify your email. The link expires in {EMAIL_VERIFICATION_HOURS} hours.",
+                user.username
+            ),
+        },
+        UserTokenPurpose::PasswordReset => Email {
+            to: user.email.clone(),
+            subject: "Reset your Nebula password".to_string(),
+            body: format!(
+                "Hi {},\n\nOpen {app_url}/reset-password?token={token} to choose a new password. The link expires in {PASSWORD_RESET_MINUTES} minutes, ignore this email if you didn't ask for it.",
+                user.username
+            ),
+        },
+    };
+
+    mailer
+        .send(email)
+        .await
+        .map_err(|err| AuthError::MailerError(err.to_string()))
 }
 
+/// The account works right away, the verification email can be sent again if it gets lost
 pub async fn register(
     database: Arc<impl UserDatabase>,
+    mailer: Arc<impl Mailer>,
     username: String,
     password: String,
     email: String,
+    app_url: &str,
 ) -> AuthResult<()> {
+    let email = email.trim().to_string();
+    validate_registration(&username, &email, &password).map_err(AuthError::InvalidFields)?;
+
     let encrypted_password = hash(&password, DEFAULT_COST)
         .map_err(|err| AuthError::PasswordHashingFailed(err.to_string()))?;
 
-    if username.is_empty() {
-        return Err(AuthError::InvalidUsernameError(
-            "The username is empty".to_string(),
-        ));
-    }
-
-    if password.is_empty() {
-        return Err(AuthError::InvalidUsernameError(
-            "The username is empty".to_string(),
-        ));
-    }
-
-    if !password.chars().any(|char| char.is_ascii_digit()) {
-        return Err(AuthError::InvalidPasswordError(
-            "The password must contain a digit".to_string(),
-        ));
-    } else if password.len() < 8 {
-        return Err(AuthError::InvalidPasswordError(
-            "The lenght of the password must be 8, or greater than 8".to_string(),
-        ));
-    }
-
     let user = User {
         id: Uuid::new_v4(),
         username,
         email,
         password_hash: encrypted_password,
+        role: UserRole::User,
+        email_verified_at: None,
         created_at: Utc::now(),
         updated_at: Utc::now(),
     };
 
-    database.create_user(user).await.map_err(|err| {
-        error!("Create user query database error: {err}");
-        AuthError::AlreadyExisting("Username already exists".to_string())
-    })?;
+    database
+        .create_user(user.clone())
+        .await
+        .map_err(AuthError::from)?;
+
+    if let Err(err) = send_token_email(
+        database.as_ref(),
+        mailer.as_ref(),
+        &user,
+        UserTokenPurpose::EmailVerification,
+        app_url,
+    )
+    .await
+    {
+        error!("Error sending the verification email of {}: {err}", user.id);
+    }
 
     Ok(())
 }
 
-pub async fn get_user_by_id_use(db: Arc<impl UserDatabase>, user_id: Uuid) -> AuthResult<User> {
-    let user = db
+pub async fn resend_verification_email(
+    database: Arc<impl UserDatabase>,
+    mailer: Arc<impl Mailer>,
+    user_id: Uuid,
+    app_url: &str,
+) -> AuthResult<()> {
+    let user = database
         .get_user_by_id(user_id)
         .await
-        .map_err(|err| AuthError::DatabaseError(err.to_string()))?;
+        .map_err(AuthError::from)?;
+
+    if user.email_verified_at.is_some() {
+        return Err(AuthError::EmailAlreadyVerified);
+    }
+
+    send_token_email(
+        database.as_ref(),
+        mailer.as_ref(),
+        &user,
+        UserTokenPurpose::EmailVerification,
+        app_url,
+    )
+    .await
+}
+
+pub async fn verify_email(database: Arc<impl UserDatabase>, token: String) -> AuthResult<()> {
+    let token = database
+        .consume_user_token(hash_token(&token), UserTokenPurpose::EmailVerification)
+        .await
+        .map_err(AuthError::from)?
+        .ok_or(AuthError::InvalidEmailToken)?;
+
+    database
+        .mark_email_verified(token.user_id)
+        .await
+        .map_err(AuthError::from)
+}
+
+/// Answers the same whether the email belongs to an account or not, so it can't be used to find
+/// out which emails are registered
+pub async fn forgot_password(
+    database: Arc<impl UserDatabase>,
+    mailer: Arc<impl Mailer>,
+    email: String,
+    app_url: &str,
+) -> AuthResult<()> {
+    let user = match database.get_user_by_email(email).await {
+        Ok(user) => user,
+        Err(UserDatabaseError::NotFound) => return Ok(()),
+        Err(err) => return Err(err.into()),
+    };
+
+    if let Err(err) = send_token_email(
+        database.as_ref(),
+        mailer.as_ref(),
+        &user,
+        UserTokenPurpose::PasswordReset,
+        app_url,
+    )
+    .await
+    {
+        error!(
+            "Error sending the password reset email of {}: {err}",
+            user.id
+        );
+    }
+
+    Ok(())
+}
+
+/// Sets the new password and logs out every session, since the old password may have leaked
+pub async fn reset_password(
+    database: Arc<impl UserDatabase>,
+    token: String,
+    password: String,
+) -> AuthResult<()> {
+    // Checked first, so a rejected password doesn't use up the token
+    validate_password(&password).map_err(|err| AuthError::InvalidFields(err.into()))?;
+
+    let token = database
+        .consume_user_token(hash_token(&token), UserTokenPurpose::PasswordReset)
+        .await
+        .map_err(AuthError::from)?
+        .ok_or(AuthError::InvalidEmailToken)?;
+
+    let password_hash = hash(&password, DEFAULT_COST)
+        .map_err(|err| AuthError::PasswordHashingFailed(err.to_string()))?;
+
+    database
+        .update_password(token.user_id, password_hash)
+        .await
+        .map_err(AuthError::from)?;
+
+    database
+        .revoke_user_sessions(token.user_id, Utc::now())
+        .await
+        .map_err(AuthError::from)
+}
+
+/// Only the given fields change, a new email has to be verified again
+pub async fn update_profile(
+    database: Arc<impl UserDatabase>,
+    mailer: Arc<impl Mailer>,
+    user_id: Uuid,
+    username: Option<String>,
+    email: Option<String>,
+    app_url: &str,
+) -> AuthResult<User> {
+    let mut user = database
+        .get_user_by_id(user_id)
+        .await
+        .map_err(AuthError::from)?;
+    let username = username.filter(|username| *username != user.username);
+    let email = email
+        .map(|email| email.trim().to_string())
+        .filter(|email| *email != user.email);
+
+    let mut errors = ValidationErrors::default();
+    if let Some(Err(err)) = username.as_deref().map(validate_username) {
+        errors.errors.push(err);
+    }
+    if let Some(Err(err)) = email.as_deref().map(validate_email) {
+        errors.errors.push(err);
+    }
+    if !errors.errors.is_empty() {
+        return Err(AuthError::InvalidFields(errors));
+    }
+
+    if username.is_none() && email.is_none() {
+        return Ok(user);
+    }
+
+    let email_changed = email.is_some();
+    if let Some(username) = username {
+        user.username = username;
+    }
+    if let Some(email) = email {
+        user.email = email;
+        user.email_verified_at = None;
+    }
+
+    let user = database.update_user(user).await.map_err(AuthError::from)?;
+
+    if email_changed
+        && let Err(err) = send_token_email(
+            database.as_ref(),
+            mailer.as_ref(),
+            &user,
+            UserTokenPurpose::EmailVerification,
+            app_url,
+        )
+        .await
+    {
+        error!("Error sending the verification email of {}: {err}", user.id);
+    }
+
+    Ok(user)
+}
+
+/// Logs out every session and returns a new pair, so the client making the change stays
+/// logged in
+pub async fn change_password(
+    database: Arc<impl UserDatabase>,
+    user_id: Uuid,
+    current_password: String,
+    new_password: String,
+    jwt_keys: Arc<JwtKeys>,
+) -> AuthResult<TokenPair> {
+    let user = database
+        .get_user_by_id(user_id)
+        .await
+        .map_err(AuthError::from)?;
+
+    let correct = verify(current_password, &user.password_hash)
+        .map_err(|err| AuthError::ErrorVerifying(err.to_string()))?;
+    if !correct {
+        return Err(AuthError::InvalidCredentials);
+    }
+
+    validate_password(&new_password).map_err(|err| {
+        AuthError::InvalidFields(
+            FieldError {
+                field: "newPassword",
+                ..err
+            }
+            .into(),
+        )
+    })?;
+
+    let password_hash = hash(&new_password, DEFAULT_COST)
+        .map_err(|err| AuthError::PasswordHashingFailed(err.to_string()))?;
+
+    database
+        .update_password(user.id, password_hash)
+        .await
+        .map_err(AuthError::from)?;
+
+    database
+        .revoke_user_sessions(user.id, Utc::now())
+        .await
+        .map_err(AuthError::from)?;
+
+    issue_tokens(database.as_ref(), &user, Uuid::new_v4(), None, &jwt_keys).await
+}
+
+/// Deletes the account and revokes the access token used for it, the refresh tokens go with
+/// the user and its other access tokens are refused once it is gone. The members of the rooms of the user are told as if the user had deleted every
+/// message and left: the new owners of the rooms through `RoleChanged` events, and the sockets
+/// of the user are closed by the `Left` ones
+pub async fn delete_account(
+    database: Arc<impl UserDatabase>,
+    user_id: Uuid,
+    jti: Uuid,
+    access_expires_at: DateTime<Utc>,
+    message_publisher: Arc<impl MessagePublisher>,
+) -> AuthResult<()> {
+    let deletion = database
+        .delete_user(user_id)
+        .await
+        .map_err(AuthError::from)?;
+
+    database
+        .revoke_access_token(jti, access_expires_at)
+        .await
+        .map_err(AuthError::from)?;
+
+    let role_changes = deletion.handovers.into_iter().filter_map(|handover| {
+        let heir = handover.new_owner?;
+        Some(RoomEvent::RoleChanged(RoleChange {
+            room_id: handover.room_id,
+            user_id: heir,
+            role: MemberRole::Owner,
+            changed_by: user_id,
+        }))
+    });
+    let deleted_messages = deletion
+        .deleted_messages
+        .into_iter()
+        .map(RoomEvent::MessageDeleted);
+    let departures = deletion.room_ids.into_iter().map(|room_id| {
+        RoomEvent::Membership(MembershipChange {
+            room_id,
+            user_id,
+            action: MembershipAction::Left,
+        })
+    });
+
+    for event in role_changes.chain(deleted_messages).chain(departures) {
+        message_publisher
+            .publish(event)
+            .await
+            .map_err(|err| AuthError::BroadcastError(err.to_string()))?;
+    }
+
+    Ok(())
+}
+
+pub async fn get_user_by_id_use(db: Arc<impl UserDatabase>, user_id: Uuid) -> AuthResult<User> {
+    let user = db.get_user_by_id(user_id).await.map_err(AuthError::from)?;
 
     Ok(user)
 }
@@ -126,51 +767,369 @@ pub enum AuthError {
     InvalidCredentials,
     #[error("encode token Error")]
     EncodingTokenError,
-    #[error("Invalid username error: {0}")]
-    InvalidUsernameError(String),
-    #[error("Invalid password error: {0}")]
-    InvalidPasswordError(String),
+    #[error("{0}")]
+    InvalidFields(ValidationErrors),
     #[error("Already existing identifier: {0}")]
     AlreadyExisting(String),
+    #[error("invalid or expired refresh token")]
+    InvalidRefreshToken,
+    #[error("refresh token already used")]
+    RefreshTokenReused,
+    #[error("too many failed login attempts, retry in {} seconds", .0.num_seconds().max(1))]
+    TooManyAttempts(Duration),
+    #[error("invalid, used or expired token")]
+    InvalidEmailToken,
+    #[error("the email is already verified")]
+    EmailAlreadyVerified,
+    #[error("mailer error: {0}")]
+    MailerError(String),
+    #[error("the user does not exist")]
+    UserNotFound,
+    #[error("the database is unavailable")]
+    Unavailable(String),
+    #[error("broadcast error {0}")]
+    BroadcastError(String),
+}
+
+impl From<UserDatabaseError> for AuthError {
+    fn from(err: UserDatabaseError) -> Self {
+        match err {
+            // Tells the client which of its unique fields is taken
+            UserDatabaseError::UsernameTaken => AuthError::AlreadyExisting("username".to_string()),
+            UserDatabaseError::EmailTaken => AuthError::AlreadyExisting("email".to_string()),
+            UserDatabaseError::NotFound => AuthError::UserNotFound,
+            UserDatabaseError::Unavailable(err) => AuthError::Unavailable(err),
+            err => AuthError::DatabaseError(err.to_string()),
+        }
+    }
 }
 
 #[cfg(test)]
 mod test {
-    use std::sync::Arc;
+    use std::{net::Ipv4Addr, sync::Arc};
 
     use bcrypt::{DEFAULT_COST, hash, verify};
-    use chrono::Utc;
-    use jsonwebtoken::{DecodingKey, Validation, decode};
-    use mockall::predicate;
+    use chrono::{Duration, Utc};
     use uuid::Uuid;
 
     use crate::{
-        domain::user::User,
+        domain::{
+            dto::MessageView,
+            event::RoomEvent,
+            room::{MemberRole, MembershipAction, RoomHandover},
+            user::{AccountDeletion, RefreshToken, User, UserRole, UserToken, UserTokenPurpose},
+        },
         use_cases::{
-            auth_service::{AuthError, Claims, get_user_by_id_use, login, register},
+            auth_service::{
+                ACCOUNT_MAX_FAILURES, AuthError, Claims, change_password, delete_account,
+                forgot_password, get_user_by_id_use, hash_token, lockout_duration, login, logout,
+                refresh_tokens, register, reset_password, update_profile, verify_email,
+            },
+            jwt_keys::JwtKeys,
+            login_attempts::MockLoginAttempts,
+            mailer::{MailerError, MockMailer},
+            realtime_broker::MockMessagePublisher,
             user_database::{MockUserDatabase, UserDatabaseError},
         },
     };
 
+    const SECRET: &str =
+        "swNItsMArrAbN2ueHZBWBA5Nk6N8zKWoybXhMK0EuhHso2IvCiFyQAIb6m_8SmicCRZ2x2nEHkxXgCYAoN3-XA";
+
+    const APP_URL: &str = "https://nebula.test";
+
+    const CLIENT_IP: Ipv4Addr = Ipv4Addr::new(10, 0, 0, 1);
+
+    fn unlocked_attempts() -> MockLoginAttempts {
+        let mut attempts = MockLoginAttempts::new();
+        attempts.expect_locked_for().returning(|_| Ok(None));
+        attempts.expect_record_failure().returning(|_, _| Ok(1));
+        attempts.expect_clear_failures().returning(|_| Ok(()));
+
+        attempts
+    }
+
+    fn juan(user_id: Uuid) -> User {
+        User {
+            id: user_id,
+            username: "juan".to_string(),
+            email: "juan@juan.juan".to_string(),
+            password_hash: hash("pasword", DEFAULT_COST).unwrap(),
+            role: UserRole::User,
+            email_verified_at: None,
+            created_at: Utc::now(),
+            updated_at: Utc::now(),
+        }
+    }
+
+    fn stored_refresh_token(user_id: Uuid, family_id: Uuid, revoked: bool) -> RefreshToken {
+        RefreshToken {
+            id: Uuid::new_v4(),
+            user_id,
+            family_id,
+            token_hash: hash_token("refresh"),
+            expires_at: Utc::now() + Duration::days(1),
+            created_at: Utc::now(),
+            revoked_at: revoked.then(Utc::now),
+        }
+    }
+
+    #[tokio::test]
+    async fn register_test() {
+        let mut db = MockUserDatabase::new();
+
+        let mut mailer = MockMailer::new();
+
+        db.expect_create_user()
+            .withf(|user| verify("password123*", &user.password_hash).unwrap_or(false))
+            .once()
+            .returning(|_| Ok(()));
+        db.expect_create_user_token()
+            .withf(|token| token.purpose == UserTokenPurpose::EmailVerification)
+            .once()
+            .returning(|_| Ok(()));
+        mailer
+            .expect_send()
+            .withf(|email| {
+                email.to == "juan@juan.juan"
+                    && email
+                        .body
+                        .contains("https://nebula.test/verify-email?token=")
+            })
+            .once()
+            .returning(|_| Ok(()));
+
+        register(
+            Arc::new(db),
+            Arc::new(mailer),
+            "juan".to_string(),
+            "password123*".to_string(),
+            "juan@juan.juan".to_string(),
+            APP_URL,
+        )
+        .await
+        .unwrap();
+    }
+
+    #[tokio::test]
+    async fn invalid_registrations_never_reach_the_database() {
+        let mut db = MockUserDatabase::new();
+
+        db.expect_create_user().never();
+
+        let result = register(
+            Arc::new(db),
+            Arc::new(MockMailer::new()),
+            "j".to_string(),
+            "password".to_string(),
+            "juan@juan.juan".to_string(),
+            APP_URL,
+        )
+        .await;
+
+        let Err(AuthError::InvalidFields(errors)) = result else {
+            panic!("expected invalid fields, got {result:?}");
+        };
+        let fields: Vec<&str> = errors.errors.iter().map(|error| error.field).collect();
+        assert_eq!(fields, vec!["username", "password"]);
+    }
+
+    #[tokio::test]
+    async fn taken_emails_are_rejected_on_registration() {
+        let mut db = MockUserDatabase::new();
+
+        db.expect_create_user()
+            .once()
+            .returning(|_| Err(UserDatabaseError::EmailTaken));
+        db.expect_create_user_token().never();
+
+        let result = register(
+            Arc::new(db),
+            Arc::new(MockMailer::new()),
+            "juan".to_string(),
+            "password123*".to_string(),
+            "JUAN@juan.juan".to_string(),
+            APP_URL,
+        )
+        .await;
+
+        assert!(matches!(result, Err(AuthError::AlreadyExisting(field)) if field == "email"));
+    }
+
+    #[tokio::test]
+    async fn register_succeeds_when_the_verification_email_fails() {
+        let mut db = MockUserDatabase::new();
+        let mut mailer = MockMailer::new();
+
+        db.expect_create_user().returning(|_| Ok(()));
+        db.expect_create_user_token().returning(|_| Ok(()));
+        mailer
+            .expect_send()
+            .returning(|_| Err(MailerError::SendingError("smtp down".to_string())));
+
+        let result = register(
+            Arc::new(db),
+            Arc::new(mailer),
+            "juan".to_string(),
+            "password123*".to_string(),
+            "juan@juan.juan".to_string(),
+            APP_URL,
+        )
+        .await;
+
+        assert!(result.is_ok());
+    }
+
+    #[tokio::test]
+    async fn verify_email_consumes_the_token() {
+        let mut db = MockUserDatabase::new();
+
+        let user_id = Uuid::new_v4();
+
+        db.expect_consume_user_token()
+            .withf(|token_hash, purpose| {
+                token_hash == &hash_token("emailed")
+                    && *purpose == UserTokenPurpose::EmailVerification
+            })
+            .once()
+            .returning(move |token_hash, purpose| {
+                Ok(Some(UserToken {
+                    id: Uuid::new_v4(),
+                    user_id,
+                    purpose,
+                    token_hash,
+                    expires_at: Utc::now() + Duration::hours(1),
+                    created_at: Utc::now(),
+                    used_at: Some(Utc::now()),
+                }))
+            });
+        db.expect_mark_email_verified()
+            .withf(move |id| *id == user_id)
+            .once()
+            .returning(|_| Ok(()));
+
+        verify_email(Arc::new(db), "emailed".to_string())
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn used_or_unknown_email_tokens_are_rejected() {
+        let mut db = MockUserDatabase::new();
+
+        db.expect_consume_user_token().returning(|_, _| Ok(None));
+        db.expect_mark_email_verified().never();
+
+        let result = verify_email(Arc::new(db), "emailed".to_string()).await;
+
+        assert!(matches!(result, Err(AuthError::InvalidEmailToken)));
+    }
+
+    #[tokio::test]
+    async fn forgot_password_of_an_unknown_email_sends_nothing() {
+        let mut db = MockUserDatabase::new();
+        let mut mailer = MockMailer::new();
+
+        db.expect_get_user_by_email()
+            .returning(|_| Err(UserDatabaseError::NotFound));
+        db.expect_create_user_token().never();
+        mailer.expect_send().never();
+
+        let result = forgot_password(
+            Arc::new(db),
+            Arc::new(mailer),
+            "nobody@juan.juan".to_string(),
+            APP_URL,
+        )
+        .await;
+
+        assert!(result.is_ok());
+    }
+
     #[tokio::test]
-    async fn register_test() {
+    async fn forgot_password_emails_a_reset_link() {
         let mut db = MockUserDatabase::new();
+        let mut mailer = MockMailer::new();
 
-        db.expect_create_user()
-            .withf(|user| verify("password123*", &user.password_hash).unwrap_or(false))
+        db.expect_get_user_by_email()
+            .returning(|_| Ok(juan(Uuid::new_v4())));
+        db.expect_create_user_token()
+            .withf(|token| {
+                token.purpose == UserTokenPurpose::PasswordReset
+                    && token.expires_at <= Utc::now() + Duration::hours(1)
+            })
+            .once()
+            .returning(|_| Ok(()));
+        mailer
+            .expect_send()
+            .withf(|email| {
+                email
+                    .body
+                    .contains("https://nebula.test/reset-password?token=")
+            })
             .once()
             .returning(|_| Ok(()));
 
-        register(
+        forgot_password(
             Arc::new(db),
-            "juan".to_string(),
-            "password123*".to_string(),
+            Arc::new(mailer),
             "juan@juan.juan".to_string(),
+            APP_URL,
+        )
+        .await
+        .unwrap();
+    }
+
+    #[tokio::test]
+    async fn reset_password_updates_it_and_logs_out_every_session() {
+        let mut db = MockUserDatabase::new();
+
+        let user_id = Uuid::new_v4();
+
+        db.expect_consume_user_token()
+            .withf(|_, purpose| *purpose == UserTokenPurpose::PasswordReset)
+            .returning(move |token_hash, purpose| {
+                Ok(Some(UserToken {
+                    id: Uuid::new_v4(),
+                    user_id,
+                    purpose,
+                    token_hash,
+                    expires_at: Utc::now() + Duration::hours(1),
+                    created_at: Utc::now(),
+                    used_at: Some(Utc::now()),
+                }))
+            });
+        db.expect_update_password()
+            .withf(move |id, password_hash| {
+                *id == user_id && verify("newpassword1", password_hash).unwrap_or(false)
+            })
+            .once()
+            .returning(|_, _| Ok(()));
+        db.expect_revoke_user_sessions()
+            .withf(move |id, _| *id == user_id)
+            .once()
+            .returning(|_, _| Ok(()));
+
+        reset_password(
+            Arc::new(db),
+            "emailed".to_string(),
+            "newpassword1".to_string(),
         )
         .await
         .unwrap();
     }
 
+    #[tokio::test]
+    async fn weak_passwords_dont_use_up_the_reset_token() {
+        let mut db = MockUserDatabase::new();
+
+        db.expect_consume_user_token().never();
+
+        let result = reset_password(Arc::new(db), "emailed".to_string(), "short".to_string()).await;
+
+        assert!(matches!(result, Err(AuthError::InvalidFields(_))));
+    }
+
     #[tokio::test]
     async fn login_test_failed() {
         let mut db = MockUserDatabase::new();
@@ -181,6 +1140,8 @@ mod test {
                 username: "juan".to_string(),
                 email: "juan@juan.juan".to_string(),
                 password_hash: hash("pasword", DEFAULT_COST).unwrap(),
+                role: UserRole::User,
+                email_verified_at: None,
                 created_at: Utc::now(),
                 updated_at: Utc::now(),
             })
@@ -188,10 +1149,13 @@ mod test {
 
         let token = login(
             Arc::new(db),
+            Arc::new(unlocked_attempts()),
             "juan".to_string(),
             "pasword2".to_string(),
-            "swNItsMArrAbN2ueHZBWBA5Nk6N8zKWoybXhMK0EuhHso2IvCiFyQAIb6m_8SmicCRZ2x2nEHkxXgCYAoN3-XA".to_string(),
-        ).await;
+            CLIENT_IP.into(),
+            Arc::new(JwtKeys::from_secret(SECRET)),
+        )
+        .await;
 
         assert!(token.is_err())
     }
@@ -204,24 +1168,38 @@ mod test {
 
         db.expect_get_user_by_username().returning(move |_| {
             Ok(User {
-                id: user_id.clone(),
+                id: user_id,
                 username: "juan".to_string(),
                 email: "juan@juan.juan".to_string(),
                 password_hash: hash("pasword", DEFAULT_COST).unwrap(),
+                role: UserRole::User,
+                email_verified_at: None,
                 created_at: Utc::now(),
                 updated_at: Utc::now(),
             })
         });
+        db.expect_create_refresh_token()
+            .withf(move |token| token.user_id == user_id && token.revoked_at.is_none())
+            .once()
+            .returning(|_| Ok(()));
 
         let token = login(
             Arc::new(db),
+            Arc::new(unlocked_attempts()),
             "juan".to_string(),
             "pasword".to_string(),
-            "swNItsMArrAbN2ueHZBWBA5Nk6N8zKWoybXhMK0EuhHso2IvCiFyQAIb6m_8SmicCRZ2x2nEHkxXgCYAoN3-XA".to_string(),
-        ).await.unwrap();
+            CLIENT_IP.into(),
+            Arc::new(JwtKeys::from_secret(SECRET)),
+        )
+        .await
+        .unwrap();
 
-        let clamis: Claims = decode(token, &DecodingKey::from_secret("swNItsMArrAbN2ueHZBWBA5Nk6N8zKWoybXhMK0EuhHso2IvCiFyQAIb6m_8SmicCRZ2x2nEHkxXgCYAoN3-XA".as_ref()), &Validation::default()).unwrap().claims;
-        assert_eq!(clamis.sub, format!("{}", user_id))
+        let clamis: Claims = JwtKeys::from_secret(SECRET)
+            .decode(&token.access_token)
+            .unwrap();
+        assert_eq!(clamis.sub, format!("{}", user_id));
+        assert_eq!(clamis.username, "juan");
+        assert_eq!(clamis.role, UserRole::User);
     }
 
     #[tokio::test]
@@ -232,24 +1210,408 @@ mod test {
 
         db.expect_get_user_by_username().returning(move |_| {
             Ok(User {
-                id: user_id.clone(),
+                id: user_id,
                 username: "juan".to_string(),
                 email: "juan@juan.juan".to_string(),
                 password_hash: hash("pasword", DEFAULT_COST).unwrap(),
+                role: UserRole::User,
+                email_verified_at: None,
                 created_at: Utc::now(),
                 updated_at: Utc::now(),
             })
         });
+        db.expect_create_refresh_token()
+            .once()
+            .returning(|_| Ok(()));
 
         let token = login(
             Arc::new(db),
+            Arc::new(unlocked_attempts()),
             "juan@juan.juan".to_string(),
             "pasword".to_string(),
-            "swNItsMArrAbN2ueHZBWBA5Nk6N8zKWoybXhMK0EuhHso2IvCiFyQAIb6m_8SmicCRZ2x2nEHkxXgCYAoN3-XA".to_string(),
-        ).await.unwrap();
+            CLIENT_IP.into(),
+            Arc::new(JwtKeys::from_secret(SECRET)),
+        )
+        .await
+        .unwrap();
+
+        let clamis: Claims = JwtKeys::from_secret(SECRET)
+            .decode(&token.access_token)
+            .unwrap();
+        assert_eq!(clamis.sub, format!("{}", user_id));
+        assert_eq!(clamis.username, "juan");
+        assert_eq!(clamis.role, UserRole::User);
+    }
+
+    #[tokio::test]
+    async fn locked_account_is_rejected_before_verifying() {
+        let mut db = MockUserDatabase::new();
+        let mut attempts = MockLoginAttempts::new();
+
+        let user_id = Uuid::new_v4();
+
+        db.expect_get_user_by_username()
+            .returning(move |_| Ok(juan(user_id)));
+        db.expect_create_refresh_token().never();
+        attempts.expect_locked_for().returning(move |key| {
+            Ok((key == format!("account:{user_id}")).then(|| Duration::seconds(20)))
+        });
+        attempts.expect_record_failure().never();
+
+        let result = login(
+            Arc::new(db),
+            Arc::new(attempts),
+            "juan".to_string(),
+            "pasword".to_string(),
+            CLIENT_IP.into(),
+            Arc::new(JwtKeys::from_secret(SECRET)),
+        )
+        .await;
+
+        assert!(
+            matches!(result, Err(AuthError::TooManyAttempts(remaining)) if remaining == Duration::seconds(20))
+        );
+    }
+
+    #[tokio::test]
+    async fn reaching_the_failure_limit_locks_the_account_and_audits_it() {
+        let mut db = MockUserDatabase::new();
+        let mut attempts = MockLoginAttempts::new();
+
+        let user_id = Uuid::new_v4();
+        let account_key = format!("account:{user_id}");
+
+        db.expect_get_user_by_username()
+            .returning(move |_| Ok(juan(user_id)));
+        db.expect_record_login_lockout()
+            .withf(move |lockout| {
+                lockout.user_id == Some(user_id)
+                    && lockout.ip_address.is_none()
+                    && lockout.failed_attempts == ACCOUNT_MAX_FAILURES as i32
+            })
+            .once()
+            .returning(|_| Ok(()));
+        attempts.expect_locked_for().returning(|_| Ok(None));
+        let failures_key = account_key.clone();
+        attempts.expect_record_failure().returning(move |key, _| {
+            Ok(if key == failures_key {
+                ACCOUNT_MAX_FAILURES
+            } else {
+                1
+            })
+        });
+        // Second lockout of the day, so twice as long as the first one
+        attempts
+            .expect_record_lockout()
+            .once()
+            .returning(|_, _| Ok(2));
+        attempts
+            .expect_lock()
+            .withf(move |key, duration| key == account_key && *duration == Duration::seconds(60))
+            .once()
+            .returning(|_, _| Ok(()));
+
+        let result = login(
+            Arc::new(db),
+            Arc::new(attempts),
+            "juan".to_string(),
+            "wrong".to_string(),
+            CLIENT_IP.into(),
+            Arc::new(JwtKeys::from_secret(SECRET)),
+        )
+        .await;
+
+        assert!(
+            matches!(result, Err(AuthError::TooManyAttempts(duration)) if duration == Duration::seconds(60))
+        );
+    }
+
+    #[tokio::test]
+    async fn unknown_identifiers_count_against_the_client_address() {
+        let mut db = MockUserDatabase::new();
+        let mut attempts = MockLoginAttempts::new();
+
+        db.expect_get_user_by_username()
+            .returning(|_| Err(UserDatabaseError::NotFound));
+        db.expect_get_user_by_email()
+            .returning(|_| Err(UserDatabaseError::NotFound));
+        attempts.expect_locked_for().returning(|_| Ok(None));
+        attempts
+            .expect_record_failure()
+            .withf(|key, _| key == format!("ip:{CLIENT_IP}"))
+            .once()
+            .returning(|_, _| Ok(1));
+
+        let result = login(
+            Arc::new(db),
+            Arc::new(attempts),
+            "nobody".to_string(),
+            "pasword".to_string(),
+            CLIENT_IP.into(),
+            Arc::new(JwtKeys::from_secret(SECRET)),
+        )
+        .await;
+
+        assert!(matches!(result, Err(AuthError::InvalidCredentials)));
+    }
+
+    #[tokio::test]
+    async fn a_database_outage_is_not_a_failed_login() {
+        let mut db = MockUserDatabase::new();
+        let mut attempts = MockLoginAttempts::new();
+
+        db.expect_get_user_by_username()
+            .returning(|_| Err(UserDatabaseError::Unavailable("pool timed out".to_string())));
+        db.expect_get_user_by_email().never();
+        attempts.expect_locked_for().returning(|_| Ok(None));
+        attempts.expect_record_failure().never();
+
+        let result = login(
+            Arc::new(db),
+            Arc::new(attempts),
+            "juan".to_string(),
+            "pasword".to_string(),
+            CLIENT_IP.into(),
+            Arc::new(JwtKeys::from_secret(SECRET)),
+        )
+        .await;
+
+        assert!(matches!(result, Err(AuthError::Unavailable(_))));
+    }
+
+    #[test]
+    fn lockouts_double_up_to_an_hour() {
+        assert_eq!(lockout_duration(1), Duration::seconds(30));
+        assert_eq!(lockout_duration(2), Duration::seconds(60));
+        assert_eq!(lockout_duration(4), Duration::seconds(240));
+        assert_eq!(lockout_duration(40), Duration::hours(1));
+    }
+
+    #[tokio::test]
+    async fn a_new_email_has_to_be_verified_again() {
+        let mut db = MockUserDatabase::new();
+        let mut mailer = MockMailer::new();
+
+        let user_id = Uuid::new_v4();
+
+        db.expect_get_user_by_id().returning(move |_| {
+            Ok(User {
+                email_verified_at: Some(Utc::now()),
+                ..juan(user_id)
+            })
+        });
+        db.expect_update_user()
+            .withf(|user| {
+                user.email == "new@juan.juan"
+                    && user.username == "juan"
+                    && user.email_verified_at.is_none()
+            })
+            .once()
+            .returning(Ok);
+        db.expect_create_user_token().once().returning(|_| Ok(()));
+        mailer
+            .expect_send()
+            .withf(|email| email.to == "new@juan.juan")
+            .once()
+            .returning(|_| Ok(()));
+
+        let user = update_profile(
+            Arc::new(db),
+            Arc::new(mailer),
+            user_id,
+            None,
+            Some("new@juan.juan".to_string()),
+            APP_URL,
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(user.email, "new@juan.juan");
+    }
+
+    #[tokio::test]
+    async fn taken_usernames_are_rejected() {
+        let mut db = MockUserDatabase::new();
+
+        db.expect_get_user_by_id().returning(|id| Ok(juan(id)));
+        db.expect_update_user()
+            .once()
+            .returning(|_| Err(UserDatabaseError::UsernameTaken));
+
+        let result = update_profile(
+            Arc::new(db),
+            Arc::new(MockMailer::new()),
+            Uuid::new_v4(),
+            Some("Pedro".to_string()),
+            None,
+            APP_URL,
+        )
+        .await;
+
+        assert!(matches!(result, Err(AuthError::AlreadyExisting(field)) if field == "username"));
+    }
+
+    #[tokio::test]
+    async fn changing_the_password_requires_the_current_one() {
+        let mut db = MockUserDatabase::new();
+
+        db.expect_get_user_by_id().returning(|id| Ok(juan(id)));
+        db.expect_update_password().never();
+
+        let result = change_password(
+            Arc::new(db),
+            Uuid::new_v4(),
+            "wrong".to_string(),
+            "newpassword1".to_string(),
+            Arc::new(JwtKeys::from_secret(SECRET)),
+        )
+        .await;
+
+        assert!(matches!(result, Err(AuthError::InvalidCredentials)));
+    }
+
+    #[tokio::test]
+    async fn changing_the_password_logs_out_the_other_sessions() {
+        let mut db = MockUserDatabase::new();
+
+        let user_id = Uuid::new_v4();
+
+        db.expect_get_user_by_id().returning(|id| Ok(juan(id)));
+        db.expect_update_password()
+            .withf(move |id, password_hash| {
+                *id == user_id && verify("newpassword1", password_hash).unwrap_or(false)
+            })
+            .once()
+            .returning(|_, _| Ok(()));
+        db.expect_revoke_user_sessions()
+            .withf(move |id, _| *id == user_id)
+            .once()
+            .returning(|_, _| Ok(()));
+        db.expect_create_refresh_token()
+            .withf(move |token| token.user_id == user_id)
+            .once()
+            .returning(|_| Ok(()));
+
+        let tokens = change_password(
+            Arc::new(db),
+            user_id,
+            "pasword".to_string(),
+            "newpassword1".to_string(),
+            Arc::new(JwtKeys::from_secret(SECRET)),
+        )
+        .await
+        .unwrap();
+
+        let claims: Claims = JwtKeys::from_secret(SECRET)
+            .decode(&tokens.access_token)
+            .unwrap();
+        assert_eq!(claims.sub, format!("{user_id}"));
+    }
 
-        let clamis: Claims = decode(token, &DecodingKey::from_secret("swNItsMArrAbN2ueHZBWBA5Nk6N8zKWoybXhMK0EuhHso2IvCiFyQAIb6m_8SmicCRZ2x2nEHkxXgCYAoN3-XA".as_ref()), &Validation::default()).unwrap().claims;
-        assert_eq!(clamis.sub, format!("{}", user_id))
+    #[tokio::test]
+    async fn deleting_the_account_revokes_its_access_token() {
+        let mut db = MockUserDatabase::new();
+
+        let user_id = Uuid::new_v4();
+        let jti = Uuid::new_v4();
+
+        db.expect_delete_user()
+            .withf(move |id| *id == user_id)
+            .once()
+            .returning(|_| Ok(AccountDeletion::default()));
+        db.expect_revoke_access_token()
+            .withf(move |revoked, _| *revoked == jti)
+            .once()
+            .returning(|_, _| Ok(()));
+
+        delete_account(
+            Arc::new(db),
+            user_id,
+            jti,
+            Utc::now(),
+            Arc::new(MockMessagePublisher::new()),
+        )
+        .await
+        .unwrap();
+    }
+
+    #[tokio::test]
+    async fn deleting_the_account_announces_its_rooms_and_messages() {
+        let mut db = MockUserDatabase::new();
+        let mut publisher = MockMessagePublisher::new();
+
+        let user_id = Uuid::new_v4();
+        let (shared_room, lonely_room, heir) = (Uuid::new_v4(), Uuid::new_v4(), Uuid::new_v4());
+        let (joined_room, message_id) = (Uuid::new_v4(), Uuid::new_v4());
+
+        db.expect_delete_user().once().returning(move |_| {
+            Ok(AccountDeletion {
+                room_ids: vec![shared_room, lonely_room, joined_room],
+                handovers: vec![
+                    RoomHandover {
+                        room_id: shared_room,
+                        new_owner: Some(heir),
+                    },
+                    RoomHandover {
+                        room_id: lonely_room,
+                        new_owner: None,
+                    },
+                ],
+                deleted_messages: vec![MessageView {
+                    id: message_id,
+                    room_id: joined_room,
+                    sender_id: None,
+                    sender_username: None,
+                    content: String::new(),
+                    created_at: Utc::now(),
+                    edited_at: None,
+                    deleted_at: Some(Utc::now()),
+                }],
+            })
+        });
+        db.expect_revoke_access_token().returning(|_, _| Ok(()));
+
+        publisher
+            .expect_publish()
+            .withf(move |event| {
+                matches!(event, RoomEvent::RoleChanged(change)
+                    if change.room_id == shared_room
+                        && change.user_id == heir
+                        && change.role == MemberRole::Owner
+                        && change.changed_by == user_id)
+            })
+            .once()
+            .returning(|_| Ok(()));
+        publisher
+            .expect_publish()
+            .withf(move |event| {
+                matches!(event, RoomEvent::MessageDeleted(message)
+                    if message.id == message_id && message.deleted_at.is_some())
+            })
+            .once()
+            .returning(|_| Ok(()));
+        for room_id in [shared_room, lonely_room, joined_room] {
+            publisher
+                .expect_publish()
+                .withf(move |event| {
+                    matches!(event, RoomEvent::Membership(change)
+                        if change.room_id == room_id
+                            && change.user_id == user_id
+                            && change.action == MembershipAction::Left)
+                })
+                .once()
+                .returning(|_| Ok(()));
+        }
+
+        delete_account(
+            Arc::new(db),
+            user_id,
+            Uuid::new_v4(),
+            Utc::now(),
+            Arc::new(publisher),
+        )
+        .await
+        .unwrap();
     }
 
     #[tokio::test]
@@ -263,6 +1625,8 @@ mod test {
             username: "john".into(),
             email: "john@example.com".into(),
             password_hash: "hashed".into(),
+            role: UserRole::User,
+            email_verified_at: None,
             created_at: Utc::now(),
             updated_at: Utc::now(),
         };
@@ -289,4 +1653,149 @@ mod test {
 
         assert!(matches!(result, Err(AuthError::DatabaseError(_))));
     }
+
+    #[tokio::test]
+    async fn refresh_rotates_the_token_within_its_family() {
+        let mut db = MockUserDatabase::new();
+
+        let user_id = Uuid::new_v4();
+        let family_id = Uuid::new_v4();
+        let stored = stored_refresh_token(user_id, family_id, false);
+        let stored_id = stored.id;
+
+        db.expect_get_refresh_token()
+            .withf(|token_hash| token_hash == &hash_token("refresh"))
+            .returning(move |_| Ok(Some(stored.clone())));
+        db.expect_rotate_refresh_token()
+            .withf(move |old_id, replacement| {
+                *old_id == stored_id
+                    && replacement.family_id == family_id
+                    && replacement.token_hash != hash_token("refresh")
+            })
+            .once()
+            .returning(|_, _| Ok(true));
+        db.expect_get_user_by_id()
+            .withf(move |id| *id == user_id)
+            .returning(move |_| {
+                Ok(User {
+                    id: user_id,
+                    username: "renamed".to_string(),
+                    email: "juan@juan.juan".to_string(),
+                    password_hash: "hashed".into(),
+                    role: UserRole::User,
+                    email_verified_at: None,
+                    created_at: Utc::now(),
+                    updated_at: Utc::now(),
+                })
+            });
+
+        let tokens = refresh_tokens(
+            Arc::new(db),
+            "refresh".to_string(),
+            Arc::new(JwtKeys::from_secret(SECRET)),
+        )
+        .await
+        .unwrap();
+
+        assert_ne!(tokens.refresh_token, "refresh");
+        let claims: Claims = JwtKeys::from_secret(SECRET)
+            .decode(&tokens.access_token)
+            .unwrap();
+        assert_eq!(claims.sub, format!("{user_id}"));
+        assert_eq!(claims.username, "renamed");
+    }
+
+    #[tokio::test]
+    async fn reused_refresh_token_revokes_its_family() {
+        let mut db = MockUserDatabase::new();
+
+        let family_id = Uuid::new_v4();
+        let stored = stored_refresh_token(Uuid::new_v4(), family_id, true);
+
+        db.expect_get_refresh_token()
+            .returning(move |_| Ok(Some(stored.clone())));
+        db.expect_rotate_refresh_token().never();
+        db.expect_revoke_token_family()
+            .withf(move |id| *id == family_id)
+            .once()
+            .returning(|_| Ok(()));
+
+        let result = refresh_tokens(
+            Arc::new(db),
+            "refresh".to_string(),
+            Arc::new(JwtKeys::from_secret(SECRET)),
+        )
+        .await;
+
+        assert!(matches!(result, Err(AuthError::RefreshTokenReused)));
+    }
+
+    #[tokio::test]
+    async fn unknown_refresh_token_is_rejected() {
+        let mut db = MockUserDatabase::new();
+
+        db.expect_get_refresh_token().returning(|_| Ok(None));
+
+        let result = refresh_tokens(
+            Arc::new(db),
+            "refresh".to_string(),
+            Arc::new(JwtKeys::from_secret(SECRET)),
+        )
+        .await;
+
+        assert!(matches!(result, Err(AuthError::InvalidRefreshToken)));
+    }
+
+    #[tokio::test]
+    async fn logout_revokes_the_access_token_and_the_refresh_family() {
+        let mut db = MockUserDatabase::new();
+
+        let user_id = Uuid::new_v4();
+        let jti = Uuid::new_v4();
+        let family_id = Uuid::new_v4();
+        let stored = stored_refresh_token(user_id, family_id, false);
+
+        db.expect_revoke_access_token()
+            .withf(move |revoked, _| *revoked == jti)
+            .once()
+            .returning(|_, _| Ok(()));
+        db.expect_get_refresh_token()
+            .returning(move |_| Ok(Some(stored.clone())));
+        db.expect_revoke_token_family()
+            .withf(move |id| *id == family_id)
+            .once()
+            .returning(|_| Ok(()));
+
+        logout(
+            Arc::new(db),
+            user_id,
+            jti,
+            Utc::now(),
+            Some("refresh".to_string()),
+        )
+        .await
+        .unwrap();
+    }
+
+    #[tokio::test]
+    async fn logout_ignores_refresh_tokens_of_other_users() {
+        let mut db = MockUserDatabase::new();
+
+        let stored = stored_refresh_token(Uuid::new_v4(), Uuid::new_v4(), false);
+
+        db.expect_revoke_access_token().returning(|_, _| Ok(()));
+        db.expect_get_refresh_token()
+            .returning(move |_| Ok(Some(stored.clone())));
+        db.expect_revoke_token_family().never();
+
+        logout(
+            Arc::new(db),
+            Uuid::new_v4(),
+            Uuid::new_v4(),
+            Utc::now(),
+            Some("refresh".to_string()),
+        )
+        .await
+        .unwrap();
+    }
 }
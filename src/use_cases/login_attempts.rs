@@ -0,0 +1,29 @@
+use chrono::Duration;
+use mockall::automock;
+use thiserror::Error;
+
+pub type LoginAttemptsResult<T> = Result<T, LoginAttemptsError>;
+
+/// Failed login attempts and lockouts, keyed by account or by client address
+#[automock]
+pub trait LoginAttempts: Send + Sync {
+    /// Time left of the lockout of the key, `None` when it isn't locked
+    async fn locked_for(&self, key: &str) -> LoginAttemptsResult<Option<Duration>>;
+
+    /// Counts a failed attempt and returns the failures of the key within `window`
+    async fn record_failure(&self, key: &str, window: Duration) -> LoginAttemptsResult<u32>;
+
+    /// Counts a lockout and returns the lockouts of the key within `window`
+    async fn record_lockout(&self, key: &str, window: Duration) -> LoginAttemptsResult<u32>;
+
+    /// Locks the key for `duration`, forgetting its failures
+    async fn lock(&self, key: &str, duration: Duration) -> LoginAttemptsResult<()>;
+
+    async fn clear_failures(&self, key: &str) -> LoginAttemptsResult<()>;
+}
+
+#[derive(Debug, Error)]
+pub enum LoginAttemptsError {
+    #[error("Internal login attempts error: {0}")]
+    InternalError(String),
+}
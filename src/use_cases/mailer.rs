@@ -0,0 +1,24 @@
+use mockall::automock;
+use thiserror::Error;
+
+pub type MailerResult<T> = Result<T, MailerError>;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Email {
+    pub to: String,
+    pub subject: String,
+    pub body: String,
+}
+
+#[automock]
+pub trait Mailer: Send + Sync {
+    async fn send(&self, email: Email) -> MailerResult<()>;
+}
+
+#[derive(Debug, Error)]
+pub enum MailerError {
+    #[error("Invalid email address: {0}")]
+    InvalidAddress(String),
+    #[error("Error sending the email: {0}")]
+    SendingError(String),
+}
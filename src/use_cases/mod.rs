@@ -1,7 +1,11 @@
 pub mod auth_service;
+pub mod jwt_keys;
+pub mod login_attempts;
+pub mod mailer;
 pub mod notification_service;
 pub mod realtime_broker;
 pub mod realtime_service;
 pub mod room_database;
 pub mod room_service;
 pub mod user_database;
+pub mod validation;
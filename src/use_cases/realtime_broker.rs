@@ -1,18 +1,37 @@
 use mockall::automock;
 use thiserror::Error;
+use uuid::Uuid;
 
-use crate::domain::room::Message;
+use crate::domain::event::{EventEnvelope, RoomEvent};
 
 pub type RealTimeBrokerResult<T> = Result<T, RealTimeBrokerError>;
 
+/// Stream of events an instance can subscribe to, the events of a room, or the membership changes
+/// of a user
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum EventTopic {
+    Room(Uuid),
+    User(Uuid),
+}
+
 #[automock]
 pub trait MessagePublisher: Send + Sync {
-    async fn broadcast_message(&self, message: Message) -> RealTimeBrokerResult<()>;
+    /// Publishes the event to every instance, wrapped in an envelope with the current version.
+    /// Membership changes and moderation actions are also published on the topic of the user
+    async fn publish(&self, event: RoomEvent) -> RealTimeBrokerResult<()>;
 }
 
 #[automock]
 pub trait MessageSubscriber: Send + Sync {
-    async fn consume_message(&mut self) -> RealTimeBrokerResult<Message>;
+    /// Waits for the next event of the subscribed topics, together with the topic it came from
+    async fn consume_event(&mut self) -> RealTimeBrokerResult<(EventTopic, EventEnvelope)>;
+
+    async fn subscribe(&mut self, topic: EventTopic) -> RealTimeBrokerResult<()>;
+
+    async fn unsubscribe(&mut self, topic: EventTopic) -> RealTimeBrokerResult<()>;
+
+    /// Opens a new connection to the broker, subscribed again to the topics of the previous one
+    async fn reconect(&mut self) -> RealTimeBrokerResult<()>;
 }
 
 #[derive(Debug, Error)]
@@ -22,4 +41,8 @@ pub enum RealTimeBrokerError {
 
     #[error("Broker connection closed")]
     BrokerConnectionClosed,
+
+    /// The connection is still usable, only the message was lost
+    #[error("Undecodable broker message: {0}")]
+    UndecodableMessage(String),
 }
@@ -1,27 +1,592 @@
-use std::sync::Arc;
+use std::{
+    sync::{
+        Arc,
+        atomic::{AtomicBool, AtomicU64, Ordering},
+    },
+    time::Duration,
+};
 
 use dashmap::DashMap;
-use tokio::sync::broadcast;
-use tracing::{error, info};
-use uuid::Uuid;
+use tokio::{
+    sync::{
+        broadcast::{self, error::RecvError},
+        mpsc, watch,
+    },
+    time::Instant,
+};
+use tracing::{error, info, warn};
 
-use crate::{domain::room::Message, use_cases::realtime_broker::MessageSubscriber};
+use crate::{
+    domain::event::EventEnvelope,
+    use_cases::realtime_broker::{EventTopic, MessageSubscriber, RealTimeBrokerError},
+};
 
+/// Changes to the topics the instance has to receive from the broker. Subscriptions carry the
+/// sender the broker sets to true once it receives the events of the topic
+#[derive(Debug)]
+pub enum SubscriptionChange {
+    Subscribe(EventTopic, watch::Sender<bool>),
+    Unsubscribe(EventTopic),
+}
+
+/// Local channel of a topic, with whether the broker is already subscribed to it
+struct TopicChannel {
+    sender: broadcast::Sender<EventEnvelope>,
+    subscribed: watch::Receiver<bool>,
+}
+
+/// Local channels the events are fanned out through. A topic stays subscribed on the broker while
+/// it has at least one local receiver
+pub struct EventChannels {
+    channels: DashMap<EventTopic, TopicChannel>,
+    changes: mpsc::UnboundedSender<SubscriptionChange>,
+}
+
+impl EventChannels {
+    /// The receiver has to be handed to `realtime_messsage_broker`, which applies the changes
+    pub fn new() -> (EventChannels, mpsc::UnboundedReceiver<SubscriptionChange>) {
+        let (changes, changes_rx) = mpsc::unbounded_channel();
+
+        let channels = EventChannels {
+            channels: DashMap::new(),
+            changes,
+        };
+
+        (channels, changes_rx)
+    }
+
+    /// Returns once the broker is subscribed to the topic, so every event published afterwards
+    /// reaches the receiver
+    pub async fn subscribe(self: &Arc<Self>, topic: EventTopic) -> TopicReceiver {
+        // The change is sent while the entry is locked, so it can't be reordered with the
+        // unsubscribe of a receiver being dropped at the same time
+        let (receiver, mut subscribed) = {
+            let channel = self.channels.entry(topic).or_insert_with(|| {
+                let (subscribed_tx, subscribed) = watch::channel(false);
+                self.notify(SubscriptionChange::Subscribe(topic, subscribed_tx));
+
+                TopicChannel {
+                    sender: broadcast::channel(channel_capacity(topic)).0,
+                    subscribed,
+                }
+            });
+
+            (channel.sender.subscribe(), channel.subscribed.clone())
+        };
+
+        // Built before waiting, so the topic is released if the caller gives up meanwhile
+        let receiver = TopicReceiver {
+            topic,
+            receiver: Some(receiver),
+            channels: self.clone(),
+        };
+
+        // Fails only when the broker is not running, which `notify` already reported
+        let _ = subscribed.wait_for(|subscribed| *subscribed).await;
+
+        receiver
+    }
+
+    /// Sends the event to the local receivers of the topic, returning how many there are
+    pub fn send(&self, topic: EventTopic, envelope: EventEnvelope) -> usize {
+        self.channels
+            .get(&topic)
+            .and_then(|channel| channel.sender.send(envelope).ok())
+            .unwrap_or(0)
+    }
+
+    fn release(&self, topic: EventTopic) {
+        self.channels.remove_if(&topic, |_, channel| {
+            let unused = channel.sender.receiver_count() == 0;
+            if unused {
+                self.notify(SubscriptionChange::Unsubscribe(topic));
+            }
+            unused
+        });
+    }
+
+    fn notify(&self, change: SubscriptionChange) {
+        if let Err(err) = self.changes.send(change) {
+            error!("The realtime broker is not running: {err}");
+        }
+    }
+}
+
+fn channel_capacity(topic: EventTopic) -> usize {
+    match topic {
+        EventTopic::Room(_) => 1_000,
+        EventTopic::User(_) => 100,
+    }
+}
+
+/// Receives the events of one topic, the topic is released once its last receiver is dropped
+pub struct TopicReceiver {
+    topic: EventTopic,
+    receiver: Option<broadcast::Receiver<EventEnvelope>>,
+    channels: Arc<EventChannels>,
+}
+
+impl TopicReceiver {
+    pub async fn recv(&mut self) -> Result<EventEnvelope, RecvError> {
+        match self.receiver.as_mut() {
+            Some(receiver) => receiver.recv().await,
+            None => Err(RecvError::Closed),
+        }
+    }
+}
+
+impl Drop for TopicReceiver {
+    fn drop(&mut self) {
+        drop(self.receiver.take());
+        self.channels.release(self.topic);
+    }
+}
+
+const INITIAL_RECONNECT_DELAY: Duration = Duration::from_millis(500);
+const MAX_RECONNECT_DELAY: Duration = Duration::from_secs(30);
+
+/// State of the realtime broker, read by the health check
+#[derive(Debug, Default)]
+pub struct BrokerHealth {
+    disconnected: AtomicBool,
+    skipped_messages: AtomicU64,
+}
+
+impl BrokerHealth {
+    pub fn is_connected(&self) -> bool {
+        !self.disconnected.load(Ordering::Relaxed)
+    }
+
+    /// Messages dropped because they could not be decoded, since the process started
+    pub fn skipped_messages(&self) -> u64 {
+        self.skipped_messages.load(Ordering::Relaxed)
+    }
+}
+
+/// Reconnection in progress: when to try again, how long to wait after that attempt fails, and
+/// the subscription changes to apply once the connection is back
+struct Reconnection {
+    retry_at: Instant,
+    delay: Duration,
+    pending: Vec<SubscriptionChange>,
+}
+
+/// Fans out the events of the broker to the local channels until the `EventChannels` are dropped.
+/// Undecodable messages are skipped, and a lost connection is reopened with exponential backoff,
+/// while subscription changes keep being received
 pub async fn realtime_messsage_broker(
-    mut messageSubscriber: impl MessageSubscriber,
-    rooms_channels: Arc<DashMap<Uuid, broadcast::Sender<Message>>>,
+    mut message_subscriber: impl MessageSubscriber,
+    channels: Arc<EventChannels>,
+    mut subscription_changes: mpsc::UnboundedReceiver<SubscriptionChange>,
+    health: Arc<BrokerHealth>,
 ) {
-    while let Ok(message) = messageSubscriber.consume_message().await {
-        let channel = if let Some(channel) = rooms_channels.get(&message.room_id) {
-            channel
-        } else {
-            info!("Clients to broadcast messages to, were not found");
-            continue;
+    let mut reconnection: Option<Reconnection> = None;
+
+    loop {
+        let retry_at = reconnection
+            .as_ref()
+            .map(|reconnection| reconnection.retry_at);
+
+        tokio::select! {
+            // Subscriptions go first, so no event is consumed for a topic about to be subscribed
+            biased;
+
+            change = subscription_changes.recv() => {
+                let Some(change) = change else { break };
+
+                match reconnection.as_mut() {
+                    // Nothing is received while disconnected, so the receivers don't have to wait
+                    // for the connection to come back
+                    Some(reconnection) => {
+                        if let SubscriptionChange::Subscribe(_, subscribed) = &change {
+                            subscribed.send_replace(true);
+                        }
+                        reconnection.pending.push(change);
+                    }
+                    None => apply_change(&mut message_subscriber, change).await,
+                }
+            }
+            _ = reconnection_due(retry_at) => {
+                if let Some(attempt) = reconnection.take() {
+                    reconnection = reconnect(&mut message_subscriber, attempt).await;
+                }
+                health.disconnected.store(reconnection.is_some(), Ordering::Relaxed);
+            }
+            consumed = message_subscriber.consume_event(), if reconnection.is_none() => {
+                match consumed {
+                    Ok((topic, envelope)) => match channels.send(topic, envelope) {
+                        0 => info!("Clients to broadcast messages to, were not found"),
+                        n_receivers => {
+                            info!("There are {n_receivers}, that will receive the message")
+                        }
+                    },
+                    Err(RealTimeBrokerError::UndecodableMessage(err)) => {
+                        let skipped = health.skipped_messages.fetch_add(1, Ordering::Relaxed) + 1;
+                        warn!("Skipping broker message ({skipped} so far): {err}");
+                    }
+                    Err(err) => {
+                        error!("Realtime broker connection lost: {err}");
+                        health.disconnected.store(true, Ordering::Relaxed);
+                        reconnection = Some(Reconnection {
+                            retry_at: Instant::now(),
+                            delay: INITIAL_RECONNECT_DELAY,
+                            pending: Vec::new(),
+                        });
+                    }
+                }
+            }
+        }
+    }
+}
+
+async fn apply_change(message_subscriber: &mut impl MessageSubscriber, change: SubscriptionChange) {
+    let result = match change {
+        SubscriptionChange::Subscribe(topic, subscribed) => {
+            let result = message_subscriber.subscribe(topic).await;
+            // Released even on errors, the receivers shouldn't wait on a broken broker
+            subscribed.send_replace(true);
+            result
+        }
+        SubscriptionChange::Unsubscribe(topic) => message_subscriber.unsubscribe(topic).await,
+    };
+
+    if let Err(err) = result {
+        error!("Error updating the broker subscriptions: {err}");
+    }
+}
+
+/// Completes when the next reconnection attempt is due, never while connected
+async fn reconnection_due(retry_at: Option<Instant>) {
+    match retry_at {
+        Some(retry_at) => tokio::time::sleep_until(retry_at).await,
+        None => std::future::pending().await,
+    }
+}
+
+/// Tries to reconnect once, returning the reconnection to try again later when it fails
+async fn reconnect(
+    message_subscriber: &mut impl MessageSubscriber,
+    reconnection: Reconnection,
+) -> Option<Reconnection> {
+    if let Err(err) = message_subscriber.reconect().await {
+        let delay = reconnection.delay;
+        warn!("Error reconnecting to the realtime broker, retrying in {delay:?}: {err}");
+
+        return Some(Reconnection {
+            retry_at: Instant::now() + delay,
+            delay: (delay * 2).min(MAX_RECONNECT_DELAY),
+            ..reconnection
+        });
+    }
+
+    info!("Reconnected to the realtime broker");
+    for change in reconnection.pending {
+        apply_change(message_subscriber, change).await;
+    }
+
+    None
+}
+
+#[cfg(test)]
+mod test {
+    use std::{
+        collections::VecDeque,
+        sync::{
+            Arc,
+            atomic::{AtomicUsize, Ordering},
+        },
+        time::Duration,
+    };
+
+    use tokio::{sync::mpsc, task::JoinHandle, time::Instant};
+    use uuid::Uuid;
+
+    use crate::{
+        domain::{
+            event::{EventEnvelope, RoomEvent},
+            room::{MembershipAction, MembershipChange},
+        },
+        use_cases::{
+            realtime_broker::{
+                EventTopic, MessageSubscriber, RealTimeBrokerError, RealTimeBrokerResult,
+            },
+            realtime_service::{
+                BrokerHealth, EventChannels, SubscriptionChange, realtime_messsage_broker,
+            },
+        },
+    };
+
+    type Consumed = RealTimeBrokerResult<(EventTopic, EventEnvelope)>;
+
+    /// Broker fed by the test, unlike a mock it waits for the next event like a real connection
+    struct FakeSubscriber {
+        events: mpsc::UnboundedReceiver<Consumed>,
+        reconnect_results: VecDeque<RealTimeBrokerResult<()>>,
+        reconnects: Arc<AtomicUsize>,
+        subscribes: Arc<AtomicUsize>,
+    }
+
+    impl MessageSubscriber for FakeSubscriber {
+        async fn consume_event(&mut self) -> Consumed {
+            match self.events.recv().await {
+                Some(consumed) => consumed,
+                None => std::future::pending().await,
+            }
+        }
+
+        async fn subscribe(&mut self, _topic: EventTopic) -> RealTimeBrokerResult<()> {
+            self.subscribes.fetch_add(1, Ordering::Relaxed);
+            Ok(())
+        }
+
+        async fn unsubscribe(&mut self, _topic: EventTopic) -> RealTimeBrokerResult<()> {
+            Ok(())
+        }
+
+        async fn reconect(&mut self) -> RealTimeBrokerResult<()> {
+            self.reconnects.fetch_add(1, Ordering::Relaxed);
+            self.reconnect_results.pop_front().unwrap_or(Ok(()))
+        }
+    }
+
+    struct RunningBroker {
+        events: mpsc::UnboundedSender<Consumed>,
+        health: Arc<BrokerHealth>,
+        reconnects: Arc<AtomicUsize>,
+        subscribes: Arc<AtomicUsize>,
+        task: JoinHandle<()>,
+    }
+
+    fn spawn_broker(
+        channels: Arc<EventChannels>,
+        changes: mpsc::UnboundedReceiver<SubscriptionChange>,
+        reconnect_results: Vec<RealTimeBrokerResult<()>>,
+    ) -> RunningBroker {
+        let (events, events_rx) = mpsc::unbounded_channel();
+        let reconnects = Arc::new(AtomicUsize::new(0));
+        let subscribes = Arc::new(AtomicUsize::new(0));
+        let health = Arc::new(BrokerHealth::default());
+
+        let subscriber = FakeSubscriber {
+            events: events_rx,
+            reconnect_results: reconnect_results.into(),
+            reconnects: reconnects.clone(),
+            subscribes: subscribes.clone(),
         };
+        let task = tokio::spawn(realtime_messsage_broker(
+            subscriber,
+            channels,
+            changes,
+            health.clone(),
+        ));
+
+        RunningBroker {
+            events,
+            health,
+            reconnects,
+            subscribes,
+            task,
+        }
+    }
 
-        match channel.send(message) {
-            Ok(n_receivers) => info!("There are {n_receivers}, that will receive the message"),
-            Err(err) => error!("{}", err),
+    fn membership_event(room_id: Uuid, user_id: Uuid) -> EventEnvelope {
+        EventEnvelope::new(RoomEvent::Membership(MembershipChange {
+            room_id,
+            user_id,
+            action: MembershipAction::Joined,
+        }))
+    }
+
+    #[tokio::test]
+    async fn events_reach_the_channel_of_their_topic() {
+        let room_id = Uuid::new_v4();
+        let user_id = Uuid::new_v4();
+
+        let (channels, changes) = EventChannels::new();
+        let channels = Arc::new(channels);
+        let broker = spawn_broker(channels.clone(), changes, vec![]);
+
+        let mut room_rx = channels.subscribe(EventTopic::Room(room_id)).await;
+        let mut user_rx = channels.subscribe(EventTopic::User(user_id)).await;
+        for topic in [EventTopic::User(user_id), EventTopic::Room(room_id)] {
+            broker
+                .events
+                .send(Ok((topic, membership_event(room_id, user_id))))
+                .unwrap();
+        }
+
+        assert!(matches!(
+            room_rx.recv().await.map(|envelope| envelope.event),
+            Ok(RoomEvent::Membership(_))
+        ));
+        assert!(matches!(
+            user_rx.recv().await.map(|envelope| envelope.event),
+            Ok(RoomEvent::Membership(change)) if change.room_id == room_id
+        ));
+
+        broker.task.abort();
+    }
+
+    #[tokio::test]
+    async fn undecodable_messages_are_skipped_and_counted() {
+        let room_id = Uuid::new_v4();
+        let topic = EventTopic::Room(room_id);
+
+        let (channels, changes) = EventChannels::new();
+        let channels = Arc::new(channels);
+        let broker = spawn_broker(channels.clone(), changes, vec![]);
+
+        let mut room_rx = channels.subscribe(topic).await;
+        broker
+            .events
+            .send(Err(RealTimeBrokerError::UndecodableMessage("{".into())))
+            .unwrap();
+        broker
+            .events
+            .send(Ok((topic, membership_event(room_id, Uuid::new_v4()))))
+            .unwrap();
+
+        assert!(room_rx.recv().await.is_ok());
+        assert_eq!(broker.health.skipped_messages(), 1);
+        assert!(broker.health.is_connected());
+        assert_eq!(broker.reconnects.load(Ordering::Relaxed), 0);
+
+        broker.task.abort();
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn lost_connections_are_reopened_with_backoff() {
+        let room_id = Uuid::new_v4();
+        let topic = EventTopic::Room(room_id);
+
+        let (channels, changes) = EventChannels::new();
+        let channels = Arc::new(channels);
+        let broker = spawn_broker(
+            channels.clone(),
+            changes,
+            vec![
+                Err(RealTimeBrokerError::BrokerConnectionClosed),
+                Err(RealTimeBrokerError::BrokerConnectionClosed),
+            ],
+        );
+        let mut room_rx = channels.subscribe(topic).await;
+        let started = Instant::now();
+        broker
+            .events
+            .send(Err(RealTimeBrokerError::BrokerConnectionClosed))
+            .unwrap();
+        broker
+            .events
+            .send(Ok((topic, membership_event(room_id, Uuid::new_v4()))))
+            .unwrap();
+
+        assert!(room_rx.recv().await.is_ok());
+        assert_eq!(broker.reconnects.load(Ordering::Relaxed), 3);
+        assert!(started.elapsed() >= Duration::from_millis(1_500));
+        assert!(broker.health.is_connected());
+
+        broker.task.abort();
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn topics_are_subscribed_while_reconnecting() {
+        let (channels, changes) = EventChannels::new();
+        let channels = Arc::new(channels);
+        let broker = spawn_broker(
+            channels.clone(),
+            changes,
+            vec![
+                Err(RealTimeBrokerError::BrokerConnectionClosed),
+                Err(RealTimeBrokerError::BrokerConnectionClosed),
+            ],
+        );
+        let _first = channels.subscribe(EventTopic::Room(Uuid::new_v4())).await;
+        broker
+            .events
+            .send(Err(RealTimeBrokerError::BrokerConnectionClosed))
+            .unwrap();
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert!(!broker.health.is_connected());
+
+        // Answered right away, and applied to the broker once it is reachable again
+        let room_id = Uuid::new_v4();
+        let mut room_rx = channels.subscribe(EventTopic::Room(room_id)).await;
+        assert!(!broker.health.is_connected());
+        assert_eq!(broker.subscribes.load(Ordering::Relaxed), 1);
+
+        tokio::time::sleep(Duration::from_secs(2)).await;
+        assert!(broker.health.is_connected());
+        assert_eq!(broker.reconnects.load(Ordering::Relaxed), 3);
+        assert_eq!(broker.subscribes.load(Ordering::Relaxed), 2);
+
+        broker
+            .events
+            .send(Ok((
+                EventTopic::Room(room_id),
+                membership_event(room_id, Uuid::new_v4()),
+            )))
+            .unwrap();
+        assert!(room_rx.recv().await.is_ok());
+
+        broker.task.abort();
+    }
+
+    #[tokio::test]
+    async fn topics_are_released_with_their_last_receiver() {
+        let topic = EventTopic::Room(Uuid::new_v4());
+
+        let (channels, mut changes) = EventChannels::new();
+        let channels = Arc::new(channels);
+
+        let first = tokio::spawn({
+            let channels = channels.clone();
+            async move { channels.subscribe(topic).await }
+        });
+        let Some(SubscriptionChange::Subscribe(subscribed_topic, subscribed)) =
+            changes.recv().await
+        else {
+            panic!("the topic should be subscribed");
         };
+        assert_eq!(subscribed_topic, topic);
+        subscribed.send_replace(true);
+
+        let first = first.await.unwrap();
+        let second = channels.subscribe(topic).await;
+        assert!(changes.try_recv().is_err());
+
+        drop(first);
+        assert!(changes.try_recv().is_err());
+
+        drop(second);
+        assert!(matches!(
+            changes.try_recv(),
+            Ok(SubscriptionChange::Unsubscribe(unsubscribed)) if unsubscribed == topic
+        ));
+        assert_eq!(
+            channels.send(topic, membership_event(Uuid::new_v4(), Uuid::new_v4())),
+            0
+        );
+    }
+
+    #[tokio::test]
+    async fn subscribing_waits_for_the_broker() {
+        let topic = EventTopic::Room(Uuid::new_v4());
+
+        let (channels, mut changes) = EventChannels::new();
+        let channels = Arc::new(channels);
+
+        let receiver = tokio::spawn({
+            let channels = channels.clone();
+            async move { channels.subscribe(topic).await }
+        });
+        let Some(SubscriptionChange::Subscribe(_, subscribed)) = changes.recv().await else {
+            panic!("the topic should be subscribed");
+        };
+
+        tokio::task::yield_now().await;
+        assert!(!receiver.is_finished());
+
+        subscribed.send_replace(true);
+        assert!(receiver.await.is_ok());
     }
 }
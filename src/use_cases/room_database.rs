@@ -3,50 +3,180 @@ use thiserror::Error;
 use uuid::Uuid;
 
 use crate::domain::{
-    room::{Message, Room, RoomMember},
-    user::User,
+    dto::{MemberView, MessageView, RoomSummary},
+    room::{
+        MemberRole, Message, MessageEdit, ModerationEntry, Room, RoomMember, RoomReadState,
+        RoomSanction, RoomUnreadCount,
+    },
 };
 
 pub type RoomDatabaseResult<T> = Result<T, RoomDatabaseError>;
 
 #[automock]
 pub trait RoomDatabase: Send + Sync {
-    /// This method returns all the public rooms
-    async fn get_public_rooms(&self) -> RoomDatabaseResult<Vec<Room>>;
+    /// Returns the public rooms left unarchived, with `is_member` computed for the given user
+    async fn get_public_room_summaries(
+        &self,
+        user_id: Uuid,
+    ) -> RoomDatabaseResult<Vec<RoomSummary>>;
 
     /// Returns only the rooms in which the user is already joined
     async fn get_user_rooms(&self, user_id: Uuid) -> RoomDatabaseResult<Vec<Room>>;
 
-    /// Return the specific information about only one room
+    /// Same rooms as `get_user_rooms`, with member counts and password flags
+    async fn get_user_room_summaries(&self, user_id: Uuid) -> RoomDatabaseResult<Vec<RoomSummary>>;
+
+    /// Return the specific information about only one room, archived rooms are not found
     async fn get_room(&self, id: Uuid) -> RoomDatabaseResult<Room>;
 
     /// Creates a room
     async fn create_room(&self, room: Room) -> RoomDatabaseResult<()>;
 
-    /// Joins a specific user to a specific room
+    /// Saves the name, visibility and password hash of the room, returning it as stored
+    async fn update_room(&self, room: Room) -> RoomDatabaseResult<Room>;
+
+    /// Deletes the room together with its memberships, messages and read markers
+    async fn delete_room(&self, room_id: Uuid) -> RoomDatabaseResult<()>;
+
+    /// Joins a specific user to a specific room, failing with `NotFound` when it is archived
     async fn create_room_membership(&self, room_member: RoomMember) -> RoomDatabaseResult<()>;
 
-    /// Removes a specific user from a specific room
+    /// Removes a specific user from a specific room. Owners are kept, as they can only leave
+    /// through `leave_owned_room`, so a room is never left without one
     async fn delete_room_membership(&self, room_id: Uuid, user_id: Uuid) -> RoomDatabaseResult<()>;
 
-    /// Get's all of the members for n specific room
-    async fn get_room_members(&self, room_id: Uuid) -> RoomDatabaseResult<Vec<User>>;
+    /// Removes the owner from the room, handing it over to its oldest remaining member, in one
+    /// transaction. Returns the new owner, or `None` when nobody else was in the room, which is
+    /// then archived
+    async fn leave_owned_room(
+        &self,
+        room_id: Uuid,
+        owner_id: Uuid,
+    ) -> RoomDatabaseResult<Option<Uuid>>;
+
+    /// Makes the member the owner of the room and the previous owner an admin, in one
+    /// transaction holding the room. Fails with `NotFound` when the new owner is not a member,
+    /// and returns `None` when `owner_id` no longer owns the room
+    async fn transfer_ownership(
+        &self,
+        room_id: Uuid,
+        owner_id: Uuid,
+        new_owner_id: Uuid,
+    ) -> RoomDatabaseResult<Option<RoomMember>>;
+
+    /// Role of the user in the room, `None` when the user is not a member
+    async fn get_member_role(
+        &self,
+        room_id: Uuid,
+        user_id: Uuid,
+    ) -> RoomDatabaseResult<Option<MemberRole>>;
+
+    /// Gives the member a new role, returning the membership as stored
+    async fn update_member_role(
+        &self,
+        room_id: Uuid,
+        user_id: Uuid,
+        role: MemberRole,
+    ) -> RoomDatabaseResult<RoomMember>;
+
+    /// Ban of the user from the room, `None` when there is none or it expired
+    async fn get_active_ban(
+        &self,
+        room_id: Uuid,
+        user_id: Uuid,
+    ) -> RoomDatabaseResult<Option<RoomSanction>>;
+
+    /// Mute of the user in the room, `None` when there is none or it expired
+    async fn get_active_mute(
+        &self,
+        room_id: Uuid,
+        user_id: Uuid,
+    ) -> RoomDatabaseResult<Option<RoomSanction>>;
+
+    /// Applies the action to the target and records it in the moderation log, in one
+    /// transaction. Kicks and bans remove the membership, bans and mutes replace any previous
+    /// one, and lifting them fails with `NotFound` when there is none in force, as kicking a
+    /// user who is not a member or banning an unknown one does
+    async fn moderate_member(&self, entry: ModerationEntry) -> RoomDatabaseResult<()>;
+
+    /// Moderation log of the room, the newest entries first
+    async fn get_moderation_log(
+        &self,
+        room_id: Uuid,
+        limit: i64,
+    ) -> RoomDatabaseResult<Vec<ModerationEntry>>;
+
+    /// Members of the room with their role, the oldest first
+    async fn get_room_members(&self, room_id: Uuid) -> RoomDatabaseResult<Vec<MemberView>>;
+
+    /// Stores the message and returns it together with the sender's username, and the creation
+    /// date assigned by the database
+    async fn create_message(&self, message: Message) -> RoomDatabaseResult<MessageView>;
 
-    /// This functions get
-    async fn create_message(&self, message: Message) -> RoomDatabaseResult<()>;
+    /// Messages of the room older than the cursor, or the latest ones without a cursor, ordered by
+    /// `(created_at, id)` DESC, so the last ones come first in the array
+    async fn get_room_messages_before(
+        &self,
+        room_id: Uuid,
+        cursor: Option<Uuid>,
+        limit: i64,
+    ) -> RoomDatabaseResult<Vec<MessageView>>;
 
-    /// This functions get all messages of an specific room, in DESC order, so the last ones, come
+    /// Messages of the room newer than the cursor closest to it, also ordered with the last ones
     /// first in the array
-    async fn get_room_messages(
+    async fn get_room_messages_after(
         &self,
         room_id: Uuid,
-        page: u32,
-        page_size: u8,
-    ) -> RoomDatabaseResult<Vec<Message>>;
+        cursor: Uuid,
+        limit: i64,
+    ) -> RoomDatabaseResult<Vec<MessageView>>;
+
+    /// Messages of any of the rooms newer than the cursor, in the order they were sent, which is
+    /// the opposite of the other message queries
+    async fn get_messages_since(
+        &self,
+        room_ids: Vec<Uuid>,
+        cursor: Uuid,
+        limit: i64,
+    ) -> RoomDatabaseResult<Vec<MessageView>>;
+
+    /// Returns one specific message
+    async fn get_message(&self, message_id: Uuid) -> RoomDatabaseResult<MessageView>;
+
+    /// Replaces the content of the message, keeping the previous one in its edit history
+    async fn edit_message(
+        &self,
+        message_id: Uuid,
+        edited_by: Uuid,
+        content: String,
+    ) -> RoomDatabaseResult<MessageView>;
+
+    /// Marks the message as deleted, the content is kept but no longer returned
+    async fn delete_message(&self, message_id: Uuid) -> RoomDatabaseResult<MessageView>;
+
+    /// Previous contents of the message, the most recent edit first
+    async fn get_message_edits(&self, message_id: Uuid) -> RoomDatabaseResult<Vec<MessageEdit>>;
+
+    /// Moves the read marker of the user forward, returns false when the given message does not
+    /// come after the one already marked as read in the history, ordered by creation then id, in
+    /// which case nothing is changed
+    async fn update_read_state(&self, read_state: RoomReadState) -> RoomDatabaseResult<bool>;
+
+    /// Counts, for every room the user is joined to, the messages of other members that arrived
+    /// after the user's read marker
+    async fn get_unread_counts(&self, user_id: Uuid) -> RoomDatabaseResult<Vec<RoomUnreadCount>>;
 }
 
 #[derive(Debug, Error)]
 pub enum RoomDatabaseError {
     #[error("Internal DB error: {0}")]
     InternalDBError(String),
+    #[error("Not found")]
+    NotFound,
+    #[error("Unique constraint {0} violated")]
+    UniqueViolation(String),
+    #[error("Foreign key constraint {0} violated")]
+    ForeignKeyViolation(String),
+    #[error("Database unavailable: {0}")]
+    Unavailable(String),
 }
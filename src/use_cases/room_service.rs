@@ -1,19 +1,24 @@
 use std::sync::Arc;
 
 use bcrypt::{DEFAULT_COST, hash, verify};
-use chrono::Utc;
+use chrono::{DateTime, Utc};
 use thiserror::Error;
 use uuid::Uuid;
 
 use crate::{
     domain::{
-        room::{MemberRole, Message, Room, RoomMember, RoomVisibility},
-        user::User,
+        dto::{MemberView, MessagePage, MessageView, RoomSummary, UserRoom},
+        event::RoomEvent,
+        room::{
+            MemberRole, MembershipAction, MembershipChange, Message, MessageAnchor, MessageEdit,
+            ModerationAction, ModerationEntry, RoleChange, Room, RoomDeletion, RoomMember,
+            RoomPermission, RoomReadState, RoomVisibility, TypingIndicator,
+        },
     },
     use_cases::{
         notification_service::{NotificationService, RoomMemberNotification},
         realtime_broker::MessagePublisher,
-        room_database::RoomDatabase,
+        room_database::{RoomDatabase, RoomDatabaseError},
     },
 };
 
@@ -24,20 +29,36 @@ pub async fn user_is_in_room(
     user_id: Uuid,
     room_id: Uuid,
 ) -> RoomResult<bool> {
-    let rooms = db
-        .get_user_rooms(user_id)
-        .await
-        .map_err(|err| RoomError::DatabaseError(err.to_string()))?;
+    let rooms = db.get_user_rooms(user_id).await?;
 
     Ok(rooms.iter().any(|room| room.id == room_id))
 }
 
+/// Guards the endpoints of a room, returning the role of the user in it. Fails with
+/// `RoomNotFound` for unknown rooms and `NotRoomMember` when the user hasn't joined
+pub async fn check_room_access(
+    db: &Arc<impl RoomDatabase>,
+    room_id: Uuid,
+    user_id: Uuid,
+) -> RoomResult<MemberRole> {
+    if let Some(role) = db.get_member_role(room_id, user_id).await? {
+        return Ok(role);
+    }
+
+    match db.get_room(room_id).await {
+        Ok(_) => Err(RoomError::NotRoomMember),
+        Err(RoomDatabaseError::NotFound) => Err(RoomError::RoomNotFound),
+        Err(err) => Err(err.into()),
+    }
+}
+
 pub async fn create_room(
     db: Arc<impl RoomDatabase>,
     visibility: RoomVisibility,
     password: Option<String>,
     name: String,
     user_id: Uuid,
+    message_publisher: Arc<impl MessagePublisher>,
 ) -> RoomResult<()> {
     let room_id = Uuid::new_v4();
     let mut hashed_pasword: Option<String> = None;
@@ -62,9 +83,7 @@ pub async fn create_room(
         created_by: user_id,
         created_at: Utc::now(),
     };
-    db.create_room(room)
-        .await
-        .map_err(|err| RoomError::DatabaseError(err.to_string()))?;
+    db.create_room(room).await?;
 
     let room_membre = RoomMember {
         room_id,
@@ -73,9 +92,17 @@ pub async fn create_room(
         joined_at: Utc::now(),
     };
 
-    db.create_room_membership(room_membre)
+    db.create_room_membership(room_membre).await?;
+
+    // Lets the sockets of the creator follow the room, as they do for the rooms they join
+    message_publisher
+        .publish(RoomEvent::Membership(MembershipChange {
+            room_id,
+            user_id,
+            action: MembershipAction::Joined,
+        }))
         .await
-        .map_err(|err| RoomError::DatabaseError(err.to_string()))?;
+        .map_err(|err| RoomError::BroadcastError(err.to_string()))?;
 
     Ok(())
 }
@@ -86,6 +113,7 @@ pub async fn join_room(
     user_id: Uuid,
     password: Option<String>,
     notification_service: Arc<impl NotificationService>,
+    message_publisher: Arc<impl MessagePublisher>,
 ) -> RoomResult<()> {
     let room_member = RoomMember {
         room_id,
@@ -100,17 +128,15 @@ pub async fn join_room(
         action: super::notification_service::RoomAction::JoinedRoom,
     };
 
-    let room = db
-        .get_room(room_id)
-        .await
-        .map_err(|err| RoomError::DatabaseError(err.to_string()))?;
+    let room = db.get_room(room_id).await.map_err(room_not_found)?;
 
-    if room.visibility == RoomVisibility::Private && password.is_none() {
-        return Err(RoomError::PasswordNotGiven);
+    if let Some(ban) = db.get_active_ban(room_id, user_id).await? {
+        return Err(RoomError::Banned(ban.expires_at));
     }
 
-    if room.visibility == RoomVisibility::Private && password.is_some() {
-        let ver = verify(password.unwrap(), &room.password_hash.unwrap())
+    if room.visibility == RoomVisibility::Private {
+        let password = password.ok_or(RoomError::PasswordNotGiven)?;
+        let ver = verify(password, &room.password_hash.unwrap_or_default())
             .map_err(|err| RoomError::BcryptError(err.to_string()))?;
         if !ver {
             return Err(RoomError::InvalidRoomPassword);
@@ -119,19 +145,39 @@ pub async fn join_room(
 
     db.create_room_membership(room_member)
         .await
-        .map_err(|err| RoomError::DatabaseError(err.to_string()))?;
+        .map_err(|err| match err {
+            RoomDatabaseError::UniqueViolation(_) => RoomError::AlreadyRoomMember,
+            // The room was deleted or archived since it was read
+            RoomDatabaseError::NotFound | RoomDatabaseError::ForeignKeyViolation(_) => {
+                RoomError::RoomNotFound
+            }
+            err => err.into(),
+        })?;
 
     notification_service
         .send_room_member_notification(notification)
         .await
         .map_err(|err| RoomError::NotificationError(err.to_string()))?;
 
+    message_publisher
+        .publish(RoomEvent::Membership(MembershipChange {
+            room_id,
+            user_id,
+            action: MembershipAction::Joined,
+        }))
+        .await
+        .map_err(|err| RoomError::BroadcastError(err.to_string()))?;
+
     Ok(())
 }
 
+/// Members can leave at any time. An owner hands the room over to its oldest remaining member,
+/// who is told through a `RoleChanged` event, and when nobody else is left the room is archived,
+/// keeping its history out of the listings, rather than deleted
 pub async fn leave_room(
     db: Arc<impl RoomDatabase>,
     notification_service: Arc<impl NotificationService>,
+    message_publisher: Arc<impl MessagePublisher>,
     room_id: Uuid,
     user_id: Uuid,
 ) -> RoomResult<()> {
@@ -141,549 +187,2965 @@ pub async fn leave_room(
         action: super::notification_service::RoomAction::LeftRoom,
     };
 
-    db.delete_room_membership(room_id, user_id)
-        .await
-        .map_err(|err| RoomError::DatabaseError(err.to_string()))?;
+    let left = RoomEvent::Membership(MembershipChange {
+        room_id,
+        user_id,
+        action: MembershipAction::Left,
+    });
+
+    let events = if db.get_member_role(room_id, user_id).await? == Some(MemberRole::Owner) {
+        match db
+            .leave_owned_room(room_id, user_id)
+            .await
+            .map_err(room_not_found)?
+        {
+            Some(heir) => vec![
+                RoomEvent::RoleChanged(RoleChange {
+                    room_id,
+                    user_id: heir,
+                    role: MemberRole::Owner,
+                    changed_by: user_id,
+                }),
+                left,
+            ],
+            None => vec![left],
+        }
+    } else {
+        db.delete_room_membership(room_id, user_id).await?;
+        vec![left]
+    };
 
     notification_service
         .send_room_member_notification(notification)
         .await
         .map_err(|err| RoomError::NotificationError(err.to_string()))?;
 
+    for event in events {
+        message_publisher
+            .publish(event)
+            .await
+            .map_err(|err| RoomError::BroadcastError(err.to_string()))?;
+    }
+
     Ok(())
 }
 
-pub async fn get_user_rooms_use(
+/// Owners and admins can change a room, the fields left as `None` are kept. A private room keeps
+/// its password unless a new one is given, and making a room public drops it
+pub async fn update_room(
     db: Arc<impl RoomDatabase>,
+    room_id: Uuid,
     user_id: Uuid,
-) -> RoomResult<Vec<Room>> {
-    let rooms = db
-        .get_user_rooms(user_id)
-        .await
-        .map_err(|err| RoomError::DatabaseError(err.to_string()))?;
+    name: Option<String>,
+    visibility: Option<RoomVisibility>,
+    password: Option<String>,
+    message_publisher: Arc<impl MessagePublisher>,
+) -> RoomResult<Room> {
+    require_permission(&db, room_id, user_id, RoomPermission::EditRoom).await?;
 
-    Ok(rooms)
-}
+    let mut room = db.get_room(room_id).await.map_err(room_not_found)?;
+
+    if let Some(name) = name {
+        room.name = name;
+    }
+
+    let visibility = visibility.unwrap_or(room.visibility);
+    room.password_hash = match (visibility, password) {
+        (RoomVisibility::Public, _) => None,
+        (RoomVisibility::Private, Some(password)) => Some(
+            hash(password, DEFAULT_COST)
+                .map_err(|err| RoomError::PasswordHashError(err.to_string()))?,
+        ),
+        (RoomVisibility::Private, None) => {
+            Some(room.password_hash.ok_or(RoomError::PasswordNotGiven)?)
+        }
+    };
+    room.visibility = visibility;
+
+    let room = db.update_room(room).await.map_err(room_not_found)?;
 
-pub async fn get_all_public_rooms(db: Arc<impl RoomDatabase>) -> RoomResult<Vec<Room>> {
-    let rooms = db
-        .get_public_rooms()
+    message_publisher
+        .publish(RoomEvent::RoomUpdated(room.clone()))
         .await
-        .map_err(|err| RoomError::DatabaseError(err.to_string()))?;
+        .map_err(|err| RoomError::BroadcastError(err.to_string()))?;
 
-    Ok(rooms)
+    Ok(room)
 }
 
-pub async fn send_message(
+/// Only the owner can delete a room, its members are told through a `RoomDeleted` event
+pub async fn delete_room(
     db: Arc<impl RoomDatabase>,
     room_id: Uuid,
     user_id: Uuid,
-    content: String,
     message_publisher: Arc<impl MessagePublisher>,
 ) -> RoomResult<()> {
-    let message = Message {
-        id: Uuid::new_v4(),
-        room_id,
-        sender_id: user_id,
-        content,
-        created_at: Utc::now(),
-    };
+    require_permission(&db, room_id, user_id, RoomPermission::DeleteRoom).await?;
 
-    db.create_message(message.clone())
-        .await
-        .map_err(|err| RoomError::DatabaseError(err.to_string()))?;
+    db.delete_room(room_id).await.map_err(room_not_found)?;
 
     message_publisher
-        .broadcast_message(message.clone())
+        .publish(RoomEvent::RoomDeleted(RoomDeletion {
+            room_id,
+            deleted_by: user_id,
+        }))
         .await
         .map_err(|err| RoomError::BroadcastError(err.to_string()))?;
 
     Ok(())
 }
 
-pub async fn obtain_messages(
-    db: Arc<impl RoomDatabase>,
-    page: u32,
-    page_size: u8,
+/// Guards the actions of a room, failing with `InsufficientRole` when the role of the user
+/// doesn't grant the permission. Returns the role, as `check_room_access` does
+async fn require_permission(
+    db: &Arc<impl RoomDatabase>,
     room_id: Uuid,
-) -> RoomResult<Vec<Message>> {
-    let messages = db
-        .get_room_messages(room_id, page, page_size)
-        .await
-        .map_err(|err| RoomError::DatabaseError(err.to_string()))?;
+    user_id: Uuid,
+    permission: RoomPermission,
+) -> RoomResult<MemberRole> {
+    let role = check_room_access(db, room_id, user_id).await?;
+
+    if !role.can(permission) {
+        return Err(RoomError::InsufficientRole);
+    }
 
-    Ok(messages)
+    Ok(role)
 }
 
-pub async fn obtain_room_members(
-    db: Arc<impl RoomDatabase>,
+/// Guards posting, editing and typing: the user needs the permission to post and must not be
+/// muted in the room
+async fn require_unmuted_poster(
+    db: &Arc<impl RoomDatabase>,
     room_id: Uuid,
-) -> RoomResult<Vec<User>> {
-    let users = db
-        .get_room_members(room_id)
-        .await
-        .map_err(|err| RoomError::DatabaseError(err.to_string()))?;
-    Ok(users)
-}
-//
-//
-//
-//
-//
-//
-//
-//
+    user_id: Uuid,
+) -> RoomResult<()> {
+    require_permission(db, room_id, user_id, RoomPermission::PostMessages).await?;
 
-#[derive(Error, Debug)]
-pub enum RoomError {
-    #[error("database Error {0}")]
-    DatabaseError(String),
-    #[error("hashing error {0}")]
-    PasswordHashError(String),
-    #[error("pasword not given")]
-    PasswordNotGiven,
-    #[error("broadcast error {0}")]
-    BroadcastError(String),
-    #[error("notification error: {0}")]
-    NotificationError(String),
-    #[error("invalid room password")]
-    InvalidRoomPassword,
-    #[error("encryt error: {0}")]
-    BcryptError(String),
+    if let Some(mute) = db.get_active_mute(room_id, user_id).await? {
+        return Err(RoomError::Muted(mute.expires_at));
+    }
+
+    Ok(())
 }
 
-#[cfg(test)]
-mod test {
-    use std::sync::Arc;
+/// Promotes or demotes a member. Owners and admins can only change the role of the members below
+/// them, to a role that is also below theirs, so ownership can't be given this way
+pub async fn change_member_role(
+    db: Arc<impl RoomDatabase>,
+    room_id: Uuid,
+    user_id: Uuid,
+    member_id: Uuid,
+    role: MemberRole,
+    message_publisher: Arc<impl MessagePublisher>,
+) -> RoomResult<RoomMember> {
+    let user_role = require_permission(&db, room_id, user_id, RoomPermission::ManageRoles).await?;
 
-    use chrono::Utc;
-    use uuid::Uuid;
+    let member_role = db
+        .get_member_role(room_id, member_id)
+        .await?
+        .ok_or(RoomError::MemberNotFound)?;
 
-    use crate::{
-        domain::{
-            room::{Message, Room, RoomVisibility},
-            user::User,
-        },
-        use_cases::{
-            notification_service::{MockNotificationService, NotificationServiceError},
-            realtime_broker::MockMessagePublisher,
-            room_database::{MockRoomDatabase, RoomDatabaseError},
-            room_service::{
-                RoomError, create_room, get_all_public_rooms, get_user_rooms_use, join_room,
-                leave_room, obtain_messages, obtain_room_members, send_message, user_is_in_room,
-            },
-        },
-    };
+    if !user_role.outranks(member_role) || !user_role.outranks(role) {
+        return Err(RoomError::InsufficientRole);
+    }
 
-    #[tokio::test]
-    async fn test_user_is_in_room_true() {
-        let mut db = MockRoomDatabase::new();
+    let member = db
+        .update_member_role(room_id, member_id, role)
+        .await
+        .map_err(|err| match err {
+            // The member left since the role was read
+            RoomDatabaseError::NotFound => RoomError::MemberNotFound,
+            err => err.into(),
+        })?;
 
-        let user_id = Uuid::new_v4();
-        let room_id = Uuid::new_v4();
+    message_publisher
+        .publish(RoomEvent::RoleChanged(RoleChange {
+            room_id,
+            user_id: member_id,
+            role,
+            changed_by: user_id,
+        }))
+        .await
+        .map_err(|err| RoomError::BroadcastError(err.to_string()))?;
 
-        db.expect_get_user_rooms().returning(move |_| {
-            Ok(vec![Room {
-                id: room_id,
-                name: "test".into(),
-                visibility: RoomVisibility::Public,
-                password_hash: None,
-                created_by: user_id,
-                created_at: Utc::now(),
-            }])
-        });
+    Ok(member)
+}
 
-        let result = user_is_in_room(Arc::new(db), user_id, room_id)
-            .await
-            .unwrap();
-        assert!(result);
+/// Only the owner can hand the room over, to another member, becoming an admin of it
+pub async fn transfer_ownership(
+    db: Arc<impl RoomDatabase>,
+    room_id: Uuid,
+    user_id: Uuid,
+    new_owner_id: Uuid,
+    message_publisher: Arc<impl MessagePublisher>,
+) -> RoomResult<RoomMember> {
+    require_permission(&db, room_id, user_id, RoomPermission::TransferOwnership).await?;
+
+    if new_owner_id == user_id {
+        return Err(RoomError::AlreadyRoomOwner);
     }
 
-    #[tokio::test]
-    async fn test_user_is_in_room_false() {
-        let mut db = MockRoomDatabase::new();
+    let new_owner = db
+        .transfer_ownership(room_id, user_id, new_owner_id)
+        .await
+        .map_err(|err| match err {
+            RoomDatabaseError::NotFound => RoomError::MemberNotFound,
+            err => err.into(),
+        })?
+        // Another transfer or a leave got there first
+        .ok_or(RoomError::InsufficientRole)?;
+
+    for (member_id, role) in [
+        (new_owner_id, MemberRole::Owner),
+        (user_id, MemberRole::Admin),
+    ] {
+        message_publisher
+            .publish(RoomEvent::RoleChanged(RoleChange {
+                room_id,
+                user_id: member_id,
+                role,
+                changed_by: user_id,
+            }))
+            .await
+            .map_err(|err| RoomError::BroadcastError(err.to_string()))?;
+    }
 
-        let user_id = Uuid::new_v4();
+    Ok(new_owner)
+}
 
-        db.expect_get_user_rooms().returning(|_| Ok(vec![]));
+/// Removes the member from the room, which it can join again
+pub async fn kick_member(
+    db: Arc<impl RoomDatabase>,
+    room_id: Uuid,
+    user_id: Uuid,
+    member_id: Uuid,
+    reason: Option<String>,
+    message_publisher: Arc<impl MessagePublisher>,
+) -> RoomResult<ModerationEntry> {
+    let entry = moderation_entry(room_id, user_id, member_id, ModerationAction::Kick, reason);
 
-        let result = user_is_in_room(Arc::new(db), user_id, Uuid::new_v4())
-            .await
-            .unwrap();
+    moderate(
+        db,
+        user_id,
+        entry,
+        RoomPermission::KickMembers,
+        message_publisher,
+    )
+    .await
+}
 
-        assert!(!result);
-    }
+/// Removes the member from the room and keeps it from joining again, until `until` or for good.
+/// Users who are not members can be banned too, keeping them from joining at all
+pub async fn ban_member(
+    db: Arc<impl RoomDatabase>,
+    room_id: Uuid,
+    user_id: Uuid,
+    member_id: Uuid,
+    reason: Option<String>,
+    until: Option<DateTime<Utc>>,
+    message_publisher: Arc<impl MessagePublisher>,
+) -> RoomResult<ModerationEntry> {
+    let entry = ModerationEntry {
+        expires_at: until,
+        ..moderation_entry(room_id, user_id, member_id, ModerationAction::Ban, reason)
+    };
 
-    #[tokio::test]
-    async fn join_public_room_ok() {
-        let mut db = MockRoomDatabase::new();
-        let mut notif = MockNotificationService::new();
+    moderate(
+        db,
+        user_id,
+        entry,
+        RoomPermission::BanMembers,
+        message_publisher,
+    )
+    .await
+}
 
-        let room_id = Uuid::new_v4();
-        let user_id = Uuid::new_v4();
+/// Lifts the ban of the user, which still has to join the room again
+pub async fn unban_user(
+    db: Arc<impl RoomDatabase>,
+    room_id: Uuid,
+    user_id: Uuid,
+    target_id: Uuid,
+    message_publisher: Arc<impl MessagePublisher>,
+) -> RoomResult<ModerationEntry> {
+    let entry = moderation_entry(room_id, user_id, target_id, ModerationAction::Unban, None);
 
-        db.expect_get_room().returning(move |_| {
-            Ok(Room {
-                id: room_id,
-                name: "Public".into(),
-                visibility: RoomVisibility::Public,
-                password_hash: None,
-                created_by: user_id,
-                created_at: Utc::now(),
-            })
-        });
+    moderate(
+        db,
+        user_id,
+        entry,
+        RoomPermission::BanMembers,
+        message_publisher,
+    )
+    .await
+}
 
-        db.expect_create_room_membership().returning(|_| Ok(()));
+/// Keeps the member from posting in the room until `until`
+pub async fn mute_member(
+    db: Arc<impl RoomDatabase>,
+    room_id: Uuid,
+    user_id: Uuid,
+    member_id: Uuid,
+    reason: Option<String>,
+    until: DateTime<Utc>,
+    message_publisher: Arc<impl MessagePublisher>,
+) -> RoomResult<ModerationEntry> {
+    let entry = ModerationEntry {
+        expires_at: Some(until),
+        ..moderation_entry(room_id, user_id, member_id, ModerationAction::Mute, reason)
+    };
 
-        notif
-            .expect_send_room_member_notification()
-            .returning(|_| Ok(()));
+    moderate(
+        db,
+        user_id,
+        entry,
+        RoomPermission::MuteMembers,
+        message_publisher,
+    )
+    .await
+}
 
-        let res = join_room(Arc::new(db), room_id, user_id, None, Arc::new(notif)).await;
+pub async fn unmute_member(
+    db: Arc<impl RoomDatabase>,
+    room_id: Uuid,
+    user_id: Uuid,
+    member_id: Uuid,
+    message_publisher: Arc<impl MessagePublisher>,
+) -> RoomResult<ModerationEntry> {
+    let entry = moderation_entry(room_id, user_id, member_id, ModerationAction::Unmute, None);
 
-        assert!(res.is_ok());
-    }
+    moderate(
+        db,
+        user_id,
+        entry,
+        RoomPermission::MuteMembers,
+        message_publisher,
+    )
+    .await
+}
 
-    #[tokio::test]
-    async fn join_private_room_without_password_fails() {
-        let mut db = MockRoomDatabase::new();
-        let notif = MockNotificationService::new();
+/// Moderation log of the room, newest entries first, only for moderators and the roles above
+pub async fn obtain_moderation_log(
+    db: Arc<impl RoomDatabase>,
+    room_id: Uuid,
+    user_id: Uuid,
+    limit: u8,
+) -> RoomResult<Vec<ModerationEntry>> {
+    require_permission(&db, room_id, user_id, RoomPermission::ReadModerationLog).await?;
 
-        let room_id = Uuid::new_v4();
-        let user_id = Uuid::new_v4();
+    let entries = db.get_moderation_log(room_id, limit.max(1) as i64).await?;
 
-        db.expect_get_room().returning(move |_| {
-            Ok(Room {
-                id: room_id,
-                name: "Private".into(),
-                visibility: RoomVisibility::Private,
-                password_hash: Some("$2b$12$somehashhere".into()),
-                created_by: user_id,
-                created_at: Utc::now(),
-            })
-        });
+    Ok(entries)
+}
 
-        let res = join_room(Arc::new(db), room_id, user_id, None, Arc::new(notif)).await;
+fn moderation_entry(
+    room_id: Uuid,
+    user_id: Uuid,
+    target_id: Uuid,
+    action: ModerationAction,
+    reason: Option<String>,
+) -> ModerationEntry {
+    ModerationEntry {
+        id: Uuid::new_v4(),
+        room_id,
+        target_id,
+        moderator_id: Some(user_id),
+        action,
+        reason,
+        expires_at: None,
+        created_at: Utc::now(),
+    }
+}
 
-        assert!(matches!(res, Err(RoomError::PasswordNotGiven)));
+/// Applies the entry once the moderator is known to have the permission and to outrank the
+/// target, then publishes it to the room and to the target
+async fn moderate(
+    db: Arc<impl RoomDatabase>,
+    user_id: Uuid,
+    entry: ModerationEntry,
+    permission: RoomPermission,
+    message_publisher: Arc<impl MessagePublisher>,
+) -> RoomResult<ModerationEntry> {
+    let moderator_role = require_permission(&db, entry.room_id, user_id, permission).await?;
+
+    // Banned users are no longer members, so there is no role to compare against, and users can
+    // be banned before they join
+    if entry.action != ModerationAction::Unban {
+        let target_role = db.get_member_role(entry.room_id, entry.target_id).await?;
+
+        match target_role {
+            Some(role) if !moderator_role.outranks(role) => {
+                return Err(RoomError::InsufficientRole);
+            }
+            None if entry.action != ModerationAction::Ban => {
+                return Err(RoomError::MemberNotFound);
+            }
+            _ => {}
+        }
     }
 
-    #[tokio::test]
-    async fn join_private_room_wrong_password_fails() {
+    db.moderate_member(entry.clone())
+        .await
+        .map_err(|err| match (err, entry.action) {
+            (RoomDatabaseError::NotFound, ModerationAction::Unban | ModerationAction::Unmute) => {
+                RoomError::NotSanctioned
+            }
+            (RoomDatabaseError::NotFound, _) => RoomError::MemberNotFound,
+            (err, _) => err.into(),
+        })?;
+
+    message_publisher
+        .publish(RoomEvent::Moderation(entry.clone()))
+        .await
+        .map_err(|err| RoomError::BroadcastError(err.to_string()))?;
+
+    Ok(entry)
+}
+
+pub async fn get_user_rooms_use(
+    db: Arc<impl RoomDatabase>,
+    user_id: Uuid,
+) -> RoomResult<Vec<Room>> {
+    let rooms = db.get_user_rooms(user_id).await?;
+
+    Ok(rooms)
+}
+
+pub async fn get_user_rooms_with_unread(
+    db: Arc<impl RoomDatabase>,
+    user_id: Uuid,
+) -> RoomResult<Vec<UserRoom>> {
+    let rooms = db.get_user_room_summaries(user_id).await?;
+
+    let unread_counts = db.get_unread_counts(user_id).await?;
+
+    let user_rooms = rooms
+        .into_iter()
+        .map(|room| {
+            let unread_count = unread_counts
+                .iter()
+                .find(|count| count.room_id == room.id)
+                .map(|count| count.unread_count)
+                .unwrap_or(0);
+
+            UserRoom { room, unread_count }
+        })
+        .collect();
+
+    Ok(user_rooms)
+}
+
+pub async fn get_all_public_rooms(
+    db: Arc<impl RoomDatabase>,
+    user_id: Uuid,
+) -> RoomResult<Vec<RoomSummary>> {
+    let rooms = db.get_public_room_summaries(user_id).await?;
+
+    Ok(rooms)
+}
+
+pub async fn send_message(
+    db: Arc<impl RoomDatabase>,
+    room_id: Uuid,
+    user_id: Uuid,
+    content: String,
+    message_publisher: Arc<impl MessagePublisher>,
+) -> RoomResult<MessageView> {
+    require_unmuted_poster(&db, room_id, user_id).await?;
+
+    let message = Message {
+        id: Uuid::new_v4(),
+        room_id,
+        sender_id: user_id,
+        content,
+        created_at: Utc::now(),
+    };
+
+    let message_view = db.create_message(message).await.map_err(|err| match err {
+        RoomDatabaseError::ForeignKeyViolation(_) => RoomError::RoomNotFound,
+        err => err.into(),
+    })?;
+
+    message_publisher
+        .publish(RoomEvent::Message(message_view.clone()))
+        .await
+        .map_err(|err| RoomError::BroadcastError(err.to_string()))?;
+
+    Ok(message_view)
+}
+
+/// Only the sender of a message can edit it
+pub async fn edit_message(
+    db: Arc<impl RoomDatabase>,
+    room_id: Uuid,
+    message_id: Uuid,
+    user_id: Uuid,
+    content: String,
+    message_publisher: Arc<impl MessagePublisher>,
+) -> RoomResult<MessageView> {
+    require_unmuted_poster(&db, room_id, user_id).await?;
+
+    let message = get_room_message(&db, room_id, message_id).await?;
+
+    if message.deleted_at.is_some() {
+        return Err(RoomError::MessageDeleted);
+    }
+
+    if message.sender_id != Some(user_id) {
+        return Err(RoomError::NotMessageSender);
+    }
+
+    let edited = db
+        .edit_message(message_id, user_id, content)
+        .await
+        .map_err(deleted_meanwhile)?;
+
+    message_publisher
+        .publish(RoomEvent::MessageEdited(edited.clone()))
+        .await
+        .map_err(|err| RoomError::BroadcastError(err.to_string()))?;
+
+    Ok(edited)
+}
+
+/// The sender of a message and the moderators of the room, or the roles above, can delete it
+pub async fn delete_message(
+    db: Arc<impl RoomDatabase>,
+    room_id: Uuid,
+    message_id: Uuid,
+    user_id: Uuid,
+    message_publisher: Arc<impl MessagePublisher>,
+) -> RoomResult<()> {
+    // Former members can't delete even their own messages anymore
+    let role = check_room_access(&db, room_id, user_id).await?;
+
+    let message = get_room_message(&db, room_id, message_id).await?;
+
+    if message.deleted_at.is_some() {
+        return Err(RoomError::MessageDeleted);
+    }
+
+    if message.sender_id != Some(user_id) && !role.can(RoomPermission::DeleteOthersMessages) {
+        return Err(RoomError::NotMessageSender);
+    }
+
+    let deleted = db
+        .delete_message(message_id)
+        .await
+        .map_err(deleted_meanwhile)?;
+
+    message_publisher
+        .publish(RoomEvent::MessageDeleted(deleted))
+        .await
+        .map_err(|err| RoomError::BroadcastError(err.to_string()))?;
+
+    Ok(())
+}
+
+pub async fn obtain_message_edits(
+    db: Arc<impl RoomDatabase>,
+    room_id: Uuid,
+    message_id: Uuid,
+    user_id: Uuid,
+) -> RoomResult<Vec<MessageEdit>> {
+    check_room_access(&db, room_id, user_id).await?;
+    get_room_message(&db, room_id, message_id).await?;
+
+    let edits = db.get_message_edits(message_id).await?;
+
+    Ok(edits)
+}
+
+pub async fn send_typing(
+    db: Arc<impl RoomDatabase>,
+    room_id: Uuid,
+    user_id: Uuid,
+    is_typing: bool,
+    message_publisher: Arc<impl MessagePublisher>,
+) -> RoomResult<()> {
+    require_unmuted_poster(&db, room_id, user_id).await?;
+
+    let typing = TypingIndicator {
+        room_id,
+        user_id,
+        is_typing,
+    };
+
+    message_publisher
+        .publish(RoomEvent::Typing(typing))
+        .await
+        .map_err(|err| RoomError::BroadcastError(err.to_string()))?;
+
+    Ok(())
+}
+
+/// Returns up to `limit` messages positioned against the anchor, with the cursors to keep paging
+/// in both directions
+pub async fn obtain_messages(
+    db: Arc<impl RoomDatabase>,
+    room_id: Uuid,
+    user_id: Uuid,
+    anchor: MessageAnchor,
+    limit: u8,
+) -> RoomResult<MessagePage> {
+    check_room_access(&db, room_id, user_id).await?;
+
+    let limit = limit.max(1) as i64;
+
+    let page = match anchor {
+        MessageAnchor::Latest => {
+            let (messages, has_older) = older_messages(&db, room_id, None, limit).await?;
+            let next_cursor = has_older.then(|| messages.last().map(|m| m.id)).flatten();
+
+            MessagePage {
+                messages,
+                next_cursor,
+                prev_cursor: None,
+            }
+        }
+        MessageAnchor::Before(cursor) => {
+            get_room_message(&db, room_id, cursor).await?;
+
+            let (messages, has_older) = older_messages(&db, room_id, Some(cursor), limit).await?;
+            let next_cursor = has_older.then(|| messages.last().map(|m| m.id)).flatten();
+            let prev_cursor = messages.first().map(|m| m.id);
+
+            MessagePage {
+                messages,
+                next_cursor,
+                prev_cursor,
+            }
+        }
+        MessageAnchor::After(cursor) => {
+            get_room_message(&db, room_id, cursor).await?;
+
+            let (messages, has_newer) = newer_messages(&db, room_id, cursor, limit).await?;
+            let next_cursor = messages.last().map(|m| m.id);
+            let prev_cursor = has_newer.then(|| messages.first().map(|m| m.id)).flatten();
+
+            MessagePage {
+                messages,
+                next_cursor,
+                prev_cursor,
+            }
+        }
+        MessageAnchor::Around(cursor) => {
+            let anchor_message = get_room_message(&db, room_id, cursor).await?;
+
+            let newer_count = (limit - 1) / 2;
+            let older_count = limit - 1 - newer_count;
+
+            let (mut messages, has_newer) =
+                newer_messages(&db, room_id, cursor, newer_count).await?;
+            let (older, has_older) =
+                older_messages(&db, room_id, Some(cursor), older_count).await?;
+
+            messages.push(anchor_message);
+            messages.extend(older);
+
+            let next_cursor = has_older.then(|| messages.last().map(|m| m.id)).flatten();
+            let prev_cursor = has_newer.then(|| messages.first().map(|m| m.id)).flatten();
+
+            MessagePage {
+                messages,
+                next_cursor,
+                prev_cursor,
+            }
+        }
+    };
+
+    Ok(page)
+}
+
+/// Messages of the rooms sent after the given one, oldest first, so a reconnecting client can
+/// catch up. Returns `None` when more than `limit` were missed and the client has to resync
+/// through the paginated history instead
+pub async fn replay_messages(
+    db: Arc<impl RoomDatabase>,
+    room_ids: Vec<Uuid>,
+    last_message_id: Uuid,
+    limit: u16,
+) -> RoomResult<Option<Vec<MessageView>>> {
+    let last_message = db
+        .get_message(last_message_id)
+        .await
+        .map_err(message_not_found)?;
+
+    if !room_ids.contains(&last_message.room_id) {
+        return Err(RoomError::MessageNotInRoom);
+    }
+
+    let messages = db
+        .get_messages_since(room_ids, last_message_id, limit as i64 + 1)
+        .await?;
+
+    if messages.len() > limit as usize {
+        return Ok(None);
+    }
+
+    Ok(Some(messages))
+}
+
+/// Fetches one message more than asked for, to know if there are older messages left
+async fn older_messages(
+    db: &Arc<impl RoomDatabase>,
+    room_id: Uuid,
+    cursor: Option<Uuid>,
+    count: i64,
+) -> RoomResult<(Vec<MessageView>, bool)> {
+    let mut messages = db
+        .get_room_messages_before(room_id, cursor, count + 1)
+        .await?;
+
+    let has_more = messages.len() as i64 > count;
+    messages.truncate(count as usize);
+
+    Ok((messages, has_more))
+}
+
+/// Same as `older_messages` in the other direction, the extra message is the newest one
+async fn newer_messages(
+    db: &Arc<impl RoomDatabase>,
+    room_id: Uuid,
+    cursor: Uuid,
+    count: i64,
+) -> RoomResult<(Vec<MessageView>, bool)> {
+    let mut messages = db
+        .get_room_messages_after(room_id, cursor, count + 1)
+        .await?;
+
+    let extra = messages.len().saturating_sub(count as usize);
+    messages.drain(..extra);
+
+    Ok((messages, extra > 0))
+}
+
+/// The room is unknown or was deleted since it was read
+fn room_not_found(err: RoomDatabaseError) -> RoomError {
+    match err {
+        RoomDatabaseError::NotFound => RoomError::RoomNotFound,
+        err => err.into(),
+    }
+}
+
+/// An unknown message is answered like one of another room
+fn message_not_found(err: RoomDatabaseError) -> RoomError {
+    match err {
+        RoomDatabaseError::NotFound => RoomError::MessageNotInRoom,
+        err => err.into(),
+    }
+}
+
+/// Editing and deleting skip deleted messages, so a missing row means it was deleted concurrently
+fn deleted_meanwhile(err: RoomDatabaseError) -> RoomError {
+    match err {
+        RoomDatabaseError::NotFound => RoomError::MessageDeleted,
+        err => err.into(),
+    }
+}
+
+async fn get_room_message(
+    db: &Arc<impl RoomDatabase>,
+    room_id: Uuid,
+    message_id: Uuid,
+) -> RoomResult<MessageView> {
+    let message = db
+        .get_message(message_id)
+        .await
+        .map_err(message_not_found)?;
+
+    if message.room_id != room_id {
+        return Err(RoomError::MessageNotInRoom);
+    }
+
+    Ok(message)
+}
+
+pub async fn mark_room_read(
+    db: Arc<impl RoomDatabase>,
+    room_id: Uuid,
+    user_id: Uuid,
+    message_id: Uuid,
+    message_publisher: Arc<impl MessagePublisher>,
+) -> RoomResult<()> {
+    check_room_access(&db, room_id, user_id).await?;
+
+    get_room_message(&db, room_id, message_id).await?;
+
+    let read_state = RoomReadState {
+        room_id,
+        user_id,
+        last_read_message_id: Some(message_id),
+        last_read_at: Utc::now(),
+    };
+
+    let advanced = db.update_read_state(read_state.clone()).await?;
+
+    if advanced {
+        message_publisher
+            .publish(RoomEvent::Read(read_state))
+            .await
+            .map_err(|err| RoomError::BroadcastError(err.to_string()))?;
+    }
+
+    Ok(())
+}
+
+/// Only the members of a room can list the others
+pub async fn obtain_room_members(
+    db: Arc<impl RoomDatabase>,
+    room_id: Uuid,
+    user_id: Uuid,
+) -> RoomResult<Vec<MemberView>> {
+    check_room_access(&db, room_id, user_id).await?;
+
+    let members = db.get_room_members(room_id).await?;
+    Ok(members)
+}
+//
+//
+//
+//
+//
+//
+//
+//
+
+#[derive(Error, Debug)]
+pub enum RoomError {
+    #[error("database Error {0}")]
+    DatabaseError(String),
+    #[error("hashing error {0}")]
+    PasswordHashError(String),
+    #[error("pasword not given")]
+    PasswordNotGiven,
+    #[error("broadcast error {0}")]
+    BroadcastError(String),
+    #[error("notification error: {0}")]
+    NotificationError(String),
+    #[error("invalid room password")]
+    InvalidRoomPassword,
+    #[error("encryt error: {0}")]
+    BcryptError(String),
+    #[error("the user is not a member of the room")]
+    NotRoomMember,
+    #[error("the message does not belong to the room")]
+    MessageNotInRoom,
+    #[error("the user did not send the message")]
+    NotMessageSender,
+    #[error("the message was deleted")]
+    MessageDeleted,
+    #[error("the room does not exist")]
+    RoomNotFound,
+    #[error("the role of the user in the room does not allow it")]
+    InsufficientRole,
+    #[error("the user is not a member of the room")]
+    MemberNotFound,
+    #[error("the user is banned from the room")]
+    Banned(Option<DateTime<Utc>>),
+    #[error("the user is muted in the room")]
+    Muted(Option<DateTime<Utc>>),
+    #[error("the user is not banned or muted in the room")]
+    NotSanctioned,
+    #[error("the user is already a member of the room")]
+    AlreadyRoomMember,
+    #[error("the user already owns the room")]
+    AlreadyRoomOwner,
+    #[error("the database is unavailable")]
+    Unavailable(String),
+}
+
+impl From<RoomDatabaseError> for RoomError {
+    fn from(err: RoomDatabaseError) -> Self {
+        match err {
+            RoomDatabaseError::Unavailable(err) => RoomError::Unavailable(err),
+            err => RoomError::DatabaseError(err.to_string()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::Arc;
+
+    use chrono::{Duration, Utc};
+    use uuid::Uuid;
+
+    use crate::{
+        domain::{
+            dto::{MemberView, MessageView, RoomSummary},
+            event::RoomEvent,
+            room::{
+                MemberRole, MembershipAction, Message, MessageAnchor, ModerationAction, Room,
+                RoomMember, RoomSanction, RoomUnreadCount, RoomVisibility,
+            },
+        },
+        use_cases::{
+            notification_service::{MockNotificationService, NotificationServiceError},
+            realtime_broker::MockMessagePublisher,
+            room_database::{MockRoomDatabase, RoomDatabaseError},
+            room_service::{
+                RoomError, ban_member, change_member_role, create_room, delete_message,
+                delete_room, edit_message, get_all_public_rooms, get_user_rooms_use,
+                get_user_rooms_with_unread, join_room, kick_member, leave_room, mark_room_read,
+                mute_member, obtain_messages, obtain_room_members, replay_messages, send_message,
+                send_typing, transfer_ownership, unban_user, update_room, user_is_in_room,
+            },
+        },
+    };
+
+    #[tokio::test]
+    async fn test_user_is_in_room_true() {
+        let mut db = MockRoomDatabase::new();
+
+        let user_id = Uuid::new_v4();
+        let room_id = Uuid::new_v4();
+
+        db.expect_get_user_rooms().returning(move |_| {
+            Ok(vec![Room {
+                id: room_id,
+                name: "test".into(),
+                visibility: RoomVisibility::Public,
+                password_hash: None,
+                created_by: user_id,
+                created_at: Utc::now(),
+            }])
+        });
+
+        let result = user_is_in_room(Arc::new(db), user_id, room_id)
+            .await
+            .unwrap();
+        assert!(result);
+    }
+
+    #[tokio::test]
+    async fn test_user_is_in_room_false() {
+        let mut db = MockRoomDatabase::new();
+
+        let user_id = Uuid::new_v4();
+
+        db.expect_get_user_rooms().returning(|_| Ok(vec![]));
+
+        let result = user_is_in_room(Arc::new(db), user_id, Uuid::new_v4())
+            .await
+            .unwrap();
+
+        assert!(!result);
+    }
+
+    #[tokio::test]
+    async fn join_public_room_ok() {
+        let mut db = MockRoomDatabase::new();
+        let mut notif = MockNotificationService::new();
+
+        let room_id = Uuid::new_v4();
+        let user_id = Uuid::new_v4();
+
+        db.expect_get_room().returning(move |_| {
+            Ok(Room {
+                id: room_id,
+                name: "Public".into(),
+                visibility: RoomVisibility::Public,
+                password_hash: None,
+                created_by: user_id,
+                created_at: Utc::now(),
+            })
+        });
+        db.expect_get_active_ban().returning(|_, _| Ok(None));
+
+        db.expect_create_room_membership().returning(|_| Ok(()));
+
+        notif
+            .expect_send_room_member_notification()
+            .returning(|_| Ok(()));
+
+        let mut publisher = MockMessagePublisher::new();
+        publisher
+            .expect_publish()
+            .withf(move |event| {
+                matches!(event, RoomEvent::Membership(change) if change.room_id == room_id
+                    && change.user_id == user_id
+                    && change.action == MembershipAction::Joined)
+            })
+            .once()
+            .returning(|_| Ok(()));
+
+        let res = join_room(
+            Arc::new(db),
+            room_id,
+            user_id,
+            None,
+            Arc::new(notif),
+            Arc::new(publisher),
+        )
+        .await;
+
+        assert!(res.is_ok());
+    }
+
+    #[tokio::test]
+    async fn join_unknown_room_fails() {
+        let mut db = MockRoomDatabase::new();
+
+        db.expect_get_room()
+            .returning(|_| Err(RoomDatabaseError::NotFound));
+        db.expect_create_room_membership().never();
+
+        let res = join_room(
+            Arc::new(db),
+            Uuid::new_v4(),
+            Uuid::new_v4(),
+            None,
+            Arc::new(MockNotificationService::new()),
+            Arc::new(MockMessagePublisher::new()),
+        )
+        .await;
+
+        assert!(matches!(res, Err(RoomError::RoomNotFound)));
+    }
+
+    #[tokio::test]
+    async fn joining_twice_fails() {
+        let mut db = MockRoomDatabase::new();
+
+        let room_id = Uuid::new_v4();
+        let user_id = Uuid::new_v4();
+
+        db.expect_get_room().returning(move |_| {
+            Ok(Room {
+                id: room_id,
+                name: "Public".into(),
+                visibility: RoomVisibility::Public,
+                password_hash: None,
+                created_by: user_id,
+                created_at: Utc::now(),
+            })
+        });
+        db.expect_get_active_ban().returning(|_, _| Ok(None));
+        db.expect_create_room_membership().returning(|_| {
+            Err(RoomDatabaseError::UniqueViolation(
+                "room_members_pkey".into(),
+            ))
+        });
+
+        let res = join_room(
+            Arc::new(db),
+            room_id,
+            Uuid::new_v4(),
+            None,
+            Arc::new(MockNotificationService::new()),
+            Arc::new(MockMessagePublisher::new()),
+        )
+        .await;
+
+        assert!(matches!(res, Err(RoomError::AlreadyRoomMember)));
+    }
+
+    #[tokio::test]
+    async fn join_private_room_without_password_fails() {
+        let mut db = MockRoomDatabase::new();
+        let notif = MockNotificationService::new();
+
+        let room_id = Uuid::new_v4();
+        let user_id = Uuid::new_v4();
+
+        db.expect_get_room().returning(move |_| {
+            Ok(Room {
+                id: room_id,
+                name: "Private".into(),
+                visibility: RoomVisibility::Private,
+                password_hash: Some("$2b$12$somehashhere".into()),
+                created_by: user_id,
+                created_at: Utc::now(),
+            })
+        });
+        db.expect_get_active_ban().returning(|_, _| Ok(None));
+
+        let res = join_room(
+            Arc::new(db),
+            room_id,
+            user_id,
+            None,
+            Arc::new(notif),
+            Arc::new(MockMessagePublisher::new()),
+        )
+        .await;
+
+        assert!(matches!(res, Err(RoomError::PasswordNotGiven)));
+    }
+
+    #[tokio::test]
+    async fn join_private_room_wrong_password_fails() {
+        let mut db = MockRoomDatabase::new();
+        let notif = MockNotificationService::new();
+
+        let room_id = Uuid::new_v4();
+        let user_id = Uuid::new_v4();
+
+        // Hash of "correct123"
+        let valid_hash = bcrypt::hash("correct123", bcrypt::DEFAULT_COST).unwrap();
+
+        db.expect_get_room().returning(move |_| {
+            Ok(Room {
+                id: room_id,
+                name: "Private".into(),
+                visibility: RoomVisibility::Private,
+                password_hash: Some(valid_hash.clone()),
+                created_by: user_id,
+                created_at: Utc::now(),
+            })
+        });
+        db.expect_get_active_ban().returning(|_, _| Ok(None));
+
+        let res = join_room(
+            Arc::new(db),
+            room_id,
+            user_id,
+            Some("wrongpass".into()),
+            Arc::new(notif),
+            Arc::new(MockMessagePublisher::new()),
+        )
+        .await;
+
+        assert!(matches!(res, Err(RoomError::InvalidRoomPassword)));
+    }
+
+    #[tokio::test]
+    async fn join_private_room_correct_password_ok() {
+        let mut db = MockRoomDatabase::new();
+        let mut notif = MockNotificationService::new();
+
+        let room_id = Uuid::new_v4();
+        let user_id = Uuid::new_v4();
+        let hash = bcrypt::hash("mypassword", bcrypt::DEFAULT_COST).unwrap();
+
+        db.expect_get_room().returning(move |_| {
+            Ok(Room {
+                id: room_id,
+                name: "Private".into(),
+                visibility: RoomVisibility::Private,
+                password_hash: Some(hash.clone()),
+                created_by: user_id,
+                created_at: Utc::now(),
+            })
+        });
+        db.expect_get_active_ban().returning(|_, _| Ok(None));
+
+        db.expect_create_room_membership().returning(|_| Ok(()));
+
+        notif
+            .expect_send_room_member_notification()
+            .returning(|_| Ok(()));
+
+        let mut publisher = MockMessagePublisher::new();
+        publisher
+            .expect_publish()
+            .withf(|event| matches!(event, RoomEvent::Membership(_)))
+            .returning(|_| Ok(()));
+
+        let res = join_room(
+            Arc::new(db),
+            room_id,
+            user_id,
+            Some("mypassword".into()),
+            Arc::new(notif),
+            Arc::new(publisher),
+        )
+        .await;
+
+        assert!(res.is_ok());
+    }
+
+    #[tokio::test]
+    async fn leave_room_ok() {
+        let mut db = MockRoomDatabase::new();
+        let mut notif = MockNotificationService::new();
+
+        let room_id = Uuid::new_v4();
+        let user_id = Uuid::new_v4();
+
+        db.expect_get_member_role()
+            .returning(|_, _| Ok(Some(MemberRole::Member)));
+        db.expect_delete_room_membership().returning(|_, _| Ok(()));
+
+        notif
+            .expect_send_room_member_notification()
+            .returning(|_| Ok(()));
+
+        let mut publisher = MockMessagePublisher::new();
+        publisher
+            .expect_publish()
+            .withf(|event| {
+                matches!(event, RoomEvent::Membership(change) if change.action == MembershipAction::Left)
+            })
+            .once()
+            .returning(|_| Ok(()));
+
+        let res = leave_room(
+            Arc::new(db),
+            Arc::new(notif),
+            Arc::new(publisher),
+            room_id,
+            user_id,
+        )
+        .await;
+
+        assert!(res.is_ok());
+    }
+
+    #[tokio::test]
+    async fn leave_room_delete_fails() {
+        let mut db = MockRoomDatabase::new();
+        let notif = MockNotificationService::new();
+
+        let room_id = Uuid::new_v4();
+        let user_id = Uuid::new_v4();
+
+        db.expect_get_member_role()
+            .returning(|_, _| Ok(Some(MemberRole::Member)));
+        db.expect_delete_room_membership()
+            .returning(|_, _| Err(RoomDatabaseError::InternalDBError("db error".into())));
+
+        let res = leave_room(
+            Arc::new(db),
+            Arc::new(notif),
+            Arc::new(MockMessagePublisher::new()),
+            room_id,
+            user_id,
+        )
+        .await;
+
+        assert!(matches!(res, Err(RoomError::DatabaseError(_))));
+    }
+
+    #[tokio::test]
+    async fn leave_room_notification_fails() {
+        let mut db = MockRoomDatabase::new();
+        let mut notif = MockNotificationService::new();
+
+        let room_id = Uuid::new_v4();
+        let user_id = Uuid::new_v4();
+
+        db.expect_get_member_role()
+            .returning(|_, _| Ok(Some(MemberRole::Member)));
+        db.expect_delete_room_membership().returning(|_, _| Ok(()));
+
+        notif.expect_send_room_member_notification().returning(|_| {
+            Err(NotificationServiceError::MessageProcessingError(
+                "notif error".into(),
+            ))
+        });
+
+        let res = leave_room(
+            Arc::new(db),
+            Arc::new(notif),
+            Arc::new(MockMessagePublisher::new()),
+            room_id,
+            user_id,
+        )
+        .await;
+
+        assert!(matches!(res, Err(RoomError::NotificationError(_))));
+    }
+
+    #[tokio::test]
+    async fn owner_leaving_hands_the_room_to_the_oldest_member() {
+        let mut notif = MockNotificationService::new();
+        let mut publisher = MockMessagePublisher::new();
+
+        let room_id = Uuid::new_v4();
+        let user_id = Uuid::new_v4();
+        let heir_id = Uuid::new_v4();
+
+        let mut db = db_with_roles(user_id, MemberRole::Owner, MemberRole::Member);
+        db.expect_leave_owned_room()
+            .once()
+            .returning(move |_, _| Ok(Some(heir_id)));
+        db.expect_delete_room_membership().never();
+
+        notif
+            .expect_send_room_member_notification()
+            .returning(|_| Ok(()));
+
+        publisher
+            .expect_publish()
+            .withf(move |event| {
+                matches!(event, RoomEvent::RoleChanged(change)
+                    if change.user_id == heir_id
+                        && change.role == MemberRole::Owner
+                        && change.changed_by == user_id)
+            })
+            .once()
+            .returning(|_| Ok(()));
+        publisher
+            .expect_publish()
+            .withf(move |event| {
+                matches!(event, RoomEvent::Membership(change)
+                    if change.user_id == user_id && change.action == MembershipAction::Left)
+            })
+            .once()
+            .returning(|_| Ok(()));
+
+        let res = leave_room(
+            Arc::new(db),
+            Arc::new(notif),
+            Arc::new(publisher),
+            room_id,
+            user_id,
+        )
+        .await;
+
+        assert!(res.is_ok());
+    }
+
+    #[tokio::test]
+    async fn last_owner_leaving_archives_the_room() {
+        let mut notif = MockNotificationService::new();
+        let mut publisher = MockMessagePublisher::new();
+
+        let room_id = Uuid::new_v4();
+        let user_id = Uuid::new_v4();
+
+        let mut db = db_with_roles(user_id, MemberRole::Owner, MemberRole::Member);
+        db.expect_leave_owned_room().returning(|_, _| Ok(None));
+
+        notif
+            .expect_send_room_member_notification()
+            .returning(|_| Ok(()));
+
+        publisher
+            .expect_publish()
+            .withf(move |event| {
+                matches!(event, RoomEvent::Membership(change)
+                    if change.room_id == room_id
+                        && change.user_id == user_id
+                        && change.action == MembershipAction::Left)
+            })
+            .once()
+            .returning(|_| Ok(()));
+
+        let res = leave_room(
+            Arc::new(db),
+            Arc::new(notif),
+            Arc::new(publisher),
+            room_id,
+            user_id,
+        )
+        .await;
+
+        assert!(res.is_ok());
+    }
+
+    #[tokio::test]
+    async fn update_room_by_owner_publishes_the_change() {
+        let mut db = MockRoomDatabase::new();
+        let mut publisher = MockMessagePublisher::new();
+
+        let room_id = Uuid::new_v4();
+
+        db.expect_get_member_role()
+            .returning(|_, _| Ok(Some(MemberRole::Owner)));
+        db.expect_get_room()
+            .returning(move |_| Ok(room_with_id(room_id)));
+        db.expect_update_room()
+            .withf(|room| {
+                room.name == "Renamed"
+                    && room.visibility == RoomVisibility::Private
+                    && room.password_hash.is_some()
+            })
+            .once()
+            .returning(Ok);
+
+        publisher
+            .expect_publish()
+            .withf(move |event| {
+                matches!(event, RoomEvent::RoomUpdated(room) if room.id == room_id && room.name == "Renamed")
+            })
+            .once()
+            .returning(|_| Ok(()));
+
+        let room = update_room(
+            Arc::new(db),
+            room_id,
+            Uuid::new_v4(),
+            Some("Renamed".into()),
+            Some(RoomVisibility::Private),
+            Some("1234".into()),
+            Arc::new(publisher),
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(room.name, "Renamed");
+    }
+
+    #[tokio::test]
+    async fn update_room_public_drops_the_password() {
+        let mut db = MockRoomDatabase::new();
+        let mut publisher = MockMessagePublisher::new();
+
+        let room_id = Uuid::new_v4();
+
+        db.expect_get_member_role()
+            .returning(|_, _| Ok(Some(MemberRole::Owner)));
+        db.expect_get_room().returning(move |_| {
+            Ok(Room {
+                visibility: RoomVisibility::Private,
+                password_hash: Some("hash".into()),
+                ..room_with_id(room_id)
+            })
+        });
+        db.expect_update_room()
+            .withf(|room| room.visibility == RoomVisibility::Public && room.password_hash.is_none())
+            .once()
+            .returning(Ok);
+        publisher.expect_publish().returning(|_| Ok(()));
+
+        let result = update_room(
+            Arc::new(db),
+            room_id,
+            Uuid::new_v4(),
+            None,
+            Some(RoomVisibility::Public),
+            None,
+            Arc::new(publisher),
+        )
+        .await;
+
+        assert!(result.is_ok());
+    }
+
+    #[tokio::test]
+    async fn update_room_private_without_password_fails() {
+        let mut db = MockRoomDatabase::new();
+
+        let room_id = Uuid::new_v4();
+
+        db.expect_get_member_role()
+            .returning(|_, _| Ok(Some(MemberRole::Owner)));
+        db.expect_get_room()
+            .returning(move |_| Ok(room_with_id(room_id)));
+
+        let result = update_room(
+            Arc::new(db),
+            room_id,
+            Uuid::new_v4(),
+            None,
+            Some(RoomVisibility::Private),
+            None,
+            Arc::new(MockMessagePublisher::new()),
+        )
+        .await;
+
+        assert!(matches!(result, Err(RoomError::PasswordNotGiven)));
+    }
+
+    #[tokio::test]
+    async fn update_room_by_member_fails() {
+        let mut db = MockRoomDatabase::new();
+
+        db.expect_get_member_role()
+            .returning(|_, _| Ok(Some(MemberRole::Member)));
+
+        let result = update_room(
+            Arc::new(db),
+            Uuid::new_v4(),
+            Uuid::new_v4(),
+            Some("Mine now".into()),
+            None,
+            None,
+            Arc::new(MockMessagePublisher::new()),
+        )
+        .await;
+
+        assert!(matches!(result, Err(RoomError::InsufficientRole)));
+    }
+
+    #[tokio::test]
+    async fn delete_room_by_owner_publishes_the_deletion() {
+        let mut db = MockRoomDatabase::new();
+        let mut publisher = MockMessagePublisher::new();
+
+        let room_id = Uuid::new_v4();
+        let user_id = Uuid::new_v4();
+
+        db.expect_get_member_role()
+            .returning(|_, _| Ok(Some(MemberRole::Owner)));
+        db.expect_delete_room().once().returning(|_| Ok(()));
+
+        publisher
+            .expect_publish()
+            .withf(move |event| {
+                matches!(event, RoomEvent::RoomDeleted(deletion)
+                    if deletion.room_id == room_id && deletion.deleted_by == user_id)
+            })
+            .once()
+            .returning(|_| Ok(()));
+
+        let result = delete_room(Arc::new(db), room_id, user_id, Arc::new(publisher)).await;
+
+        assert!(result.is_ok());
+    }
+
+    #[tokio::test]
+    async fn delete_room_by_member_fails() {
+        let mut db = MockRoomDatabase::new();
+
+        db.expect_get_member_role()
+            .returning(|_, _| Ok(Some(MemberRole::Member)));
+
+        let result = delete_room(
+            Arc::new(db),
+            Uuid::new_v4(),
+            Uuid::new_v4(),
+            Arc::new(MockMessagePublisher::new()),
+        )
+        .await;
+
+        assert!(matches!(result, Err(RoomError::InsufficientRole)));
+    }
+
+    /// Database where the user has the first role and the member the second one
+    fn db_with_roles(
+        user_id: Uuid,
+        user_role: MemberRole,
+        member_role: MemberRole,
+    ) -> MockRoomDatabase {
+        let mut db = MockRoomDatabase::new();
+
+        db.expect_get_member_role().returning(move |_, id| {
+            Ok(Some(if id == user_id {
+                user_role
+            } else {
+                member_role
+            }))
+        });
+
+        db
+    }
+
+    #[tokio::test]
+    async fn change_member_role_by_owner_publishes_the_change() {
+        let mut publisher = MockMessagePublisher::new();
+
+        let room_id = Uuid::new_v4();
+        let user_id = Uuid::new_v4();
+        let member_id = Uuid::new_v4();
+
+        let mut db = db_with_roles(user_id, MemberRole::Owner, MemberRole::Member);
+        db.expect_update_member_role()
+            .withf(move |_, id, role| *id == member_id && *role == MemberRole::Admin)
+            .once()
+            .returning(|room_id, user_id, role| {
+                Ok(RoomMember {
+                    room_id,
+                    user_id,
+                    role,
+                    joined_at: Utc::now(),
+                })
+            });
+
+        publisher
+            .expect_publish()
+            .withf(move |event| {
+                matches!(event, RoomEvent::RoleChanged(change)
+                    if change.user_id == member_id
+                        && change.role == MemberRole::Admin
+                        && change.changed_by == user_id)
+            })
+            .once()
+            .returning(|_| Ok(()));
+
+        let member = change_member_role(
+            Arc::new(db),
+            room_id,
+            user_id,
+            member_id,
+            MemberRole::Admin,
+            Arc::new(publisher),
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(member.role, MemberRole::Admin);
+    }
+
+    #[tokio::test]
+    async fn admins_cannot_promote_to_their_own_role() {
+        let user_id = Uuid::new_v4();
+        let db = db_with_roles(user_id, MemberRole::Admin, MemberRole::Moderator);
+
+        let result = change_member_role(
+            Arc::new(db),
+            Uuid::new_v4(),
+            user_id,
+            Uuid::new_v4(),
+            MemberRole::Admin,
+            Arc::new(MockMessagePublisher::new()),
+        )
+        .await;
+
+        assert!(matches!(result, Err(RoomError::InsufficientRole)));
+    }
+
+    #[tokio::test]
+    async fn admins_cannot_demote_other_admins() {
+        let user_id = Uuid::new_v4();
+        let db = db_with_roles(user_id, MemberRole::Admin, MemberRole::Admin);
+
+        let result = change_member_role(
+            Arc::new(db),
+            Uuid::new_v4(),
+            user_id,
+            Uuid::new_v4(),
+            MemberRole::Member,
+            Arc::new(MockMessagePublisher::new()),
+        )
+        .await;
+
+        assert!(matches!(result, Err(RoomError::InsufficientRole)));
+    }
+
+    #[tokio::test]
+    async fn moderators_cannot_change_roles() {
+        let user_id = Uuid::new_v4();
+        let db = db_with_roles(user_id, MemberRole::Moderator, MemberRole::Member);
+
+        let result = change_member_role(
+            Arc::new(db),
+            Uuid::new_v4(),
+            user_id,
+            Uuid::new_v4(),
+            MemberRole::Moderator,
+            Arc::new(MockMessagePublisher::new()),
+        )
+        .await;
+
+        assert!(matches!(result, Err(RoomError::InsufficientRole)));
+    }
+
+    #[tokio::test]
+    async fn change_member_role_of_non_member_fails() {
+        let mut db = MockRoomDatabase::new();
+        let user_id = Uuid::new_v4();
+
+        db.expect_get_member_role()
+            .returning(move |_, id| Ok((id == user_id).then_some(MemberRole::Owner)));
+
+        let result = change_member_role(
+            Arc::new(db),
+            Uuid::new_v4(),
+            user_id,
+            Uuid::new_v4(),
+            MemberRole::Moderator,
+            Arc::new(MockMessagePublisher::new()),
+        )
+        .await;
+
+        assert!(matches!(result, Err(RoomError::MemberNotFound)));
+    }
+
+    #[tokio::test]
+    async fn transfer_ownership_makes_the_previous_owner_an_admin() {
+        let mut publisher = MockMessagePublisher::new();
+
+        let room_id = Uuid::new_v4();
+        let user_id = Uuid::new_v4();
+        let member_id = Uuid::new_v4();
+
+        let mut db = db_with_roles(user_id, MemberRole::Owner, MemberRole::Moderator);
+        db.expect_transfer_ownership()
+            .withf(move |_, owner, new_owner| *owner == user_id && *new_owner == member_id)
+            .once()
+            .returning(|room_id, _, user_id| {
+                Ok(Some(RoomMember {
+                    room_id,
+                    user_id,
+                    role: MemberRole::Owner,
+                    joined_at: Utc::now(),
+                }))
+            });
+
+        publisher
+            .expect_publish()
+            .withf(move |event| {
+                matches!(event, RoomEvent::RoleChanged(change)
+                    if change.user_id == member_id && change.role == MemberRole::Owner)
+            })
+            .once()
+            .returning(|_| Ok(()));
+        publisher
+            .expect_publish()
+            .withf(move |event| {
+                matches!(event, RoomEvent::RoleChanged(change)
+                    if change.user_id == user_id && change.role == MemberRole::Admin)
+            })
+            .once()
+            .returning(|_| Ok(()));
+
+        let owner = transfer_ownership(
+            Arc::new(db),
+            room_id,
+            user_id,
+            member_id,
+            Arc::new(publisher),
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(owner.user_id, member_id);
+        assert_eq!(owner.role, MemberRole::Owner);
+    }
+
+    #[tokio::test]
+    async fn only_the_owner_can_transfer_the_room() {
+        let user_id = Uuid::new_v4();
+
+        let res = transfer_ownership(
+            Arc::new(db_with_roles(
+                user_id,
+                MemberRole::Admin,
+                MemberRole::Member,
+            )),
+            Uuid::new_v4(),
+            user_id,
+            Uuid::new_v4(),
+            Arc::new(MockMessagePublisher::new()),
+        )
+        .await;
+
+        assert!(matches!(res, Err(RoomError::InsufficientRole)));
+    }
+
+    #[tokio::test]
+    async fn transfer_ownership_fails_when_the_room_changed_hands_meanwhile() {
+        let user_id = Uuid::new_v4();
+
+        let mut db = db_with_roles(user_id, MemberRole::Owner, MemberRole::Member);
+        db.expect_transfer_ownership()
+            .once()
+            .returning(|_, _, _| Ok(None));
+
+        let res = transfer_ownership(
+            Arc::new(db),
+            Uuid::new_v4(),
+            user_id,
+            Uuid::new_v4(),
+            Arc::new(MockMessagePublisher::new()),
+        )
+        .await;
+
+        assert!(matches!(res, Err(RoomError::InsufficientRole)));
+    }
+
+    #[tokio::test]
+    async fn transfer_ownership_to_a_non_member_fails() {
+        let user_id = Uuid::new_v4();
+
+        let mut db = db_with_roles(user_id, MemberRole::Owner, MemberRole::Member);
+        db.expect_transfer_ownership()
+            .returning(|_, _, _| Err(RoomDatabaseError::NotFound));
+
+        let res = transfer_ownership(
+            Arc::new(db),
+            Uuid::new_v4(),
+            user_id,
+            Uuid::new_v4(),
+            Arc::new(MockMessagePublisher::new()),
+        )
+        .await;
+
+        assert!(matches!(res, Err(RoomError::MemberNotFound)));
+    }
+
+    #[tokio::test]
+    async fn kick_by_moderator_is_logged_and_published() {
+        let mut publisher = MockMessagePublisher::new();
+
+        let room_id = Uuid::new_v4();
+        let user_id = Uuid::new_v4();
+        let member_id = Uuid::new_v4();
+
+        let mut db = db_with_roles(user_id, MemberRole::Moderator, MemberRole::Member);
+        db.expect_moderate_member()
+            .withf(move |entry| {
+                entry.target_id == member_id
+                    && entry.moderator_id == Some(user_id)
+                    && entry.action == ModerationAction::Kick
+            })
+            .once()
+            .returning(|_| Ok(()));
+
+        publisher
+            .expect_publish()
+            .withf(move |event| {
+                matches!(event, RoomEvent::Moderation(entry)
+                    if entry.target_id == member_id && entry.removes_target())
+            })
+            .once()
+            .returning(|_| Ok(()));
+
+        let entry = kick_member(
+            Arc::new(db),
+            room_id,
+            user_id,
+            member_id,
+            Some("spam".into()),
+            Arc::new(publisher),
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(entry.reason.as_deref(), Some("spam"));
+    }
+
+    #[tokio::test]
+    async fn moderators_cannot_kick_admins() {
+        let user_id = Uuid::new_v4();
+        let db = db_with_roles(user_id, MemberRole::Moderator, MemberRole::Admin);
+
+        let result = kick_member(
+            Arc::new(db),
+            Uuid::new_v4(),
+            user_id,
+            Uuid::new_v4(),
+            None,
+            Arc::new(MockMessagePublisher::new()),
+        )
+        .await;
+
+        assert!(matches!(result, Err(RoomError::InsufficientRole)));
+    }
+
+    #[tokio::test]
+    async fn moderators_cannot_ban() {
+        let user_id = Uuid::new_v4();
+        let db = db_with_roles(user_id, MemberRole::Moderator, MemberRole::Member);
+
+        let result = ban_member(
+            Arc::new(db),
+            Uuid::new_v4(),
+            user_id,
+            Uuid::new_v4(),
+            None,
+            None,
+            Arc::new(MockMessagePublisher::new()),
+        )
+        .await;
+
+        assert!(matches!(result, Err(RoomError::InsufficientRole)));
+    }
+
+    #[tokio::test]
+    async fn users_can_be_banned_before_joining_but_not_kicked() {
+        let user_id = Uuid::new_v4();
+        let outsider_id = Uuid::new_v4();
+
         let mut db = MockRoomDatabase::new();
-        let notif = MockNotificationService::new();
+        db.expect_get_member_role()
+            .returning(move |_, id| Ok((id == user_id).then_some(MemberRole::Admin)));
+        db.expect_moderate_member()
+            .withf(move |entry| {
+                entry.target_id == outsider_id && entry.action == ModerationAction::Ban
+            })
+            .once()
+            .returning(|_| Ok(()));
+        let mut publisher = MockMessagePublisher::new();
+        publisher.expect_publish().once().returning(|_| Ok(()));
+        let (db, publisher) = (Arc::new(db), Arc::new(publisher));
+
+        let kicked = kick_member(
+            db.clone(),
+            Uuid::new_v4(),
+            user_id,
+            outsider_id,
+            None,
+            publisher.clone(),
+        )
+        .await;
+        assert!(matches!(kicked, Err(RoomError::MemberNotFound)));
+
+        ban_member(
+            db,
+            Uuid::new_v4(),
+            user_id,
+            outsider_id,
+            None,
+            None,
+            publisher,
+        )
+        .await
+        .unwrap();
+    }
+
+    #[tokio::test]
+    async fn mute_expires_when_asked() {
+        let mut publisher = MockMessagePublisher::new();
+
+        let user_id = Uuid::new_v4();
+        let until = Utc::now() + Duration::minutes(10);
+
+        let mut db = db_with_roles(user_id, MemberRole::Admin, MemberRole::Moderator);
+        db.expect_moderate_member()
+            .withf(move |entry| {
+                entry.action == ModerationAction::Mute && entry.expires_at == Some(until)
+            })
+            .once()
+            .returning(|_| Ok(()));
+        publisher.expect_publish().once().returning(|_| Ok(()));
+
+        let result = mute_member(
+            Arc::new(db),
+            Uuid::new_v4(),
+            user_id,
+            Uuid::new_v4(),
+            None,
+            until,
+            Arc::new(publisher),
+        )
+        .await;
+
+        assert!(result.is_ok());
+    }
+
+    #[tokio::test]
+    async fn unban_without_ban_fails() {
+        let mut db = MockRoomDatabase::new();
+
+        db.expect_get_member_role()
+            .returning(|_, _| Ok(Some(MemberRole::Owner)));
+        db.expect_moderate_member()
+            .returning(|_| Err(RoomDatabaseError::NotFound));
+
+        let result = unban_user(
+            Arc::new(db),
+            Uuid::new_v4(),
+            Uuid::new_v4(),
+            Uuid::new_v4(),
+            Arc::new(MockMessagePublisher::new()),
+        )
+        .await;
+
+        assert!(matches!(result, Err(RoomError::NotSanctioned)));
+    }
+
+    #[tokio::test]
+    async fn banned_users_cannot_join_with_the_password() {
+        let mut db = MockRoomDatabase::new();
+
+        let room_id = Uuid::new_v4();
+        let until = Utc::now() + Duration::hours(1);
+
+        db.expect_get_room().returning(move |_| {
+            Ok(Room {
+                visibility: RoomVisibility::Private,
+                password_hash: Some(bcrypt::hash("secret", 4).unwrap()),
+                ..room_with_id(room_id)
+            })
+        });
+        db.expect_get_active_ban()
+            .returning(move |room_id, user_id| {
+                Ok(Some(RoomSanction {
+                    room_id,
+                    user_id,
+                    expires_at: Some(until),
+                }))
+            });
+
+        let result = join_room(
+            Arc::new(db),
+            room_id,
+            Uuid::new_v4(),
+            Some("secret".into()),
+            Arc::new(MockNotificationService::new()),
+            Arc::new(MockMessagePublisher::new()),
+        )
+        .await;
+
+        assert!(matches!(result, Err(RoomError::Banned(Some(end))) if end == until));
+    }
+
+    #[tokio::test]
+    async fn muted_members_cannot_post() {
+        let mut db = MockRoomDatabase::new();
+
+        db.expect_get_member_role()
+            .returning(|_, _| Ok(Some(MemberRole::Member)));
+        db.expect_get_active_mute().returning(|room_id, user_id| {
+            Ok(Some(RoomSanction {
+                room_id,
+                user_id,
+                expires_at: Some(Utc::now() + Duration::minutes(5)),
+            }))
+        });
+
+        let result = send_message(
+            Arc::new(db),
+            Uuid::new_v4(),
+            Uuid::new_v4(),
+            "hello?".into(),
+            Arc::new(MockMessagePublisher::new()),
+        )
+        .await;
+
+        assert!(matches!(result, Err(RoomError::Muted(_))));
+    }
+
+    #[tokio::test]
+    async fn muted_members_cannot_edit_or_type() {
+        let mut db = MockRoomDatabase::new();
+        let user_id = Uuid::new_v4();
+
+        db.expect_get_member_role()
+            .returning(|_, _| Ok(Some(MemberRole::Member)));
+        db.expect_get_active_mute().returning(|room_id, user_id| {
+            Ok(Some(RoomSanction {
+                room_id,
+                user_id,
+                expires_at: None,
+            }))
+        });
+        db.expect_get_message().never();
+        db.expect_edit_message().never();
+        let db = Arc::new(db);
+
+        let edited = edit_message(
+            db.clone(),
+            Uuid::new_v4(),
+            Uuid::new_v4(),
+            user_id,
+            "edited".into(),
+            Arc::new(MockMessagePublisher::new()),
+        )
+        .await;
+        let typing = send_typing(
+            db,
+            Uuid::new_v4(),
+            user_id,
+            true,
+            Arc::new(MockMessagePublisher::new()),
+        )
+        .await;
+
+        assert!(matches!(edited, Err(RoomError::Muted(None))));
+        assert!(matches!(typing, Err(RoomError::Muted(None))));
+    }
+
+    #[tokio::test]
+    async fn banned_or_kicked_users_cannot_edit_or_type() {
+        let mut db = MockRoomDatabase::new();
+        let user_id = Uuid::new_v4();
+
+        // Banning or kicking a member drops its membership
+        db.expect_get_member_role().returning(|_, _| Ok(None));
+        db.expect_get_room().returning(|id| Ok(room_with_id(id)));
+        db.expect_get_message().never();
+        db.expect_edit_message().never();
+        let db = Arc::new(db);
+
+        let edited = edit_message(
+            db.clone(),
+            Uuid::new_v4(),
+            Uuid::new_v4(),
+            user_id,
+            "edited".into(),
+            Arc::new(MockMessagePublisher::new()),
+        )
+        .await;
+        let typing = send_typing(
+            db,
+            Uuid::new_v4(),
+            user_id,
+            false,
+            Arc::new(MockMessagePublisher::new()),
+        )
+        .await;
+
+        assert!(matches!(edited, Err(RoomError::NotRoomMember)));
+        assert!(matches!(typing, Err(RoomError::NotRoomMember)));
+    }
+
+    #[tokio::test]
+    async fn test_create_room_private_with_password() {
+        let mut db = MockRoomDatabase::new();
+        let user_id = Uuid::new_v4();
+
+        db.expect_create_room().returning(|_| Ok(()));
+
+        db.expect_create_room_membership().returning(|_| Ok(()));
+
+        let mut publisher = MockMessagePublisher::new();
+        publisher
+            .expect_publish()
+            .withf(move |event| {
+                matches!(event, RoomEvent::Membership(change)
+                    if change.user_id == user_id && change.action == MembershipAction::Joined)
+            })
+            .once()
+            .returning(|_| Ok(()));
+
+        let res = create_room(
+            Arc::new(db),
+            RoomVisibility::Private,
+            Some("1234".into()),
+            "My Room".into(),
+            user_id,
+            Arc::new(publisher),
+        )
+        .await;
+
+        assert!(res.is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_create_room_private_missing_password() {
+        let db = MockRoomDatabase::new();
 
-        let room_id = Uuid::new_v4();
         let user_id = Uuid::new_v4();
 
-        // Hash of "correct123"
-        let valid_hash = bcrypt::hash("correct123", bcrypt::DEFAULT_COST).unwrap();
+        let res = create_room(
+            Arc::new(db),
+            RoomVisibility::Private,
+            None,
+            "My Room".into(),
+            user_id,
+            Arc::new(MockMessagePublisher::new()),
+        )
+        .await;
 
-        db.expect_get_room().returning(move |_| {
-            Ok(Room {
-                id: room_id,
-                name: "Private".into(),
-                visibility: RoomVisibility::Private,
-                password_hash: Some(valid_hash.clone()),
+        assert!(matches!(res, Err(RoomError::PasswordNotGiven)));
+    }
+
+    #[tokio::test]
+    async fn test_get_user_rooms() {
+        let mut db = MockRoomDatabase::new();
+        let user_id = Uuid::new_v4();
+
+        db.expect_get_user_rooms().returning(move |_| {
+            Ok(vec![Room {
+                id: Uuid::new_v4(),
+                name: "Test".into(),
+                visibility: RoomVisibility::Public,
+                password_hash: None,
                 created_by: user_id,
                 created_at: Utc::now(),
-            })
+            }])
         });
 
-        let res = join_room(
+        let res = get_user_rooms_use(Arc::new(db), user_id).await.unwrap();
+
+        assert_eq!(res.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_get_all_public_rooms() {
+        let mut db = MockRoomDatabase::new();
+
+        db.expect_get_public_room_summaries().returning(|_| {
+            let mut summary = summary_with_id(Uuid::new_v4());
+            summary.is_member = false;
+            Ok(vec![summary])
+        });
+
+        let rooms = get_all_public_rooms(Arc::new(db), Uuid::new_v4())
+            .await
+            .unwrap();
+
+        assert_eq!(rooms.len(), 1);
+        assert!(!rooms[0].is_member);
+    }
+
+    #[tokio::test]
+    async fn test_obtain_messages_latest_page() {
+        let mut db = MockRoomDatabase::new();
+        db.expect_get_member_role()
+            .returning(|_, _| Ok(Some(MemberRole::Member)));
+        let room_id = Uuid::new_v4();
+
+        db.expect_get_room_messages_before()
+            .withf(|_, cursor, limit| cursor.is_none() && *limit == 11)
+            .returning(move |_, _, limit| Ok(messages_in_room(limit as usize, room_id)));
+
+        let page = obtain_messages(
             Arc::new(db),
             room_id,
-            user_id,
-            Some("wrongpass".into()),
-            Arc::new(notif),
+            Uuid::new_v4(),
+            MessageAnchor::Latest,
+            10,
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(page.messages.len(), 10);
+        assert_eq!(page.next_cursor, Some(page.messages[9].id));
+        assert_eq!(page.prev_cursor, None);
+    }
+
+    #[tokio::test]
+    async fn test_obtain_messages_zero_page_size() {
+        let mut db = MockRoomDatabase::new();
+        db.expect_get_member_role()
+            .returning(|_, _| Ok(Some(MemberRole::Member)));
+        let room_id = Uuid::new_v4();
+
+        db.expect_get_room_messages_before()
+            .withf(|_, _, limit| *limit == 2)
+            .returning(move |_, _, _| Ok(messages_in_room(1, room_id)));
+
+        let page = obtain_messages(
+            Arc::new(db),
+            room_id,
+            Uuid::new_v4(),
+            MessageAnchor::Latest,
+            0,
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(page.messages.len(), 1);
+        assert_eq!(page.next_cursor, None);
+    }
+
+    #[tokio::test]
+    async fn test_obtain_messages_after_drops_the_newest_extra() {
+        let mut db = MockRoomDatabase::new();
+        db.expect_get_member_role()
+            .returning(|_, _| Ok(Some(MemberRole::Member)));
+        let room_id = Uuid::new_v4();
+        let cursor = Uuid::new_v4();
+        let newer = messages_in_room(4, room_id);
+        let returned = newer.clone();
+
+        db.expect_get_message()
+            .returning(move |id| Ok(message_in_room(id, room_id)));
+        db.expect_get_room_messages_after()
+            .withf(move |_, after, limit| *after == cursor && *limit == 4)
+            .returning(move |_, _, _| Ok(returned.clone()));
+
+        let page = obtain_messages(
+            Arc::new(db),
+            room_id,
+            Uuid::new_v4(),
+            MessageAnchor::After(cursor),
+            3,
+        )
+        .await
+        .unwrap();
+
+        let ids: Vec<Uuid> = page.messages.iter().map(|m| m.id).collect();
+        assert_eq!(ids, vec![newer[1].id, newer[2].id, newer[3].id]);
+        assert_eq!(page.prev_cursor, Some(newer[1].id));
+        assert_eq!(page.next_cursor, Some(newer[3].id));
+    }
+
+    #[tokio::test]
+    async fn test_obtain_messages_around_includes_the_anchor() {
+        let mut db = MockRoomDatabase::new();
+        db.expect_get_member_role()
+            .returning(|_, _| Ok(Some(MemberRole::Member)));
+        let room_id = Uuid::new_v4();
+        let cursor = Uuid::new_v4();
+
+        db.expect_get_message()
+            .returning(move |id| Ok(message_in_room(id, room_id)));
+        db.expect_get_room_messages_after()
+            .withf(|_, _, limit| *limit == 3)
+            .returning(move |_, _, _| Ok(messages_in_room(1, room_id)));
+        db.expect_get_room_messages_before()
+            .withf(move |_, before, limit| *before == Some(cursor) && *limit == 3)
+            .returning(move |_, _, _| Ok(messages_in_room(3, room_id)));
+
+        let page = obtain_messages(
+            Arc::new(db),
+            room_id,
+            Uuid::new_v4(),
+            MessageAnchor::Around(cursor),
+            5,
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(page.messages.len(), 4);
+        assert_eq!(page.messages[1].id, cursor);
+        assert_eq!(page.prev_cursor, None);
+        assert_eq!(page.next_cursor, Some(page.messages[3].id));
+    }
+
+    #[tokio::test]
+    async fn test_obtain_messages_cursor_from_other_room() {
+        let mut db = MockRoomDatabase::new();
+        db.expect_get_member_role()
+            .returning(|_, _| Ok(Some(MemberRole::Member)));
+
+        db.expect_get_message()
+            .returning(|id| Ok(message_in_room(id, Uuid::new_v4())));
+
+        let result = obtain_messages(
+            Arc::new(db),
+            Uuid::new_v4(),
+            Uuid::new_v4(),
+            MessageAnchor::Before(Uuid::new_v4()),
+            10,
         )
         .await;
 
-        assert!(matches!(res, Err(RoomError::InvalidRoomPassword)));
+        assert!(matches!(result, Err(RoomError::MessageNotInRoom)));
     }
 
     #[tokio::test]
-    async fn join_private_room_correct_password_ok() {
+    async fn test_obtain_messages_not_member() {
         let mut db = MockRoomDatabase::new();
-        let mut notif = MockNotificationService::new();
 
-        let room_id = Uuid::new_v4();
-        let user_id = Uuid::new_v4();
-        let hash = bcrypt::hash("mypassword", bcrypt::DEFAULT_COST).unwrap();
+        db.expect_get_member_role().returning(|_, _| Ok(None));
+        db.expect_get_room().returning(|id| Ok(room_with_id(id)));
+        db.expect_get_room_messages_before().never();
+
+        let result = obtain_messages(
+            Arc::new(db),
+            Uuid::new_v4(),
+            Uuid::new_v4(),
+            MessageAnchor::Latest,
+            10,
+        )
+        .await;
+
+        assert!(matches!(result, Err(RoomError::NotRoomMember)));
+    }
+
+    #[tokio::test]
+    async fn test_obtain_room_members_of_unknown_room() {
+        let mut db = MockRoomDatabase::new();
+
+        db.expect_get_member_role().returning(|_, _| Ok(None));
+        db.expect_get_room()
+            .returning(|_| Err(RoomDatabaseError::NotFound));
+        db.expect_get_room_members().never();
+
+        let result = obtain_room_members(Arc::new(db), Uuid::new_v4(), Uuid::new_v4()).await;
+
+        assert!(matches!(result, Err(RoomError::RoomNotFound)));
+    }
+
+    #[tokio::test]
+    async fn test_send_message_not_member() {
+        let mut db = MockRoomDatabase::new();
+
+        db.expect_get_member_role().returning(|_, _| Ok(None));
+        db.expect_get_room().returning(|id| Ok(room_with_id(id)));
+        db.expect_create_message().never();
+
+        let result = send_message(
+            Arc::new(db),
+            Uuid::new_v4(),
+            Uuid::new_v4(),
+            "hi".into(),
+            Arc::new(MockMessagePublisher::new()),
+        )
+        .await;
+
+        assert!(matches!(result, Err(RoomError::NotRoomMember)));
+    }
+
+    #[tokio::test]
+    async fn test_replay_messages() {
+        let mut db = MockRoomDatabase::new();
+        let room_id = Uuid::new_v4();
+        let last_message_id = Uuid::new_v4();
+
+        db.expect_get_message()
+            .returning(move |id| Ok(message_in_room(id, room_id)));
+        db.expect_get_messages_since()
+            .withf(move |room_ids, cursor, limit| {
+                room_ids == &vec![room_id] && *cursor == last_message_id && *limit == 11
+            })
+            .returning(move |_, _, _| Ok(messages_in_room(3, room_id)));
+
+        let replay = replay_messages(Arc::new(db), vec![room_id], last_message_id, 10)
+            .await
+            .unwrap();
+
+        assert_eq!(replay.map(|messages| messages.len()), Some(3));
+    }
+
+    #[tokio::test]
+    async fn test_replay_messages_too_many_missed() {
+        let mut db = MockRoomDatabase::new();
+        let room_id = Uuid::new_v4();
+
+        db.expect_get_message()
+            .returning(move |id| Ok(message_in_room(id, room_id)));
+        db.expect_get_messages_since()
+            .returning(move |_, _, limit| Ok(messages_in_room(limit as usize, room_id)));
+
+        let replay = replay_messages(Arc::new(db), vec![room_id], Uuid::new_v4(), 10)
+            .await
+            .unwrap();
+
+        assert!(replay.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_replay_messages_cursor_from_other_room() {
+        let mut db = MockRoomDatabase::new();
+
+        db.expect_get_message()
+            .returning(|id| Ok(message_in_room(id, Uuid::new_v4())));
+
+        let result = replay_messages(Arc::new(db), vec![Uuid::new_v4()], Uuid::new_v4(), 10).await;
+
+        assert!(matches!(result, Err(RoomError::MessageNotInRoom)));
+    }
+
+    #[tokio::test]
+    async fn test_obtain_room_members() {
+        let mut db = MockRoomDatabase::new();
+        db.expect_get_member_role()
+            .returning(|_, _| Ok(Some(MemberRole::Member)));
+        let room_id = Uuid::new_v4();
+
+        db.expect_get_room_members().returning(|_| {
+            Ok(vec![MemberView {
+                user_id: Uuid::new_v4(),
+                username: "john".into(),
+                role: MemberRole::Owner,
+                joined_at: Utc::now(),
+            }])
+        });
+
+        let members = obtain_room_members(Arc::new(db), room_id, Uuid::new_v4())
+            .await
+            .unwrap();
+
+        assert_eq!(members.len(), 1);
+        assert_eq!(members[0].role, MemberRole::Owner);
+    }
+
+    #[tokio::test]
+    async fn test_send_message_success() {
+        let mut db = MockRoomDatabase::new();
+        db.expect_get_member_role()
+            .returning(|_, _| Ok(Some(MemberRole::Member)));
+        db.expect_get_active_mute().returning(|_, _| Ok(None));
+        let mut publisher = MockMessagePublisher::new();
+
+        let room_id = Uuid::new_v4();
+        let user_id = Uuid::new_v4();
+        let content = "Hello World!".to_string();
+
+        // Expect DB create_message to succeed
+        db.expect_create_message()
+            .returning(|message| Ok(view_of(message)));
+
+        // Expect the message to be published, with the sender's username attached
+        publisher
+            .expect_publish()
+            .withf(|event| {
+                matches!(event, RoomEvent::Message(message)
+                    if message.sender_username.as_deref() == Some("john"))
+            })
+            .returning(|_| Ok(()));
+
+        let result =
+            send_message(Arc::new(db), room_id, user_id, content, Arc::new(publisher)).await;
+
+        assert_eq!(result.unwrap().content, "Hello World!");
+    }
+
+    #[tokio::test]
+    async fn test_send_message_db_fail() {
+        let mut db = MockRoomDatabase::new();
+        db.expect_get_member_role()
+            .returning(|_, _| Ok(Some(MemberRole::Member)));
+        db.expect_get_active_mute().returning(|_, _| Ok(None));
+        let publisher = MockMessagePublisher::new();
+
+        db.expect_create_message()
+            .returning(|_| Err(RoomDatabaseError::InternalDBError("db error".into())));
+
+        let result = send_message(
+            Arc::new(db),
+            Uuid::new_v4(),
+            Uuid::new_v4(),
+            "hi".into(),
+            Arc::new(publisher),
+        )
+        .await;
+
+        assert!(matches!(result, Err(RoomError::DatabaseError(_))));
+    }
+
+    #[tokio::test]
+    async fn test_send_message_broadcast_fail() {
+        let mut db = MockRoomDatabase::new();
+        db.expect_get_member_role()
+            .returning(|_, _| Ok(Some(MemberRole::Member)));
+        db.expect_get_active_mute().returning(|_, _| Ok(None));
+        let mut publisher = MockMessagePublisher::new();
+
+        db.expect_create_message()
+            .returning(|message| Ok(view_of(message)));
+
+        publisher.expect_publish().returning(|_| {
+            Err(
+                crate::use_cases::realtime_broker::RealTimeBrokerError::InternalBrokerError(
+                    "broadcast err".into(),
+                ),
+            )
+        });
+
+        let result = send_message(
+            Arc::new(db),
+            Uuid::new_v4(),
+            Uuid::new_v4(),
+            "hello".into(),
+            Arc::new(publisher),
+        )
+        .await;
+
+        assert!(matches!(result, Err(RoomError::BroadcastError(_))));
+    }
 
-        db.expect_get_room().returning(move |_| {
-            Ok(Room {
-                id: room_id,
-                name: "Private".into(),
-                visibility: RoomVisibility::Private,
-                password_hash: Some(hash.clone()),
-                created_by: user_id,
-                created_at: Utc::now(),
-            })
-        });
+    fn room_with_id(room_id: Uuid) -> Room {
+        Room {
+            id: room_id,
+            name: "Read".into(),
+            visibility: RoomVisibility::Public,
+            password_hash: None,
+            created_by: Uuid::new_v4(),
+            created_at: Utc::now(),
+        }
+    }
 
-        db.expect_create_room_membership().returning(|_| Ok(()));
+    fn summary_with_id(room_id: Uuid) -> RoomSummary {
+        RoomSummary {
+            id: room_id,
+            name: "Summary".into(),
+            visibility: RoomVisibility::Public,
+            has_password: false,
+            created_by: Uuid::new_v4(),
+            created_at: Utc::now(),
+            member_count: 1,
+            is_member: true,
+        }
+    }
 
-        notif
-            .expect_send_room_member_notification()
-            .returning(|_| Ok(()));
+    fn view_of(message: Message) -> MessageView {
+        MessageView {
+            id: message.id,
+            room_id: message.room_id,
+            sender_id: Some(message.sender_id),
+            sender_username: Some("john".into()),
+            content: message.content,
+            created_at: message.created_at,
+            edited_at: None,
+            deleted_at: None,
+        }
+    }
 
-        let res = join_room(
-            Arc::new(db),
+    fn message_in_room(message_id: Uuid, room_id: Uuid) -> MessageView {
+        view_of(Message {
+            id: message_id,
             room_id,
-            user_id,
-            Some("mypassword".into()),
-            Arc::new(notif),
-        )
-        .await;
+            sender_id: Uuid::new_v4(),
+            content: "msg".into(),
+            created_at: Utc::now(),
+        })
+    }
 
-        assert!(res.is_ok());
+    fn message_sent_by(message_id: Uuid, room_id: Uuid, sender_id: Uuid) -> MessageView {
+        MessageView {
+            sender_id: Some(sender_id),
+            ..message_in_room(message_id, room_id)
+        }
+    }
+
+    fn messages_in_room(count: usize, room_id: Uuid) -> Vec<MessageView> {
+        (0..count)
+            .map(|_| message_in_room(Uuid::new_v4(), room_id))
+            .collect()
     }
 
     #[tokio::test]
-    async fn leave_room_ok() {
+    async fn test_get_user_rooms_with_unread() {
         let mut db = MockRoomDatabase::new();
-        let mut notif = MockNotificationService::new();
-
-        let room_id = Uuid::new_v4();
-        let user_id = Uuid::new_v4();
-
-        db.expect_delete_room_membership().returning(|_, _| Ok(()));
+        let read_room = Uuid::new_v4();
+        let unread_room = Uuid::new_v4();
+
+        db.expect_get_user_room_summaries().returning(move |_| {
+            Ok(vec![
+                summary_with_id(read_room),
+                summary_with_id(unread_room),
+            ])
+        });
 
-        notif
-            .expect_send_room_member_notification()
-            .returning(|_| Ok(()));
+        db.expect_get_unread_counts().returning(move |_| {
+            Ok(vec![RoomUnreadCount {
+                room_id: unread_room,
+                unread_count: 4,
+            }])
+        });
 
-        let res = leave_room(Arc::new(db), Arc::new(notif), room_id, user_id).await;
+        let rooms = get_user_rooms_with_unread(Arc::new(db), Uuid::new_v4())
+            .await
+            .unwrap();
 
-        assert!(res.is_ok());
+        assert_eq!(rooms.len(), 2);
+        assert_eq!(rooms[0].unread_count, 0);
+        assert_eq!(rooms[1].room.id, unread_room);
+        assert_eq!(rooms[1].unread_count, 4);
     }
 
     #[tokio::test]
-    async fn leave_room_delete_fails() {
+    async fn test_mark_room_read_broadcasts_when_advanced() {
         let mut db = MockRoomDatabase::new();
-        let notif = MockNotificationService::new();
+        let mut publisher = MockMessagePublisher::new();
 
         let room_id = Uuid::new_v4();
         let user_id = Uuid::new_v4();
+        let message_id = Uuid::new_v4();
+
+        db.expect_get_member_role()
+            .returning(|_, _| Ok(Some(MemberRole::Member)));
+        db.expect_get_message()
+            .returning(move |id| Ok(message_in_room(id, room_id)));
+        db.expect_update_read_state()
+            .withf(move |read_state| {
+                read_state.user_id == user_id && read_state.last_read_message_id == Some(message_id)
+            })
+            .once()
+            .returning(|_| Ok(true));
 
-        db.expect_delete_room_membership()
-            .returning(|_, _| Err(RoomDatabaseError::InternalDBError("db error".into())));
+        publisher
+            .expect_publish()
+            .withf(move |event| {
+                matches!(event, RoomEvent::Read(read_state) if read_state.room_id == room_id)
+            })
+            .once()
+            .returning(|_| Ok(()));
 
-        let res = leave_room(Arc::new(db), Arc::new(notif), room_id, user_id).await;
+        let result = mark_room_read(
+            Arc::new(db),
+            room_id,
+            user_id,
+            message_id,
+            Arc::new(publisher),
+        )
+        .await;
 
-        assert!(matches!(res, Err(RoomError::DatabaseError(_))));
+        assert!(result.is_ok());
     }
 
     #[tokio::test]
-    async fn leave_room_notification_fails() {
+    async fn test_mark_room_read_not_advanced_skips_broadcast() {
         let mut db = MockRoomDatabase::new();
-        let mut notif = MockNotificationService::new();
+        let publisher = MockMessagePublisher::new();
 
         let room_id = Uuid::new_v4();
-        let user_id = Uuid::new_v4();
-
-        db.expect_delete_room_membership().returning(|_, _| Ok(()));
 
-        notif.expect_send_room_member_notification().returning(|_| {
-            Err(NotificationServiceError::MessageProcessingError(
-                "notif error".into(),
-            ))
-        });
+        db.expect_get_member_role()
+            .returning(|_, _| Ok(Some(MemberRole::Member)));
+        db.expect_get_message()
+            .returning(move |id| Ok(message_in_room(id, room_id)));
+        db.expect_update_read_state().returning(|_| Ok(false));
 
-        let res = leave_room(Arc::new(db), Arc::new(notif), room_id, user_id).await;
+        let result = mark_room_read(
+            Arc::new(db),
+            room_id,
+            Uuid::new_v4(),
+            Uuid::new_v4(),
+            Arc::new(publisher),
+        )
+        .await;
 
-        assert!(matches!(res, Err(RoomError::NotificationError(_))));
+        assert!(result.is_ok());
     }
 
     #[tokio::test]
-    async fn test_create_room_private_with_password() {
+    async fn test_mark_room_read_not_member() {
         let mut db = MockRoomDatabase::new();
-        let user_id = Uuid::new_v4();
-
-        db.expect_create_room().returning(|_| Ok(()));
+        let publisher = MockMessagePublisher::new();
 
-        db.expect_create_room_membership().returning(|_| Ok(()));
+        db.expect_get_member_role().returning(|_, _| Ok(None));
+        db.expect_get_room().returning(|id| Ok(room_with_id(id)));
 
-        let res = create_room(
+        let result = mark_room_read(
             Arc::new(db),
-            RoomVisibility::Private,
-            Some("1234".into()),
-            "My Room".into(),
-            user_id,
+            Uuid::new_v4(),
+            Uuid::new_v4(),
+            Uuid::new_v4(),
+            Arc::new(publisher),
         )
         .await;
 
-        assert!(res.is_ok());
+        assert!(matches!(result, Err(RoomError::NotRoomMember)));
     }
 
     #[tokio::test]
-    async fn test_create_room_private_missing_password() {
-        let db = MockRoomDatabase::new();
+    async fn test_mark_room_read_message_from_other_room() {
+        let mut db = MockRoomDatabase::new();
+        let publisher = MockMessagePublisher::new();
 
-        let user_id = Uuid::new_v4();
+        let room_id = Uuid::new_v4();
 
-        let res = create_room(
+        db.expect_get_member_role()
+            .returning(|_, _| Ok(Some(MemberRole::Member)));
+        db.expect_get_message()
+            .returning(|id| Ok(message_in_room(id, Uuid::new_v4())));
+
+        let result = mark_room_read(
             Arc::new(db),
-            RoomVisibility::Private,
-            None,
-            "My Room".into(),
-            user_id,
+            room_id,
+            Uuid::new_v4(),
+            Uuid::new_v4(),
+            Arc::new(publisher),
         )
         .await;
 
-        assert!(matches!(res, Err(RoomError::PasswordNotGiven)));
+        assert!(matches!(result, Err(RoomError::MessageNotInRoom)));
     }
 
     #[tokio::test]
-    async fn test_get_user_rooms() {
+    async fn test_edit_message_by_sender() {
         let mut db = MockRoomDatabase::new();
+        db.expect_get_member_role()
+            .returning(|_, _| Ok(Some(MemberRole::Member)));
+        db.expect_get_active_mute().returning(|_, _| Ok(None));
+        let mut publisher = MockMessagePublisher::new();
+
+        let room_id = Uuid::new_v4();
         let user_id = Uuid::new_v4();
 
-        db.expect_get_user_rooms().returning(move |_| {
-            Ok(vec![Room {
-                id: Uuid::new_v4(),
-                name: "Test".into(),
-                visibility: RoomVisibility::Public,
-                password_hash: None,
-                created_by: user_id.clone(),
-                created_at: Utc::now(),
-            }])
-        });
+        db.expect_get_message()
+            .returning(move |id| Ok(message_sent_by(id, room_id, user_id)));
+        db.expect_edit_message()
+            .withf(move |_, edited_by, content| *edited_by == user_id && content == "edited")
+            .once()
+            .returning(move |id, _, content| {
+                Ok(MessageView {
+                    content,
+                    edited_at: Some(Utc::now()),
+                    ..message_sent_by(id, room_id, user_id)
+                })
+            });
+
+        publisher
+            .expect_publish()
+            .withf(|event| {
+                matches!(event, RoomEvent::MessageEdited(message)
+                    if message.content == "edited" && message.edited_at.is_some())
+            })
+            .once()
+            .returning(|_| Ok(()));
 
-        let res = get_user_rooms_use(Arc::new(db), user_id.clone())
-            .await
-            .unwrap();
+        let edited = edit_message(
+            Arc::new(db),
+            room_id,
+            Uuid::new_v4(),
+            user_id,
+            "edited".into(),
+            Arc::new(publisher),
+        )
+        .await
+        .unwrap();
 
-        assert_eq!(res.len(), 1);
+        assert_eq!(edited.content, "edited");
     }
 
     #[tokio::test]
-    async fn test_get_all_public_rooms() {
+    async fn test_edit_message_by_other_user() {
         let mut db = MockRoomDatabase::new();
+        db.expect_get_member_role()
+            .returning(|_, _| Ok(Some(MemberRole::Member)));
+        db.expect_get_active_mute().returning(|_, _| Ok(None));
+        let publisher = MockMessagePublisher::new();
 
-        db.expect_get_public_rooms().returning(|| {
-            Ok(vec![Room {
-                id: Uuid::new_v4(),
-                name: "Public Room".into(),
-                visibility: RoomVisibility::Public,
-                password_hash: None,
-                created_by: Uuid::new_v4(),
-                created_at: Utc::now(),
-            }])
-        });
+        let room_id = Uuid::new_v4();
 
-        let rooms = get_all_public_rooms(Arc::new(db)).await.unwrap();
+        db.expect_get_message()
+            .returning(move |id| Ok(message_in_room(id, room_id)));
 
-        assert_eq!(rooms.len(), 1);
+        let result = edit_message(
+            Arc::new(db),
+            room_id,
+            Uuid::new_v4(),
+            Uuid::new_v4(),
+            "edited".into(),
+            Arc::new(publisher),
+        )
+        .await;
+
+        assert!(matches!(result, Err(RoomError::NotMessageSender)));
     }
 
     #[tokio::test]
-    async fn test_obtain_messages() {
+    async fn test_edit_deleted_message() {
         let mut db = MockRoomDatabase::new();
+        db.expect_get_member_role()
+            .returning(|_, _| Ok(Some(MemberRole::Member)));
+        db.expect_get_active_mute().returning(|_, _| Ok(None));
+        let publisher = MockMessagePublisher::new();
+
         let room_id = Uuid::new_v4();
+        let user_id = Uuid::new_v4();
 
-        db.expect_get_room_messages().returning(move |_, _, _| {
-            Ok(vec![Message {
-                id: Uuid::new_v4(),
-                room_id: room_id.clone(),
-                sender_id: Uuid::new_v4(),
-                content: "msg".into(),
-                created_at: Utc::now(),
-            }])
+        db.expect_get_message().returning(move |id| {
+            Ok(MessageView {
+                deleted_at: Some(Utc::now()),
+                ..message_sent_by(id, room_id, user_id)
+            })
         });
 
-        let msgs = obtain_messages(Arc::new(db), 1, 10, room_id).await.unwrap();
+        let result = edit_message(
+            Arc::new(db),
+            room_id,
+            Uuid::new_v4(),
+            user_id,
+            "edited".into(),
+            Arc::new(publisher),
+        )
+        .await;
 
-        assert_eq!(msgs.len(), 1);
+        assert!(matches!(result, Err(RoomError::MessageDeleted)));
     }
 
     #[tokio::test]
-    async fn test_obtain_room_members() {
+    async fn test_delete_message_by_room_owner() {
         let mut db = MockRoomDatabase::new();
+        let mut publisher = MockMessagePublisher::new();
+
         let room_id = Uuid::new_v4();
 
-        db.expect_get_room_members().returning(|_| {
-            Ok(vec![User {
-                id: Uuid::new_v4(),
-                username: "john".into(),
-                email: "john@example.com".into(),
-                password_hash: "hash".into(),
-                created_at: Utc::now(),
-                updated_at: Utc::now(),
-            }])
+        db.expect_get_message()
+            .returning(move |id| Ok(message_in_room(id, room_id)));
+        db.expect_get_member_role()
+            .returning(|_, _| Ok(Some(MemberRole::Owner)));
+        db.expect_delete_message().once().returning(move |id| {
+            Ok(MessageView {
+                content: String::new(),
+                deleted_at: Some(Utc::now()),
+                ..message_in_room(id, room_id)
+            })
         });
 
-        let members = obtain_room_members(Arc::new(db), room_id).await.unwrap();
+        publisher
+            .expect_publish()
+            .withf(|event| {
+                matches!(event, RoomEvent::MessageDeleted(message) if message.deleted_at.is_some())
+            })
+            .once()
+            .returning(|_| Ok(()));
+
+        let result = delete_message(
+            Arc::new(db),
+            room_id,
+            Uuid::new_v4(),
+            Uuid::new_v4(),
+            Arc::new(publisher),
+        )
+        .await;
 
-        assert_eq!(members.len(), 1);
+        assert!(result.is_ok());
     }
 
     #[tokio::test]
-    async fn test_send_message_success() {
+    async fn test_delete_message_by_moderator() {
         let mut db = MockRoomDatabase::new();
         let mut publisher = MockMessagePublisher::new();
 
         let room_id = Uuid::new_v4();
-        let user_id = Uuid::new_v4();
-        let content = "Hello World!".to_string();
-
-        // Expect DB create_message to succeed
-        db.expect_create_message().returning(|_| Ok(()));
 
-        // Expect publisher broadcast_message to succeed
-        publisher.expect_broadcast_message().returning(|_| Ok(()));
+        db.expect_get_message()
+            .returning(move |id| Ok(message_in_room(id, room_id)));
+        db.expect_get_member_role()
+            .returning(|_, _| Ok(Some(MemberRole::Moderator)));
+        db.expect_delete_message()
+            .once()
+            .returning(move |id| Ok(message_in_room(id, room_id)));
+        publisher.expect_publish().once().returning(|_| Ok(()));
 
-        let result =
-            send_message(Arc::new(db), room_id, user_id, content, Arc::new(publisher)).await;
+        let result = delete_message(
+            Arc::new(db),
+            room_id,
+            Uuid::new_v4(),
+            Uuid::new_v4(),
+            Arc::new(publisher),
+        )
+        .await;
 
         assert!(result.is_ok());
     }
 
     #[tokio::test]
-    async fn test_send_message_db_fail() {
+    async fn test_delete_message_by_other_member() {
         let mut db = MockRoomDatabase::new();
         let publisher = MockMessagePublisher::new();
 
-        db.expect_create_message()
-            .returning(|_| Err(RoomDatabaseError::InternalDBError("db error".into())));
+        let room_id = Uuid::new_v4();
 
-        let result = send_message(
+        db.expect_get_message()
+            .returning(move |id| Ok(message_in_room(id, room_id)));
+        db.expect_get_member_role()
+            .returning(|_, _| Ok(Some(MemberRole::Member)));
+
+        let result = delete_message(
             Arc::new(db),
+            room_id,
             Uuid::new_v4(),
             Uuid::new_v4(),
-            "hi".into(),
             Arc::new(publisher),
         )
         .await;
 
-        assert!(matches!(result, Err(RoomError::DatabaseError(_))));
+        assert!(matches!(result, Err(RoomError::NotMessageSender)));
     }
 
     #[tokio::test]
-    async fn test_send_message_broadcast_fail() {
+    async fn test_delete_own_message_after_leaving() {
         let mut db = MockRoomDatabase::new();
-        let mut publisher = MockMessagePublisher::new();
+        let publisher = MockMessagePublisher::new();
 
-        db.expect_create_message().returning(|_| Ok(()));
+        let room_id = Uuid::new_v4();
+        let user_id = Uuid::new_v4();
 
-        publisher.expect_broadcast_message().returning(|_| {
-            Err(
-                crate::use_cases::realtime_broker::RealTimeBrokerError::InternalBrokerError(
-                    "broadcast err".into(),
-                ),
-            )
-        });
+        db.expect_get_member_role().returning(|_, _| Ok(None));
+        db.expect_get_room().returning(|id| Ok(room_with_id(id)));
+        db.expect_get_message()
+            .returning(move |id| Ok(message_sent_by(id, room_id, user_id)));
+        db.expect_delete_message().never();
 
-        let result = send_message(
+        let result = delete_message(
             Arc::new(db),
+            room_id,
             Uuid::new_v4(),
-            Uuid::new_v4(),
-            "hello".into(),
+            user_id,
             Arc::new(publisher),
         )
         .await;
 
-        assert!(matches!(result, Err(RoomError::BroadcastError(_))));
+        assert!(matches!(result, Err(RoomError::NotRoomMember)));
+    }
+
+    #[tokio::test]
+    async fn test_send_typing_broadcasts_indicator() {
+        let mut db = MockRoomDatabase::new();
+        let mut publisher = MockMessagePublisher::new();
+        db.expect_get_member_role()
+            .returning(|_, _| Ok(Some(MemberRole::Member)));
+        db.expect_get_active_mute().returning(|_, _| Ok(None));
+
+        let room_id = Uuid::new_v4();
+        let user_id = Uuid::new_v4();
+
+        publisher
+            .expect_publish()
+            .withf(move |event| {
+                matches!(event, RoomEvent::Typing(typing)
+                    if typing.room_id == room_id && typing.user_id == user_id && typing.is_typing)
+            })
+            .once()
+            .returning(|_| Ok(()));
+
+        let result = send_typing(Arc::new(db), room_id, user_id, true, Arc::new(publisher)).await;
+
+        assert!(result.is_ok());
     }
 }
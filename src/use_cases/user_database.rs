@@ -1,8 +1,11 @@
+use chrono::{DateTime, Utc};
 use mockall::automock;
 use thiserror::Error;
 use uuid::Uuid;
 
-use crate::domain::user::User;
+use crate::domain::user::{
+    AccountDeletion, LoginLockout, RefreshToken, User, UserToken, UserTokenPurpose,
+};
 
 pub type UserDatabaseResult<T> = Result<T, UserDatabaseError>;
 
@@ -10,17 +13,96 @@ pub trait UserDatabaseClone: UserDatabase + Clone {}
 
 #[automock]
 pub trait UserDatabase: Send + Sync {
+    /// Fails with `UsernameTaken` or `EmailTaken` when another user has them, in any case
     async fn create_user(&self, user: User) -> UserDatabaseResult<()>;
 
+    /// Usernames and emails are matched regardless of case, `NotFound` when nobody has them
     async fn get_user_by_username(&self, username: String) -> UserDatabaseResult<User>;
 
     async fn get_user_by_email(&self, email: String) -> UserDatabaseResult<User>;
 
     async fn get_user_by_id(&self, id: Uuid) -> UserDatabaseResult<User>;
+
+    /// Stores the username, email and verification of the user, touching `updated_at`
+    async fn update_user(&self, user: User) -> UserDatabaseResult<User>;
+
+    /// Deletes the user with everything of theirs. Every room they own goes to its oldest
+    /// remaining member, or is archived when nobody else is in it, as when they leave it. Their
+    /// messages are kept deleted, without content nor sender
+    async fn delete_user(&self, user_id: Uuid) -> UserDatabaseResult<AccountDeletion>;
+
+    async fn create_refresh_token(&self, token: RefreshToken) -> UserDatabaseResult<()>;
+
+    async fn get_refresh_token(
+        &self,
+        token_hash: String,
+    ) -> UserDatabaseResult<Option<RefreshToken>>;
+
+    /// Revokes `old_id` and stores its replacement in one step. Returns false without storing
+    /// anything when `old_id` was already revoked, so a token can only be rotated once
+    async fn rotate_refresh_token(
+        &self,
+        old_id: Uuid,
+        replacement: RefreshToken,
+    ) -> UserDatabaseResult<bool>;
+
+    async fn revoke_token_family(&self, family_id: Uuid) -> UserDatabaseResult<()>;
+
+    /// Adds the access token to the revocation list until it expires
+    async fn revoke_access_token(
+        &self,
+        jti: Uuid,
+        expires_at: DateTime<Utc>,
+    ) -> UserDatabaseResult<()>;
+
+    /// Whether the token is in the revocation list, was issued before the sessions of its user
+    /// were revoked, or its user no longer exists
+    async fn is_access_token_revoked(
+        &self,
+        jti: Uuid,
+        user_id: Uuid,
+        issued_at: DateTime<Utc>,
+    ) -> UserDatabaseResult<bool>;
+
+    async fn record_login_lockout(&self, lockout: LoginLockout) -> UserDatabaseResult<()>;
+
+    async fn create_user_token(&self, token: UserToken) -> UserDatabaseResult<()>;
+
+    /// Marks the token as used and returns it, `None` when it is unknown, used or expired
+    async fn consume_user_token(
+        &self,
+        token_hash: String,
+        purpose: UserTokenPurpose,
+    ) -> UserDatabaseResult<Option<UserToken>>;
+
+    async fn mark_email_verified(&self, user_id: Uuid) -> UserDatabaseResult<()>;
+
+    async fn update_password(&self, user_id: Uuid, password_hash: String)
+    -> UserDatabaseResult<()>;
+
+    /// Revokes every refresh token of the user and the access tokens issued up to `revoked_at`,
+    /// logging out all of their sessions
+    async fn revoke_user_sessions(
+        &self,
+        user_id: Uuid,
+        revoked_at: DateTime<Utc>,
+    ) -> UserDatabaseResult<()>;
 }
 
 #[derive(Debug, Error)]
 pub enum UserDatabaseError {
     #[error("Internal DB error: {0}")]
     InternalDBError(String),
+    #[error("Not found")]
+    NotFound,
+    #[error("Unique constraint {0} violated")]
+    UniqueViolation(String),
+    #[error("Foreign key constraint {0} violated")]
+    ForeignKeyViolation(String),
+    #[error("Database unavailable: {0}")]
+    Unavailable(String),
+    #[error("The username is taken")]
+    UsernameTaken,
+    #[error("The email is taken")]
+    EmailTaken,
 }
@@ -0,0 +1,199 @@
+use std::fmt::Display;
+
+use serde::Serialize;
+use thiserror::Error;
+
+const USERNAME_MIN_LEN: usize = 3;
+
+const USERNAME_MAX_LEN: usize = 32;
+
+const EMAIL_MAX_LEN: usize = 254;
+
+const PASSWORD_MIN_LEN: usize = 8;
+
+/// bcrypt only looks at the first 72 bytes, anything after them wouldn't count
+const PASSWORD_MAX_BYTES: usize = 72;
+
+/// A rule the value of a field breaks, `field` is named as in the request body
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct FieldError {
+    pub field: &'static str,
+    pub message: String,
+}
+
+impl FieldError {
+    fn new(field: &'static str, message: impl Into<String>) -> FieldError {
+        FieldError {
+            field,
+            message: message.into(),
+        }
+    }
+}
+
+/// Every field error of a request, so the client can show them all at once
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Error)]
+pub struct ValidationErrors {
+    pub errors: Vec<FieldError>,
+}
+
+impl Display for ValidationErrors {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let errors: Vec<String> = self
+            .errors
+            .iter()
+            .map(|error| format!("{}: {}", error.field, error.message))
+            .collect();
+
+        write!(f, "invalid fields ({})", errors.join(", "))
+    }
+}
+
+impl From<FieldError> for ValidationErrors {
+    fn from(error: FieldError) -> Self {
+        ValidationErrors {
+            errors: vec![error],
+        }
+    }
+}
+
+impl ValidationErrors {
+    fn check(&mut self, result: Result<(), FieldError>) {
+        if let Err(error) = result {
+            self.errors.push(error);
+        }
+    }
+
+    fn into_result(self) -> Result<(), ValidationErrors> {
+        if self.errors.is_empty() {
+            Ok(())
+        } else {
+            Err(self)
+        }
+    }
+}
+
+/// Letters, digits, `_`, `.` and `-`, so usernames can't be mistaken for emails
+pub fn validate_username(username: &str) -> Result<(), FieldError> {
+    let len = username.chars().count();
+    if !(USERNAME_MIN_LEN..=USERNAME_MAX_LEN).contains(&len) {
+        return Err(FieldError::new(
+            "username",
+            format!("must have between {USERNAME_MIN_LEN} and {USERNAME_MAX_LEN} characters"),
+        ));
+    }
+
+    if !username
+        .chars()
+        .all(|char| char.is_ascii_alphanumeric() || matches!(char, '_' | '.' | '-'))
+    {
+        return Err(FieldError::new(
+            "username",
+            "may only contain letters, digits, '_', '.' and '-'",
+        ));
+    }
+
+    Ok(())
+}
+
+/// Only the shape is checked, the verification email proves the address works
+pub fn validate_email(email: &str) -> Result<(), FieldError> {
+    let invalid = || FieldError::new("email", "must be a valid email address");
+
+    if email.len() > EMAIL_MAX_LEN || email.chars().any(char::is_whitespace) {
+        return Err(invalid());
+    }
+
+    let Some((local, domain)) = email.split_once('@') else {
+        return Err(invalid());
+    };
+
+    let domain_is_valid = domain.contains('.')
+        && domain
+            .split('.')
+            .all(|label| !label.is_empty() && !label.starts_with('-') && !label.ends_with('-'));
+
+    if local.is_empty() || local.len() > 64 || domain.contains('@') || !domain_is_valid {
+        return Err(invalid());
+    }
+
+    Ok(())
+}
+
+pub fn validate_password(password: &str) -> Result<(), FieldError> {
+    if password.chars().count() < PASSWORD_MIN_LEN {
+        return Err(FieldError::new(
+            "password",
+            format!("must have at least {PASSWORD_MIN_LEN} characters"),
+        ));
+    }
+
+    if password.len() > PASSWORD_MAX_BYTES {
+        return Err(FieldError::new(
+            "password",
+            format!("must have at most {PASSWORD_MAX_BYTES} bytes"),
+        ));
+    }
+
+    if !password.chars().any(|char| char.is_ascii_digit()) {
+        return Err(FieldError::new("password", "must contain a digit"));
+    }
+
+    Ok(())
+}
+
+pub fn validate_registration(
+    username: &str,
+    email: &str,
+    password: &str,
+) -> Result<(), ValidationErrors> {
+    let mut errors = ValidationErrors::default();
+    errors.check(validate_username(username));
+    errors.check(validate_email(email));
+    errors.check(validate_password(password));
+
+    errors.into_result()
+}
+
+#[cfg(test)]
+mod test {
+    use crate::use_cases::validation::{
+        validate_email, validate_password, validate_registration, validate_username,
+    };
+
+    #[test]
+    fn usernames_are_checked_for_length_and_charset() {
+        assert!(validate_username("juan.perez_1").is_ok());
+        assert!(validate_username("jp").is_err());
+        assert!(validate_username(&"j".repeat(33)).is_err());
+        assert!(validate_username("juan perez").is_err());
+        assert!(validate_username("juan@juan.juan").is_err());
+    }
+
+    #[test]
+    fn emails_need_a_local_part_and_a_domain() {
+        assert!(validate_email("juan@juan.juan").is_ok());
+        assert!(validate_email("juan+chat@mail.juan.juan").is_ok());
+        assert!(validate_email("juan").is_err());
+        assert!(validate_email("@juan.juan").is_err());
+        assert!(validate_email("juan@juan").is_err());
+        assert!(validate_email("juan@juan..juan").is_err());
+        assert!(validate_email("juan@@juan.juan").is_err());
+        assert!(validate_email("juan @juan.juan").is_err());
+    }
+
+    #[test]
+    fn passwords_need_a_digit_and_fit_in_bcrypt() {
+        assert!(validate_password("password1").is_ok());
+        assert!(validate_password("pass1").is_err());
+        assert!(validate_password("password").is_err());
+        assert!(validate_password(&format!("{}1", "a".repeat(72))).is_err());
+    }
+
+    #[test]
+    fn registration_reports_every_invalid_field() {
+        let errors = validate_registration("j", "juan", "password1").unwrap_err();
+
+        let fields: Vec<&str> = errors.errors.iter().map(|error| error.field).collect();
+        assert_eq!(fields, vec!["username", "email"]);
+    }
+}
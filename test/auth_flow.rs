@@ -1,9 +1,15 @@
-use std::sync::Arc;
+use std::{net::Ipv4Addr, sync::Arc};
 
-use jsonwebtoken::{DecodingKey, Validation, decode};
-use nebula_backend::use_cases::{
-    auth_service::{AuthError, Claims, get_user_by_id_use, login, register},
-    user_database::UserDatabase,
+use nebula_backend::{
+    domain::user::UserRole,
+    use_cases::{
+        auth_service::{
+            AuthError, Claims, change_password, forgot_password, get_user_by_id_use, login, logout, refresh_tokens,
+            register, reset_password, update_profile, verify_email,
+        },
+        jwt_keys::JwtKeys,
+        user_database::UserDatabase,
+    },
 };
 use uuid::Uuid;
 
@@ -16,25 +22,21 @@ async fn register_and_login_against_postgres() {
     let config = common::IntegrationConfig::load();
     let (database, pool) = common::provision_database(&config).await;
 
-    let username = format!("integration-user-{}", Uuid::new_v4().simple());
+    let username = common::unique_name("integration-user-");
     let email = format!("{username}@example.com");
     let password = "password123*".to_string();
 
-    register(Arc::new(database.clone()), username.clone(), password.clone(), email.clone())
+    register(Arc::new(database.clone()), common::mailer(), username.clone(), password.clone(), email.clone(), common::APP_URL)
         .await
         .expect("register should succeed against postgres");
 
-    let token = login(Arc::new(database.clone()), username.clone(), password.clone(), config.jwt_secret.clone())
+    let token = login(Arc::new(database.clone()), common::login_attempts(&config), username.clone(), password.clone(), Ipv4Addr::LOCALHOST.into(), Arc::new(JwtKeys::from_secret(&config.jwt_secret)))
         .await
         .expect("login should return a signed JWT");
 
-    let claims: Claims = decode(
-        &token,
-        &DecodingKey::from_secret(config.jwt_secret.as_ref()),
-        &Validation::default(),
-    )
-    .expect("jwt should decode with the provided secret")
-    .claims;
+    let claims: Claims = JwtKeys::from_secret(&config.jwt_secret)
+        .decode(&token.access_token)
+        .expect("jwt should decode with the provided secret");
 
     let fetched = get_user_by_id_use(Arc::new(database), Uuid::parse_str(&claims.sub).unwrap())
         .await
@@ -42,6 +44,8 @@ async fn register_and_login_against_postgres() {
 
     assert_eq!(fetched.email, email);
     assert_eq!(fetched.username, username);
+    assert_eq!(claims.username, username);
+    assert_eq!(claims.role, UserRole::User);
 
     common::reset_tables(&pool).await;
 }
@@ -52,25 +56,21 @@ async fn login_with_email_succeeds() {
     let config = common::IntegrationConfig::load();
     let (database, pool) = common::provision_database(&config).await;
 
-    let username = format!("integration-email-{}", Uuid::new_v4().simple());
+    let username = common::unique_name("integration-email-");
     let email = format!("{username}@example.com");
     let password = "password123*".to_string();
 
-    register(Arc::new(database.clone()), username.clone(), password.clone(), email.clone())
+    register(Arc::new(database.clone()), common::mailer(), username.clone(), password.clone(), email.clone(), common::APP_URL)
         .await
         .expect("register should succeed");
 
-    let token = login(Arc::new(database.clone()), email.clone(), password.clone(), config.jwt_secret.clone())
+    let token = login(Arc::new(database.clone()), common::login_attempts(&config), email.clone(), password.clone(), Ipv4Addr::LOCALHOST.into(), Arc::new(JwtKeys::from_secret(&config.jwt_secret)))
         .await
         .expect("login with email should succeed");
 
-    let claims: Claims = decode(
-        &token,
-        &DecodingKey::from_secret(config.jwt_secret.as_ref()),
-        &Validation::default(),
-    )
-    .expect("jwt should decode")
-    .claims;
+    let claims: Claims = JwtKeys::from_secret(&config.jwt_secret)
+        .decode(&token.access_token)
+        .expect("jwt should decode");
 
     let user = get_user_by_id_use(
         Arc::new(database),
@@ -89,19 +89,21 @@ async fn login_with_wrong_password_fails() {
     let config = common::IntegrationConfig::load();
     let (database, pool) = common::provision_database(&config).await;
 
-    let username = format!("integration-wrong-pass-{}", Uuid::new_v4().simple());
+    let username = common::unique_name("integration-wrong-pass-");
     let email = format!("{username}@example.com");
     let password = "password123*".to_string();
 
-    register(Arc::new(database.clone()), username.clone(), password.clone(), email.clone())
+    register(Arc::new(database.clone()), common::mailer(), username.clone(), password.clone(), email.clone(), common::APP_URL)
         .await
         .expect("register should succeed");
 
     let err = login(
         Arc::new(database.clone()),
+        common::login_attempts(&config),
         username.clone(),
         "nottherightone".to_string(),
-        config.jwt_secret.clone(),
+        Ipv4Addr::LOCALHOST.into(),
+        Arc::new(JwtKeys::from_secret(&config.jwt_secret)),
     )
     .await
     .expect_err("login should fail with wrong password");
@@ -120,29 +122,276 @@ async fn duplicate_username_is_rejected() {
     let config = common::IntegrationConfig::load();
     let (database, pool) = common::provision_database(&config).await;
 
-    let username = format!("dup-user-{}", Uuid::new_v4().simple());
+    let username = common::unique_name("dup-user-");
     let email = format!("{username}@example.com");
     let password = "password123*".to_string();
 
-    register(Arc::new(database.clone()), username.clone(), password.clone(), email.clone())
+    register(Arc::new(database.clone()), common::mailer(), username.clone(), password.clone(), email.clone(), common::APP_URL)
         .await
         .expect("initial register should succeed");
 
     let second_email = format!("second-{username}@example.com");
     let err = register(
         Arc::new(database.clone()),
-        username.clone(),
+        common::mailer(),
+        username.to_uppercase(),
         password.clone(),
         second_email,
+        common::APP_URL,
     )
     .await
     .expect_err("duplicate username should fail");
 
     match err {
-        AuthError::AlreadyExisting(_) => {}
+        AuthError::AlreadyExisting(field) => assert_eq!(field, "username"),
+        other => panic!("expected AlreadyExisting, got {other:?}"),
+    }
+
+    common::reset_tables(&pool).await;
+}
+
+#[tokio::test]
+#[serial]
+async fn duplicate_email_is_rejected_regardless_of_case() {
+    let config = common::IntegrationConfig::load();
+    let (database, pool) = common::provision_database(&config).await;
+
+    let username = common::unique_name("dup-email-");
+    let email = format!("{username}@example.com");
+    let password = "password123*".to_string();
+
+    register(Arc::new(database.clone()), common::mailer(), username.clone(), password.clone(), email.clone(), common::APP_URL)
+        .await
+        .expect("initial register should succeed");
+
+    let err = register(Arc::new(database.clone()), common::mailer(), format!("{username}-2"), password.clone(), email.to_uppercase(), common::APP_URL)
+        .await
+        .expect_err("duplicate email should fail");
+
+    match err {
+        AuthError::AlreadyExisting(field) => assert_eq!(field, "email"),
         other => panic!("expected AlreadyExisting, got {other:?}"),
     }
 
+    let user = database.get_user_by_username(username.to_uppercase())
+        .await
+        .expect("usernames should match regardless of case");
+    assert_eq!(user.username, username);
+
+    common::reset_tables(&pool).await;
+}
+
+#[tokio::test]
+#[serial]
+async fn invalid_registrations_report_every_field() {
+    let config = common::IntegrationConfig::load();
+    let (database, pool) = common::provision_database(&config).await;
+
+    let err = register(Arc::new(database.clone()), common::mailer(), "no spaces allowed".to_string(), "short".to_string(), "not-an-email".to_string(), common::APP_URL)
+        .await
+        .expect_err("invalid fields should fail");
+
+    match err {
+        AuthError::InvalidFields(errors) => {
+            let fields: Vec<&str> = errors.errors.iter().map(|error| error.field).collect();
+            assert_eq!(fields, vec!["username", "email", "password"]);
+        }
+        other => panic!("expected InvalidFields, got {other:?}"),
+    }
+
+    common::reset_tables(&pool).await;
+}
+#[tokio::test]
+#[serial]
+async fn refresh_tokens_rotate_and_logout_revokes_them() {
+    let config = common::IntegrationConfig::load();
+    let (database, pool) = common::provision_database(&config).await;
+
+    let username = common::unique_name("refresh-user-");
+    let email = format!("{username}@example.com");
+    let password = "password123*".to_string();
+
+    register(Arc::new(database.clone()), common::mailer(), username.clone(), password.clone(), email.clone(), common::APP_URL)
+        .await
+        .expect("register should succeed");
+
+    let first = login(Arc::new(database.clone()), common::login_attempts(&config), username.clone(), password.clone(), Ipv4Addr::LOCALHOST.into(), Arc::new(JwtKeys::from_secret(&config.jwt_secret)))
+        .await
+        .expect("login should succeed");
+
+    let second = refresh_tokens(Arc::new(database.clone()), first.refresh_token.clone(), Arc::new(JwtKeys::from_secret(&config.jwt_secret)))
+        .await
+        .expect("a fresh refresh token should rotate");
+    assert_ne!(second.refresh_token, first.refresh_token);
+
+    // Using the rotated token again kills the whole family, including the token that replaced it
+    let reused = refresh_tokens(Arc::new(database.clone()), first.refresh_token.clone(), Arc::new(JwtKeys::from_secret(&config.jwt_secret))).await;
+    assert!(matches!(reused, Err(AuthError::RefreshTokenReused)));
+    let after_reuse = refresh_tokens(Arc::new(database.clone()), second.refresh_token.clone(), Arc::new(JwtKeys::from_secret(&config.jwt_secret))).await;
+    assert!(matches!(after_reuse, Err(AuthError::RefreshTokenReused)));
+
+    let third = login(Arc::new(database.clone()), common::login_attempts(&config), username.clone(), password.clone(), Ipv4Addr::LOCALHOST.into(), Arc::new(JwtKeys::from_secret(&config.jwt_secret)))
+        .await
+        .expect("login should succeed again");
+    let claims: Claims = JwtKeys::from_secret(&config.jwt_secret)
+        .decode(&third.access_token)
+        .expect("jwt should decode with the provided secret");
+    let jti = Uuid::parse_str(&claims.jti).expect("jti should be a uuid");
+    let user_id = Uuid::parse_str(&claims.sub).expect("sub should be a uuid");
+
+    logout(
+        Arc::new(database.clone()),
+        user_id,
+        jti,
+        chrono::Utc::now() + chrono::Duration::minutes(15),
+        Some(third.refresh_token.clone()),
+    )
+    .await
+    .expect("logout should succeed");
+
+    assert!(database.is_access_token_revoked(jti, user_id, chrono::Utc::now()).await.expect("revocation lookup should succeed"));
+    let after_logout = refresh_tokens(Arc::new(database.clone()), third.refresh_token.clone(), Arc::new(JwtKeys::from_secret(&config.jwt_secret))).await;
+    assert!(after_logout.is_err());
+
     common::reset_tables(&pool).await;
 }
 use serial_test::serial;
+
+#[tokio::test]
+#[serial]
+async fn repeated_failed_logins_lock_the_account() {
+    let config = common::IntegrationConfig::load();
+    let (database, pool) = common::provision_database(&config).await;
+    common::flush_redis(&config.redis_url).await;
+
+    let username = common::unique_name("lockout-user-");
+    let email = format!("{username}@example.com");
+    let password = "password123*".to_string();
+
+    register(Arc::new(database.clone()), common::mailer(), username.clone(), password.clone(), email.clone(), common::APP_URL)
+        .await
+        .expect("register should succeed");
+
+    let attempts = common::login_attempts(&config);
+    let mut results = Vec::new();
+    for _ in 0..5 {
+        results.push(login(Arc::new(database.clone()), attempts.clone(), username.clone(), "wrong-password".to_string(), Ipv4Addr::LOCALHOST.into(), Arc::new(JwtKeys::from_secret(&config.jwt_secret))).await);
+    }
+
+    assert!(results[..4].iter().all(|result| matches!(result, Err(AuthError::InvalidCredentials))));
+    assert!(matches!(results[4], Err(AuthError::TooManyAttempts(_))));
+
+    // Even the right password is refused until the lockout is over
+    let locked = login(Arc::new(database.clone()), attempts.clone(), username.clone(), password.clone(), Ipv4Addr::LOCALHOST.into(), Arc::new(JwtKeys::from_secret(&config.jwt_secret))).await;
+    assert!(matches!(locked, Err(AuthError::TooManyAttempts(_))));
+
+    let audited: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM login_lockouts WHERE user_id IS NOT NULL")
+        .fetch_one(&pool)
+        .await
+        .expect("lockouts should be readable");
+    assert_eq!(audited, 1);
+
+    common::reset_tables(&pool).await;
+}
+
+#[tokio::test]
+#[serial]
+async fn emailed_tokens_verify_the_email_and_reset_the_password() {
+    let config = common::IntegrationConfig::load();
+    let (database, pool) = common::provision_database(&config).await;
+
+    let username = common::unique_name("mail-user-");
+    let email = format!("{username}@example.com");
+    let password = "password123*".to_string();
+
+    register(Arc::new(database.clone()), common::mailer(), username.clone(), password.clone(), email.clone(), common::APP_URL)
+        .await
+        .expect("register should succeed");
+
+    let verification_token = common::emailed_token(&email, "verify-email");
+    verify_email(Arc::new(database.clone()), verification_token.clone())
+        .await
+        .expect("the emailed token should verify the email");
+    let used = verify_email(Arc::new(database.clone()), verification_token).await;
+    assert!(matches!(used, Err(AuthError::InvalidEmailToken)));
+
+    let user = database.get_user_by_email(email.clone()).await.expect("user exists");
+    assert!(user.email_verified_at.is_some());
+
+    let session = login(Arc::new(database.clone()), common::login_attempts(&config), username.clone(), password.clone(), Ipv4Addr::LOCALHOST.into(), Arc::new(JwtKeys::from_secret(&config.jwt_secret)))
+        .await
+        .expect("login should succeed");
+
+    forgot_password(Arc::new(database.clone()), common::mailer(), email.clone(), common::APP_URL)
+        .await
+        .expect("forgot password should succeed");
+    let reset_token = common::emailed_token(&email, "reset-password");
+    reset_password(Arc::new(database.clone()), reset_token.clone(), "newpassword456".to_string())
+        .await
+        .expect("the emailed token should reset the password");
+
+    let reused = reset_password(Arc::new(database.clone()), reset_token, "otherpassword789".to_string()).await;
+    assert!(matches!(reused, Err(AuthError::InvalidEmailToken)));
+
+    // Sessions opened with the old password are gone
+    let refreshed = refresh_tokens(Arc::new(database.clone()), session.refresh_token, Arc::new(JwtKeys::from_secret(&config.jwt_secret))).await;
+    assert!(refreshed.is_err());
+
+    let old_password = login(Arc::new(database.clone()), common::login_attempts(&config), username.clone(), password.clone(), Ipv4Addr::LOCALHOST.into(), Arc::new(JwtKeys::from_secret(&config.jwt_secret))).await;
+    assert!(matches!(old_password, Err(AuthError::InvalidCredentials)));
+    login(Arc::new(database.clone()), common::login_attempts(&config), username.clone(), "newpassword456".to_string(), Ipv4Addr::LOCALHOST.into(), Arc::new(JwtKeys::from_secret(&config.jwt_secret)))
+        .await
+        .expect("the new password should work");
+
+    common::reset_tables(&pool).await;
+}
+
+#[tokio::test]
+#[serial]
+async fn profile_changes_are_stored_and_the_password_change_revokes_sessions() {
+    let config = common::IntegrationConfig::load();
+    let (database, pool) = common::provision_database(&config).await;
+
+    let username = common::unique_name("profile-user-");
+    let email = format!("{username}@example.com");
+    let password = "password123*".to_string();
+
+    register(Arc::new(database.clone()), common::mailer(), username.clone(), password.clone(), email.clone(), common::APP_URL)
+        .await
+        .expect("register should succeed");
+    let before = database.get_user_by_username(username.clone()).await.expect("user exists");
+
+    let renamed = common::unique_name("renamed-");
+    let updated = update_profile(Arc::new(database.clone()), common::mailer(), before.id, Some(renamed.clone()), None, common::APP_URL)
+        .await
+        .expect("the profile update should succeed");
+    assert_eq!(updated.username, renamed);
+    assert_eq!(updated.email, email);
+    assert!(updated.updated_at > before.updated_at);
+
+    let session = login(Arc::new(database.clone()), common::login_attempts(&config), renamed.clone(), password.clone(), Ipv4Addr::LOCALHOST.into(), Arc::new(JwtKeys::from_secret(&config.jwt_secret)))
+        .await
+        .expect("login with the new username should succeed");
+
+    let wrong = change_password(Arc::new(database.clone()), before.id, "not-the-password1".to_string(), "newpassword456".to_string(), Arc::new(JwtKeys::from_secret(&config.jwt_secret))).await;
+    assert!(matches!(wrong, Err(AuthError::InvalidCredentials)));
+
+    let tokens = change_password(Arc::new(database.clone()), before.id, password.clone(), "newpassword456".to_string(), Arc::new(JwtKeys::from_secret(&config.jwt_secret)))
+        .await
+        .expect("the password change should succeed");
+
+    // Access tokens issued before the change are refused, the returned one keeps working
+    let issued_before = chrono::Utc::now() - chrono::Duration::minutes(1);
+    assert!(database.is_access_token_revoked(Uuid::new_v4(), before.id, issued_before).await.expect("revocation lookup should succeed"));
+    let claims: Claims = JwtKeys::from_secret(&config.jwt_secret).decode(&tokens.access_token).expect("jwt should decode");
+    let (new_jti, new_issued_at) = (Uuid::parse_str(&claims.jti).unwrap(), chrono::DateTime::from_timestamp(claims.iat as i64, 0).unwrap());
+    assert!(!database.is_access_token_revoked(new_jti, before.id, new_issued_at).await.unwrap());
+
+    let old_session = refresh_tokens(Arc::new(database.clone()), session.refresh_token, Arc::new(JwtKeys::from_secret(&config.jwt_secret))).await;
+    assert!(old_session.is_err());
+    refresh_tokens(Arc::new(database.clone()), tokens.refresh_token, Arc::new(JwtKeys::from_secret(&config.jwt_secret)))
+        .await
+        .expect("the pair returned by the change should keep working");
+
+    common::reset_tables(&pool).await;
+}
@@ -1,9 +1,16 @@
-use std::env;
+// Each integration suite includes this module, and not all of them use every helper.
+#![allow(dead_code)]
+
+use std::{env, path::PathBuf, sync::Arc};
 
 use deadpool_redis::redis::cmd;
 use deadpool_redis::{Config as RedisConfig, Runtime};
-use nebula_backend::infra::database::PostgresDatabase;
+use nebula_backend::infra::{
+    database::PostgresDatabase, mailer::FileMailer, redis::login_attempts::RedisLoginAttempts,
+};
+use nebula_backend::use_cases::realtime_broker::MockMessagePublisher;
 use sqlx::{PgPool, postgres::PgPoolOptions};
+use uuid::Uuid;
 
 #[derive(Clone)]
 pub struct IntegrationConfig {
@@ -25,6 +32,11 @@ impl IntegrationConfig {
     }
 }
 
+/// Usernames are capped at 32 characters, so only a slice of a uuid fits after the prefix
+pub fn unique_name(prefix: &str) -> String {
+    format!("{prefix}{}", &Uuid::new_v4().simple().to_string()[..8])
+}
+
 pub async fn provision_database(config: &IntegrationConfig) -> (PostgresDatabase, PgPool) {
     let database = PostgresDatabase::new(&config.database_url)
         .await
@@ -42,7 +54,7 @@ pub async fn provision_database(config: &IntegrationConfig) -> (PostgresDatabase
 }
 
 pub async fn reset_tables(pool: &PgPool) {
-    sqlx::query("TRUNCATE TABLE messages, room_members, rooms, users RESTART IDENTITY CASCADE;")
+    sqlx::query("TRUNCATE TABLE messages, room_members, rooms, users, revoked_access_tokens, login_lockouts, user_tokens RESTART IDENTITY CASCADE;")
         .execute(pool)
         .await
         .expect("failed to truncate tables for test isolation");
@@ -64,3 +76,62 @@ pub async fn flush_redis(redis_url: &str) {
         .await
         .expect("failed to flush redis state before tests");
 }
+
+/// Without redis the attempts aren't counted, logins still go through
+pub fn login_attempts(config: &IntegrationConfig) -> Arc<RedisLoginAttempts> {
+    let pool = RedisConfig::from_url(&config.redis_url)
+        .create_pool(Some(Runtime::Tokio1))
+        .expect("failed to build redis pool for integration tests");
+
+    Arc::new(RedisLoginAttempts::new(pool))
+}
+
+pub const APP_URL: &str = "https://nebula.test";
+
+fn mail_dir() -> PathBuf {
+    let dir = env::temp_dir().join("nebula-integration-mails");
+    std::fs::create_dir_all(&dir).expect("failed to create the mail directory for integration tests");
+
+    dir
+}
+
+pub fn mailer() -> Arc<FileMailer> {
+    Arc::new(FileMailer::new(mail_dir(), "Nebula <no-reply@nebula.test>"))
+}
+
+/// Publisher for the use cases whose events a test doesn't look at
+pub fn publisher() -> Arc<MockMessagePublisher> {
+    let mut publisher = MockMessagePublisher::new();
+    publisher.expect_publish().returning(|_| Ok(()));
+
+    Arc::new(publisher)
+}
+
+/// Token of the last link emailed to `to` for `path`, as in `reset-password`
+pub fn emailed_token(to: &str, path: &str) -> String {
+    let mut emails: Vec<_> = std::fs::read_dir(mail_dir())
+        .expect("failed to read the mail directory")
+        .filter_map(|entry| entry.ok())
+        .filter(|entry| entry.path().extension().is_some_and(|extension| extension == "eml"))
+        .filter_map(|entry| {
+            let modified = entry.metadata().ok()?.modified().ok()?;
+            // Quoted-printable, long lines are folded with soft line breaks
+            let content = std::fs::read_to_string(entry.path())
+                .ok()?
+                .replace("=\r\n", "")
+                .replace("=3D", "=");
+            Some((modified, content))
+        })
+        .filter(|(_, content)| content.contains(&format!("To: {to}")))
+        .collect();
+    emails.sort_by_key(|(modified, _)| *modified);
+
+    let (_, content) = emails.pop().expect("an email should have been sent");
+    let link = format!("{path}?token=");
+    let start = content.find(&link).expect("the email should have the link") + link.len();
+
+    content[start..]
+        .chars()
+        .take_while(|char| char.is_ascii_alphanumeric() || *char == '-' || *char == '_')
+        .collect()
+}
@@ -1,16 +1,17 @@
-use std::{sync::Arc, time::Duration};
+use std::{net::Ipv4Addr, sync::Arc, time::Duration};
 
 use futures::StreamExt;
-use jsonwebtoken::{DecodingKey, Validation, decode};
 use nebula_backend::{
     domain::{
-        room::{Message, RoomVisibility},
+        event::{EVENT_VERSION, EventEnvelope, RoomEvent},
+        room::{MemberRole, MembershipAction, Message, MessageAnchor, ModerationAction, RoomReadState, RoomVisibility},
     },
     infra::redis::RedisPublisher,
     use_cases::{
-        auth_service::{Claims, login, register},
-        room_service::{create_room, get_all_public_rooms, get_user_rooms_use, join_room, leave_room, obtain_messages, send_message},
-        user_database::UserDatabase,
+        auth_service::{Claims, delete_account, login, register},
+        room_service::{ban_member, change_member_role, create_room, delete_message, delete_room, edit_message, get_all_public_rooms, get_user_rooms_use, get_user_rooms_with_unread, join_room, kick_member, leave_room, mark_room_read, mute_member, obtain_message_edits, obtain_messages, obtain_moderation_log, obtain_room_members, replay_messages, send_message, send_typing, transfer_ownership, unban_user, unmute_member, update_room},
+        room_database::RoomDatabase,
+        user_database::{UserDatabase, UserDatabaseError},
     },
 };
 use tokio::time::timeout;
@@ -20,6 +21,7 @@ use nebula_backend::use_cases::{
     realtime_broker::MockMessagePublisher,
 };
 use nebula_backend::use_cases::room_service::RoomError;
+use nebula_backend::use_cases::jwt_keys::JwtKeys;
 
 #[path = "common/mod.rs"]
 mod common;
@@ -30,21 +32,34 @@ async fn login_and_get_id<T: UserDatabase + Send + Sync + 'static>(
     password: String,
     jwt_secret: &str,
 ) -> Uuid {
-    let token = login(db, identifier, password, jwt_secret.to_string())
+    let config = common::IntegrationConfig::load();
+    let token = login(
+        db,
+        common::login_attempts(&config),
+        identifier,
+        password,
+        Ipv4Addr::LOCALHOST.into(),
+        Arc::new(JwtKeys::from_secret(jwt_secret)),
+    )
         .await
         .expect("login should succeed");
 
-    let claims: Claims = decode(
-        &token,
-        &DecodingKey::from_secret(jwt_secret.as_ref()),
-        &Validation::default(),
-    )
-    .expect("jwt should decode")
-    .claims;
+    let claims: Claims = JwtKeys::from_secret(jwt_secret)
+        .decode(&token.access_token)
+        .expect("jwt should decode");
 
     Uuid::parse_str(&claims.sub).expect("sub should be uuid")
 }
 
+fn membership_publisher() -> MockMessagePublisher {
+    let mut publisher = MockMessagePublisher::new();
+    publisher
+        .expect_publish()
+        .withf(|event| matches!(event, RoomEvent::Membership(_)))
+        .returning(|_| Ok(()));
+    publisher
+}
+
 #[tokio::test]
 #[serial]
 async fn room_lifecycle_persists_and_broadcasts_messages() {
@@ -52,18 +67,18 @@ async fn room_lifecycle_persists_and_broadcasts_messages() {
     let (database, pool) = common::provision_database(&config).await;
     common::flush_redis(&config.redis_url).await;
 
-    let username = format!("integration-room-owner-{}", Uuid::new_v4().simple());
+    let username = common::unique_name("integration-room-owner-");
     let email = format!("{username}@example.com");
     let password = "Password123*".to_string();
 
-    register(Arc::new(database.clone()), username.clone(), password.clone(), email.clone())
+    register(Arc::new(database.clone()), common::mailer(), username.clone(), password.clone(), email.clone(), common::APP_URL)
     .await
     .expect("user registration should succeed");
 
     let owner_id =
         login_and_get_id(Arc::new(database.clone()), username.clone(), password.clone(), &config.jwt_secret).await;
 
-    create_room(Arc::new(database.clone()), RoomVisibility::Public, None, "integration-room".to_string(), owner_id)
+    create_room(Arc::new(database.clone()), RoomVisibility::Public, None, "integration-room".to_string(), owner_id, common::publisher())
     .await
     .expect("room creation should persist to postgres");
 
@@ -84,9 +99,9 @@ async fn room_lifecycle_persists_and_broadcasts_messages() {
         .await
         .expect("failed to open redis pubsub");
     pubsub
-        .subscribe("chat:messages")
+        .subscribe(format!("chat:room:{room_id}"))
         .await
-        .expect("failed to subscribe to chat messages channel");
+        .expect("failed to subscribe to the room channel");
     let mut message_stream = pubsub.into_on_message();
 
     let publisher = Arc::new(RedisPublisher::new(&config.redis_url).await);
@@ -109,10 +124,15 @@ async fn room_lifecycle_persists_and_broadcasts_messages() {
         .expect("listener task should complete")
         .expect("redis payload should parse into string");
 
-    let broadcasted: Message =
-        serde_json::from_str(&payload).expect("redis payload should deserialize into Message");
+    let envelope: EventEnvelope =
+        serde_json::from_str(&payload).expect("redis payload should deserialize into an event");
+    assert_eq!(envelope.version, EVENT_VERSION);
+    let RoomEvent::Message(broadcasted) = envelope.event else {
+        panic!("redis payload should carry a message event");
+    };
     assert_eq!(broadcasted.room_id, room_id);
-    assert_eq!(broadcasted.sender_id, owner_id);
+    assert_eq!(broadcasted.sender_id, Some(owner_id));
+    assert_eq!(broadcasted.sender_username, Some(username));
     assert_eq!(broadcasted.content, content);
 
     let stored: Vec<Message> = sqlx::query_as::<_, Message>(
@@ -135,11 +155,11 @@ async fn private_room_requires_password() {
     let config = common::IntegrationConfig::load();
     let (database, pool) = common::provision_database(&config).await;
 
-    let username = format!("private-no-pass-{}", Uuid::new_v4().simple());
+    let username = common::unique_name("private-no-pass-");
     let email = format!("{username}@example.com");
     let password = "Password123*".to_string();
 
-    register(Arc::new(database.clone()), username.clone(), password.clone(), email.clone())
+    register(Arc::new(database.clone()), common::mailer(), username.clone(), password.clone(), email.clone(), common::APP_URL)
         .await
         .expect("user registration should succeed");
 
@@ -152,6 +172,7 @@ async fn private_room_requires_password() {
         None,
         "no-pass-room".to_string(),
         owner_id,
+        common::publisher(),
     )
     .await;
 
@@ -166,10 +187,10 @@ async fn join_private_room_with_wrong_password_fails() {
     let config = common::IntegrationConfig::load();
     let (database, pool) = common::provision_database(&config).await;
 
-    let owner_name = format!("private-owner-{}", Uuid::new_v4().simple());
+    let owner_name = common::unique_name("private-owner-");
     let owner_email = format!("{owner_name}@example.com");
     let password = "Password123*".to_string();
-    register(Arc::new(database.clone()), owner_name.clone(), password.clone(), owner_email.clone())
+    register(Arc::new(database.clone()), common::mailer(), owner_name.clone(), password.clone(), owner_email.clone(), common::APP_URL)
         .await
         .expect("owner registration should succeed");
     let owner_id =
@@ -181,17 +202,20 @@ async fn join_private_room_with_wrong_password_fails() {
         Some("roomsecret".to_string()),
         "private-room".to_string(),
         owner_id,
+        common::publisher(),
     )
     .await
     .expect("room creation should succeed");
 
-    let joiner_name = format!("joiner-{}", Uuid::new_v4().simple());
+    let joiner_name = common::unique_name("joiner-");
     let joiner_email = format!("{joiner_name}@example.com");
     register(
         Arc::new(database.clone()),
+        common::mailer(),
         joiner_name.clone(),
         password.clone(),
         joiner_email.clone(),
+        common::APP_URL,
     )
     .await
     .expect("joiner registration should succeed");
@@ -212,6 +236,7 @@ async fn join_private_room_with_wrong_password_fails() {
         joiner_id,
         Some("wrongpass".into()),
         Arc::new(notif),
+        Arc::new(MockMessagePublisher::new()),
     )
     .await;
 
@@ -220,16 +245,403 @@ async fn join_private_room_with_wrong_password_fails() {
     common::reset_tables(&pool).await;
 }
 
+#[tokio::test]
+#[serial]
+async fn missing_rows_and_duplicates_are_told_apart() {
+    let config = common::IntegrationConfig::load();
+    let (database, pool) = common::provision_database(&config).await;
+
+    let owner_name = common::unique_name("twice-owner-");
+    register(Arc::new(database.clone()), common::mailer(), owner_name.clone(), "Password123*".to_string(), format!("{owner_name}@example.com"), common::APP_URL)
+        .await
+        .expect("owner registration should succeed");
+    let owner_id = database.get_user_by_username(owner_name.clone()).await.expect("owner should exist").id;
+
+    assert!(matches!(database.get_user_by_id(Uuid::new_v4()).await, Err(UserDatabaseError::NotFound)));
+
+    create_room(Arc::new(database.clone()), RoomVisibility::Public, None, "twice-room".to_string(), owner_id, common::publisher())
+        .await
+        .expect("room creation should succeed");
+    let room_id = get_user_rooms_use(Arc::new(database.clone()), owner_id).await.unwrap().first().unwrap().id;
+
+    let again = join_room(Arc::new(database.clone()), room_id, owner_id, None, Arc::new(MockNotificationService::new()), Arc::new(MockMessagePublisher::new())).await;
+    assert!(matches!(again, Err(RoomError::AlreadyRoomMember)), "got {again:?}");
+
+    let unknown = join_room(Arc::new(database.clone()), Uuid::new_v4(), owner_id, None, Arc::new(MockNotificationService::new()), Arc::new(MockMessagePublisher::new())).await;
+    assert!(matches!(unknown, Err(RoomError::RoomNotFound)), "got {unknown:?}");
+
+    let edits = obtain_message_edits(Arc::new(database.clone()), room_id, Uuid::new_v4(), owner_id).await;
+    assert!(matches!(edits, Err(RoomError::MessageNotInRoom)), "got {edits:?}");
+
+    common::reset_tables(&pool).await;
+}
+
+#[tokio::test]
+#[serial]
+async fn only_members_can_read_post_and_list_members() {
+    let config = common::IntegrationConfig::load();
+    let (database, pool) = common::provision_database(&config).await;
+
+    let password = "Password123*".to_string();
+    let owner_name = common::unique_name("guard-owner-");
+    register(Arc::new(database.clone()), common::mailer(), owner_name.clone(), password.clone(), format!("{owner_name}@example.com"), common::APP_URL)
+        .await
+        .expect("owner registration should succeed");
+    let owner_id = database.get_user_by_username(owner_name).await.expect("owner should exist").id;
+    let outsider_name = common::unique_name("guard-outsider-");
+    register(Arc::new(database.clone()), common::mailer(), outsider_name.clone(), password.clone(), format!("{outsider_name}@example.com"), common::APP_URL)
+        .await
+        .expect("outsider registration should succeed");
+    let outsider_id = database.get_user_by_username(outsider_name).await.expect("outsider should exist").id;
+
+    create_room(Arc::new(database.clone()), RoomVisibility::Private, Some("roomsecret".to_string()), "guarded-room".to_string(), owner_id, common::publisher())
+        .await
+        .expect("room creation should succeed");
+    let room_id = get_user_rooms_use(Arc::new(database.clone()), owner_id).await.unwrap().first().unwrap().id;
+
+    let mut publisher = MockMessagePublisher::new();
+    publisher.expect_publish().returning(|_| Ok(()));
+    let publisher = Arc::new(publisher);
+    let message = send_message(Arc::new(database.clone()), room_id, owner_id, "members only".to_string(), publisher.clone())
+        .await
+        .expect("the owner should be able to post");
+
+    let history = obtain_messages(Arc::new(database.clone()), room_id, outsider_id, MessageAnchor::Latest, 10).await;
+    assert!(matches!(history, Err(RoomError::NotRoomMember)), "got {history:?}");
+    let posted = send_message(Arc::new(database.clone()), room_id, outsider_id, "let me in".to_string(), publisher.clone()).await;
+    assert!(matches!(posted, Err(RoomError::NotRoomMember)), "got {posted:?}");
+    let members = obtain_room_members(Arc::new(database.clone()), room_id, outsider_id).await;
+    assert!(matches!(members, Err(RoomError::NotRoomMember)), "got {members:?}");
+    let edits = obtain_message_edits(Arc::new(database.clone()), room_id, message.id, outsider_id).await;
+    assert!(matches!(edits, Err(RoomError::NotRoomMember)), "got {edits:?}");
+
+    let unknown = obtain_messages(Arc::new(database.clone()), Uuid::new_v4(), outsider_id, MessageAnchor::Latest, 10).await;
+    assert!(matches!(unknown, Err(RoomError::RoomNotFound)), "got {unknown:?}");
+
+    let mut notif = MockNotificationService::new();
+    notif.expect_send_room_member_notification().returning(|_| Ok(()));
+    let notif = Arc::new(notif);
+    join_room(Arc::new(database.clone()), room_id, outsider_id, Some("roomsecret".to_string()), notif.clone(), publisher.clone())
+        .await
+        .expect("joining with the password should succeed");
+
+    let history = obtain_messages(Arc::new(database.clone()), room_id, outsider_id, MessageAnchor::Latest, 10)
+        .await
+        .expect("members should read the history");
+    assert_eq!(history.messages[0].content, "members only");
+    let members = obtain_room_members(Arc::new(database.clone()), room_id, outsider_id)
+        .await
+        .expect("members should list the others");
+    assert_eq!(members.len(), 2);
+    assert_eq!((members[0].user_id, members[0].role), (owner_id, MemberRole::Owner));
+    assert_eq!((members[1].user_id, members[1].role), (outsider_id, MemberRole::Member));
+
+    let own = send_message(Arc::new(database.clone()), room_id, outsider_id, "soon gone".to_string(), publisher.clone())
+        .await
+        .expect("members should post");
+
+    leave_room(Arc::new(database.clone()), notif, publisher.clone(), room_id, outsider_id)
+        .await
+        .expect("leaving should succeed");
+    let posted = send_message(Arc::new(database.clone()), room_id, outsider_id, "still here?".to_string(), publisher.clone()).await;
+    assert!(matches!(posted, Err(RoomError::NotRoomMember)), "got {posted:?}");
+    let deleted = delete_message(Arc::new(database.clone()), room_id, own.id, outsider_id, publisher.clone()).await;
+    assert!(matches!(deleted, Err(RoomError::NotRoomMember)), "got {deleted:?}");
+
+    common::reset_tables(&pool).await;
+}
+
+#[tokio::test]
+#[serial]
+async fn only_the_owner_can_update_and_delete_a_room() {
+    let config = common::IntegrationConfig::load();
+    let (database, pool) = common::provision_database(&config).await;
+
+    let password = "Password123*".to_string();
+    let owner_name = common::unique_name("admin-owner-");
+    register(Arc::new(database.clone()), common::mailer(), owner_name.clone(), password.clone(), format!("{owner_name}@example.com"), common::APP_URL)
+        .await
+        .expect("owner registration should succeed");
+    let owner_id = database.get_user_by_username(owner_name).await.expect("owner should exist").id;
+    let member_name = common::unique_name("admin-member-");
+    register(Arc::new(database.clone()), common::mailer(), member_name.clone(), password.clone(), format!("{member_name}@example.com"), common::APP_URL)
+        .await
+        .expect("member registration should succeed");
+    let member_id = database.get_user_by_username(member_name).await.expect("member should exist").id;
+
+    create_room(Arc::new(database.clone()), RoomVisibility::Private, Some("firstsecret".to_string()), "admin-room".to_string(), owner_id, common::publisher())
+        .await
+        .expect("room creation should succeed");
+    let room_id = get_user_rooms_use(Arc::new(database.clone()), owner_id).await.unwrap().first().unwrap().id;
+
+    let mut publisher = MockMessagePublisher::new();
+    publisher.expect_publish().returning(|_| Ok(()));
+    let publisher = Arc::new(publisher);
+    let mut notif = MockNotificationService::new();
+    notif.expect_send_room_member_notification().returning(|_| Ok(()));
+    let notif = Arc::new(notif);
+
+    let room = update_room(Arc::new(database.clone()), room_id, owner_id, Some("renamed-room".to_string()), None, Some("rotatedsecret".to_string()), publisher.clone())
+        .await
+        .expect("the owner should be able to update the room");
+    assert_eq!(room.name, "renamed-room");
+    assert_eq!(room.visibility, RoomVisibility::Private);
+
+    let old_password = join_room(Arc::new(database.clone()), room_id, member_id, Some("firstsecret".to_string()), notif.clone(), publisher.clone()).await;
+    assert!(matches!(old_password, Err(RoomError::InvalidRoomPassword)), "got {old_password:?}");
+    join_room(Arc::new(database.clone()), room_id, member_id, Some("rotatedsecret".to_string()), notif.clone(), publisher.clone())
+        .await
+        .expect("the rotated password should be accepted");
+
+    let by_member = update_room(Arc::new(database.clone()), room_id, member_id, None, Some(RoomVisibility::Public), None, publisher.clone()).await;
+    assert!(matches!(by_member, Err(RoomError::InsufficientRole)), "got {by_member:?}");
+    let by_member = delete_room(Arc::new(database.clone()), room_id, member_id, publisher.clone()).await;
+    assert!(matches!(by_member, Err(RoomError::InsufficientRole)), "got {by_member:?}");
+
+    update_room(Arc::new(database.clone()), room_id, owner_id, None, Some(RoomVisibility::Public), None, publisher.clone())
+        .await
+        .expect("the owner should be able to make the room public");
+    let public_rooms = get_all_public_rooms(Arc::new(database.clone()), owner_id).await.unwrap();
+    let summary = public_rooms.iter().find(|room| room.id == room_id).expect("the room should be public now");
+    assert!(!summary.has_password);
+
+    send_message(Arc::new(database.clone()), room_id, member_id, "soon gone".to_string(), publisher.clone())
+        .await
+        .expect("the member should be able to post");
+    delete_room(Arc::new(database.clone()), room_id, owner_id, publisher.clone())
+        .await
+        .expect("the owner should be able to delete the room");
+
+    let history = obtain_messages(Arc::new(database.clone()), room_id, member_id, MessageAnchor::Latest, 10).await;
+    assert!(matches!(history, Err(RoomError::RoomNotFound)), "got {history:?}");
+    assert!(get_user_rooms_use(Arc::new(database.clone()), member_id).await.unwrap().is_empty());
+    let again = delete_room(Arc::new(database.clone()), room_id, owner_id, publisher).await;
+    assert!(matches!(again, Err(RoomError::RoomNotFound)), "got {again:?}");
+
+    common::reset_tables(&pool).await;
+}
+
+#[tokio::test]
+#[serial]
+async fn roles_grant_their_permissions() {
+    let config = common::IntegrationConfig::load();
+    let (database, pool) = common::provision_database(&config).await;
+
+    let password = "Password123*".to_string();
+    let mut ids = Vec::new();
+    for prefix in ["roles-owner-", "roles-admin-", "roles-mod-", "roles-member-"] {
+        let name = common::unique_name(prefix);
+        register(Arc::new(database.clone()), common::mailer(), name.clone(), password.clone(), format!("{name}@example.com"), common::APP_URL)
+            .await
+            .expect("registration should succeed");
+        ids.push(database.get_user_by_username(name).await.expect("user should exist").id);
+    }
+    let [owner_id, admin_id, moderator_id, member_id] = ids[..] else { unreachable!() };
+
+    create_room(Arc::new(database.clone()), RoomVisibility::Public, None, "roles-room".to_string(), owner_id, common::publisher())
+        .await
+        .expect("room creation should succeed");
+    let room_id = get_user_rooms_use(Arc::new(database.clone()), owner_id).await.unwrap().first().unwrap().id;
+
+    let mut publisher = MockMessagePublisher::new();
+    publisher.expect_publish().returning(|_| Ok(()));
+    let publisher = Arc::new(publisher);
+    let mut notif = MockNotificationService::new();
+    notif.expect_send_room_member_notification().returning(|_| Ok(()));
+    let notif = Arc::new(notif);
+    for user_id in [admin_id, moderator_id, member_id] {
+        join_room(Arc::new(database.clone()), room_id, user_id, None, notif.clone(), publisher.clone())
+            .await
+            .expect("joining should succeed");
+    }
+
+    let admin = change_member_role(Arc::new(database.clone()), room_id, owner_id, admin_id, MemberRole::Admin, publisher.clone())
+        .await
+        .expect("the owner should be able to promote to admin");
+    assert_eq!(admin.role, MemberRole::Admin);
+    change_member_role(Arc::new(database.clone()), room_id, admin_id, moderator_id, MemberRole::Moderator, publisher.clone())
+        .await
+        .expect("admins should be able to promote to moderator");
+    assert_eq!(database.get_member_role(room_id, moderator_id).await.unwrap(), Some(MemberRole::Moderator));
+
+    let over_owner = change_member_role(Arc::new(database.clone()), room_id, admin_id, owner_id, MemberRole::Member, publisher.clone()).await;
+    assert!(matches!(over_owner, Err(RoomError::InsufficientRole)), "got {over_owner:?}");
+    let by_moderator = change_member_role(Arc::new(database.clone()), room_id, moderator_id, member_id, MemberRole::Moderator, publisher.clone()).await;
+    assert!(matches!(by_moderator, Err(RoomError::InsufficientRole)), "got {by_moderator:?}");
+
+    update_room(Arc::new(database.clone()), room_id, admin_id, Some("renamed-by-admin".to_string()), None, None, publisher.clone())
+        .await
+        .expect("admins should be able to edit the room");
+    let by_moderator = update_room(Arc::new(database.clone()), room_id, moderator_id, Some("renamed-by-mod".to_string()), None, None, publisher.clone()).await;
+    assert!(matches!(by_moderator, Err(RoomError::InsufficientRole)), "got {by_moderator:?}");
+    let by_admin = delete_room(Arc::new(database.clone()), room_id, admin_id, publisher.clone()).await;
+    assert!(matches!(by_admin, Err(RoomError::InsufficientRole)), "got {by_admin:?}");
+
+    let message = send_message(Arc::new(database.clone()), room_id, member_id, "moderate me".to_string(), publisher.clone())
+        .await
+        .expect("members should be able to post");
+    delete_message(Arc::new(database.clone()), room_id, message.id, moderator_id, publisher.clone())
+        .await
+        .expect("moderators should be able to delete the messages of others");
+
+    change_member_role(Arc::new(database.clone()), room_id, owner_id, moderator_id, MemberRole::Member, publisher.clone())
+        .await
+        .expect("the owner should be able to demote");
+    let message = send_message(Arc::new(database.clone()), room_id, member_id, "not anymore".to_string(), publisher.clone()).await.unwrap();
+    let demoted = delete_message(Arc::new(database.clone()), room_id, message.id, moderator_id, publisher).await;
+    assert!(matches!(demoted, Err(RoomError::NotMessageSender)), "got {demoted:?}");
+
+    common::reset_tables(&pool).await;
+}
+
+#[tokio::test]
+#[serial]
+async fn moderators_kick_ban_and_mute_and_every_action_is_logged() {
+    let config = common::IntegrationConfig::load();
+    let (database, pool) = common::provision_database(&config).await;
+
+    let password = "Password123*".to_string();
+    let mut ids = Vec::new();
+    for prefix in ["mod-owner-", "mod-moderator-", "mod-member-"] {
+        let name = common::unique_name(prefix);
+        register(Arc::new(database.clone()), common::mailer(), name.clone(), password.clone(), format!("{name}@example.com"), common::APP_URL)
+            .await
+            .expect("registration should succeed");
+        ids.push(database.get_user_by_username(name).await.expect("user should exist").id);
+    }
+    let [owner_id, moderator_id, member_id] = ids[..] else { unreachable!() };
+
+    create_room(Arc::new(database.clone()), RoomVisibility::Private, Some("modsecret".to_string()), "moderated-room".to_string(), owner_id, common::publisher())
+        .await
+        .expect("room creation should succeed");
+    let room_id = get_user_rooms_use(Arc::new(database.clone()), owner_id).await.unwrap().first().unwrap().id;
+
+    let mut publisher = MockMessagePublisher::new();
+    publisher.expect_publish().returning(|_| Ok(()));
+    let publisher = Arc::new(publisher);
+    let mut notif = MockNotificationService::new();
+    notif.expect_send_room_member_notification().returning(|_| Ok(()));
+    let notif = Arc::new(notif);
+    for user_id in [moderator_id, member_id] {
+        join_room(Arc::new(database.clone()), room_id, user_id, Some("modsecret".to_string()), notif.clone(), publisher.clone())
+            .await
+            .expect("joining should succeed");
+    }
+    change_member_role(Arc::new(database.clone()), room_id, owner_id, moderator_id, MemberRole::Moderator, publisher.clone()).await.unwrap();
+
+    let spoken = send_message(Arc::new(database.clone()), room_id, member_id, "before the mute".to_string(), publisher.clone())
+        .await
+        .expect("members should be able to post");
+    mute_member(Arc::new(database.clone()), room_id, moderator_id, member_id, Some("calm down".to_string()), chrono::Utc::now() + chrono::Duration::minutes(10), publisher.clone())
+        .await
+        .expect("moderators should be able to mute");
+    let muted = send_message(Arc::new(database.clone()), room_id, member_id, "let me talk".to_string(), publisher.clone()).await;
+    assert!(matches!(muted, Err(RoomError::Muted(Some(_)))), "got {muted:?}");
+    let edited = edit_message(Arc::new(database.clone()), room_id, spoken.id, member_id, "still talking".to_string(), publisher.clone()).await;
+    assert!(matches!(edited, Err(RoomError::Muted(Some(_)))), "got {edited:?}");
+    let typing = send_typing(Arc::new(database.clone()), room_id, member_id, true, publisher.clone()).await;
+    assert!(matches!(typing, Err(RoomError::Muted(Some(_)))), "got {typing:?}");
+    unmute_member(Arc::new(database.clone()), room_id, moderator_id, member_id, publisher.clone())
+        .await
+        .expect("moderators should be able to unmute");
+    send_message(Arc::new(database.clone()), room_id, member_id, "thanks".to_string(), publisher.clone())
+        .await
+        .expect("unmuted members should be able to post");
+
+    kick_member(Arc::new(database.clone()), room_id, moderator_id, member_id, None, publisher.clone())
+        .await
+        .expect("moderators should be able to kick");
+    assert_eq!(database.get_member_role(room_id, member_id).await.unwrap(), None);
+    join_room(Arc::new(database.clone()), room_id, member_id, Some("modsecret".to_string()), notif.clone(), publisher.clone())
+        .await
+        .expect("kicked members should be able to join again");
+
+    let by_moderator = ban_member(Arc::new(database.clone()), room_id, moderator_id, member_id, None, None, publisher.clone()).await;
+    assert!(matches!(by_moderator, Err(RoomError::InsufficientRole)), "got {by_moderator:?}");
+    let over_owner = kick_member(Arc::new(database.clone()), room_id, moderator_id, owner_id, None, publisher.clone()).await;
+    assert!(matches!(over_owner, Err(RoomError::InsufficientRole)), "got {over_owner:?}");
+
+    ban_member(Arc::new(database.clone()), room_id, owner_id, member_id, Some("spam".to_string()), None, publisher.clone())
+        .await
+        .expect("the owner should be able to ban");
+    let banned = join_room(Arc::new(database.clone()), room_id, member_id, Some("modsecret".to_string()), notif.clone(), publisher.clone()).await;
+    assert!(matches!(banned, Err(RoomError::Banned(None))), "got {banned:?}");
+    let edited = edit_message(Arc::new(database.clone()), room_id, spoken.id, member_id, "from outside".to_string(), publisher.clone()).await;
+    assert!(matches!(edited, Err(RoomError::NotRoomMember)), "got {edited:?}");
+    let typing = send_typing(Arc::new(database.clone()), room_id, member_id, true, publisher.clone()).await;
+    assert!(matches!(typing, Err(RoomError::NotRoomMember)), "got {typing:?}");
+    unban_user(Arc::new(database.clone()), room_id, owner_id, member_id, publisher.clone())
+        .await
+        .expect("the owner should be able to unban");
+    let again = unban_user(Arc::new(database.clone()), room_id, owner_id, member_id, publisher.clone()).await;
+    assert!(matches!(again, Err(RoomError::NotSanctioned)), "got {again:?}");
+    join_room(Arc::new(database.clone()), room_id, member_id, Some("modsecret".to_string()), notif, publisher.clone())
+        .await
+        .expect("unbanned users should be able to join again");
+
+    let by_member = obtain_moderation_log(Arc::new(database.clone()), room_id, member_id, 50).await;
+    assert!(matches!(by_member, Err(RoomError::InsufficientRole)), "got {by_member:?}");
+    let log = obtain_moderation_log(Arc::new(database.clone()), room_id, moderator_id, 50)
+        .await
+        .expect("moderators should be able to read the log");
+    let actions: Vec<ModerationAction> = log.iter().map(|entry| entry.action).collect();
+    assert_eq!(actions, vec![ModerationAction::Unban, ModerationAction::Ban, ModerationAction::Kick, ModerationAction::Unmute, ModerationAction::Mute]);
+    assert_eq!(log[1].reason.as_deref(), Some("spam"));
+    assert_eq!(log[1].moderator_id, Some(owner_id));
+
+    common::reset_tables(&pool).await;
+}
+
+#[tokio::test]
+#[serial]
+async fn users_banned_before_joining_cannot_join() {
+    let config = common::IntegrationConfig::load();
+    let (database, pool) = common::provision_database(&config).await;
+
+    let password = "Password123*".to_string();
+    let mut ids = Vec::new();
+    for prefix in ["ban-owner-", "ban-outsider-"] {
+        let name = common::unique_name(prefix);
+        register(Arc::new(database.clone()), common::mailer(), name.clone(), password.clone(), format!("{name}@example.com"), common::APP_URL)
+            .await
+            .expect("registration should succeed");
+        ids.push(database.get_user_by_username(name).await.expect("user should exist").id);
+    }
+    let [owner_id, outsider_id] = ids[..] else { unreachable!() };
+
+    create_room(Arc::new(database.clone()), RoomVisibility::Public, None, "guarded-room".to_string(), owner_id, common::publisher())
+        .await
+        .expect("room creation should succeed");
+    let room_id = get_user_rooms_use(Arc::new(database.clone()), owner_id).await.unwrap().first().unwrap().id;
+
+    let not_member = kick_member(Arc::new(database.clone()), room_id, owner_id, outsider_id, None, common::publisher()).await;
+    assert!(matches!(not_member, Err(RoomError::MemberNotFound)), "got {not_member:?}");
+    let unknown = ban_member(Arc::new(database.clone()), room_id, owner_id, Uuid::new_v4(), None, None, common::publisher()).await;
+    assert!(matches!(unknown, Err(RoomError::MemberNotFound)), "got {unknown:?}");
+
+    ban_member(Arc::new(database.clone()), room_id, owner_id, outsider_id, Some("known troll".to_string()), None, common::publisher())
+        .await
+        .expect("users who never joined should be bannable");
+
+    let mut notif = MockNotificationService::new();
+    notif.expect_send_room_member_notification().never();
+    let joined = join_room(Arc::new(database.clone()), room_id, outsider_id, None, Arc::new(notif), common::publisher()).await;
+    assert!(matches!(joined, Err(RoomError::Banned(None))), "got {joined:?}");
+    assert_eq!(database.get_member_role(room_id, outsider_id).await.unwrap(), None);
+
+    common::reset_tables(&pool).await;
+}
+
 #[tokio::test]
 #[serial]
 async fn join_and_leave_private_room_succeeds() {
     let config = common::IntegrationConfig::load();
     let (database, pool) = common::provision_database(&config).await;
 
-    let owner_name = format!("private-owner-{}", Uuid::new_v4().simple());
+    let owner_name = common::unique_name("private-owner-");
     let owner_email = format!("{owner_name}@example.com");
     let password = "Password123*".to_string();
-    register(Arc::new(database.clone()), owner_name.clone(), password.clone(), owner_email.clone())
+    register(Arc::new(database.clone()), common::mailer(), owner_name.clone(), password.clone(), owner_email.clone(), common::APP_URL)
         .await
         .expect("owner registration should succeed");
     let owner_id =
@@ -241,17 +653,20 @@ async fn join_and_leave_private_room_succeeds() {
         Some("roomsecret".to_string()),
         "private-room".to_string(),
         owner_id,
+        common::publisher(),
     )
     .await
     .expect("room creation should succeed");
 
-    let joiner_name = format!("joiner-{}", Uuid::new_v4().simple());
+    let joiner_name = common::unique_name("joiner-");
     let joiner_email = format!("{joiner_name}@example.com");
     register(
         Arc::new(database.clone()),
+        common::mailer(),
         joiner_name.clone(),
         password.clone(),
         joiner_email.clone(),
+        common::APP_URL,
     )
     .await
     .expect("joiner registration should succeed");
@@ -276,6 +691,7 @@ async fn join_and_leave_private_room_succeeds() {
         joiner_id,
         Some("roomsecret".into()),
         Arc::new(notif),
+        Arc::new(membership_publisher()),
     )
     .await
     .expect("join should work");
@@ -288,6 +704,7 @@ async fn join_and_leave_private_room_succeeds() {
     leave_room(
         Arc::new(database.clone()),
         Arc::new(notif_leave),
+        Arc::new(membership_publisher()),
         room_id,
         joiner_id,
     )
@@ -303,10 +720,10 @@ async fn get_all_public_rooms_lists_created_room() {
     let config = common::IntegrationConfig::load();
     let (database, pool) = common::provision_database(&config).await;
 
-    let username = format!("public-owner-{}", Uuid::new_v4().simple());
+    let username = common::unique_name("public-owner-");
     let email = format!("{username}@example.com");
     let password = "Password123*".to_string();
-    register(Arc::new(database.clone()), username.clone(), password.clone(), email.clone())
+    register(Arc::new(database.clone()), common::mailer(), username.clone(), password.clone(), email.clone(), common::APP_URL)
         .await
         .expect("user registration should succeed");
     let owner_id =
@@ -318,16 +735,20 @@ async fn get_all_public_rooms_lists_created_room() {
         None,
         "public-room".to_string(),
         owner_id,
+        common::publisher(),
     )
     .await
     .expect("room creation should succeed");
 
-    let rooms = get_all_public_rooms(Arc::new(database.clone()))
+    let rooms = get_all_public_rooms(Arc::new(database.clone()), owner_id)
         .await
         .expect("should list public rooms");
 
     assert_eq!(rooms.len(), 1);
     assert_eq!(rooms[0].name, "public-room");
+    assert_eq!(rooms[0].member_count, 1);
+    assert!(rooms[0].is_member);
+    assert!(!rooms[0].has_password);
 
     common::reset_tables(&pool).await;
 }
@@ -338,10 +759,10 @@ async fn obtain_messages_respects_pagination() {
     let config = common::IntegrationConfig::load();
     let (database, pool) = common::provision_database(&config).await;
 
-    let username = format!("pagination-owner-{}", Uuid::new_v4().simple());
+    let username = common::unique_name("pagination-owner-");
     let email = format!("{username}@example.com");
     let password = "Password123*".to_string();
-    register(Arc::new(database.clone()), username.clone(), password.clone(), email.clone())
+    register(Arc::new(database.clone()), common::mailer(), username.clone(), password.clone(), email.clone(), common::APP_URL)
         .await
         .expect("user registration should succeed");
     let owner_id =
@@ -353,6 +774,7 @@ async fn obtain_messages_respects_pagination() {
         None,
         "pagination-room".to_string(),
         owner_id,
+        common::publisher(),
     )
     .await
     .expect("room creation should succeed");
@@ -366,7 +788,8 @@ async fn obtain_messages_respects_pagination() {
 
     let mut publisher = MockMessagePublisher::new();
     publisher
-        .expect_broadcast_message()
+        .expect_publish()
+        .withf(|event| matches!(event, RoomEvent::Message(_)))
         .times(15)
         .returning(|_| Ok(()));
     let publisher = Arc::new(publisher);
@@ -383,16 +806,662 @@ async fn obtain_messages_respects_pagination() {
         .expect("message should be stored");
     }
 
-    let page_one = obtain_messages(Arc::new(database.clone()), 1, 10, room_id)
+    let page_one = obtain_messages(Arc::new(database.clone()), room_id, owner_id, MessageAnchor::Latest, 10)
         .await
         .expect("page one should succeed");
-    let page_two = obtain_messages(Arc::new(database.clone()), 2, 10, room_id)
+    assert_eq!(page_one.messages.len(), 10);
+    assert_eq!(page_one.messages[0].content, "msg-14");
+    assert_eq!(page_one.prev_cursor, None);
+
+    let next_cursor = page_one.next_cursor.expect("older messages should be left");
+    let page_two = obtain_messages(
+        Arc::new(database.clone()),
+        room_id,
+        owner_id,
+        MessageAnchor::Before(next_cursor),
+        10,
+    )
+    .await
+    .expect("page two should succeed");
+    assert_eq!(page_two.messages.len(), 5);
+    assert_eq!(page_two.messages[0].content, "msg-4");
+    assert_eq!(page_two.messages[4].content, "msg-0");
+    assert_eq!(page_two.next_cursor, None);
+
+    let prev_cursor = page_two.prev_cursor.expect("newer messages should be left");
+    let newer = obtain_messages(
+        Arc::new(database.clone()),
+        room_id,
+        owner_id,
+        MessageAnchor::After(prev_cursor),
+        3,
+    )
+    .await
+    .expect("newer page should succeed");
+    let contents: Vec<&str> = newer.messages.iter().map(|m| m.content.as_str()).collect();
+    assert_eq!(contents, vec!["msg-7", "msg-6", "msg-5"]);
+    assert!(newer.prev_cursor.is_some());
+
+    let around = obtain_messages(
+        Arc::new(database.clone()),
+        room_id,
+        owner_id,
+        MessageAnchor::Around(page_one.messages[5].id),
+        5,
+    )
+    .await
+    .expect("page around a message should succeed");
+    let contents: Vec<&str> = around.messages.iter().map(|m| m.content.as_str()).collect();
+    assert_eq!(contents, vec!["msg-11", "msg-10", "msg-9", "msg-8", "msg-7"]);
+
+    common::reset_tables(&pool).await;
+}
+
+#[tokio::test]
+#[serial]
+async fn replay_returns_the_messages_sent_after_the_last_one() {
+    let config = common::IntegrationConfig::load();
+    let (database, pool) = common::provision_database(&config).await;
+
+    let username = common::unique_name("replay-owner-");
+    let password = "Password123*".to_string();
+    register(
+        Arc::new(database.clone()),
+        common::mailer(),
+        username.clone(),
+        password.clone(),
+        format!("{username}@example.com"),
+        common::APP_URL,
+    )
+    .await
+    .expect("user registration should succeed");
+    let owner_id =
+        login_and_get_id(Arc::new(database.clone()), username, password, &config.jwt_secret).await;
+
+    for name in ["replay-room-a", "replay-room-b"] {
+        create_room(
+            Arc::new(database.clone()),
+            RoomVisibility::Public,
+            None,
+            name.to_string(),
+            owner_id,
+            common::publisher(),
+        )
+        .await
+        .expect("room creation should succeed");
+    }
+
+    let room_ids: Vec<Uuid> = get_user_rooms_use(Arc::new(database.clone()), owner_id)
+        .await
+        .unwrap()
+        .iter()
+        .map(|room| room.id)
+        .collect();
+
+    let mut publisher = MockMessagePublisher::new();
+    publisher.expect_publish().returning(|_| Ok(()));
+    let publisher = Arc::new(publisher);
+
+    let mut sent = Vec::new();
+    for idx in 0..6 {
+        let message = send_message(
+            Arc::new(database.clone()),
+            room_ids[idx % 2],
+            owner_id,
+            format!("msg-{idx}"),
+            publisher.clone(),
+        )
+        .await
+        .expect("message should be stored");
+        sent.push(message.id);
+    }
+
+    let replay = replay_messages(Arc::new(database.clone()), room_ids.clone(), sent[1], 10)
+        .await
+        .expect("replay should succeed")
+        .expect("the missed messages fit in the limit");
+    let replayed: Vec<Uuid> = replay.iter().map(|message| message.id).collect();
+    assert_eq!(replayed, sent[2..].to_vec());
+
+    let too_many = replay_messages(Arc::new(database.clone()), room_ids, sent[0], 3)
+        .await
+        .expect("replay should succeed");
+    assert!(too_many.is_none());
+
+    common::reset_tables(&pool).await;
+}
+
+#[tokio::test]
+#[serial]
+async fn edited_and_deleted_messages_keep_their_history() {
+    let config = common::IntegrationConfig::load();
+    let (database, pool) = common::provision_database(&config).await;
+
+    let password = "Password123*".to_string();
+    let owner_name = common::unique_name("edit-owner-");
+    register(
+        Arc::new(database.clone()),
+        common::mailer(),
+        owner_name.clone(),
+        password.clone(),
+        format!("{owner_name}@example.com"),
+        common::APP_URL,
+    )
+    .await
+    .expect("owner registration should succeed");
+    let owner_id =
+        login_and_get_id(Arc::new(database.clone()), owner_name, password.clone(), &config.jwt_secret).await;
+
+    let member_name = common::unique_name("edit-member-");
+    register(
+        Arc::new(database.clone()),
+        common::mailer(),
+        member_name.clone(),
+        password.clone(),
+        format!("{member_name}@example.com"),
+        common::APP_URL,
+    )
+    .await
+    .expect("member registration should succeed");
+    let member_id =
+        login_and_get_id(Arc::new(database.clone()), member_name, password, &config.jwt_secret).await;
+
+    create_room(
+        Arc::new(database.clone()),
+        RoomVisibility::Public,
+        None,
+        "edit-room".to_string(),
+        owner_id,
+        common::publisher(),
+    )
+    .await
+    .expect("room creation should succeed");
+
+    let room_id = get_user_rooms_use(Arc::new(database.clone()), owner_id)
         .await
-        .expect("page two should succeed");
+        .unwrap()
+        .first()
+        .unwrap()
+        .id;
 
-    assert_eq!(page_one.len(), 10);
-    assert_eq!(page_two.len(), 5);
+    let mut notif = MockNotificationService::new();
+    notif
+        .expect_send_room_member_notification()
+        .returning(|_| Ok(()));
+    join_room(
+        Arc::new(database.clone()),
+        room_id,
+        member_id,
+        None,
+        Arc::new(notif),
+        Arc::new(membership_publisher()),
+    )
+    .await
+    .expect("join should work");
+
+    let mut publisher = MockMessagePublisher::new();
+    publisher
+        .expect_publish()
+        .withf(|event| matches!(event, RoomEvent::Message(_)))
+        .returning(|_| Ok(()));
+    publisher
+        .expect_publish()
+        .withf(|event| matches!(event, RoomEvent::MessageEdited(_)))
+        .times(2)
+        .returning(|_| Ok(()));
+    publisher
+        .expect_publish()
+        .withf(|event| matches!(event, RoomEvent::MessageDeleted(_)))
+        .times(1)
+        .returning(|_| Ok(()));
+    let publisher = Arc::new(publisher);
+
+    let message = send_message(
+        Arc::new(database.clone()),
+        room_id,
+        member_id,
+        "first".to_string(),
+        publisher.clone(),
+    )
+    .await
+    .expect("message should be stored");
+
+    for content in ["second", "third"] {
+        edit_message(
+            Arc::new(database.clone()),
+            room_id,
+            message.id,
+            member_id,
+            content.to_string(),
+            publisher.clone(),
+        )
+        .await
+        .expect("the sender should be able to edit");
+    }
+
+    let not_sender = edit_message(
+        Arc::new(database.clone()),
+        room_id,
+        message.id,
+        owner_id,
+        "hijacked".to_string(),
+        publisher.clone(),
+    )
+    .await;
+    assert!(matches!(not_sender, Err(RoomError::NotMessageSender)));
+
+    let edits = obtain_message_edits(Arc::new(database.clone()), room_id, message.id, member_id)
+        .await
+        .expect("history should be listed");
+    let previous: Vec<&str> = edits.iter().map(|edit| edit.previous_content.as_str()).collect();
+    assert_eq!(previous, vec!["second", "first"]);
+
+    delete_message(
+        Arc::new(database.clone()),
+        room_id,
+        message.id,
+        owner_id,
+        publisher.clone(),
+    )
+    .await
+    .expect("the room owner should be able to delete");
+
+    let page = obtain_messages(Arc::new(database.clone()), room_id, member_id, MessageAnchor::Latest, 10)
+        .await
+        .expect("messages should be listed");
+    assert_eq!(page.messages[0].content, "");
+    assert!(page.messages[0].edited_at.is_some());
+    assert!(page.messages[0].deleted_at.is_some());
+
+    common::reset_tables(&pool).await;
+}
+
+#[tokio::test]
+#[serial]
+async fn unread_counts_follow_the_read_marker() {
+    let config = common::IntegrationConfig::load();
+    let (database, pool) = common::provision_database(&config).await;
+
+    let password = "Password123*".to_string();
+    let owner_name = common::unique_name("read-owner-");
+    register(
+        Arc::new(database.clone()),
+        common::mailer(),
+        owner_name.clone(),
+        password.clone(),
+        format!("{owner_name}@example.com"),
+        common::APP_URL,
+    )
+    .await
+    .expect("owner registration should succeed");
+    let owner_id =
+        login_and_get_id(Arc::new(database.clone()), owner_name, password.clone(), &config.jwt_secret).await;
+
+    let reader_name = common::unique_name("read-member-");
+    register(
+        Arc::new(database.clone()),
+        common::mailer(),
+        reader_name.clone(),
+        password.clone(),
+        format!("{reader_name}@example.com"),
+        common::APP_URL,
+    )
+    .await
+    .expect("reader registration should succeed");
+    let reader_id =
+        login_and_get_id(Arc::new(database.clone()), reader_name, password.clone(), &config.jwt_secret).await;
+
+    create_room(
+        Arc::new(database.clone()),
+        RoomVisibility::Public,
+        None,
+        "read-room".to_string(),
+        owner_id,
+        common::publisher(),
+    )
+    .await
+    .expect("room creation should succeed");
+
+    let room_id = get_user_rooms_use(Arc::new(database.clone()), owner_id)
+        .await
+        .unwrap()
+        .first()
+        .unwrap()
+        .id;
+
+    let mut notif = MockNotificationService::new();
+    notif
+        .expect_send_room_member_notification()
+        .returning(|_| Ok(()));
+    join_room(
+        Arc::new(database.clone()),
+        room_id,
+        reader_id,
+        None,
+        Arc::new(notif),
+        Arc::new(membership_publisher()),
+    )
+    .await
+    .expect("join should work");
+
+    let mut publisher = MockMessagePublisher::new();
+    publisher
+        .expect_publish()
+        .withf(|event| matches!(event, RoomEvent::Message(_)))
+        .returning(|_| Ok(()));
+    publisher
+        .expect_publish()
+        .withf(|event| matches!(event, RoomEvent::Read(_)))
+        .times(1)
+        .returning(|_| Ok(()));
+    let publisher = Arc::new(publisher);
+
+    for idx in 0..3 {
+        send_message(
+            Arc::new(database.clone()),
+            room_id,
+            owner_id,
+            format!("msg-{idx}"),
+            publisher.clone(),
+        )
+        .await
+        .expect("message should be stored");
+    }
+
+    let unread = get_user_rooms_with_unread(Arc::new(database.clone()), reader_id)
+        .await
+        .expect("rooms should be listed");
+    assert_eq!(unread[0].unread_count, 3);
+
+    let own_unread = get_user_rooms_with_unread(Arc::new(database.clone()), owner_id)
+        .await
+        .expect("rooms should be listed");
+    assert_eq!(own_unread[0].unread_count, 0);
+
+    // Newest first, so index 1 is the second message sent.
+    let messages = obtain_messages(Arc::new(database.clone()), room_id, reader_id, MessageAnchor::Latest, 10)
+        .await
+        .expect("messages should be listed")
+        .messages;
+
+    mark_room_read(
+        Arc::new(database.clone()),
+        room_id,
+        reader_id,
+        messages[1].id,
+        publisher.clone(),
+    )
+    .await
+    .expect("marking as read should work");
+
+    // Moving the marker backwards is ignored and not broadcast again.
+    mark_room_read(
+        Arc::new(database.clone()),
+        room_id,
+        reader_id,
+        messages[2].id,
+        publisher.clone(),
+    )
+    .await
+    .expect("marking an older message should be a no-op");
+
+    let unread = get_user_rooms_with_unread(Arc::new(database.clone()), reader_id)
+        .await
+        .expect("rooms should be listed");
+    assert_eq!(unread[0].unread_count, 1);
+
+    // Messages sent at the same time are ordered by id, as in the history
+    sqlx::query("UPDATE messages SET created_at = $2 WHERE room_id = $1").bind(room_id).bind(chrono::Utc::now()).execute(&pool).await.unwrap();
+    sqlx::query("DELETE FROM room_read_state WHERE user_id = $1").bind(reader_id).execute(&pool).await.unwrap();
+    let tied = obtain_messages(Arc::new(database.clone()), room_id, reader_id, MessageAnchor::Latest, 10).await.unwrap().messages;
+    let read_up_to = |message_id: Uuid| RoomReadState { room_id, user_id: reader_id, last_read_message_id: Some(message_id), last_read_at: chrono::Utc::now() };
+    assert!(database.update_read_state(read_up_to(tied[1].id)).await.unwrap());
+    assert!(!database.update_read_state(read_up_to(tied[2].id)).await.unwrap(), "the marker should not move back to an earlier id");
+    let unread = get_user_rooms_with_unread(Arc::new(database.clone()), reader_id).await.unwrap();
+    assert_eq!(unread[0].unread_count, 1);
+    assert!(database.update_read_state(read_up_to(tied[0].id)).await.unwrap());
 
     common::reset_tables(&pool).await;
 }
 use serial_test::serial;
+
+#[tokio::test]
+#[serial]
+async fn stream_consumer_resumes_after_a_restart() {
+    use nebula_backend::{
+        domain::room::TypingIndicator,
+        infra::redis::streams::{RedisStreamConsumer, RedisStreamPublisher, StreamRetention},
+        use_cases::realtime_broker::{EventTopic, MessagePublisher, MessageSubscriber},
+    };
+
+    let config = common::IntegrationConfig::load();
+    common::flush_redis(&config.redis_url).await;
+
+    let retention = StreamRetention { max_len: 100, max_age: Duration::from_secs(60) };
+    let instance_id = format!("integration-{}", Uuid::new_v4().simple());
+    let room_id = Uuid::new_v4();
+    let typing = |user_id| RoomEvent::Typing(TypingIndicator { room_id, user_id, is_typing: true });
+
+    let publisher = RedisStreamPublisher::new(&config.redis_url, retention);
+    let mut consumer = RedisStreamConsumer::new(&config.redis_url, &instance_id, retention).await;
+    consumer
+        .subscribe(EventTopic::Room(room_id))
+        .await
+        .expect("stream subscription should succeed");
+
+    let first_user = Uuid::new_v4();
+    publisher.publish(typing(first_user)).await.expect("first event should be appended");
+
+    let (topic, envelope) = timeout(Duration::from_secs(5), consumer.consume_event())
+        .await
+        .expect("first event should arrive in time")
+        .expect("first event should decode");
+    assert_eq!(topic, EventTopic::Room(room_id));
+    assert!(matches!(envelope.event, RoomEvent::Typing(indicator) if indicator.user_id == first_user));
+
+    // Events published while the instance is down are read once it subscribes again
+    drop(consumer);
+    let second_user = Uuid::new_v4();
+    publisher.publish(typing(second_user)).await.expect("second event should be appended");
+
+    let mut consumer = RedisStreamConsumer::new(&config.redis_url, &instance_id, retention).await;
+    consumer
+        .subscribe(EventTopic::Room(room_id))
+        .await
+        .expect("stream subscription should succeed");
+
+    let (_, envelope) = timeout(Duration::from_secs(5), consumer.consume_event())
+        .await
+        .expect("missed event should arrive in time")
+        .expect("missed event should decode");
+    assert!(matches!(envelope.event, RoomEvent::Typing(indicator) if indicator.user_id == second_user));
+}
+
+#[tokio::test]
+#[serial]
+async fn deleting_an_account_hands_its_rooms_over_or_archives_them() {
+    let config = common::IntegrationConfig::load();
+    let (database, pool) = common::provision_database(&config).await;
+
+    let password = "Password123*".to_string();
+    let owner_name = common::unique_name("leaving-owner-");
+    register(Arc::new(database.clone()), common::mailer(), owner_name.clone(), password.clone(), format!("{owner_name}@example.com"), common::APP_URL)
+        .await
+        .expect("owner registration should succeed");
+    let owner_id =
+        login_and_get_id(Arc::new(database.clone()), owner_name.clone(), password.clone(), &config.jwt_secret).await;
+
+    let member_name = common::unique_name("heir-");
+    register(Arc::new(database.clone()), common::mailer(), member_name.clone(), password.clone(), format!("{member_name}@example.com"), common::APP_URL)
+        .await
+        .expect("member registration should succeed");
+    let member_id =
+        login_and_get_id(Arc::new(database.clone()), member_name.clone(), password.clone(), &config.jwt_secret).await;
+
+    for name in ["shared-room", "lonely-room"] {
+        create_room(Arc::new(database.clone()), RoomVisibility::Public, None, name.to_string(), owner_id, common::publisher())
+            .await
+            .expect("room creation should succeed");
+    }
+    let rooms = get_user_rooms_use(Arc::new(database.clone()), owner_id).await.expect("rooms should be listed");
+    let room_named = |name: &str| rooms.iter().find(|room| room.name == name).expect("room should exist").id;
+    let (shared_room, lonely_room) = (room_named("shared-room"), room_named("lonely-room"));
+
+    let mut notif = MockNotificationService::new();
+    notif.expect_send_room_member_notification().returning(|_| Ok(()));
+    let notif = Arc::new(notif);
+    join_room(Arc::new(database.clone()), shared_room, member_id, None, notif.clone(), Arc::new(membership_publisher()))
+        .await
+        .expect("join should work");
+    create_room(Arc::new(database.clone()), RoomVisibility::Public, None, "member-room".to_string(), member_id, common::publisher())
+        .await
+        .expect("room creation should succeed");
+    let member_room = get_user_rooms_use(Arc::new(database.clone()), member_id).await.unwrap().into_iter().find(|room| room.name == "member-room").unwrap().id;
+    join_room(Arc::new(database.clone()), member_room, owner_id, None, notif, common::publisher())
+        .await
+        .expect("join should work");
+    let posted = send_message(Arc::new(database.clone()), member_room, owner_id, "soon a tombstone".to_string(), common::publisher())
+        .await
+        .expect("members should post");
+
+    let mut publisher = MockMessagePublisher::new();
+    publisher
+        .expect_publish()
+        .withf(move |event| matches!(event, RoomEvent::RoleChanged(change) if change.room_id == shared_room && change.user_id == member_id && change.changed_by == owner_id))
+        .once()
+        .returning(|_| Ok(()));
+    publisher
+        .expect_publish()
+        .withf(move |event| matches!(event, RoomEvent::MessageDeleted(message) if message.id == posted.id && message.sender_id.is_none()))
+        .once()
+        .returning(|_| Ok(()));
+    for room_id in [shared_room, lonely_room, member_room] {
+        publisher
+            .expect_publish()
+            .withf(move |event| matches!(event, RoomEvent::Membership(change) if change.room_id == room_id && change.user_id == owner_id && change.action == MembershipAction::Left))
+            .once()
+            .returning(|_| Ok(()));
+    }
+    delete_account(Arc::new(database.clone()), owner_id, Uuid::new_v4(), chrono::Utc::now() + chrono::Duration::minutes(15), Arc::new(publisher))
+        .await
+        .expect("the account should be deleted");
+
+    assert!(database.get_user_by_id(owner_id).await.is_err());
+    assert!(database.is_access_token_revoked(Uuid::new_v4(), owner_id, chrono::Utc::now()).await.unwrap(), "tokens of deleted users should be refused");
+
+    let (created_by, role): (Uuid, String) = sqlx::query_as(
+        "SELECT r.created_by, m.role FROM rooms r JOIN room_members m ON m.room_id = r.id AND m.user_id = r.created_by WHERE r.id = $1",
+    )
+    .bind(shared_room)
+    .fetch_one(&pool)
+    .await
+    .expect("the shared room should remain");
+    assert_eq!(created_by, member_id);
+    assert_eq!(role, "owner");
+
+    let (created_by, archived): (Option<Uuid>, bool) = sqlx::query_as("SELECT created_by, archived_at IS NOT NULL FROM rooms WHERE id = $1")
+        .bind(lonely_room)
+        .fetch_one(&pool)
+        .await
+        .expect("the lonely room should be kept with its history");
+    assert_eq!((created_by, archived), (None, true));
+    assert!(database.get_room(lonely_room).await.is_err(), "archived rooms should not be found");
+
+    let members = obtain_room_members(Arc::new(database.clone()), member_room, member_id).await.unwrap();
+    assert_eq!(members.len(), 1);
+    let history = obtain_messages(Arc::new(database.clone()), member_room, member_id, MessageAnchor::Latest, 10).await.unwrap();
+    let tombstone = history.messages.iter().find(|message| message.id == posted.id).expect("the message should be kept deleted");
+    assert!(tombstone.deleted_at.is_some() && tombstone.sender_id.is_none() && tombstone.content.is_empty());
+
+    common::reset_tables(&pool).await;
+}
+
+#[tokio::test]
+#[serial]
+async fn owners_transfer_their_rooms_and_hand_them_over_when_leaving() {
+    let config = common::IntegrationConfig::load();
+    let (database, pool) = common::provision_database(&config).await;
+
+    let password = "Password123*".to_string();
+    let mut ids = Vec::new();
+    for prefix in ["owner-first-", "owner-second-", "owner-third-"] {
+        let name = common::unique_name(prefix);
+        register(Arc::new(database.clone()), common::mailer(), name.clone(), password.clone(), format!("{name}@example.com"), common::APP_URL)
+            .await
+            .expect("registration should succeed");
+        ids.push(database.get_user_by_username(name).await.expect("user should exist").id);
+    }
+    let [first_id, second_id, third_id] = ids[..] else { unreachable!() };
+
+    create_room(Arc::new(database.clone()), RoomVisibility::Public, None, "owner-room".to_string(), first_id, common::publisher())
+        .await
+        .expect("room creation should succeed");
+    let room_id = get_user_rooms_use(Arc::new(database.clone()), first_id).await.unwrap().first().unwrap().id;
+
+    let mut publisher = MockMessagePublisher::new();
+    publisher.expect_publish().returning(|_| Ok(()));
+    let publisher = Arc::new(publisher);
+    let mut notif = MockNotificationService::new();
+    notif.expect_send_room_member_notification().returning(|_| Ok(()));
+    let notif = Arc::new(notif);
+    for user_id in [second_id, third_id] {
+        join_room(Arc::new(database.clone()), room_id, user_id, None, notif.clone(), publisher.clone())
+            .await
+            .expect("joining should succeed");
+    }
+
+    let to_self = transfer_ownership(Arc::new(database.clone()), room_id, first_id, first_id, publisher.clone()).await;
+    assert!(matches!(to_self, Err(RoomError::AlreadyRoomOwner)), "got {to_self:?}");
+    let by_member = transfer_ownership(Arc::new(database.clone()), room_id, third_id, third_id, publisher.clone()).await;
+    assert!(matches!(by_member, Err(RoomError::InsufficientRole)), "got {by_member:?}");
+
+    let owner = transfer_ownership(Arc::new(database.clone()), room_id, first_id, third_id, publisher.clone())
+        .await
+        .expect("the owner should be able to hand the room over");
+    assert_eq!(owner.role, MemberRole::Owner);
+    assert_eq!(database.get_member_role(room_id, first_id).await.unwrap(), Some(MemberRole::Admin));
+    assert_eq!(database.get_room(room_id).await.unwrap().created_by, third_id);
+    // A transfer racing with this one finds its owner demoted and changes nothing
+    let stale = database.transfer_ownership(room_id, first_id, second_id).await.expect("the stale transfer should be answered");
+    assert!(stale.is_none());
+    assert_eq!(database.get_member_role(room_id, second_id).await.unwrap(), Some(MemberRole::Member));
+
+    leave_room(Arc::new(database.clone()), notif.clone(), publisher.clone(), room_id, third_id)
+        .await
+        .expect("the owner should be able to leave");
+    assert_eq!(database.get_member_role(room_id, third_id).await.unwrap(), None);
+    assert_eq!(database.get_member_role(room_id, first_id).await.unwrap(), Some(MemberRole::Owner), "the oldest member should inherit the room");
+    assert_eq!(database.get_room(room_id).await.unwrap().created_by, first_id);
+
+    leave_room(Arc::new(database.clone()), notif.clone(), publisher.clone(), room_id, first_id)
+        .await
+        .expect("the owner should be able to leave");
+    assert_eq!(database.get_member_role(room_id, second_id).await.unwrap(), Some(MemberRole::Owner));
+
+    leave_room(Arc::new(database.clone()), notif.clone(), publisher.clone(), room_id, second_id)
+        .await
+        .expect("the last member should be able to leave");
+    assert!(database.get_room(room_id).await.is_err(), "the empty room should be archived");
+    let listed = get_all_public_rooms(Arc::new(database.clone()), second_id).await.unwrap();
+    assert!(listed.iter().all(|room| room.id != room_id), "archived rooms should not be listed");
+    let archived: Option<chrono::DateTime<chrono::Utc>> = sqlx::query_scalar("SELECT archived_at FROM rooms WHERE id = $1")
+        .bind(room_id)
+        .fetch_one(&pool)
+        .await
+        .unwrap();
+    assert!(archived.is_some(), "the room should be kept with its history");
+    let rejoined = join_room(Arc::new(database.clone()), room_id, third_id, None, notif.clone(), publisher.clone()).await;
+    assert!(matches!(rejoined, Err(RoomError::RoomNotFound)), "got {rejoined:?}");
+
+    delete_account(Arc::new(database.clone()), second_id, Uuid::new_v4(), chrono::Utc::now() + chrono::Duration::minutes(15), publisher.clone())
+        .await
+        .expect("the last owner of an archived room should be able to delete the account");
+    let created_by: Option<Uuid> = sqlx::query_scalar("SELECT created_by FROM rooms WHERE id = $1")
+        .bind(room_id)
+        .fetch_one(&pool)
+        .await
+        .expect("the archived room should outlive its last owner");
+    assert_eq!(created_by, None);
+
+    common::reset_tables(&pool).await;
+}